@@ -0,0 +1,40 @@
+//! Self-contained history of one backed-up root (`snapback bundle`).
+//!
+//! A bundle is just another place to copy snapshots to: creating one copies
+//! the root's info record, manifests and referenced content into a
+//! [`BundleWriter`], and importing one copies them back out of a
+//! [`BundleReader`], skipping objects the repository already has.
+
+use std::{path::Path, sync::Arc};
+
+use crate::{
+    repository::{CopyOptions, CopyReport, Repository},
+    storage::{BundleReader, BundleWriter, Storage},
+};
+
+use super::{copy, delta, find_backup_info};
+
+pub(crate) fn create(repo: &Repository, root: &Path, output: &Path) -> anyhow::Result<CopyReport> {
+    let source = find_backup_info(repo, root)?;
+    let writer = Arc::new(BundleWriter::create(output)?);
+    let bundle = Repository::with_storage(repo.config().clone(), writer.clone());
+    let options = CopyOptions {
+        prefixes: vec![source.backup_prefix],
+        snapshots: None,
+    };
+    let report = copy::copy(repo, &bundle, &options)?;
+    writer.finish()?;
+    Ok(report)
+}
+
+pub(crate) fn import(repo: &Repository, bundle: &Path) -> anyhow::Result<CopyReport> {
+    let reader = Arc::new(BundleReader::open(bundle)?);
+    if reader.exists(delta::DELTA_KEY)? {
+        return Err(anyhow::anyhow!(
+            "{} only holds changes; apply it with `snapback apply-bundle`",
+            bundle.display()
+        ));
+    }
+    let bundle = Repository::with_storage(repo.config().clone(), reader);
+    copy::copy(&bundle, repo, &CopyOptions::default())
+}
@@ -0,0 +1,167 @@
+//! Replication between repositories (`snapback copy`).
+//!
+//! Content objects go first, then manifests in snapshot order, then the info
+//! record, so an interrupted copy never leaves the destination with a
+//! manifest whose content is missing. Running it again picks up where it
+//! stopped: manifests already present are skipped, and only objects the
+//! destination reports missing are transferred.
+
+use std::{collections::BTreeSet, fs, fs::File, io::Write};
+
+use crate::{
+    repository::{CopiedSource, CopyOptions, CopyReport, Repository},
+    snapshot::{BackupInfo, Snapshot},
+    storage::{self, Storage},
+    util,
+};
+
+use super::get_backup_files_by_prefix;
+
+/// How many content keys are checked against the destination per round trip.
+const WANT_BATCH: usize = 1000;
+
+pub(crate) fn copy(
+    from: &Repository,
+    to: &Repository,
+    options: &CopyOptions,
+) -> anyhow::Result<CopyReport> {
+    let source = from.storage();
+    let destination = to.storage();
+    let destination_infos = BackupInfo::read_all(destination)?;
+
+    let mut infos = BackupInfo::read_all(source)?;
+    if !options.prefixes.is_empty() {
+        for prefix in &options.prefixes {
+            if !infos.iter().any(|(info, _)| &info.backup_prefix == prefix) {
+                return Err(anyhow::anyhow!("No backup with prefix {} to copy", prefix));
+            }
+        }
+        infos.retain(|(info, _)| options.prefixes.contains(&info.backup_prefix));
+    }
+    infos.sort_by(|(a, _), (b, _)| a.path_to_root.cmp(&b.path_to_root));
+
+    // Every snapshot only records changes, so the ones before the last
+    // selected snapshot are needed to restore it.
+    let up_to = options
+        .snapshots
+        .as_ref()
+        .map(|numbers| numbers.iter().copied().max().unwrap_or(0));
+
+    let mut report = CopyReport::default();
+    for (info, _) in infos {
+        let existing = destination_infos
+            .iter()
+            .find(|(other, _)| other.path_to_root == info.path_to_root);
+        if let Some((other, _)) =
+            existing.filter(|(other, _)| other.backup_prefix != info.backup_prefix)
+        {
+            return Err(anyhow::anyhow!(
+                "Destination already backs up {} under prefix {}, not {}",
+                info.path_to_root.display(),
+                other.backup_prefix,
+                info.backup_prefix
+            ));
+        }
+
+        let mut copied = CopiedSource {
+            source: info.clone(),
+            snapshots: Vec::new(),
+        };
+        for (number, key) in get_backup_files_by_prefix(source, &info.backup_prefix)? {
+            if up_to.is_some_and(|up_to| number > up_to) {
+                continue;
+            }
+            let manifest = source.get(&key)?;
+            if destination.exists(&key)? {
+                if destination.get(&key)? != manifest {
+                    return Err(anyhow::anyhow!(
+                        "Snapshot {} differs between the repositories; refusing to overwrite it",
+                        key
+                    ));
+                }
+                continue;
+            }
+
+            let snapshot = Snapshot::read(source, &key, number)?;
+            let content: BTreeSet<String> = snapshot
+                .entries
+                .into_iter()
+                .filter_map(|entry| entry.content_path)
+                .filter(|content| !content.is_empty())
+                .collect();
+            copy_objects(source, destination, content, &mut report)?;
+
+            destination.put(&key, &manifest)?;
+            copied.snapshots.push(number);
+        }
+
+        // Also repairs a destination where an interrupted copy stored the
+        // manifests but not the info record.
+        let info_missing = existing.is_none()
+            && !get_backup_files_by_prefix(destination, &info.backup_prefix)?.is_empty();
+        if info_missing || !copied.snapshots.is_empty() {
+            let info_key = existing
+                .map(|(_, key)| key.clone())
+                .unwrap_or_else(|| storage::info_key(&info.backup_prefix));
+            destination.put(&info_key, serde_json::to_string_pretty(&info)?.as_bytes())?;
+        }
+        report.sources.push(copied);
+    }
+
+    Ok(report)
+}
+
+/// Transfers the objects in `keys` that `destination` does not have yet.
+fn copy_objects(
+    source: &dyn Storage,
+    destination: &dyn Storage,
+    keys: BTreeSet<String>,
+    report: &mut CopyReport,
+) -> anyhow::Result<()> {
+    let keys: Vec<String> = keys.into_iter().collect();
+    for batch in keys.chunks(WANT_BATCH) {
+        let missing = destination.missing(batch)?;
+        report.objects_present += batch.len() - missing.len();
+        for key in missing {
+            report.bytes_copied += copy_object(source, destination, &key)?;
+            report.objects_copied += 1;
+        }
+    }
+    Ok(())
+}
+
+/// Copies one content object, checking on the way that its bytes still match
+/// the hash in its key.
+pub(super) fn copy_object(
+    source: &dyn Storage,
+    destination: &dyn Storage,
+    key: &str,
+) -> anyhow::Result<u64> {
+    let staging_dir = destination.staging_dir();
+    fs::create_dir_all(&staging_dir)?;
+    let temp_path = util::atomic::temp_path_in(&staging_dir);
+
+    let result = (|| {
+        let mut temp = File::create(&temp_path)?;
+        let reader = source
+            .reader(key)
+            .map_err(|e| anyhow::anyhow!("Cannot read {} from the source: {:#}", key, e))?;
+        let (hash, size) = util::hash::hash_reader_with(reader, |chunk| temp.write_all(chunk))?;
+        drop(temp);
+
+        if storage::content_key(&hash) != key {
+            return Err(anyhow::anyhow!(
+                "Content object {} is corrupt in the source repository (its hash is {})",
+                key,
+                hash
+            ));
+        }
+        destination.put_file(key, &temp_path)?;
+        Ok(size)
+    })();
+
+    if result.is_err() {
+        let _ = fs::remove_file(&temp_path);
+    }
+    result
+}
@@ -0,0 +1,422 @@
+//! Changes between two snapshots, packed for offline transfer
+//! (`snapback delta-bundle` and `snapback apply-bundle`).
+//!
+//! A delta bundle is a bundle (see `storage::bundle`) holding the manifests
+//! after `from` up to `to`, the info record, and the content objects those
+//! manifests reference that the `from` state does not already contain. A
+//! `delta.json` entry describes the `from` state as a map of relative paths
+//! to content hashes. Before anything is applied, the target, either a
+//! repository or a working tree, must reproduce that map exactly.
+
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
+    fs::{self, File},
+    io::Write,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use serde::{Deserialize, Serialize};
+use walkdir::WalkDir;
+
+use crate::{
+    config::Config,
+    repository::{Change, ChangeKind, DeltaReport, Repository},
+    snapshot::{BackupInfo, Snapshot, SnapshotEntry},
+    storage::{self, BundleReader, BundleWriter, Storage},
+    util,
+};
+
+use super::{
+    copy, export::archive_name, find_backup_info, get_backup_files_by_prefix, latest_entries,
+    replay,
+};
+
+/// Bundle entry that makes a bundle a delta bundle.
+pub(crate) const DELTA_KEY: &str = "delta.json";
+
+#[derive(Serialize, Deserialize)]
+struct DeltaHeader {
+    source: BackupInfo,
+    from: u32,
+    to: u32,
+    /// Hash of every file at `from`, by path relative to the root.
+    base: BTreeMap<String, String>,
+}
+
+pub(crate) fn create(
+    repo: &Repository,
+    root: &Path,
+    from: u32,
+    to: u32,
+    output: &Path,
+) -> anyhow::Result<DeltaReport> {
+    if from >= to {
+        return Err(anyhow::anyhow!(
+            "Backup #{} must come before backup #{}",
+            from,
+            to
+        ));
+    }
+    let storage = repo.storage();
+    let source = find_backup_info(repo, root)?;
+    let manifests = get_backup_files_by_prefix(storage, &source.backup_prefix)?;
+    for number in [from, to] {
+        if !manifests.iter().any(|(n, _)| *n == number) {
+            return Err(anyhow::anyhow!(
+                "Backup #{} of {} does not exist",
+                number,
+                root.display()
+            ));
+        }
+    }
+
+    let base_files = replay(storage, &source.backup_prefix, from)?.files;
+    let base = relative_hashes(&source, &base_files);
+    let to_files = replay(storage, &source.backup_prefix, to)?.files;
+    let changes = changes(&source, &base, &to_files);
+
+    // Objects the `from` state holds are already on the other side, either
+    // in the repository or as files in the working tree.
+    let present: HashSet<&str> = base_files
+        .iter()
+        .filter_map(|entry| entry.content_path.as_deref())
+        .collect();
+    let mut objects = BTreeSet::new();
+    let mut added = Vec::new();
+    for (number, key) in manifests.into_iter().filter(|(n, _)| *n > from && *n <= to) {
+        let snapshot = Snapshot::read(storage, &key, number)?;
+        objects.extend(
+            snapshot
+                .entries
+                .into_iter()
+                .filter_map(|entry| entry.content_path)
+                .filter(|content| !content.is_empty() && !present.contains(content.as_str())),
+        );
+        added.push(key);
+    }
+
+    let writer = Arc::new(BundleWriter::create(output)?);
+    let header = DeltaHeader {
+        source: source.clone(),
+        from,
+        to,
+        base,
+    };
+    writer.put(DELTA_KEY, &serde_json::to_vec_pretty(&header)?)?;
+
+    let mut report = DeltaReport {
+        source: source.clone(),
+        from,
+        to,
+        objects: 0,
+        bytes: 0,
+        changes,
+    };
+    for key in &objects {
+        report.bytes += copy::copy_object(storage, writer.as_ref(), key)?;
+        report.objects += 1;
+    }
+    for key in &added {
+        writer.put(key, &storage.get(key)?)?;
+    }
+    writer.put(
+        &storage::info_key(&source.backup_prefix),
+        serde_json::to_string_pretty(&source)?.as_bytes(),
+    )?;
+    writer.finish()?;
+    Ok(report)
+}
+
+/// Adds the bundle's snapshots to `repo`, which must hold the root at
+/// exactly the bundle's `from` state.
+pub(crate) fn apply_to_repository(repo: &Repository, path: &Path) -> anyhow::Result<DeltaReport> {
+    let (bundle, header) = open(path)?;
+    let storage = repo.storage();
+    let prefix = &header.source.backup_prefix;
+
+    let has_from = get_backup_files_by_prefix(storage, prefix)?
+        .iter()
+        .any(|(n, _)| *n == header.from);
+    if !has_from {
+        return Err(anyhow::anyhow!(
+            "The repository has no backup #{} of {} to apply the bundle to",
+            header.from,
+            header.source.path_to_root.display()
+        ));
+    }
+    let base_files = replay(storage, prefix, header.from)?.files;
+    verify_base(
+        &header,
+        &relative_hashes(&header.source, &base_files),
+        "the repository",
+    )?;
+
+    let bundle = Repository::with_storage(repo.config().clone(), bundle);
+    let copied = copy::copy(&bundle, repo, &Default::default())?;
+    let to_files = replay(storage, prefix, header.to)?.files;
+    Ok(DeltaReport {
+        changes: changes(&header.source, &header.base, &to_files),
+        source: header.source,
+        from: header.from,
+        to: header.to,
+        objects: copied.objects_copied,
+        bytes: copied.bytes_copied,
+    })
+}
+
+/// Brings the working tree `dir`, which must hold exactly the bundle's
+/// `from` state, to its `to` state. Files matching `config`'s exclude
+/// patterns are ignored, as they are when backing up.
+pub(crate) fn apply_to_tree(
+    path: &Path,
+    dir: &Path,
+    config: &Config,
+) -> anyhow::Result<DeltaReport> {
+    let (bundle, header) = open(path)?;
+    verify_base(
+        &header,
+        &tree_hashes(dir, config)?,
+        &dir.display().to_string(),
+    )?;
+
+    let mut entries = Vec::new();
+    for (number, key) in get_backup_files_by_prefix(bundle.as_ref(), &header.source.backup_prefix)?
+    {
+        entries.extend(Snapshot::read(bundle.as_ref(), &key, number)?.entries);
+    }
+    let mut latest: Vec<SnapshotEntry> = latest_entries(entries).into_values().collect();
+    latest.sort_by(|a, b| a.path.cmp(&b.path));
+
+    // Where each hash can be found in the tree, for content the bundle
+    // leaves out because the `from` state already has it.
+    let in_tree: HashMap<&str, &str> = header
+        .base
+        .iter()
+        .map(|(name, hash)| (hash.as_str(), name.as_str()))
+        .collect();
+
+    let mut report = DeltaReport {
+        source: header.source.clone(),
+        from: header.from,
+        to: header.to,
+        objects: 0,
+        bytes: 0,
+        changes: Vec::new(),
+    };
+    // Everything is staged next to its target first, so files that are about
+    // to be replaced or removed can still serve as sources.
+    let mut staged: Vec<(PathBuf, PathBuf)> = Vec::new();
+    let mut removed = Vec::new();
+    let result = (|| {
+        for entry in &latest {
+            let name = archive_name(&header.source, entry);
+            let current = header.base.get(&name);
+            if entry.deleted {
+                if current.is_some() {
+                    removed.push(dir.join(&name));
+                    report
+                        .changes
+                        .push(Change::new(&entry.path, ChangeKind::Deleted));
+                }
+                continue;
+            }
+            if current == Some(&entry.hash) {
+                continue;
+            }
+
+            let target = dir.join(&name);
+            let parent = target.parent().unwrap_or(dir);
+            fs::create_dir_all(parent)?;
+            let temp = util::atomic::temp_path_in(parent);
+            staged.push((temp.clone(), target));
+            let size = stage(&bundle, dir, &in_tree, entry, &temp)
+                .map_err(|e| anyhow::anyhow!("Cannot apply {}: {:#}", name, e))?;
+            report.objects += 1;
+            report.bytes += size;
+            let kind = if current.is_some() {
+                ChangeKind::Modified
+            } else {
+                ChangeKind::Added
+            };
+            report.changes.push(Change::new(&entry.path, kind));
+        }
+        Ok::<_, anyhow::Error>(())
+    })();
+    if let Err(e) = result {
+        for (temp, _) in &staged {
+            let _ = fs::remove_file(temp);
+        }
+        return Err(e);
+    }
+
+    for path in removed {
+        fs::remove_file(&path)
+            .map_err(|e| anyhow::anyhow!("Cannot remove {}: {}", path.display(), e))?;
+    }
+    for (temp, target) in staged {
+        fs::rename(&temp, &target)
+            .map_err(|e| anyhow::anyhow!("Cannot write {}: {}", target.display(), e))?;
+    }
+    Ok(report)
+}
+
+/// Writes the content of `entry` to `temp`, from the bundle or from the tree
+/// file that already has it, and checks it against the entry's hash.
+fn stage(
+    bundle: &BundleReader,
+    dir: &Path,
+    in_tree: &HashMap<&str, &str>,
+    entry: &SnapshotEntry,
+    temp: &Path,
+) -> anyhow::Result<u64> {
+    let content = entry
+        .content_path
+        .as_deref()
+        .filter(|key| !key.is_empty())
+        .ok_or_else(|| anyhow::anyhow!("no content was stored for this file"))?;
+    let source: Box<dyn std::io::Read + '_> = if bundle.exists(content)? {
+        bundle.reader(content)?
+    } else if let Some(name) = in_tree.get(entry.hash.as_str()) {
+        Box::new(File::open(dir.join(name))?)
+    } else {
+        return Err(anyhow::anyhow!("the bundle does not contain {}", content));
+    };
+
+    let mut file = File::create(temp)?;
+    let (hash, size) = util::hash::hash_reader_with(source, |chunk| file.write_all(chunk))?;
+    if hash != entry.hash {
+        return Err(anyhow::anyhow!(
+            "content does not match its recorded hash {}",
+            entry.hash
+        ));
+    }
+    Ok(size)
+}
+
+fn open(path: &Path) -> anyhow::Result<(Arc<BundleReader>, DeltaHeader)> {
+    let bundle = BundleReader::open(path)?;
+    if !bundle.exists(DELTA_KEY)? {
+        return Err(anyhow::anyhow!(
+            "{} holds a full history, not changes; load it with `snapback bundle import`",
+            path.display()
+        ));
+    }
+    let header: DeltaHeader = serde_json::from_slice(&bundle.get(DELTA_KEY)?)?;
+    Ok((Arc::new(bundle), header))
+}
+
+/// Fails unless `actual` is exactly the bundle's `from` state.
+fn verify_base(
+    header: &DeltaHeader,
+    actual: &BTreeMap<String, String>,
+    target: &str,
+) -> anyhow::Result<()> {
+    let mismatch = header
+        .base
+        .iter()
+        .find(|(name, hash)| actual.get(*name) != Some(hash))
+        .map(|(name, _)| name)
+        .or_else(|| actual.keys().find(|name| !header.base.contains_key(*name)));
+    match mismatch {
+        Some(name) => Err(anyhow::anyhow!(
+            "{} is not at backup #{}: {} differs; nothing was applied",
+            target,
+            header.from,
+            name
+        )),
+        None => Ok(()),
+    }
+}
+
+fn relative_hashes(source: &BackupInfo, files: &[SnapshotEntry]) -> BTreeMap<String, String> {
+    files
+        .iter()
+        .map(|entry| (archive_name(source, entry), entry.hash.clone()))
+        .collect()
+}
+
+/// Hashes every file under `dir` that a backup would include.
+fn tree_hashes(dir: &Path, config: &Config) -> anyhow::Result<BTreeMap<String, String>> {
+    let mut hashes = BTreeMap::new();
+    for entry in WalkDir::new(dir) {
+        let entry = entry?;
+        if !entry.path().is_file() {
+            continue;
+        }
+        let relative = entry.path().strip_prefix(dir).unwrap_or(entry.path());
+        if config.should_exclude(relative) {
+            continue;
+        }
+        let (hash, _) = util::hash::hash_reader_with(File::open(entry.path())?, |_| Ok(()))?;
+        let name: Vec<_> = relative
+            .components()
+            .map(|c| c.as_os_str().to_string_lossy())
+            .collect();
+        hashes.insert(name.join("/"), hash);
+    }
+    Ok(hashes)
+}
+
+/// File-level differences between the `from` state and `to_files`.
+fn changes(
+    source: &BackupInfo,
+    base: &BTreeMap<String, String>,
+    to_files: &[SnapshotEntry],
+) -> Vec<Change> {
+    let mut changes = Vec::new();
+    let mut remaining = base.clone();
+    for entry in to_files {
+        match remaining.remove(&archive_name(source, entry)) {
+            Some(hash) if hash == entry.hash => {}
+            Some(_) => changes.push(Change::new(&entry.path, ChangeKind::Modified)),
+            None => changes.push(Change::new(&entry.path, ChangeKind::Added)),
+        }
+    }
+    for name in remaining.keys() {
+        let path = source.path_to_root.join(name);
+        changes.push(Change::new(&path.to_string_lossy(), ChangeKind::Deleted));
+    }
+    changes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hashes(files: &[(&str, &str)]) -> BTreeMap<String, String> {
+        files
+            .iter()
+            .map(|(name, hash)| (name.to_string(), hash.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn the_base_must_match_file_for_file() {
+        let header = DeltaHeader {
+            source: BackupInfo {
+                timestamp: chrono::Utc::now(),
+                path_to_root: PathBuf::from("/project"),
+                backup_prefix: "project".to_string(),
+            },
+            from: 4,
+            to: 7,
+            base: hashes(&[("a.txt", "1"), ("dir/b.txt", "2")]),
+        };
+        let check = |files| verify_base(&header, &hashes(files), "tree").map_err(|e| e.to_string());
+
+        assert!(check(&[("a.txt", "1"), ("dir/b.txt", "2")]).is_ok());
+        assert_eq!(
+            check(&[("a.txt", "1"), ("dir/b.txt", "3")]),
+            Err("tree is not at backup #4: dir/b.txt differs; nothing was applied".to_string())
+        );
+        assert!(check(&[("a.txt", "1")])
+            .unwrap_err()
+            .contains("dir/b.txt differs"));
+        assert!(
+            check(&[("a.txt", "1"), ("dir/b.txt", "2"), ("new.txt", "4")])
+                .unwrap_err()
+                .contains("new.txt differs")
+        );
+    }
+}
@@ -0,0 +1,206 @@
+//! Point-in-time archives of a backed-up root (`snapback export`).
+//!
+//! The snapshot is replayed from its manifests and every file is streamed
+//! from the content store straight into the archive, so nothing is written
+//! to disk besides the archive itself. Archive paths are relative to the
+//! root; permissions and modification times come from the manifest entry
+//! when they were recorded.
+
+use std::{
+    io::{self, Read, Seek, Write},
+    path::{Component, Path},
+};
+
+use chrono::{DateTime, Datelike, Timelike, Utc};
+use flate2::{write::GzEncoder, Compression};
+use zip::{write::SimpleFileOptions, CompressionMethod, ZipWriter};
+
+use crate::{
+    repository::{ExportReport, Repository},
+    snapshot::{BackupInfo, SnapshotEntry},
+    storage::Storage,
+};
+
+use super::{find_backup_info, get_backup_files_by_prefix, replay};
+
+/// Permissions given to files whose mode was not recorded.
+const DEFAULT_MODE: u32 = 0o644;
+
+/// Entries at least this large need zip64 headers.
+const ZIP64_THRESHOLD: u64 = u32::MAX as u64;
+
+pub(crate) fn export_tar(
+    repo: &Repository,
+    root: &Path,
+    number: u32,
+    gzip: bool,
+    out: impl Write,
+) -> anyhow::Result<ExportReport> {
+    if gzip {
+        let mut encoder = GzEncoder::new(out, Compression::default());
+        let report = write_tar(repo, root, number, &mut encoder)?;
+        encoder.finish()?;
+        Ok(report)
+    } else {
+        write_tar(repo, root, number, out)
+    }
+}
+
+fn write_tar(
+    repo: &Repository,
+    root: &Path,
+    number: u32,
+    out: impl Write,
+) -> anyhow::Result<ExportReport> {
+    let storage = repo.storage();
+    let mut builder = tar::Builder::new(out);
+    let report = for_each_file(repo, root, number, |name, entry| {
+        let mut header = tar::Header::new_gnu();
+        header.set_entry_type(tar::EntryType::Regular);
+        header.set_size(entry.size);
+        header.set_mode(entry.mode.unwrap_or(DEFAULT_MODE));
+        header.set_mtime(file_time(entry).timestamp().max(0) as u64);
+        builder.append_data(&mut header, name, content(storage, entry)?)?;
+        Ok(())
+    })?;
+    builder.into_inner()?.flush()?;
+    Ok(report)
+}
+
+pub(crate) fn export_zip(
+    repo: &Repository,
+    root: &Path,
+    number: u32,
+    out: impl Write + Seek,
+) -> anyhow::Result<ExportReport> {
+    let storage = repo.storage();
+    let mut zip = ZipWriter::new(out);
+    let report = for_each_file(repo, root, number, |name, entry| {
+        let options = SimpleFileOptions::default()
+            .compression_method(CompressionMethod::Deflated)
+            .unix_permissions(entry.mode.unwrap_or(DEFAULT_MODE))
+            .last_modified_time(zip_time(file_time(entry)))
+            .large_file(entry.size >= ZIP64_THRESHOLD);
+        zip.start_file(name, options)?;
+        io::copy(&mut content(storage, entry)?, &mut zip)?;
+        Ok(())
+    })?;
+    zip.finish()?.flush()?;
+    Ok(report)
+}
+
+/// Calls `add` with the archive name of every file in snapshot `number` of
+/// `root`, in path order.
+fn for_each_file(
+    repo: &Repository,
+    root: &Path,
+    number: u32,
+    mut add: impl FnMut(&str, &SnapshotEntry) -> anyhow::Result<()>,
+) -> anyhow::Result<ExportReport> {
+    let source = find_backup_info(repo, root)?;
+    let exists = get_backup_files_by_prefix(repo.storage(), &source.backup_prefix)?
+        .iter()
+        .any(|(n, _)| *n == number);
+    if !exists {
+        return Err(anyhow::anyhow!(
+            "Backup #{} of {} does not exist",
+            number,
+            source.path_to_root.display()
+        ));
+    }
+    let state = replay(repo.storage(), &source.backup_prefix, number)?;
+
+    let mut report = ExportReport {
+        source: source.clone(),
+        snapshot: number,
+        files: 0,
+        bytes: 0,
+        not_backed_up: state.not_backed_up,
+    };
+    for entry in &state.files {
+        let name = archive_name(&source, entry);
+        add(&name, entry).map_err(|e| anyhow::anyhow!("Cannot export {}: {:#}", entry.path, e))?;
+        report.files += 1;
+        report.bytes += entry.size;
+    }
+    Ok(report)
+}
+
+/// Path of `entry` inside the archive: relative to the root, with `/`
+/// separators.
+pub(super) fn archive_name(source: &BackupInfo, entry: &SnapshotEntry) -> String {
+    let path = Path::new(&entry.path);
+    let relative = path.strip_prefix(&source.path_to_root).unwrap_or(path);
+    relative
+        .components()
+        .filter_map(|component| match component {
+            Component::Normal(part) => Some(part.to_string_lossy()),
+            _ => None,
+        })
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+/// Streams the stored content of `entry`, failing if it does not have the
+/// recorded size, since tar headers are written before the data.
+fn content<'a>(storage: &'a dyn Storage, entry: &SnapshotEntry) -> anyhow::Result<impl Read + 'a> {
+    let key = entry
+        .content_path
+        .as_deref()
+        .filter(|key| !key.is_empty())
+        .ok_or_else(|| anyhow::anyhow!("no content was stored for this file"))?;
+    if !storage.exists(key)? {
+        return Err(anyhow::anyhow!(
+            "content object {} is missing from the repository",
+            key
+        ));
+    }
+    Ok(ExactReader {
+        inner: storage.reader(key)?,
+        remaining: entry.size,
+    })
+}
+
+fn file_time(entry: &SnapshotEntry) -> DateTime<Utc> {
+    entry.mtime.unwrap_or(entry.modify_time)
+}
+
+/// Zip timestamps have no time zone and only cover 1980 to 2107; times
+/// outside that range become the zip epoch.
+fn zip_time(time: DateTime<Utc>) -> zip::DateTime {
+    zip::DateTime::from_date_and_time(
+        time.year().clamp(0, u16::MAX as i32) as u16,
+        time.month() as u8,
+        time.day() as u8,
+        time.hour() as u8,
+        time.minute() as u8,
+        time.second() as u8,
+    )
+    .unwrap_or_default()
+}
+
+/// Yields exactly `remaining` bytes of `inner`, or an error.
+struct ExactReader<R> {
+    inner: R,
+    remaining: u64,
+}
+
+impl<R: Read> Read for ExactReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.remaining == 0 {
+            return Ok(0);
+        }
+        let limit = buf
+            .len()
+            .min(self.remaining.min(usize::MAX as u64) as usize);
+        let read = self.inner.read(&mut buf[..limit])?;
+        if read == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                format!("stored content is {} bytes short", self.remaining),
+            ));
+        }
+        self.remaining -= read as u64;
+        Ok(read)
+    }
+}
@@ -0,0 +1,205 @@
+//! Snapshot history as a git repository (`snapback export-git`).
+//!
+//! Every `backup_N.json` of a root becomes one commit on a branch: changed
+//! files are written as blobs, deletions remove them from the tree, and the
+//! commit carries the snapshot's time. The last line of each message names
+//! the backup it came from, so running the export again continues after the
+//! last exported snapshot. Blobs git already has are not stored twice.
+
+use std::{io, path::Path};
+
+use git2::{build::CheckoutBuilder, ErrorCode, FileMode, Oid, Signature, Time};
+
+use crate::{
+    repository::{GitExportOptions, GitExportReport, Repository},
+    snapshot::{BackupInfo, Snapshot, SnapshotEntry},
+    storage::Storage,
+};
+
+use super::{export::archive_name, find_backup_info, get_backup_files_by_prefix};
+
+/// Trailer that records which backup a commit was made from.
+const TRAILER: &str = "Snapback-Backup";
+
+pub(crate) fn export(
+    repo: &Repository,
+    root: &Path,
+    dir: &Path,
+    options: &GitExportOptions,
+) -> anyhow::Result<GitExportReport> {
+    let storage = repo.storage();
+    let source = find_backup_info(repo, root)?;
+    let git = open_or_init(dir, &options.branch)?;
+    let refname = format!("refs/heads/{}", options.branch);
+
+    let mut parent = match git.find_reference(&refname) {
+        Ok(reference) => Some(reference.peel_to_commit()?),
+        Err(e) if e.code() == ErrorCode::NotFound => None,
+        Err(e) => return Err(e.into()),
+    };
+    let last = match &parent {
+        Some(commit) => Some(last_exported(commit, &source, &options.branch)?),
+        None => None,
+    };
+
+    // A checked-out branch is brought up to date afterwards, which must not
+    // throw away anyone's edits.
+    let checked_out = !git.is_bare()
+        && git
+            .find_reference("HEAD")?
+            .symbolic_target()
+            .is_some_and(|target| target == refname);
+    if checked_out && parent.is_some() && !is_clean(&git)? {
+        return Err(anyhow::anyhow!(
+            "{} has local changes on {}; commit or discard them first",
+            dir.display(),
+            options.branch
+        ));
+    }
+
+    let mut report = GitExportReport {
+        source: source.clone(),
+        branch: options.branch.clone(),
+        exported: Vec::new(),
+        head: parent.as_ref().map(|commit| commit.id().to_string()),
+    };
+    for (number, key) in get_backup_files_by_prefix(storage, &source.backup_prefix)? {
+        if last.is_some_and(|last| number <= last) {
+            continue;
+        }
+        let snapshot = Snapshot::read(storage, &key, number)?;
+        let baseline = match &parent {
+            Some(commit) => commit.tree()?,
+            None => git.find_tree(git.treebuilder(None)?.write()?)?,
+        };
+
+        let mut update = git2::build::TreeUpdateBuilder::new();
+        for entry in &snapshot.entries {
+            let name = archive_name(&source, entry);
+            if entry.deleted {
+                if baseline.get_path(Path::new(&name)).is_ok() {
+                    update.remove(name.as_str());
+                }
+            } else {
+                let blob = write_blob(&git, storage, entry)
+                    .map_err(|e| anyhow::anyhow!("Cannot export {}: {:#}", entry.path, e))?;
+                update.upsert(name.as_str(), blob, file_mode(entry));
+            }
+        }
+        let tree = git.find_tree(update.create_updated(&git, &baseline)?)?;
+
+        let time = Time::new(snapshot.timestamp.timestamp(), 0);
+        let signature = Signature::new("snapback", "snapback@localhost", &time)?;
+        let parents: Vec<_> = parent.iter().collect();
+        let id = git.commit(
+            Some(&refname),
+            &signature,
+            &signature,
+            &message(&source, &snapshot),
+            &tree,
+            &parents,
+        )?;
+        parent = Some(git.find_commit(id)?);
+        report.exported.push(number);
+        report.head = Some(id.to_string());
+    }
+
+    if checked_out && !report.exported.is_empty() {
+        git.checkout_head(Some(CheckoutBuilder::new().force()))?;
+    }
+    Ok(report)
+}
+
+fn open_or_init(dir: &Path, branch: &str) -> anyhow::Result<git2::Repository> {
+    match git2::Repository::open(dir) {
+        Ok(git) => Ok(git),
+        Err(e) if e.code() == ErrorCode::NotFound => Ok(git2::Repository::init_opts(
+            dir,
+            git2::RepositoryInitOptions::new().initial_head(branch),
+        )?),
+        Err(e) => Err(anyhow::anyhow!(
+            "Cannot open git repository {}: {}",
+            dir.display(),
+            e
+        )),
+    }
+}
+
+/// Finds the backup the branch tip was exported from.
+fn last_exported(
+    commit: &git2::Commit<'_>,
+    source: &BackupInfo,
+    branch: &str,
+) -> anyhow::Result<u32> {
+    let trailer = commit
+        .message()
+        .unwrap_or_default()
+        .lines()
+        .find_map(|line| {
+            let value = line.strip_prefix(TRAILER)?.strip_prefix(':')?.trim();
+            let (prefix, number) = value.rsplit_once('#')?;
+            Some((prefix.to_string(), number.parse::<u32>().ok()?))
+        });
+    match trailer {
+        Some((prefix, number)) if prefix == source.backup_prefix => Ok(number),
+        Some((prefix, _)) => Err(anyhow::anyhow!(
+            "Branch {} holds backups of {}, not {}; choose another with --branch",
+            branch,
+            prefix,
+            source.backup_prefix
+        )),
+        None => Err(anyhow::anyhow!(
+            "Branch {} has commits that were not exported by snapback; choose another with --branch",
+            branch
+        )),
+    }
+}
+
+fn is_clean(git: &git2::Repository) -> anyhow::Result<bool> {
+    let mut options = git2::StatusOptions::new();
+    options.include_untracked(false).include_ignored(false);
+    Ok(git.statuses(Some(&mut options))?.is_empty())
+}
+
+fn write_blob(
+    git: &git2::Repository,
+    storage: &dyn Storage,
+    entry: &SnapshotEntry,
+) -> anyhow::Result<Oid> {
+    let key = entry
+        .content_path
+        .as_deref()
+        .filter(|key| !key.is_empty())
+        .ok_or_else(|| anyhow::anyhow!("no content was stored for this file"))?;
+    let mut writer = git.blob_writer(None)?;
+    io::copy(&mut storage.reader(key)?, &mut writer)?;
+    Ok(writer.commit()?)
+}
+
+fn file_mode(entry: &SnapshotEntry) -> FileMode {
+    match entry.mode {
+        Some(mode) if mode & 0o111 != 0 => FileMode::BlobExecutable,
+        _ => FileMode::Blob,
+    }
+}
+
+fn message(source: &BackupInfo, snapshot: &Snapshot) -> String {
+    let mut message = format!(
+        "Backup #{} of {}\n\n{} changes, {} deletions\n",
+        snapshot.number,
+        source.path_to_root.display(),
+        snapshot.changes(),
+        snapshot.deletions()
+    );
+    if snapshot.is_partial() {
+        message.push_str("\nThese files could not be backed up and keep their previous version:\n");
+        for failure in &snapshot.failures {
+            message.push_str(&format!("  {}: {}\n", failure.path, failure.error));
+        }
+    }
+    message.push_str(&format!(
+        "\n{}: {}#{}\n",
+        TRAILER, source.backup_prefix, snapshot.number
+    ));
+    message
+}
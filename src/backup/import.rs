@@ -0,0 +1,217 @@
+//! Snapshots made from existing archives (`snapback import`).
+//!
+//! Every regular file in the archive is hashed and stored like a scanned
+//! file, placed under the root it is imported as, and the result is diffed
+//! against the root's latest state, exactly as `create` does for a directory
+//! tree. The new snapshot is appended to the root's chain with the time the
+//! archive was taken.
+
+use std::{
+    collections::BTreeMap,
+    fs::{self, File},
+    io::{BufReader, Read},
+    path::{Component, Path, PathBuf},
+};
+
+use chrono::{DateTime, NaiveDate, Utc};
+use flate2::read::GzDecoder;
+
+use crate::{
+    repository::{ArchiveFormat, CreateReport, ImportOptions, Repository},
+    snapshot::{BackupInfo, Snapshot},
+};
+
+use super::{
+    diff_entries, generate_prefix, get_backup, get_backup_files_by_prefix,
+    pipeline::{self, ScannedFile},
+    read_entries, Backup,
+};
+
+pub(crate) fn import(
+    repo: &Repository,
+    archive: &Path,
+    root: &Path,
+    options: &ImportOptions,
+) -> anyhow::Result<CreateReport> {
+    let format = options
+        .format
+        .or_else(|| ArchiveFormat::from_path(archive))
+        .ok_or_else(|| {
+            anyhow::anyhow!("Cannot tell the archive format of {}", archive.display())
+        })?;
+    let storage = repo.storage();
+    let prefix = generate_prefix(repo, root)?;
+
+    // Snapshots are replayed in number order but files are matched by time,
+    // so an older archive cannot follow a newer snapshot.
+    let manifests = get_backup_files_by_prefix(storage, &prefix)?;
+    if let Some((number, key)) = manifests.last() {
+        let latest = Snapshot::read(storage, key, *number)?;
+        if latest.timestamp > options.time {
+            return Err(anyhow::anyhow!(
+                "Backup #{} of {} was taken at {}, after {}; import archives oldest first",
+                number,
+                root.display(),
+                latest.timestamp.format("%Y-%m-%d %H:%M:%S UTC"),
+                options.time.format("%Y-%m-%d %H:%M:%S UTC")
+            ));
+        }
+    }
+
+    let staging_dir = storage.staging_dir();
+    fs::create_dir_all(&staging_dir)?;
+    let mut files = ArchiveFiles {
+        repo,
+        root,
+        staging_dir: &staging_dir,
+        strip_components: options.strip_components,
+        files: BTreeMap::new(),
+    };
+    let file = File::open(archive)
+        .map_err(|e| anyhow::anyhow!("Cannot open {}: {}", archive.display(), e))?;
+    match format {
+        ArchiveFormat::Tar => files.read_tar(BufReader::new(file))?,
+        ArchiveFormat::TarGz => files.read_tar(GzDecoder::new(BufReader::new(file)))?,
+        ArchiveFormat::Zip => files.read_zip(file)?,
+    }
+    if files.files.is_empty() {
+        return Err(anyhow::anyhow!(
+            "{} contains no files to import",
+            archive.display()
+        ));
+    }
+
+    let previous = read_entries(storage, &prefix, u32::MAX)?;
+    let scanned = files.files.into_values().map(Ok).collect();
+    let (snapshot, changes) = diff_entries(previous, scanned, options.time, None);
+    // A root that already has a record keeps it as it is; only the new
+    // snapshot carries the archive's time.
+    let timestamp = get_backup(repo, &prefix)?.map_or(options.time, |info| info.timestamp);
+
+    Backup {
+        repo,
+        snapshot,
+        changes,
+        removed_temp_files: 0,
+        backup_info: BackupInfo {
+            backup_prefix: prefix,
+            path_to_root: root.to_path_buf(),
+            timestamp,
+        },
+    }
+    .write_backup()
+}
+
+/// The regular files of an archive, stored and keyed by their path under
+/// the root. A path that appears twice keeps its last entry, as when the
+/// archive is extracted.
+struct ArchiveFiles<'a> {
+    repo: &'a Repository,
+    root: &'a Path,
+    staging_dir: &'a Path,
+    strip_components: usize,
+    files: BTreeMap<PathBuf, ScannedFile>,
+}
+
+impl ArchiveFiles<'_> {
+    fn read_tar(&mut self, reader: impl Read) -> anyhow::Result<()> {
+        let mut archive = tar::Archive::new(reader);
+        for entry in archive.entries()? {
+            let entry = entry?;
+            if !entry.header().entry_type().is_file() {
+                continue;
+            }
+            let name = entry.path()?.into_owned();
+            let mode = entry.header().mode().ok().map(|mode| mode & 0o7777);
+            let mtime = entry
+                .header()
+                .mtime()
+                .ok()
+                .and_then(|secs| DateTime::from_timestamp(secs as i64, 0));
+            self.add(&name, entry, mode, mtime)?;
+        }
+        Ok(())
+    }
+
+    fn read_zip(&mut self, file: File) -> anyhow::Result<()> {
+        let mut archive = zip::ZipArchive::new(BufReader::new(file))?;
+        for index in 0..archive.len() {
+            let entry = archive.by_index(index)?;
+            if !entry.is_file() || entry.is_symlink() {
+                continue;
+            }
+            let name = PathBuf::from(entry.name());
+            let mode = entry.unix_mode().map(|mode| mode & 0o7777);
+            let mtime = entry.last_modified().and_then(zip_time);
+            self.add(&name, entry, mode, mtime)?;
+        }
+        Ok(())
+    }
+
+    fn add(
+        &mut self,
+        name: &Path,
+        content: impl Read,
+        mode: Option<u32>,
+        mtime: Option<DateTime<Utc>>,
+    ) -> anyhow::Result<()> {
+        let Some(relative) = self.relative_path(name)? else {
+            return Ok(());
+        };
+        if self.repo.config().should_exclude(&relative) {
+            return Ok(());
+        }
+
+        let (hash, size, content_path) =
+            pipeline::store_content(content, self.repo.storage(), self.staging_dir)
+                .map_err(|e| anyhow::anyhow!("Cannot import {}: {:#}", name.display(), e))?;
+        let path = self.root.join(&relative);
+        self.files.insert(
+            path.clone(),
+            ScannedFile {
+                path,
+                size,
+                hash,
+                content_path,
+                changed_during_backup: false,
+                mode,
+                mtime,
+            },
+        );
+        Ok(())
+    }
+
+    /// Strips the leading components from an archive path. Returns `None`
+    /// for entries that lie entirely within the stripped part, and refuses
+    /// paths that would escape the root.
+    fn relative_path(&self, name: &Path) -> anyhow::Result<Option<PathBuf>> {
+        let mut parts = Vec::new();
+        for component in name.components() {
+            match component {
+                Component::Normal(part) => parts.push(part),
+                Component::CurDir => {}
+                _ => {
+                    return Err(anyhow::anyhow!(
+                        "Archive entry {} points outside the archive",
+                        name.display()
+                    ))
+                }
+            }
+        }
+        if parts.len() <= self.strip_components {
+            return Ok(None);
+        }
+        Ok(Some(parts[self.strip_components..].iter().collect()))
+    }
+}
+
+/// Zip timestamps have no time zone; they are taken as UTC.
+fn zip_time(time: zip::DateTime) -> Option<DateTime<Utc>> {
+    NaiveDate::from_ymd_opt(time.year().into(), time.month().into(), time.day().into())?
+        .and_hms_opt(
+            time.hour().into(),
+            time.minute().into(),
+            time.second().into(),
+        )
+        .map(|time| time.and_utc())
+}
@@ -1,60 +1,54 @@
 use std::{
-    fs::{self, create_dir_all, read_dir},
+    collections::{BTreeMap, HashMap, HashSet},
+    fs::{self, File},
+    io,
     path::{Path, PathBuf},
 };
 
-use chrono::DateTime;
-use serde::{Deserialize, Serialize};
+use chrono::{DateTime, Utc};
 use uuid::Uuid;
-use walkdir::WalkDir;
 
 use crate::{
-    config::{self, Config},
-    util,
+    repository::{
+        Change, ChangeKind, CreateOptions, CreateReport, Repository, RestoreOptions, RestoreReport,
+    },
+    snapshot::{self, BackupInfo, ContentType, FailedFile, Snapshot, SnapshotEntry},
+    storage::{self, Storage},
 };
 
-#[derive(Debug, Serialize)]
-pub struct Backup {
-    file_info: Vec<FileInfo>,
+pub(crate) mod bundle;
+pub(crate) mod copy;
+pub(crate) mod delta;
+pub(crate) mod export;
+pub(crate) mod git;
+pub(crate) mod import;
+pub(crate) mod pipeline;
+pub(crate) mod prune;
+pub(crate) mod verify;
+
+/// A snapshot being built by `create` that has not been written yet.
+pub(crate) struct Backup<'a> {
+    repo: &'a Repository,
+    snapshot: Snapshot,
     backup_info: BackupInfo,
+    changes: Vec<Change>,
+    removed_temp_files: usize,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-pub struct BackupInfo {
-    pub timestamp: DateTime<chrono::Utc>,
-    pub path_to_root: PathBuf,
-    pub backup_prefix: String,
-}
-
-impl BackupInfo {
-    fn get_path(&self) -> Option<(BackupInfo, PathBuf)> {
-        let config = Config::read_config();
-        let backup_info_path = config.get_default_backup_info_path();
-        let backup_info = Self::get_backup_info_by_path(backup_info_path);
-        backup_info
-            .into_iter()
-            .find(|(info, _)| info.backup_prefix == self.backup_prefix)
-    }
-    fn get_backup_info_by_path(backup_info_path: String) -> Vec<(BackupInfo, PathBuf)> {
-        match read_dir(&backup_info_path) {
-            Ok(entries) => entries
-                .filter_map(|entry| entry.ok())
-                .filter_map(|entry| {
-                    let content = fs::read_to_string(entry.path()).ok()?;
-                    let info: BackupInfo = serde_json::from_str(&content).ok()?;
-                    Some((info, entry.path()))
-                })
-                .collect(),
-            Err(_) => Vec::new(),
-        }
-    }
-}
-
-impl Backup {
-    pub fn new(root_dir: PathBuf) -> anyhow::Result<Self> {
-        let prefix = generate_prefix(&root_dir);
+impl<'a> Backup<'a> {
+    pub(crate) fn new(
+        repo: &'a Repository,
+        root_dir: PathBuf,
+        options: &CreateOptions,
+    ) -> anyhow::Result<Self> {
+        let prefix = generate_prefix(repo, &root_dir)?;
+        let removed_temp_files = repo.storage().remove_leftovers();
+        let (snapshot, changes) = Self::build_info(repo, &root_dir, &prefix, options)?;
         Ok(Self {
-            file_info: Self::build_info(&root_dir, &prefix)?,
+            repo,
+            snapshot,
+            changes,
+            removed_temp_files,
             backup_info: BackupInfo {
                 backup_prefix: prefix,
                 path_to_root: root_dir,
@@ -63,627 +57,440 @@ impl Backup {
         })
     }
 
-    pub fn write_backup(&mut self) -> anyhow::Result<()> {
+    pub(crate) fn write_backup(mut self) -> anyhow::Result<CreateReport> {
         // If no changes detected, skip backup creation
-        if self.file_info.is_empty() {
-            println!("No changes detected. Skipping backup creation.");
-            return Ok(());
+        if self.snapshot.entries.is_empty() && self.snapshot.failures.is_empty() {
+            return Ok(CreateReport {
+                source: self.backup_info,
+                snapshot: None,
+                changes: self.changes,
+                removed_temp_files: self.removed_temp_files,
+            });
         }
 
-        let backup = serde_json::to_string_pretty(&self.file_info)?;
-        let backup_info = serde_json::to_string_pretty(&self.backup_info)?;
-        let config = Config::read_config();
-        let backup_info_path = config.get_default_backup_info_path();
+        let storage = self.repo.storage();
 
-        match self.backup_info.get_path() {
-            Some(path) => {
-                fs::write(path.1, backup_info)?;
-            }
-            None => {
-                let backup_info_file_path = Path::new(&backup_info_path)
-                    .join(&self.backup_info.backup_prefix)
-                    .with_extension("json");
-                create_dir_all(backup_info_file_path.parent().unwrap())?;
-                fs::write(backup_info_file_path, backup_info)?;
-            }
-        }
+        self.snapshot.number = Self::next_backup_number(storage, &self.backup_info.backup_prefix)?;
+        let backup = serde_json::to_string_pretty(&self.snapshot)?;
+        let backup_info = serde_json::to_string_pretty(&self.backup_info)?;
 
-        let binding = config::Config::read_config();
-        let backup_path = binding.get_default_backup_path();
-        let next_backup_path =
-            Self::next_backup_file(&PathBuf::from(backup_path), &self.backup_info.backup_prefix);
-        create_dir_all(Path::new(&next_backup_path).parent().unwrap())?;
-        fs::write(&next_backup_path, backup)?;
-
-        println!(
-            "Backup created with {} changes at: {}",
-            self.file_info.len(),
-            next_backup_path.display()
-        );
-        Ok(())
+        // Content objects are already in place; the info record goes next and
+        // the manifest is stored last, so a crash never leaves a manifest
+        // pointing at data that was not written.
+        let info_key = existing_backup_info_key(self.repo, &self.backup_info.backup_prefix)?
+            .unwrap_or_else(|| storage::info_key(&self.backup_info.backup_prefix));
+        storage.put(&info_key, backup_info.as_bytes())?;
+
+        let manifest_key =
+            storage::manifest_key(&self.backup_info.backup_prefix, self.snapshot.number);
+        storage.put(&manifest_key, backup.as_bytes())?;
+
+        Ok(CreateReport {
+            source: self.backup_info,
+            snapshot: Some(self.snapshot),
+            changes: self.changes,
+            removed_temp_files: self.removed_temp_files,
+        })
     }
 
-    fn next_backup_file(backup_dir: &PathBuf, prefix: &str) -> PathBuf {
-        if let Err(_) = create_dir_all(backup_dir) {
-            return backup_dir.join(prefix).join("backup_0.json");
-        }
-
-        let backup_files = Self::get_backup_files_by_prefix(backup_dir, prefix);
-
-        if backup_files.is_empty() {
-            return backup_dir.join(prefix).join("backup_0.json");
-        }
-
-        let max_number = backup_files
+    fn next_backup_number(storage: &dyn Storage, prefix: &str) -> anyhow::Result<u32> {
+        Ok(get_backup_files_by_prefix(storage, prefix)?
             .iter()
-            .filter_map(|path| {
-                let file_name = path.file_name()?.to_string_lossy();
-                if file_name.starts_with("backup_") && file_name.ends_with(".json") {
-                    let number_part = file_name
-                        .trim_start_matches("backup_")
-                        .trim_end_matches(".json");
-                    number_part.parse::<u32>().ok()
-                } else {
-                    None
-                }
-            })
+            .map(|(number, _)| number + 1)
             .max()
-            .unwrap_or(0);
-
-        backup_dir
-            .join(prefix)
-            .join(format!("backup_{}.json", max_number + 1))
+            .unwrap_or(0))
     }
 
-    fn build_info(path: &PathBuf, prefix: &str) -> anyhow::Result<Vec<FileInfo>> {
-        let backup = Self::get_backup(prefix);
+    fn build_info(
+        repo: &Repository,
+        path: &Path,
+        prefix: &str,
+        options: &CreateOptions,
+    ) -> anyhow::Result<(Snapshot, Vec<Change>)> {
+        let backup = get_backup(repo, prefix)?;
+        let jobs = options.jobs;
         match backup {
             Some(backup_info) => {
-                let file_infos = Self::process_exits_backup(&backup_info.backup_prefix, path);
-                file_infos
+                let changed = options
+                    .changed_paths
+                    .as_deref()
+                    .map(|paths| outermost_paths(path, paths));
+                Self::process_exits_backup(
+                    repo,
+                    &backup_info.backup_prefix,
+                    path,
+                    jobs,
+                    changed.as_deref(),
+                )
             }
             None => {
-                let mut file_infos = Vec::new();
-
-                for entry in WalkDir::new(path).into_iter().filter_map(|e| e.ok()) {
-                    let path = entry.path();
-
-                    // Обробляємо тільки файли
-                    if path.is_file() {
-                        let size = fs::metadata(&path)?.len();
-                        let hash = util::hash::calculate_file_hash(&path)?;
-
-                        // Для першого backup'а зберігаємо контент всіх файлів
-                        let backup_path = config::Config::read_config().get_default_backup_path();
-                        let content_path = match FileInfo::store_content(
-                            &path.to_string_lossy(),
-                            &hash,
-                            &PathBuf::from(&backup_path),
-                            ContentType::FullCopy,
-                        ) {
-                            Ok(path) => Some(path),
-                            Err(e) => {
-                                println!("Failed to store content for {}: {}", path.display(), e);
-                                None
-                            }
-                        };
-                        
-                        file_infos.push(FileInfo::new(
-                            path.to_string_lossy().to_string(),
-                            size,
-                            hash,
-                            chrono::Utc::now(),
-                            false,
-                            ContentType::FullCopy,
-                            content_path,
-                        ));
+                // Для першого backup'а зберігаємо контент всіх файлів
+                let mut snapshot = Snapshot::new(0);
+                let mut changes = Vec::new();
+                for scanned in
+                    pipeline::scan(path, &HashMap::new(), repo.storage(), repo.config(), jobs)?
+                {
+                    match scanned {
+                        Ok(scanned) => {
+                            let entry = scanned.into_entry(chrono::Utc::now());
+                            changes.push(Change::new(&entry.path, ChangeKind::Added));
+                            snapshot.entries.push(entry);
+                        }
+                        Err(failure) => snapshot.record_failure(failure),
                     }
                 }
-                Ok(file_infos)
+                Ok((snapshot, changes))
             }
         }
     }
-    fn get_backup(prefix: &str) -> Option<BackupInfo> {
-        let config = Config::read_config();
-        let backup_info_path = config.get_default_backup_info_path();
-
-        let backup_info = BackupInfo::get_backup_info_by_path(backup_info_path);
-        backup_info
-            .into_iter()
-            .find(|(info, _)| info.backup_prefix == prefix)
-            .map(|(info, _)| info)
-    }
 
-    fn get_backup_files_by_prefix(backup_dir: &PathBuf, prefix: &str) -> Vec<PathBuf> {
-        let prefix_dir = backup_dir.join(prefix);
-
-        match read_dir(&prefix_dir) {
-            Ok(entries) => entries
-                .filter_map(|entry| entry.ok())
-                .filter_map(|entry| {
-                    let file_name = entry.file_name().to_string_lossy().to_string();
-                    if file_name.starts_with("backup_") && file_name.ends_with(".json") {
-                        Some(entry.path())
-                    } else {
-                        None
-                    }
-                })
-                .collect(),
-            Err(_) => Vec::new(),
-        }
-    }
-
-    fn process_exits_backup(prefix: &str, path: &PathBuf) -> anyhow::Result<Vec<FileInfo>> {
-        let backup_path = config::Config::read_config().get_default_backup_path();
-        let files = Self::get_backup_files_by_prefix(&PathBuf::from(&backup_path), prefix);
-        println!("files -> {:#?}", files);
-        let file_infos = FileInfo::get_vec_file_info_by_paths(files);
-
-        let mut file_info_new = Vec::new();
-        let mut processed_paths = std::collections::HashSet::new();
-
-        // Обробляємо поточні файли
-        for entry in WalkDir::new(path).into_iter().filter_map(|e| e.ok()) {
-            let current_path = entry.path();
-            if current_path.is_file() {
-                let current_path_str = current_path.to_string_lossy().to_string();
-                processed_paths.insert(current_path_str.clone());
-
-                // Знаходимо найновіший запис про цей файл
-                let latest_file_record = file_infos
-                    .iter()
-                    .filter(|f| f.path == current_path_str)
-                    .max_by_key(|f| f.modify_time);
-
-                match latest_file_record {
-                    Some(existing_file) => {
-                        let size = fs::metadata(&current_path)?.len();
-                        let hash = util::hash::calculate_file_hash(&current_path)?;
-
-                        if existing_file.deleted {
-                            println!("File restored: {}", current_path_str);
-                            file_info_new.push(FileInfo::new_simple(
-                                current_path_str,
-                                size,
-                                hash,
-                                chrono::Utc::now(),
-                                false,
-                            ));
-                        }
-                        // Якщо файл існував і змінився
-                        else if existing_file.size != size || existing_file.hash != hash {
-                            println!("File changed: {}", current_path_str);
-                            file_info_new.push(FileInfo::new_simple(
-                                current_path_str,
-                                size,
-                                hash,
-                                chrono::Utc::now(),
-                                false,
-                            ));
-                        } else {
-                            println!("File unchanged: {}", current_path_str);
-                        }
-                    }
-                    None => {
-                        println!("New file: {}", current_path_str);
-                        let size = fs::metadata(&current_path)?.len();
-                        let hash = util::hash::calculate_file_hash(&current_path)?;
-
-                        file_info_new.push(FileInfo::new_simple(
-                            current_path_str,
-                            size,
-                            hash,
-                            chrono::Utc::now(),
-                            false,
-                        ));
-                    }
-                }
+    /// With `changed`, only those files and directories are scanned and
+    /// everything else is taken to be as the previous snapshots left it.
+    fn process_exits_backup(
+        repo: &Repository,
+        prefix: &str,
+        path: &Path,
+        jobs: usize,
+        changed: Option<&[PathBuf]>,
+    ) -> anyhow::Result<(Snapshot, Vec<Change>)> {
+        let file_infos = read_entries(repo.storage(), prefix, u32::MAX)?;
+        // Обробляємо поточні файли. Контент кожного файлу вже збережений
+        // пайплайном під його хешем, тож незмінні файли нічого не додають.
+        let known = latest_entries(file_infos.clone());
+        let scanned = match changed {
+            Some(paths) => {
+                pipeline::scan_paths(path, paths, &known, repo.storage(), repo.config(), jobs)?
             }
-        }
+            None => pipeline::scan(path, &known, repo.storage(), repo.config(), jobs)?,
+        };
+        Ok(diff_entries(
+            file_infos,
+            scanned,
+            chrono::Utc::now(),
+            changed,
+        ))
+    }
+}
 
-        // Групуємо файли по шляху і беремо тільки найновіші записи
-        let mut latest_files: std::collections::HashMap<String, FileInfo> =
-            std::collections::HashMap::new();
-        for file_info in file_infos {
-            match latest_files.get(&file_info.path) {
-                Some(existing) => {
-                    // Якщо поточний файл новіший, замінюємо
-                    if file_info.modify_time > existing.modify_time {
-                        latest_files.insert(file_info.path.clone(), file_info);
-                    }
-                }
-                None => {
-                    latest_files.insert(file_info.path.clone(), file_info);
-                }
+/// Builds the snapshot that takes a root from the state recorded in
+/// `file_infos` to the `scanned` files: new and changed files are added and
+/// files that are gone are recorded as deleted, all at `time`. With `scope`,
+/// only files under those paths can have gone; the rest were not scanned.
+pub(crate) fn diff_entries(
+    file_infos: Vec<SnapshotEntry>,
+    scanned: Vec<Result<pipeline::ScannedFile, FailedFile>>,
+    time: DateTime<Utc>,
+    scope: Option<&[PathBuf]>,
+) -> (Snapshot, Vec<Change>) {
+    let mut snapshot = Snapshot::new(0);
+    snapshot.timestamp = time;
+    let mut changes = Vec::new();
+    let mut processed_paths = HashSet::new();
+
+    for scanned in scanned {
+        let scanned = match scanned {
+            Ok(scanned) => scanned,
+            Err(failure) => {
+                // Файл існує, але не збережений: не вважаємо його видаленим,
+                // попередня версія лишається останньою для restore
+                processed_paths.insert(failure.path.clone());
+                snapshot.record_failure(failure);
+                continue;
             }
-        }
+        };
+        let current_path_str = scanned.path.to_string_lossy().to_string();
+        processed_paths.insert(current_path_str.clone());
 
-        // Додаємо видалені файли (тільки ті що не були видалені раніше)
-        for (path, latest_file) in latest_files {
-            if !processed_paths.contains(&path) && (!latest_file.deleted) {
-                println!("File deleted: {}", path);
-                let mut deleted_file = latest_file;
-                deleted_file.deleted = true;
-                deleted_file.modify_time = chrono::Utc::now();
-                file_info_new.push(deleted_file);
+        // Знаходимо найновіший запис про цей файл
+        let latest_file_record = file_infos
+            .iter()
+            .filter(|f| f.path == current_path_str)
+            .max_by_key(|f| f.modify_time);
+
+        let kind = match latest_file_record {
+            Some(existing_file) if existing_file.deleted => ChangeKind::Reappeared,
+            // Якщо файл існував і змінився
+            Some(existing_file)
+                if existing_file.size != scanned.size || existing_file.hash != scanned.hash =>
+            {
+                ChangeKind::Modified
             }
-        }
+            Some(_) => continue,
+            None => ChangeKind::Added,
+        };
 
-        println!("Total changes to backup: {} files", file_info_new.len());
-        
-        // Зберігаємо контент для кожного файлу і повертаємо оновлений список
-        let mut updated_file_infos = Vec::new();
-        for mut file_info in file_info_new {
-            if !file_info.deleted {
-                // Зберігаємо контент тільки для не видалених файлів
-                match FileInfo::store_content(
-                    &file_info.path,
-                    &file_info.hash,
-                    &PathBuf::from(&backup_path),
-                    ContentType::FullCopy,
-                ) {
-                    Ok(content_path) => {
-                        file_info.content_path = Some(content_path);
-                        file_info.content_type = ContentType::FullCopy;
-                        println!("Stored content for: {}", file_info.path);
-                    }
-                    Err(e) => {
-                        println!("Failed to store content for {}: {}", file_info.path, e);
-                        // Все одно додаємо файл, але без збереженого контенту
-                        file_info.content_type = ContentType::Unchanged;
-                    }
-                }
-            } else {
-                // Для видалених файлів контент не потрібен
-                file_info.content_type = ContentType::Unchanged;
-                file_info.content_path = None;
-            }
-            updated_file_infos.push(file_info);
-        }
-        
-        Ok(updated_file_infos)
+        changes.push(Change::new(&current_path_str, kind));
+        snapshot.entries.push(scanned.into_entry(time));
     }
 
-    pub(crate) fn restore(backup_number: u32, path: &PathBuf) -> anyhow::Result<()> {
-        let config = Config::read_config();
-        let backup_path = config.get_default_backup_path();
-        let backup_info_path = config.get_default_backup_info_path();
-        let existing_backups = BackupInfo::get_backup_info_by_path(backup_info_path);
-
-        // Find backup for the given path
-        let backup_info = existing_backups
-            .into_iter()
-            .find(|backup| backup.0.path_to_root == *path)
-            .ok_or(anyhow::anyhow!(
-                "No backup found for path: {}",
-                path.display()
-            ))?
-            .0;
-
-        // Get all backup files up to the specified number inclusive
-        let backup_files_paths = Self::get_backup_files_by_prefix(
-            &PathBuf::from(backup_path),
-            &backup_info.backup_prefix,
-        );
-        let backup_file_paths: Vec<_> = backup_files_paths
-            .iter()
-            .filter_map(|path| {
-                let file_name = path.file_name()?.to_string_lossy();
-                if file_name.starts_with("backup_") && file_name.ends_with(".json") {
-                    let number_str = file_name
-                        .trim_start_matches("backup_")
-                        .trim_end_matches(".json");
-                    let number = number_str.parse::<u32>().ok()?;
-                    if number <= backup_number {
-                        Some((number, path.clone()))
-                    } else {
-                        None
-                    }
-                } else {
-                    None
-                }
-            })
-            .collect();
-
-        if backup_file_paths.is_empty() {
-            return Err(anyhow::anyhow!(
-                "No backup files found up to backup #{}",
-                backup_number
-            ));
-        }
-
-        // Read files from all backups up to specified number
-        let mut all_file_infos = Vec::new();
-        for (_, backup_path) in &backup_file_paths {
-            let files = FileInfo::get_file_info_by_path(backup_path);
-            all_file_infos.extend(files);
-        }
-
-        // Групуємо файли по шляху і беремо тільки найновіші записи
-        let mut latest_files: std::collections::HashMap<String, FileInfo> =
-            std::collections::HashMap::new();
-        for file_info in all_file_infos {
-            match latest_files.get(&file_info.path) {
-                Some(existing) => {
-                    // Якщо поточний файл новіший, замінюємо
-                    if file_info.modify_time > existing.modify_time {
-                        latest_files.insert(file_info.path.clone(), file_info);
-                    }
-                }
-                None => {
-                    latest_files.insert(file_info.path.clone(), file_info);
-                }
-            }
+    // Додаємо видалені файли (тільки ті що не були видалені раніше)
+    for (path, latest_file) in latest_entries(file_infos) {
+        let in_scope = scope.is_none_or(|paths| {
+            paths
+                .iter()
+                .any(|scanned| Path::new(&path).starts_with(scanned))
+        });
+        if in_scope && !processed_paths.contains(&path) && (!latest_file.deleted) {
+            let mut deleted_file = latest_file;
+            deleted_file.deleted = true;
+            deleted_file.modify_time = time;
+            // Для видалених файлів контент не потрібен
+            deleted_file.content_type = ContentType::Unchanged;
+            deleted_file.content_path = None;
+            deleted_file.changed_during_backup = false;
+            changes.push(Change::new(&path, ChangeKind::Deleted));
+            snapshot.entries.push(deleted_file);
         }
+    }
 
-        // Фільтруємо тільки файли які не видалені
-        let file_infos: Vec<_> = latest_files.into_values().filter(|f| !f.deleted).collect();
+    (snapshot, changes)
+}
 
-        if file_infos.is_empty() {
-            println!("No files found in backup #{}", backup_number);
-            return Ok(());
+/// Resolves `changed` against `root`, drops paths outside it and paths
+/// inside other listed paths, so nothing is scanned twice.
+fn outermost_paths(root: &Path, changed: &[PathBuf]) -> Vec<PathBuf> {
+    let mut paths: Vec<PathBuf> = changed
+        .iter()
+        .map(|path| root.join(path))
+        .filter(|path| path.starts_with(root))
+        .collect();
+    paths.sort();
+    let mut outermost: Vec<PathBuf> = Vec::new();
+    for path in paths {
+        if !outermost.iter().any(|kept| path.starts_with(kept)) {
+            outermost.push(path);
         }
+    }
+    outermost
+}
 
-        println!(
-            "Restoring {} files from backup #{}...",
-            file_infos.len(),
-            backup_number
-        );
-
-        // Відновлюємо файли
-        for file_info in file_infos {
-            if !file_info.deleted {
-                match Self::restore_single_file(&file_info) {
-                    Ok(_) => println!("✓ Restored: {}", file_info.path),
-                    Err(e) => println!("✗ Failed to restore {}: {}", file_info.path, e),
-                }
+pub(crate) fn restore(
+    repo: &Repository,
+    backup_number: u32,
+    path: &Path,
+    options: &RestoreOptions,
+) -> anyhow::Result<RestoreReport> {
+    let storage = repo.storage();
+    let backup_info = find_backup_info(repo, path)?;
+    let state = replay(storage, &backup_info.backup_prefix, backup_number)?;
+    let mut report = RestoreReport {
+        snapshot: backup_number,
+        not_backed_up: state.not_backed_up,
+        ..RestoreReport::default()
+    };
+
+    // Відновлюємо файли
+    for file_info in state.files {
+        let target_path = match &options.target {
+            Some(target) => {
+                let relative = Path::new(&file_info.path)
+                    .strip_prefix(&backup_info.path_to_root)
+                    .unwrap_or(Path::new(&file_info.path));
+                target.join(relative)
             }
+            None => PathBuf::from(&file_info.path),
+        };
+
+        let result = if options.dry_run {
+            Ok(())
+        } else {
+            restore_content(&file_info, storage, &target_path)
+        };
+        match result {
+            Ok(_) => report.restored.push(target_path),
+            Err(e) => report.failed.push(FailedFile {
+                path: file_info.path,
+                error: format!("{:#}", e),
+            }),
         }
-
-        println!("Restore completed!");
-        Ok(())
     }
 
-    fn restore_single_file(file_info: &FileInfo) -> anyhow::Result<()> {
-        let file_path = Path::new(&file_info.path);
-        println!("restore file: {}", file_path.display());
-        let restore_path = file_path;
-
-        let config = Config::read_config();
-        let backup_path = config.get_default_backup_path();
-        let backup_dir = PathBuf::from(backup_path);
+    Ok(report)
+}
 
-        FileInfo::restore_content(file_info, &backup_dir, &restore_path.to_path_buf())?;
+/// The files of a root as of one snapshot.
+pub(crate) struct ReplayedSnapshot {
+    /// Latest version of every file that exists at the snapshot, by path.
+    pub files: Vec<SnapshotEntry>,
+    /// Files that failed to back up in this or an earlier snapshot and have
+    /// not been backed up since; `files` holds their previous version, if
+    /// any. Sorted by path.
+    pub not_backed_up: Vec<FailedFile>,
+}
 
-        Ok(())
+/// Replays snapshots `0..=number` of `prefix`.
+pub(crate) fn replay(
+    storage: &dyn Storage,
+    prefix: &str,
+    number: u32,
+) -> anyhow::Result<ReplayedSnapshot> {
+    // Get all backup files up to the specified number inclusive
+    let backup_file_keys: Vec<_> = get_backup_files_by_prefix(storage, prefix)?
+        .into_iter()
+        .filter(|(n, _)| *n <= number)
+        .collect();
+
+    if backup_file_keys.is_empty() {
+        return Err(anyhow::anyhow!(
+            "No backup files found up to backup #{}",
+            number
+        ));
     }
 
-    pub(crate) fn list_backups(path: &PathBuf) -> anyhow::Result<()> {
-        let config = Config::read_config();
-        let backup_path = config.get_default_backup_path();
-        let backup_info_path = config.get_default_backup_info_path();
-        let existing_backups = BackupInfo::get_backup_info_by_path(backup_info_path);
-
-        // Find backup for the given path
-        let backup_info = existing_backups
-            .into_iter()
-            .find(|backup| backup.0.path_to_root == *path)
-            .ok_or(anyhow::anyhow!(
-                "No backup found for path: {}",
-                path.display()
-            ))?
-            .0;
-
-        // Отримуємо всі backup файли
-        let backup_files = Self::get_backup_files_by_prefix(
-            &PathBuf::from(backup_path),
-            &backup_info.backup_prefix,
-        );
-
-        if backup_files.is_empty() {
-            println!("No backup files found for this project");
-            return Ok(());
+    // Read files from all backups up to specified number. A failure stays
+    // until a later snapshot records the file; snapshots that only rescan
+    // some paths may never look at it again.
+    let mut not_backed_up = BTreeMap::new();
+    let mut all_file_infos = Vec::new();
+    for (n, manifest_key) in &backup_file_keys {
+        let snapshot = Snapshot::read(storage, manifest_key, *n)?;
+        for entry in &snapshot.entries {
+            not_backed_up.remove(&entry.path);
         }
-
-        println!("Available backups for: {}", path.display());
-        println!("Backup prefix: {}", backup_info.backup_prefix);
-        println!("─────────────────────────────────────────────");
-
-        // Сортуємо backup файли по номеру
-        let mut numbered_backups: Vec<(u32, PathBuf)> = backup_files
-            .into_iter()
-            .filter_map(|path| {
-                let file_name = path.file_name()?.to_string_lossy();
-                if file_name.starts_with("backup_") && file_name.ends_with(".json") {
-                    let number_str = file_name
-                        .trim_start_matches("backup_")
-                        .trim_end_matches(".json");
-                    let number = number_str.parse::<u32>().ok()?;
-                    Some((number, path))
-                } else {
-                    None
-                }
-            })
-            .collect();
-
-        numbered_backups.sort_by_key(|(number, _)| *number);
-
-        for (number, backup_path) in numbered_backups {
-            let file_infos = FileInfo::get_file_info_by_path(&backup_path);
-            let changes = file_infos.iter().filter(|f| !f.deleted).count();
-            let deletions = file_infos.iter().filter(|f| f.deleted).count();
-
-            if let Ok(metadata) = fs::metadata(&backup_path) {
-                if let Ok(modified) = metadata.modified() {
-                    let datetime: chrono::DateTime<chrono::Utc> = modified.into();
-                    println!(
-                        "Backup #{}: {} changes, {} deletions ({})",
-                        number,
-                        changes,
-                        deletions,
-                        datetime.format("%Y-%m-%d %H:%M:%S UTC")
-                    );
-                }
-            }
+        for failure in snapshot.failures {
+            not_backed_up.insert(failure.path.clone(), failure);
         }
-
-        println!("\nUse: snapback restore <backup_number> <path>");
-        Ok(())
+        all_file_infos.extend(snapshot.entries);
     }
+    let not_backed_up = not_backed_up.into_values().collect();
+
+    // Фільтруємо тільки файли які не видалені
+    let mut files: Vec<_> = latest_entries(all_file_infos)
+        .into_values()
+        .filter(|f| !f.deleted)
+        .collect();
+    files.sort_by(|a, b| a.path.cmp(&b.path));
+
+    Ok(ReplayedSnapshot {
+        files,
+        not_backed_up,
+    })
 }
 
-fn generate_prefix(root_dir: &PathBuf) -> String {
-    let config = Config::read_config();
-    let backup_info_path = config.get_default_backup_info_path();
-    let existing_backups = BackupInfo::get_backup_info_by_path(backup_info_path);
-
-    for (backup_info, _) in existing_backups {
-        if backup_info.path_to_root == *root_dir {
-            return backup_info.backup_prefix;
-        }
-    }
+pub(crate) fn list_backups(repo: &Repository, path: &Path) -> anyhow::Result<Vec<Snapshot>> {
+    let backup_info = find_backup_info(repo, path)?;
 
-    let root_dir_str = root_dir.to_string_lossy().to_string();
-    let uuid = Uuid::new_v4().to_string();
-    let mut prefix = root_dir_str.split("/").last().unwrap().to_string();
-    prefix.push_str(&uuid);
-    prefix
+    // Отримуємо всі backup файли, відсортовані по номеру
+    get_backup_files_by_prefix(repo.storage(), &backup_info.backup_prefix)?
+        .into_iter()
+        .map(|(number, manifest_key)| Snapshot::read(repo.storage(), &manifest_key, number))
+        .collect()
 }
 
-#[derive(Debug, Serialize, Deserialize, PartialEq, Eq)]
-struct FileInfo {
-    path: String,
-    size: u64,
-    hash: String,
-    modify_time: DateTime<chrono::Utc>,
-    deleted: bool,
-    content_type: ContentType,
-    content_path: Option<String>, // Шлях до збереженого контенту
+/// Finds the record for the backed-up root `path`.
+pub(crate) fn find_backup_info(repo: &Repository, path: &Path) -> anyhow::Result<BackupInfo> {
+    BackupInfo::read_all(repo.storage())?
+        .into_iter()
+        .map(|(info, _)| info)
+        .find(|info| info.path_to_root == path)
+        .ok_or(anyhow::anyhow!(
+            "No backup found for path: {}",
+            path.display()
+        ))
 }
 
-#[derive(Debug, Serialize, Deserialize, PartialEq, Eq)]
-enum ContentType {
-    FullCopy,
-    Delta { base_hash: String },
-    Unchanged,
+fn get_backup(repo: &Repository, prefix: &str) -> anyhow::Result<Option<BackupInfo>> {
+    Ok(BackupInfo::read_all(repo.storage())?
+        .into_iter()
+        .find(|(info, _)| info.backup_prefix == prefix)
+        .map(|(info, _)| info))
 }
 
-impl FileInfo {
-    fn new(
-        path: String,
-        size: u64,
-        hash: String,
-        modify_time: DateTime<chrono::Utc>,
-        deleted: bool,
-        content_type: ContentType,
-        content_path: Option<String>,
-    ) -> Self {
-        Self {
-            path,
-            size,
-            hash,
-            modify_time,
-            deleted,
-            content_type,
-            content_path,
-        }
-    }
-
-    fn new_simple(
-        path: String,
-        size: u64,
-        hash: String,
-        modify_time: DateTime<chrono::Utc>,
-        deleted: bool,
-    ) -> Self {
-        Self::new(
-            path,
-            size,
-            hash,
-            modify_time,
-            deleted,
-            ContentType::Unchanged,
-            None,
-        )
-    }
+fn existing_backup_info_key(repo: &Repository, prefix: &str) -> anyhow::Result<Option<String>> {
+    Ok(BackupInfo::read_all(repo.storage())?
+        .into_iter()
+        .find(|(info, _)| info.backup_prefix == prefix)
+        .map(|(_, key)| key))
+}
 
-    fn get_file_info_by_path(path: &PathBuf) -> Vec<FileInfo> {
-        let content = fs::read_to_string(path).ok().unwrap_or_default();
-        serde_json::from_str::<Vec<FileInfo>>(&content).unwrap_or_default()
-    }
+/// Lists the `backup_N.json` manifests of `prefix`, sorted by number.
+pub(crate) fn get_backup_files_by_prefix(
+    storage: &dyn Storage,
+    prefix: &str,
+) -> anyhow::Result<Vec<(u32, String)>> {
+    let mut files: Vec<_> = storage
+        .list(prefix)?
+        .into_iter()
+        .filter_map(|key| snapshot::snapshot_number(&key).map(|number| (number, key)))
+        .collect();
+    files.sort_by_key(|(number, _)| *number);
+    Ok(files)
+}
 
-    fn get_vec_file_info_by_paths(paths: Vec<PathBuf>) -> Vec<FileInfo> {
-        paths
-            .iter()
-            .flat_map(|path| Self::get_file_info_by_path(path))
-            .collect()
+/// Reads the entries of every snapshot of `prefix` up to `up_to` inclusive.
+fn read_entries(
+    storage: &dyn Storage,
+    prefix: &str,
+    up_to: u32,
+) -> anyhow::Result<Vec<SnapshotEntry>> {
+    let mut entries = Vec::new();
+    for (number, key) in get_backup_files_by_prefix(storage, prefix)? {
+        if number <= up_to {
+            entries.extend(Snapshot::read(storage, &key, number)?.entries);
+        }
     }
+    Ok(entries)
+}
 
-    fn store_content(
-        file_path: &str,
-        content_hash: &str,
-        backup_dir: &PathBuf,
-        content_type: ContentType,
-    ) -> anyhow::Result<String> {
-        let content_dir = backup_dir.join("content");
-        fs::create_dir_all(&content_dir)?;
-
-        let content_file_path = content_dir.join(format!("{}.dat", content_hash));
-        let relative_path = content_file_path
-            .strip_prefix(backup_dir)
-            .unwrap_or(&content_file_path)
-            .to_string_lossy()
-            .to_string();
-
-        match content_type {
-            ContentType::FullCopy => {
-                fs::copy(file_path, &content_file_path)?;
-                println!("Stored full copy: {}", file_path);
-            }
-            ContentType::Delta { .. } => {
-                fs::copy(file_path, &content_file_path)?;
-                println!("Stored delta (full for now): {}", file_path);
+/// Групуємо файли по шляху і беремо тільки найновіші записи
+fn latest_entries(entries: Vec<SnapshotEntry>) -> HashMap<String, SnapshotEntry> {
+    let mut latest_files: HashMap<String, SnapshotEntry> = HashMap::new();
+    for file_info in entries {
+        match latest_files.get(&file_info.path) {
+            Some(existing) => {
+                // Якщо поточний файл новіший, замінюємо
+                if file_info.modify_time > existing.modify_time {
+                    latest_files.insert(file_info.path.clone(), file_info);
+                }
             }
-            ContentType::Unchanged => {
-                return Ok(String::new());
+            None => {
+                latest_files.insert(file_info.path.clone(), file_info);
             }
         }
+    }
+    latest_files
+}
 
-        Ok(relative_path)
+/// Відновлює контент файлу з backup'а
+fn restore_content(
+    file_info: &SnapshotEntry,
+    storage: &dyn Storage,
+    target_path: &Path,
+) -> anyhow::Result<()> {
+    let content_key = file_info
+        .content_path
+        .as_deref()
+        .filter(|content_key| !content_key.is_empty())
+        .ok_or_else(|| anyhow::anyhow!("no content was stored for this file"))?;
+
+    if !storage.exists(content_key)? {
+        return Err(anyhow::anyhow!(
+            "content object {} is missing from the repository",
+            content_key
+        ));
     }
 
-    /// Відновлює контент файлу з backup'а
-    fn restore_content(
-        file_info: &FileInfo,
-        backup_dir: &PathBuf,
-        target_path: &PathBuf,
-    ) -> anyhow::Result<()> {
-        if let Some(content_path) = &file_info.content_path {
-            if !content_path.is_empty() {
-                let source_path = backup_dir.join(content_path);
-
-                if source_path.exists() {
-                    if let Some(parent) = target_path.parent() {
-                        fs::create_dir_all(parent)?;
-                    }
+    if let Some(parent) = target_path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let mut source = storage.reader(content_key)?;
+    io::copy(&mut source, &mut File::create(target_path)?)?;
 
-                    fs::copy(&source_path, target_path)?;
-                    println!("Restored content from: {}", source_path.display());
-                    return Ok(());
-                }
-            }
-        }
+    Ok(())
+}
 
-        // Fallback: створюємо порожній файл
-        if let Some(parent) = target_path.parent() {
-            fs::create_dir_all(parent)?;
-        }
-        fs::write(target_path, vec![0u8; file_info.size as usize])?;
-        println!(
-            "Created placeholder file (no content stored): {}",
-            target_path.display()
-        );
+fn generate_prefix(repo: &Repository, root_dir: &Path) -> anyhow::Result<String> {
+    let existing_backups = BackupInfo::read_all(repo.storage())?;
 
-        Ok(())
+    for (backup_info, _) in existing_backups {
+        if backup_info.path_to_root == root_dir {
+            return Ok(backup_info.backup_prefix);
+        }
     }
+
+    let root_dir_str = root_dir.to_string_lossy().to_string();
+    let uuid = Uuid::new_v4().to_string();
+    let mut prefix = root_dir_str.split("/").last().unwrap().to_string();
+    prefix.push_str(&uuid);
+    Ok(prefix)
 }
@@ -0,0 +1,549 @@
+//! Multi-threaded scan pipeline used by `create`.
+//!
+//! A walker thread enumerates the tree in a stable order, a pool of workers
+//! hashes every file while copying it into the staging directory, and the
+//! calling thread reorders the results so the manifest comes out in walk
+//! order regardless of which worker finished first. The walker only runs
+//! `window` files ahead of the last result handed back, which keeps memory
+//! bounded on large trees.
+//!
+//! Files whose size and modification time match the previous snapshot are
+//! not read at all. Staged files are published a window at a time: one
+//! [`Storage::missing`] call finds the objects the repository lacks, and
+//! only those are uploaded.
+
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    fs::{self, File},
+    io::{Read, Write},
+    path::{Path, PathBuf},
+    sync::{mpsc, Arc, Mutex},
+    thread,
+    time::Duration,
+};
+
+use walkdir::WalkDir;
+
+use crate::{
+    config::Config,
+    snapshot::{ContentType, FailedFile, SnapshotEntry},
+    storage::{self, Storage},
+    util,
+};
+
+/// A file that was hashed and stored by the pipeline.
+pub(crate) struct ScannedFile {
+    pub path: PathBuf,
+    pub size: u64,
+    pub hash: String,
+    /// Storage key of the stored content.
+    pub content_path: String,
+    /// The file kept changing while it was read, so `hash` and the stored
+    /// content describe only one of its intermediate states.
+    pub changed_during_backup: bool,
+    pub mode: Option<u32>,
+    pub mtime: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+impl ScannedFile {
+    /// Turns the file into a manifest entry recorded at `time`.
+    pub(crate) fn into_entry(self, time: chrono::DateTime<chrono::Utc>) -> SnapshotEntry {
+        SnapshotEntry {
+            path: self.path.to_string_lossy().to_string(),
+            size: self.size,
+            hash: self.hash,
+            modify_time: time,
+            deleted: false,
+            content_type: ContentType::FullCopy,
+            content_path: Some(self.content_path),
+            changed_during_backup: self.changed_during_backup,
+            mode: self.mode,
+            mtime: self.mtime,
+        }
+    }
+}
+
+pub(crate) fn default_jobs() -> usize {
+    thread::available_parallelism()
+        .map(|jobs| jobs.get())
+        .unwrap_or(1)
+}
+
+/// Walks `root`, hashing every file that `config` does not exclude and
+/// storing its content in `storage`. `known` holds the latest entry of every
+/// file from earlier snapshots, by path; files that still match theirs are
+/// taken as they are. Results are returned in walk order. A file that cannot
+/// be read or stored does not stop the scan; it comes back as a `FailedFile`.
+pub(crate) fn scan(
+    root: &Path,
+    known: &HashMap<String, SnapshotEntry>,
+    storage: &dyn Storage,
+    config: &Config,
+    jobs: usize,
+) -> anyhow::Result<Vec<Result<ScannedFile, FailedFile>>> {
+    scan_paths(root, &[root.to_path_buf()], known, storage, config, jobs)
+}
+
+/// Like [`scan`], but only walks `paths`, files or directories inside
+/// `root`. Paths that no longer exist are skipped. Exclude patterns still
+/// apply relative to `root`.
+pub(crate) fn scan_paths(
+    root: &Path,
+    paths: &[PathBuf],
+    known: &HashMap<String, SnapshotEntry>,
+    storage: &dyn Storage,
+    config: &Config,
+    jobs: usize,
+) -> anyhow::Result<Vec<Result<ScannedFile, FailedFile>>> {
+    let jobs = jobs.max(1);
+    let window = jobs * 4;
+    let staging_dir = storage.staging_dir();
+    fs::create_dir_all(&staging_dir)?;
+
+    let (path_tx, path_rx) = mpsc::sync_channel::<(usize, PathBuf)>(jobs * 2);
+    let path_rx = Arc::new(Mutex::new(path_rx));
+    let (result_tx, result_rx) = mpsc::channel::<(usize, Result<Scanned, FailedFile>)>();
+    let (credit_tx, credit_rx) = mpsc::sync_channel::<()>(window);
+    for _ in 0..window {
+        credit_tx.send(())?;
+    }
+
+    thread::scope(|scope| {
+        // Owned here so an early return drops it and unblocks the walker.
+        let credit_tx = credit_tx;
+
+        scope.spawn(move || {
+            // Excluded directories are not descended into at all
+            let files = paths
+                .iter()
+                .flat_map(|path| {
+                    WalkDir::new(path)
+                        .sort_by_file_name()
+                        .into_iter()
+                        .filter_entry(|e| {
+                            let relative = e.path().strip_prefix(root).unwrap_or(e.path());
+                            if e.file_type().is_dir() {
+                                !config.should_exclude_dir(relative)
+                            } else {
+                                !config.should_exclude(relative)
+                            }
+                        })
+                })
+                .filter_map(|e| e.ok())
+                .filter(|e| e.path().is_file());
+
+            for (index, entry) in files.enumerate() {
+                if credit_rx.recv().is_err() || path_tx.send((index, entry.into_path())).is_err() {
+                    break;
+                }
+            }
+        });
+
+        for _ in 0..jobs {
+            let path_rx = Arc::clone(&path_rx);
+            let result_tx = result_tx.clone();
+            let staging_dir = &staging_dir;
+            scope.spawn(move || loop {
+                let next = path_rx
+                    .lock()
+                    .map_err(|_| ())
+                    .and_then(|rx| rx.recv().map_err(|_| ()));
+                let Ok((index, path)) = next else {
+                    break;
+                };
+                let previous = known.get(path.to_string_lossy().as_ref());
+                let result = scan_file(&path, previous, staging_dir).map_err(|e| FailedFile {
+                    path: path.to_string_lossy().to_string(),
+                    error: format!("{:#}", e),
+                });
+                if result_tx.send((index, result)).is_err() {
+                    break;
+                }
+            });
+        }
+        drop(result_tx);
+
+        let mut pending = BTreeMap::new();
+        let mut next = 0;
+        let mut files = Vec::new();
+        let mut staged = Vec::new();
+        for (index, result) in result_rx {
+            pending.insert(index, result);
+            while let Some(result) = pending.remove(&next) {
+                match result {
+                    Ok(Scanned {
+                        file,
+                        staged: Some(temp),
+                    }) => {
+                        staged.push((files.len(), temp));
+                        files.push(Ok(file));
+                    }
+                    Ok(Scanned { file, staged: None }) => files.push(Ok(file)),
+                    Err(failure) => files.push(Err(failure)),
+                }
+                next += 1;
+                let _ = credit_tx.send(());
+            }
+            if staged.len() >= window {
+                publish_staged(storage, &mut files, std::mem::take(&mut staged), jobs);
+            }
+        }
+        publish_staged(storage, &mut files, staged, jobs);
+        Ok(files)
+    })
+}
+
+/// What a worker hands back for one file.
+struct Scanned {
+    file: ScannedFile,
+    /// The staged copy of the content, still to be published, or `None` if
+    /// the file was unchanged and its object is already stored.
+    staged: Option<PathBuf>,
+}
+
+/// Stores the staged files at `batch`, pairs of an index into `files` and
+/// the staged copy, under their content keys. Objects the repository
+/// already has are not uploaded again. A file whose content cannot be
+/// stored is turned into a failure.
+fn publish_staged(
+    storage: &dyn Storage,
+    files: &mut [Result<ScannedFile, FailedFile>],
+    batch: Vec<(usize, PathBuf)>,
+    jobs: usize,
+) {
+    if batch.is_empty() {
+        return;
+    }
+    let key = |index: usize| match &files[index] {
+        Ok(file) => file.content_path.clone(),
+        Err(_) => unreachable!("only scanned files are staged"),
+    };
+    let keys: Vec<_> = batch.iter().map(|(index, _)| key(*index)).collect();
+    let missing = match storage.missing(&keys) {
+        Ok(missing) => missing.into_iter().collect::<HashSet<_>>(),
+        Err(e) => {
+            for (index, temp) in batch {
+                let _ = fs::remove_file(&temp);
+                fail(files, index, &e);
+            }
+            return;
+        }
+    };
+
+    // Two files with the same content are only uploaded once
+    let mut uploads = Vec::new();
+    let mut claimed = HashSet::new();
+    for (index, temp) in batch {
+        let key = key(index);
+        if missing.contains(&key) && claimed.insert(key.clone()) {
+            uploads.push((key, temp));
+        } else {
+            let _ = fs::remove_file(&temp);
+        }
+    }
+
+    let chunk = uploads.len().div_ceil(jobs).max(1);
+    let failures: Vec<_> = thread::scope(|scope| {
+        let workers: Vec<_> = uploads
+            .chunks(chunk)
+            .map(|uploads| {
+                scope.spawn(move || {
+                    let mut failures = Vec::new();
+                    for (key, temp) in uploads {
+                        if let Err(e) = storage.put_file(key, temp) {
+                            let _ = fs::remove_file(temp);
+                            failures.push((key.clone(), e));
+                        }
+                    }
+                    failures
+                })
+            })
+            .collect();
+        workers
+            .into_iter()
+            .flat_map(|worker| worker.join().expect("upload worker panicked"))
+            .collect()
+    });
+    // Every file with that content is left without it
+    for (key, e) in failures {
+        let failed: Vec<_> = files
+            .iter()
+            .enumerate()
+            .filter(|(_, file)| file.as_ref().is_ok_and(|file| file.content_path == key))
+            .map(|(index, _)| index)
+            .collect();
+        for index in failed {
+            fail(files, index, &e);
+        }
+    }
+}
+
+fn fail(files: &mut [Result<ScannedFile, FailedFile>], index: usize, error: &anyhow::Error) {
+    if let Ok(file) = &files[index] {
+        files[index] = Err(FailedFile {
+            path: file.path.to_string_lossy().to_string(),
+            error: format!("Cannot store content: {:#}", error),
+        });
+    }
+}
+
+/// How many times a file that keeps changing while it is read is retried
+/// before it is recorded as changed during backup.
+const MAX_READ_ATTEMPTS: usize = 3;
+
+/// A file modified this close to when its entry was recorded may have
+/// changed again without its modification time moving, so it is read.
+const RACY_WINDOW: Duration = Duration::from_secs(2);
+
+/// Takes `path` from `previous` if its size and modification time still
+/// match, and otherwise hashes and stages it, re-reading it when its size or
+/// modification time moved while it was being read. Only the read that is
+/// kept is staged for storing. If the file never holds still, the last read
+/// is kept and flagged through `ScannedFile::changed_during_backup`.
+fn scan_file(
+    path: &Path,
+    previous: Option<&SnapshotEntry>,
+    staging_dir: &Path,
+) -> anyhow::Result<Scanned> {
+    let mut attempt = 1;
+    loop {
+        let before = fs::metadata(path)?;
+        if attempt == 1 {
+            if let Some(file) = previous.and_then(|entry| unchanged(path, entry, &before)) {
+                return Ok(Scanned { file, staged: None });
+            }
+        }
+        let (hash, size, temp) = stage(File::open(path)?, staging_dir)?;
+        let after = fs::metadata(path);
+
+        let stable = after.as_ref().is_ok_and(|after| {
+            size == before.len()
+                && before.len() == after.len()
+                && before.modified().ok() == after.modified().ok()
+        });
+
+        if stable || attempt == MAX_READ_ATTEMPTS {
+            let after = match after {
+                Ok(after) => after,
+                Err(e) => {
+                    let _ = fs::remove_file(&temp);
+                    return Err(e.into());
+                }
+            };
+            return Ok(Scanned {
+                file: ScannedFile {
+                    path: path.to_path_buf(),
+                    size,
+                    content_path: storage::content_key(&hash),
+                    hash,
+                    changed_during_backup: !stable,
+                    mode: file_mode(&after),
+                    mtime: after.modified().ok().map(Into::into),
+                },
+                staged: Some(temp),
+            });
+        }
+        let _ = fs::remove_file(&temp);
+        attempt += 1;
+    }
+}
+
+/// The file as `entry` recorded it, if its size and modification time are
+/// the same and it was last modified well before the entry was written.
+fn unchanged(path: &Path, entry: &SnapshotEntry, metadata: &fs::Metadata) -> Option<ScannedFile> {
+    let mtime: chrono::DateTime<chrono::Utc> = metadata.modified().ok()?.into();
+    let settled = chrono::Duration::from_std(RACY_WINDOW).ok()?;
+    let matches = !entry.deleted
+        && !entry.changed_during_backup
+        && entry.size == metadata.len()
+        && entry.mtime == Some(mtime)
+        && mtime + settled < entry.modify_time;
+    if !matches {
+        return None;
+    }
+    Some(ScannedFile {
+        path: path.to_path_buf(),
+        size: entry.size,
+        hash: entry.hash.clone(),
+        content_path: entry.content_path.clone()?,
+        changed_during_backup: false,
+        mode: file_mode(metadata),
+        mtime: Some(mtime),
+    })
+}
+
+#[cfg(unix)]
+fn file_mode(metadata: &fs::Metadata) -> Option<u32> {
+    use std::os::unix::fs::PermissionsExt;
+    Some(metadata.permissions().mode() & 0o7777)
+}
+
+#[cfg(not(unix))]
+fn file_mode(_metadata: &fs::Metadata) -> Option<u32> {
+    None
+}
+
+/// Hashes `source` while copying the bytes into a new file in
+/// `staging_dir`. Returns the hash, the size and the staged file.
+fn stage(source: impl Read, staging_dir: &Path) -> anyhow::Result<(String, u64, PathBuf)> {
+    let temp_path = util::atomic::temp_path_in(staging_dir);
+    let result = (|| {
+        let mut temp = File::create(&temp_path)?;
+        util::hash::hash_reader_with(source, |chunk| temp.write_all(chunk))
+    })();
+    match result {
+        Ok((hash, size)) => Ok((hash, size, temp_path)),
+        Err(e) => {
+            let _ = fs::remove_file(&temp_path);
+            Err(e)
+        }
+    }
+}
+
+/// Stores the staged file `temp` under `key` unless the object is already
+/// there. The staged file is gone afterwards either way.
+fn publish(storage: &dyn Storage, key: &str, temp: &Path) -> anyhow::Result<()> {
+    let result = match storage.exists(key) {
+        Ok(true) => fs::remove_file(temp).map_err(Into::into),
+        Ok(false) => storage.put_file(key, temp),
+        Err(e) => Err(e),
+    };
+    if result.is_err() {
+        let _ = fs::remove_file(temp);
+    }
+    result
+}
+
+/// Hashes `source` while copying the bytes into a staged file that is then
+/// stored as `content/<hash>.dat`. Because the key is derived from the bytes
+/// that were actually written, an object's key always matches its content.
+/// Returns the hash, the size and the content key.
+pub(crate) fn store_content(
+    source: impl Read,
+    storage: &dyn Storage,
+    staging_dir: &Path,
+) -> anyhow::Result<(String, u64, String)> {
+    let (hash, size, temp) = stage(source, staging_dir)?;
+    let key = storage::content_key(&hash);
+    publish(storage, &key, &temp)?;
+    Ok((hash, size, key))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::MemoryStorage;
+
+    fn tree(files: usize) -> tempfile::TempDir {
+        let dir = tempfile::tempdir().unwrap();
+        for index in 0..files {
+            let path = dir.path().join(format!("d{}/f{:03}.txt", index % 3, index));
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            // Every fifth file shares its content with the others
+            let content = if index % 5 == 0 {
+                "shared".to_string()
+            } else {
+                format!("file {}", index)
+            };
+            fs::write(path, content).unwrap();
+        }
+        dir
+    }
+
+    fn scanned(dir: &Path, storage: &dyn Storage, jobs: usize) -> Vec<(PathBuf, String)> {
+        let config = Config {
+            exclude_patterns: Vec::new(),
+            ..Config::default()
+        };
+        scan(dir, &HashMap::new(), storage, &config, jobs)
+            .unwrap()
+            .into_iter()
+            .map(|file| {
+                let file = file.unwrap();
+                (file.path, file.hash)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn results_come_back_in_walk_order_for_any_number_of_jobs() {
+        let dir = tree(100);
+        let serial = scanned(dir.path(), &MemoryStorage::new(), 1);
+        let mut sorted = serial.clone();
+        sorted.sort();
+        assert_eq!(serial, sorted);
+        assert_eq!(serial.len(), 100);
+        for jobs in [2, 8, 0] {
+            assert_eq!(scanned(dir.path(), &MemoryStorage::new(), jobs), serial);
+        }
+    }
+
+    #[test]
+    fn every_distinct_content_is_stored_once() {
+        let dir = tree(100);
+        let storage = MemoryStorage::new();
+        let files = scanned(dir.path(), &storage, 8);
+        let mut hashes: Vec<_> = files.into_iter().map(|(_, hash)| hash).collect();
+        hashes.sort();
+        hashes.dedup();
+        let stored = storage.list(storage::CONTENT_DIR).unwrap();
+        assert_eq!(stored.len(), hashes.len());
+        for hash in hashes {
+            let content = storage.get(&storage::content_key(&hash)).unwrap();
+            assert_eq!(
+                util::hash::hash_reader_with(&content[..], |_| Ok(()))
+                    .unwrap()
+                    .0,
+                hash
+            );
+        }
+    }
+
+    #[test]
+    fn settled_files_are_taken_from_the_previous_entry() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("a.txt");
+        fs::write(&path, "one").unwrap();
+        let metadata = fs::metadata(&path).unwrap();
+        let mtime: chrono::DateTime<chrono::Utc> = metadata.modified().unwrap().into();
+        let entry = SnapshotEntry {
+            path: path.to_string_lossy().to_string(),
+            size: 3,
+            hash: "recorded".to_string(),
+            modify_time: mtime + chrono::Duration::minutes(1),
+            deleted: false,
+            content_type: ContentType::FullCopy,
+            content_path: Some(storage::content_key("recorded")),
+            changed_during_backup: false,
+            mode: None,
+            mtime: Some(mtime),
+        };
+
+        let scanned = scan_file(&path, Some(&entry), dir.path()).unwrap();
+        assert!(scanned.staged.is_none());
+        assert_eq!(scanned.file.hash, "recorded");
+
+        // Recorded right after the last change, it may have changed again
+        let racy = SnapshotEntry {
+            modify_time: mtime,
+            ..entry
+        };
+        let scanned = scan_file(&path, Some(&racy), dir.path()).unwrap();
+        assert_ne!(scanned.file.hash, "recorded");
+        fs::remove_file(scanned.staged.unwrap()).unwrap();
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn files_that_never_hold_still_are_flagged() {
+        // Files in /proc report a size of 0 but read as more, so every read
+        // looks like the file changed underneath it.
+        let staging = tempfile::tempdir().unwrap();
+        let scanned = scan_file(Path::new("/proc/self/status"), None, staging.path()).unwrap();
+        assert!(scanned.file.changed_during_backup);
+        assert!(scanned.file.size > 0);
+        // Only the kept read is left in the staging directory
+        assert_eq!(fs::read_dir(staging.path()).unwrap().count(), 1);
+        assert_eq!(scanned.staged.unwrap().parent(), Some(staging.path()));
+    }
+}
@@ -0,0 +1,84 @@
+//! Retention for a backed-up root (`snapback prune`).
+//!
+//! Snapshots only record changes, so the oldest snapshot that is kept is
+//! first rewritten to hold the complete state at its point; only then are
+//! the snapshots before it removed. Content objects no remaining manifest
+//! refers to are deleted last. An interrupted prune leaves every kept
+//! snapshot restorable, and running it again finishes the job.
+
+use std::{collections::HashSet, path::Path};
+
+use crate::{
+    repository::{PruneReport, Repository},
+    snapshot::{BackupInfo, Snapshot, SnapshotStatus},
+    storage::{self, Storage},
+};
+
+use super::{find_backup_info, get_backup_files_by_prefix, replay};
+
+pub(crate) fn prune(repo: &Repository, root: &Path, keep: u32) -> anyhow::Result<PruneReport> {
+    if keep == 0 {
+        return Err(anyhow::anyhow!("At least one backup must be kept"));
+    }
+    let storage = repo.storage();
+    let source = find_backup_info(repo, root)?;
+    let manifests = get_backup_files_by_prefix(storage, &source.backup_prefix)?;
+
+    let mut report = PruneReport {
+        source: source.clone(),
+        removed: Vec::new(),
+        kept: manifests.len().min(keep as usize),
+        objects_removed: 0,
+    };
+    if manifests.len() > keep as usize {
+        let split = manifests.len() - keep as usize;
+        let (first_kept, key) = &manifests[split];
+
+        let state = replay(storage, &source.backup_prefix, *first_kept)?;
+        let mut base = Snapshot::read(storage, key, *first_kept)?;
+        base.entries = state.files;
+        // Failures from the removed snapshots still apply to the new base
+        base.failures = Vec::new();
+        base.status = SnapshotStatus::Complete;
+        for failure in state.not_backed_up {
+            base.record_failure(failure);
+        }
+        storage.put(key, serde_json::to_string_pretty(&base)?.as_bytes())?;
+
+        for (number, key) in &manifests[..split] {
+            storage.delete(key)?;
+            report.removed.push(*number);
+        }
+    }
+    report.objects_removed = collect_garbage(storage)?;
+    Ok(report)
+}
+
+/// Deletes content objects that no manifest of any root refers to, and
+/// returns how many were removed. Info records and manifests must all be
+/// readable; otherwise nothing is deleted.
+fn collect_garbage(storage: &dyn Storage) -> anyhow::Result<usize> {
+    let mut referenced = HashSet::new();
+    for key in storage.list(storage::INFO_DIR)? {
+        let info: BackupInfo = serde_json::from_slice(&storage.get(&key)?)
+            .map_err(|e| anyhow::anyhow!("Unreadable info record {}: {}", key, e))?;
+        for (number, key) in get_backup_files_by_prefix(storage, &info.backup_prefix)? {
+            let snapshot = Snapshot::read(storage, &key, number)?;
+            referenced.extend(
+                snapshot
+                    .entries
+                    .into_iter()
+                    .filter_map(|entry| entry.content_path),
+            );
+        }
+    }
+
+    let mut removed = 0;
+    for key in storage.list(storage::CONTENT_DIR)? {
+        if key.ends_with(".dat") && !referenced.contains(&key) {
+            storage.delete(&key)?;
+            removed += 1;
+        }
+    }
+    Ok(removed)
+}
@@ -0,0 +1,107 @@
+//! Integrity checks (`snapback verify`).
+//!
+//! Every manifest of the checked roots must be readable, and every content
+//! object they refer to must exist. When data is read as well, each object
+//! is hashed and compared with what the manifests recorded, which catches
+//! silent corruption but downloads the whole repository from remote
+//! backends.
+
+use std::{collections::BTreeMap, io, path::Path};
+
+use crate::{
+    repository::{Repository, VerifyOptions, VerifyProblem, VerifyReport},
+    snapshot::{BackupInfo, Snapshot},
+    util,
+};
+
+use super::{find_backup_info, get_backup_files_by_prefix};
+
+/// How many content keys are checked per round trip.
+const MISSING_BATCH: usize = 1000;
+
+pub(crate) fn verify(
+    repo: &Repository,
+    root: Option<&Path>,
+    options: &VerifyOptions,
+) -> anyhow::Result<VerifyReport> {
+    let storage = repo.storage();
+    let sources = match root {
+        Some(root) => vec![find_backup_info(repo, root)?],
+        None => {
+            let mut sources: Vec<_> = BackupInfo::read_all(storage)?
+                .into_iter()
+                .map(|(info, _)| info)
+                .collect();
+            sources.sort_by(|a, b| a.path_to_root.cmp(&b.path_to_root));
+            sources
+        }
+    };
+
+    let mut report = VerifyReport {
+        sources: sources.clone(),
+        snapshots: 0,
+        objects: 0,
+        bytes: 0,
+        problems: Vec::new(),
+    };
+    // Content key -> recorded hash and size
+    let mut objects = BTreeMap::new();
+    for source in &sources {
+        for (number, key) in get_backup_files_by_prefix(storage, &source.backup_prefix)? {
+            let snapshot = match Snapshot::read(storage, &key, number) {
+                Ok(snapshot) => snapshot,
+                Err(e) => {
+                    report.problems.push(VerifyProblem::new(&key, e));
+                    continue;
+                }
+            };
+            report.snapshots += 1;
+            for entry in snapshot.entries.into_iter().filter(|e| !e.deleted) {
+                match entry.content_path.filter(|path| !path.is_empty()) {
+                    Some(content) => {
+                        objects.entry(content).or_insert((entry.hash, entry.size));
+                    }
+                    None => report.problems.push(VerifyProblem::new(
+                        &key,
+                        format!("{} has no stored content", entry.path),
+                    )),
+                }
+            }
+        }
+    }
+
+    let keys: Vec<String> = objects.keys().cloned().collect();
+    for batch in keys.chunks(MISSING_BATCH) {
+        for key in storage.missing(batch)? {
+            objects.remove(&key);
+            report
+                .problems
+                .push(VerifyProblem::new(&key, "content object is missing"));
+        }
+    }
+    report.objects = objects.len();
+
+    if options.read_data {
+        for (key, (hash, size)) in &objects {
+            let read = storage.reader(key).and_then(|reader| {
+                util::hash::hash_reader_with(reader, |_| Ok::<_, io::Error>(()))
+            });
+            match read {
+                Ok((actual, actual_size)) => {
+                    report.bytes += actual_size;
+                    if &actual != hash || actual_size != *size {
+                        report.problems.push(VerifyProblem::new(
+                            key,
+                            format!(
+                                "content is damaged: expected {} bytes with hash {}, found {} bytes with hash {}",
+                                size, hash, actual_size, actual
+                            ),
+                        ));
+                    }
+                }
+                Err(e) => report.problems.push(VerifyProblem::new(key, e)),
+            }
+        }
+    }
+    Ok(report)
+}
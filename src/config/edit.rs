@@ -0,0 +1,378 @@
+//! Changing single settings in a config file (`snapback config set` and
+//! `unset`).
+//!
+//! Only the edited file is read and written; the other layers are left
+//! alone. Every change is checked as `load` checks the file before it is
+//! kept, so a file is never written in a state `load` would refuse.
+//! The file is written back in its own format, without its comments.
+
+use std::path::{Path, PathBuf};
+
+use serde_json::{Map, Value};
+
+use crate::util::atomic;
+
+use super::{layers, Format};
+
+/// One config file, edited in memory and written back with [`save`].
+///
+/// [`save`]: ConfigFile::save
+#[derive(Debug, Clone)]
+pub struct ConfigFile {
+    path: PathBuf,
+    format: Format,
+    values: Map<String, Value>,
+    /// The file as read had comments, which [`save`](Self::save) drops.
+    has_comments: bool,
+}
+
+impl ConfigFile {
+    /// Opens `path`. A file that does not exist yet starts out empty and is
+    /// only created by [`save`](Self::save). An existing file may be invalid,
+    /// so that a bad key can still be unset.
+    pub fn open(path: &Path) -> anyhow::Result<Self> {
+        let format = Format::from_path(path)?;
+        let (values, has_comments) = if path.exists() {
+            let content = std::fs::read_to_string(path)
+                .map_err(|e| anyhow::anyhow!("Cannot read config {}: {}", path.display(), e))?;
+            let values = format
+                .parse(&content)
+                .map_err(|e| anyhow::anyhow!("Invalid config {}: {:#}", path.display(), e))?;
+            // JSON has no comments; in TOML and YAML a `#` outside a string
+            // starts one, and one inside a string is rare enough to warn about.
+            (values, format != Format::Json && content.contains('#'))
+        } else {
+            (Map::new(), false)
+        };
+        Ok(Self {
+            path: path.to_path_buf(),
+            format,
+            values,
+            has_comments,
+        })
+    }
+
+    /// Whether saving drops comments the file had.
+    pub fn drops_comments(&self) -> bool {
+        self.has_comments
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// The value of `key` in this file alone.
+    pub fn get(&self, key: &str) -> Option<&Value> {
+        let mut parts = key.split('.');
+        let mut value = self.values.get(parts.next()?)?;
+        for part in parts {
+            value = value.get(part)?;
+        }
+        Some(value).filter(|value| !value.is_null())
+    }
+
+    /// Sets `key`, which may be dotted, to `text`: JSON such as `20`,
+    /// `true` or `["a", "b"]`, or a plain string. `null` removes the key.
+    pub fn set(&mut self, key: &str, text: &str) -> anyhow::Result<()> {
+        layers::check_key(key)?;
+        self.change(&[text], |values, mut items| {
+            match items.remove(0) {
+                // Files cannot hold `null` in every format; unset instead
+                Value::Null => {
+                    remove(values, key);
+                }
+                value => insert(values, key, value),
+            }
+            Ok(())
+        })
+    }
+
+    /// Appends `items` to the list at `key`, skipping those already in it.
+    pub fn add(&mut self, key: &str, items: &[&str]) -> anyhow::Result<()> {
+        layers::check_key(key)?;
+        self.change(items, |values, items| {
+            let mut list = match remove(values, key) {
+                None | Some(Value::Null) => Vec::new(),
+                Some(Value::Array(list)) => list,
+                Some(_) => return Err(anyhow::anyhow!("{} is not a list", key)),
+            };
+            for item in items {
+                if !list.contains(&item) {
+                    list.push(item);
+                }
+            }
+            insert(values, key, Value::Array(list));
+            Ok(())
+        })
+    }
+
+    /// Removes `items` from the list at `key`, and the key itself once the
+    /// list is empty. Returns the items that were not in the list.
+    pub fn remove<'a>(&mut self, key: &str, items: &[&'a str]) -> anyhow::Result<Vec<&'a str>> {
+        let mut missing = Vec::new();
+        let mut list = match self.get(key) {
+            None => Vec::new(),
+            Some(Value::Array(list)) => list.clone(),
+            Some(_) => return Err(anyhow::anyhow!("{} is not a list", key)),
+        };
+        for item in items {
+            let before = list.len();
+            let parsed = layers::parse_value(item);
+            list.retain(|existing| *existing != parsed && existing.as_str() != Some(item));
+            if list.len() == before {
+                missing.push(*item);
+            }
+        }
+        if missing.len() == items.len() {
+            return Ok(missing);
+        }
+
+        let original = self.values.clone();
+        remove(&mut self.values, key);
+        if !list.is_empty() {
+            insert(&mut self.values, key, Value::Array(list));
+        }
+        if let Err(e) = self.validate() {
+            self.values = original;
+            return Err(e);
+        }
+        Ok(missing)
+    }
+
+    /// Removes `key` from the file. Returns whether it was there.
+    pub fn unset(&mut self, key: &str) -> anyhow::Result<bool> {
+        let original = self.values.clone();
+        if remove(&mut self.values, key).is_none() {
+            return Ok(false);
+        }
+        if let Err(e) = self.validate() {
+            self.values = original;
+            return Err(e);
+        }
+        Ok(true)
+    }
+
+    /// Writes the file, creating its directory if needed. The old file is
+    /// replaced atomically, so an interrupted save never leaves half a config.
+    pub fn save(&self) -> anyhow::Result<()> {
+        let text = self.format.to_string(&self.values)?;
+        atomic::write(&self.path, text)
+            .map_err(|e| anyhow::anyhow!("Cannot write config {}: {}", self.path.display(), e))
+    }
+
+    /// Applies `change` to the values parsed from `texts`. If that does not
+    /// give a valid file, the texts are tried again as plain strings, so a
+    /// path like `2024` is not taken for a number.
+    fn change(
+        &mut self,
+        texts: &[&str],
+        change: impl Fn(&mut Map<String, Value>, Vec<Value>) -> anyhow::Result<()>,
+    ) -> anyhow::Result<()> {
+        let original = self.values.clone();
+        if let Err(e) = change(
+            &mut self.values,
+            texts.iter().map(|text| layers::parse_value(text)).collect(),
+        ) {
+            self.values = original;
+            return Err(e);
+        }
+        let Err(error) = self.validate() else {
+            return Ok(());
+        };
+
+        let mut as_strings = original.clone();
+        let retried = change(
+            &mut as_strings,
+            texts.iter().map(|text| Value::from(*text)).collect(),
+        );
+        if retried.is_ok() && check(&as_strings).is_ok() {
+            self.values = as_strings;
+            return Ok(());
+        }
+        self.values = original;
+        Err(error)
+    }
+
+    fn validate(&self) -> anyhow::Result<()> {
+        check(&self.values).map_err(|e| {
+            anyhow::anyhow!(
+                "The change would make {} invalid: {}",
+                self.path.display(),
+                e
+            )
+        })
+    }
+}
+
+fn check(values: &Map<String, Value>) -> anyhow::Result<()> {
+    serde_json::from_value::<layers::Layer>(Value::Object(values.clone()))
+        .map(|_| ())
+        .map_err(anyhow::Error::from)
+}
+
+/// Sets the dotted `key`, replacing anything in the way with objects.
+fn insert(map: &mut Map<String, Value>, key: &str, value: Value) {
+    let (parents, last) = match key.rsplit_once('.') {
+        Some((parents, last)) => (Some(parents), last),
+        None => (None, key),
+    };
+    let mut map = map;
+    for part in parents.into_iter().flat_map(|parents| parents.split('.')) {
+        let entry = map
+            .entry(part.to_string())
+            .or_insert_with(|| Value::Object(Map::new()));
+        if !entry.is_object() {
+            *entry = Value::Object(Map::new());
+        }
+        map = entry.as_object_mut().expect("just made an object");
+    }
+    map.insert(last.to_string(), value);
+}
+
+/// Removes the dotted `key`, and any objects left empty by that.
+fn remove(map: &mut Map<String, Value>, key: &str) -> Option<Value> {
+    match key.split_once('.') {
+        None => map.remove(key),
+        Some((first, rest)) => {
+            let inner = map.get_mut(first)?.as_object_mut()?;
+            let removed = remove(inner, rest);
+            if inner.is_empty() {
+                map.remove(first);
+            }
+            removed
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn set_takes_json_or_plain_strings() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut file = ConfigFile::open(&dir.path().join("config.json")).unwrap();
+
+        file.set("max_backup_count", "20").unwrap();
+        file.set("backup_default_path", "2024").unwrap();
+        file.set("s3.bucket", "team").unwrap();
+
+        assert_eq!(file.get("max_backup_count"), Some(&json!(20)));
+        assert_eq!(file.get("backup_default_path"), Some(&json!("2024")));
+        assert_eq!(file.get("s3"), Some(&json!({ "bucket": "team" })));
+
+        file.set("max_backup_count", "null").unwrap();
+        assert_eq!(file.get("max_backup_count"), None);
+    }
+
+    #[test]
+    fn invalid_changes_are_refused_and_undone() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut file = ConfigFile::open(&dir.path().join("config.json")).unwrap();
+        file.set("max_backup_count", "20").unwrap();
+
+        let e = file
+            .set("max_backup_count", "lots")
+            .unwrap_err()
+            .to_string();
+        assert!(e.starts_with("The change would make"), "{}", e);
+        let e = file.set("hooks.pre_creat", "sync").unwrap_err().to_string();
+        assert!(e.contains("unknown field `pre_creat`"), "{}", e);
+        let e = file.set("max_backups", "3").unwrap_err().to_string();
+        assert!(
+            e.starts_with("Unknown configuration key max_backups"),
+            "{}",
+            e
+        );
+
+        assert_eq!(file.get("max_backup_count"), Some(&json!(20)));
+        assert_eq!(file.get("hooks"), None);
+    }
+
+    #[test]
+    fn lists_are_added_to_and_removed_from() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut file = ConfigFile::open(&dir.path().join("config.json")).unwrap();
+
+        file.add("exclude_patterns", &["*.o", "dist/", "*.o"])
+            .unwrap();
+        file.add("exclude_patterns", &["!reset", "dist/"]).unwrap();
+        assert_eq!(
+            file.get("exclude_patterns"),
+            Some(&json!(["*.o", "dist/", "!reset"]))
+        );
+
+        let missing = file.remove("exclude_patterns", &["*.o", "*.swp"]).unwrap();
+        assert_eq!(missing, ["*.swp"]);
+        assert_eq!(
+            file.get("exclude_patterns"),
+            Some(&json!(["dist/", "!reset"]))
+        );
+
+        file.remove("exclude_patterns", &["dist/", "!reset"])
+            .unwrap();
+        assert_eq!(file.get("exclude_patterns"), None);
+
+        file.set("max_backup_count", "5").unwrap();
+        let e = file
+            .add("max_backup_count", &["6"])
+            .unwrap_err()
+            .to_string();
+        assert_eq!(e, "max_backup_count is not a list");
+        assert_eq!(file.get("max_backup_count"), Some(&json!(5)));
+        let e = file
+            .remove("max_backup_count", &["5"])
+            .unwrap_err()
+            .to_string();
+        assert_eq!(e, "max_backup_count is not a list");
+    }
+
+    #[test]
+    fn unset_removes_keys_and_emptied_tables() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut file = ConfigFile::open(&dir.path().join("config.json")).unwrap();
+        file.set("s3.endpoint", "http://minio").unwrap();
+        file.set("s3.bucket", "team").unwrap();
+
+        assert!(file.unset("s3.bucket").unwrap());
+        assert_eq!(file.get("s3"), Some(&json!({ "endpoint": "http://minio" })));
+        assert!(!file.unset("s3.bucket").unwrap());
+        assert!(file.unset("s3.endpoint").unwrap());
+        assert_eq!(file.get("s3"), None);
+        assert!(!file.unset("ssh_key_file").unwrap());
+    }
+
+    #[test]
+    fn files_are_only_written_by_save() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("nested/config.toml");
+        let mut file = ConfigFile::open(&path).unwrap();
+        file.set("max_backup_count", "7").unwrap();
+        assert!(!path.exists());
+
+        file.save().unwrap();
+        let text = std::fs::read_to_string(&path).unwrap();
+        assert_eq!(text.trim(), "max_backup_count = 7");
+        let file = ConfigFile::open(&path).unwrap();
+        assert_eq!(file.get("max_backup_count"), Some(&json!(7)));
+        assert!(!file.drops_comments());
+
+        std::fs::write(&path, "# kept by hand\nmax_backup_count = 7\n").unwrap();
+        assert!(ConfigFile::open(&path).unwrap().drops_comments());
+    }
+
+    #[test]
+    fn a_bad_key_can_still_be_unset() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("config.yaml");
+        std::fs::write(&path, "max_backup_count: 3\nmax_backups: 5\n").unwrap();
+        let mut file = ConfigFile::open(&path).unwrap();
+
+        assert!(file.set("backup_default_path", "/backups").is_err());
+        assert!(file.unset("max_backups").unwrap());
+        file.set("backup_default_path", "/backups").unwrap();
+        assert_eq!(file.get("max_backup_count"), Some(&json!(3)));
+    }
+}
@@ -0,0 +1,155 @@
+//! Config file formats, chosen by file extension.
+
+use std::path::{Path, PathBuf};
+
+use serde::{de::DeserializeOwned, Serialize};
+
+/// Extensions tried, in this order, wherever a config file is looked for.
+pub const EXTENSIONS: &[&str] = &["json", "toml", "yaml", "yml"];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Json,
+    Toml,
+    Yaml,
+}
+
+impl Format {
+    pub fn from_path(path: &Path) -> anyhow::Result<Self> {
+        let extension = path
+            .extension()
+            .and_then(|extension| extension.to_str())
+            .map(str::to_ascii_lowercase);
+        match extension.as_deref() {
+            Some("json") => Ok(Self::Json),
+            Some("toml") => Ok(Self::Toml),
+            Some("yaml" | "yml") => Ok(Self::Yaml),
+            _ => Err(anyhow::anyhow!(
+                "Cannot tell the format of config {}: use a .json, .toml, .yaml or .yml file",
+                path.display()
+            )),
+        }
+    }
+
+    /// Parses `text`. Errors name the line and column they were found at.
+    pub fn parse<T: DeserializeOwned>(self, text: &str) -> anyhow::Result<T> {
+        match self {
+            Self::Json => serde_json::from_str(text).map_err(anyhow::Error::from),
+            Self::Toml => toml::from_str(text).map_err(|e| {
+                // The message ends with a newline after the source excerpt
+                anyhow::anyhow!("{}", e.to_string().trim_end())
+            }),
+            // An empty YAML document is an empty config, as in TOML
+            Self::Yaml if text.trim().is_empty() => Self::Yaml.parse("{}"),
+            Self::Yaml => serde_yaml_ng::from_str(text).map_err(anyhow::Error::from),
+        }
+    }
+
+    pub fn to_string<T: Serialize>(self, value: &T) -> anyhow::Result<String> {
+        Ok(match self {
+            Self::Json => serde_json::to_string_pretty(value)?,
+            Self::Toml => toml::to_string_pretty(value)?,
+            Self::Yaml => serde_yaml_ng::to_string(value)?,
+        })
+    }
+}
+
+/// The config files that exist at `path` with any of the [`EXTENSIONS`].
+pub fn existing(path: &Path) -> Vec<PathBuf> {
+    EXTENSIONS
+        .iter()
+        .map(|extension| path.with_extension(extension))
+        .filter(|candidate| candidate.exists())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+
+    #[test]
+    fn format_follows_the_extension() {
+        assert_eq!(
+            Format::from_path(Path::new("a/config.json")).unwrap(),
+            Format::Json
+        );
+        assert_eq!(
+            Format::from_path(Path::new("config.TOML")).unwrap(),
+            Format::Toml
+        );
+        assert_eq!(
+            Format::from_path(Path::new("config.yml")).unwrap(),
+            Format::Yaml
+        );
+        assert_eq!(
+            Format::from_path(Path::new("config.yaml")).unwrap(),
+            Format::Yaml
+        );
+        assert!(Format::from_path(Path::new("config")).is_err());
+        assert!(Format::from_path(Path::new("config.ini")).is_err());
+    }
+
+    #[test]
+    fn every_format_reads_the_same_config() {
+        let json = r#"{ "max_backup_count": 5, "exclude_patterns": ["*.o"],
+                        "hooks": { "pre_create": "sync" } }"#;
+        let toml = "max_backup_count = 5\nexclude_patterns = [\"*.o\"]\n\n\
+                    [hooks]\npre_create = \"sync\"\n";
+        let yaml =
+            "max_backup_count: 5\nexclude_patterns:\n  - '*.o'\nhooks:\n  pre_create: sync\n";
+
+        for (format, text) in [
+            (Format::Json, json),
+            (Format::Toml, toml),
+            (Format::Yaml, yaml),
+        ] {
+            let config: Config = format.parse(text).unwrap();
+            assert_eq!(config.max_backup_count, Some(5), "{:?}", format);
+            assert_eq!(config.exclude_patterns, ["*.o"], "{:?}", format);
+            assert_eq!(
+                config.hooks.pre_create.as_deref(),
+                Some("sync"),
+                "{:?}",
+                format
+            );
+
+            let written = format.to_string(&config).unwrap();
+            let again: Config = format.parse(&written).unwrap();
+            assert_eq!(again.hooks, config.hooks, "{:?}", format);
+        }
+    }
+
+    #[test]
+    fn empty_files_are_empty_configs() {
+        for format in [Format::Toml, Format::Yaml] {
+            let config: Config = format.parse("\n").unwrap();
+            assert_eq!(config.max_backup_count, Config::default().max_backup_count);
+        }
+    }
+
+    #[test]
+    fn errors_name_the_line_and_column() {
+        let e = Format::Json
+            .parse::<Config>("{\n  \"max_backup_count\": 5,\n  \"bogus\": 1\n}")
+            .unwrap_err()
+            .to_string();
+        assert!(e.contains("unknown field `bogus`"), "{}", e);
+        assert!(e.contains("line 3 column"), "{}", e);
+
+        let e = Format::Toml
+            .parse::<Config>("max_backup_count = 5\nbogus = 1\n")
+            .unwrap_err()
+            .to_string();
+        assert!(e.contains("unknown field `bogus`"), "{}", e);
+        assert!(e.contains("line 2"), "{}", e);
+        assert!(!e.ends_with('\n'));
+
+        let e = Format::Yaml
+            .parse::<Config>("max_backup_count: 5\nbogus: 1\n")
+            .unwrap_err()
+            .to_string();
+        assert!(e.contains("unknown field `bogus`"), "{}", e);
+        assert!(e.contains("line 2 column"), "{}", e);
+    }
+}
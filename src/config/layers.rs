@@ -0,0 +1,743 @@
+//! Layered configuration.
+//!
+//! Settings are read from, lowest priority first: built-in defaults, the
+//! system file, the user file, the project file in the current directory,
+//! `SNAPBACK_*` environment variables and `--set key=value` overrides. An
+//! explicit `--config` file takes the place of the three files. A key set in
+//! a higher layer replaces the lower value, except `exclude_patterns`, whose
+//! entries are added to the inherited list: `!reset` drops everything
+//! inherited so far and `!<pattern>` removes a single pattern. Where every
+//! value came from is kept for `config show --origin`.
+//!
+//! Each file may be JSON, TOML or YAML, after its extension, and is checked
+//! on its own before it is merged, so a mistake is reported with its file,
+//! line and column. At most one file may exist per layer.
+
+use std::{
+    collections::BTreeMap,
+    env, fmt,
+    path::{Path, PathBuf},
+};
+
+use serde::Deserialize;
+use serde_json::{Map, Value};
+
+use super::{format, Config, Format, HooksConfig, ProfileConfig, ScheduleConfig};
+
+/// Entry of `exclude_patterns` that drops the inherited patterns.
+pub const RESET: &str = "!reset";
+
+/// Top-level keys `--set` accepts.
+const KEYS: &[&str] = &[
+    "backup_default_path",
+    "backup_info_default_path",
+    "max_backup_count",
+    "exclude_patterns",
+    "ssh_key_file",
+    "s3",
+    "hooks",
+    "profiles",
+    "schedules",
+];
+
+/// Environment variables and the keys they set.
+const ENV_KEYS: &[(&str, &str)] = &[
+    ("SNAPBACK_BACKUP_PATH", "backup_default_path"),
+    ("SNAPBACK_INFO_PATH", "backup_info_default_path"),
+    ("SNAPBACK_MAX_BACKUPS", "max_backup_count"),
+    ("SNAPBACK_SSH_KEY", "ssh_key_file"),
+];
+
+/// Where the configuration is read from and what overrides it.
+#[derive(Debug, Clone, Default)]
+pub struct LoadOptions {
+    /// Read this file instead of the system, user and project files. Unlike
+    /// those, it must exist.
+    pub config_file: Option<PathBuf>,
+    /// `key=value` overrides, applied last. Keys may be dotted, such as
+    /// `s3.bucket`; values are JSON, or plain strings.
+    pub overrides: Vec<String>,
+}
+
+/// The layer a value came from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Origin {
+    Default,
+    System(PathBuf),
+    User(PathBuf),
+    Project(PathBuf),
+    /// The file given with `--config`.
+    File(PathBuf),
+    Environment(String),
+    /// A command line flag, such as `--set key=value` or `--repo`.
+    CommandLine(String),
+}
+
+impl fmt::Display for Origin {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Default => write!(f, "default"),
+            Self::System(path) => write!(f, "system config {}", path.display()),
+            Self::User(path) => write!(f, "user config {}", path.display()),
+            Self::Project(path) => write!(f, "project config {}", path.display()),
+            Self::File(path) => write!(f, "config file {}", path.display()),
+            Self::Environment(name) => write!(f, "environment {}", name),
+            Self::CommandLine(flag) => write!(f, "command line {}", flag),
+        }
+    }
+}
+
+/// A loaded configuration together with the origin of every value.
+#[derive(Debug, Clone)]
+pub struct LayeredConfig {
+    pub config: Config,
+    /// Flattened keys such as `s3.bucket`, with their value and origin.
+    values: BTreeMap<String, (Value, Origin)>,
+    excludes: Vec<(String, Origin)>,
+    files: Vec<Origin>,
+}
+
+impl LayeredConfig {
+    /// The config files that were read, lowest priority first.
+    pub fn files(&self) -> &[Origin] {
+        &self.files
+    }
+
+    /// Every value with the layer it came from, in key order. Each exclude
+    /// pattern is listed on its own.
+    pub fn entries(&self) -> Vec<(String, String, &Origin)> {
+        let mut entries: Vec<_> = self
+            .values
+            .iter()
+            .map(|(key, (value, origin))| (key.clone(), value.to_string(), origin))
+            .collect();
+        entries.extend(self.excludes.iter().map(|(pattern, origin)| {
+            (
+                "exclude_patterns[]".to_string(),
+                Value::from(pattern.as_str()).to_string(),
+                origin,
+            )
+        }));
+        entries.sort_by(|a, b| a.0.cmp(&b.0));
+        entries
+    }
+
+    /// Replaces the configuration with `config`, a modified copy of it such
+    /// as the result of `--profile` or `--repo`, attributing whatever changed
+    /// to `origin`.
+    pub fn apply(&mut self, config: Config, origin: Origin) -> anyhow::Result<()> {
+        let mut values = BTreeMap::new();
+        flatten(&to_map(&config)?, "", &mut values);
+        self.values = values
+            .into_iter()
+            .map(|(key, value)| {
+                let origin = match self.values.remove(&key) {
+                    Some((old, old_origin)) if old == value => old_origin,
+                    None if value.is_null() => Origin::Default,
+                    _ => origin.clone(),
+                };
+                (key, (value, origin))
+            })
+            .collect();
+        if config.exclude_patterns != self.config.exclude_patterns {
+            self.excludes = config
+                .exclude_patterns
+                .iter()
+                .map(|pattern| (pattern.clone(), origin.clone()))
+                .collect();
+        }
+        self.config = config;
+        Ok(())
+    }
+}
+
+pub(super) fn load(options: &LoadOptions) -> anyhow::Result<LayeredConfig> {
+    let mut layers = Layers::new()?;
+
+    match &options.config_file {
+        Some(path) => {
+            let map = read_file(path)?;
+            layers.merge(map, Origin::File(path.clone()))?;
+        }
+        None => {
+            let files = [
+                system_config_file()?.map(|path| (path.clone(), Origin::System(path))),
+                single_file(&Config::get_user_config_path())?
+                    .map(|path| (path.clone(), Origin::User(path))),
+                single_file(&Config::get_local_config_path())?
+                    .map(|path| (path.clone(), Origin::Project(path))),
+            ];
+            for (path, origin) in files.into_iter().flatten() {
+                layers.merge(read_file(&path)?, origin)?;
+            }
+        }
+    }
+
+    for (name, key) in ENV_KEYS {
+        let Ok(value) = env::var(name) else {
+            continue;
+        };
+        let value = match *key {
+            "max_backup_count" => value
+                .parse::<u32>()
+                .map(Value::from)
+                .map_err(|_| anyhow::anyhow!("{} must be a whole number, not {:?}", name, value))?,
+            _ => Value::from(value),
+        };
+        layers.set(key, value, Origin::Environment(name.to_string()))?;
+    }
+
+    for assignment in &options.overrides {
+        let (key, value) = assignment
+            .split_once('=')
+            .ok_or_else(|| anyhow::anyhow!("Expected key=value, got {}", assignment))?;
+        let key = key.trim();
+        check_key(key)?;
+        layers.set(
+            key,
+            parse_value(value),
+            Origin::CommandLine(format!("--set {}", assignment)),
+        )?;
+    }
+
+    layers.finish()
+}
+
+/// Fails unless `key`, possibly dotted, starts with a known top-level key.
+pub(super) fn check_key(key: &str) -> anyhow::Result<()> {
+    let top = key.split('.').next().unwrap_or_default();
+    if KEYS.contains(&top) {
+        Ok(())
+    } else {
+        Err(anyhow::anyhow!(
+            "Unknown configuration key {}; known keys: {}",
+            key,
+            KEYS.join(", ")
+        ))
+    }
+}
+
+/// A value given on the command line: JSON if it parses, a string if not.
+pub(super) fn parse_value(text: &str) -> Value {
+    serde_json::from_str(text).unwrap_or_else(|_| Value::from(text))
+}
+
+/// The file named by `SNAPBACK_SYSTEM_CONFIG`, or `/etc/snapback/config`
+/// with one of the config extensions, if it exists.
+fn system_config_file() -> anyhow::Result<Option<PathBuf>> {
+    match env::var_os("SNAPBACK_SYSTEM_CONFIG") {
+        Some(path) => Ok(Some(PathBuf::from(path)).filter(|path| path.exists())),
+        None => single_file(Path::new("/etc/snapback/config.json")),
+    }
+}
+
+/// The config file at `path`, whichever extension it has. Two files for
+/// the same layer are refused rather than one being silently ignored.
+fn single_file(path: &Path) -> anyhow::Result<Option<PathBuf>> {
+    let mut found = format::existing(path);
+    if found.len() > 1 {
+        let names: Vec<_> = found
+            .iter()
+            .map(|path| path.display().to_string())
+            .collect();
+        return Err(anyhow::anyhow!(
+            "Found several config files where one is expected: {}; keep only one",
+            names.join(", ")
+        ));
+    }
+    Ok(found.pop())
+}
+
+struct Layers {
+    value: Map<String, Value>,
+    /// Origin of every key path that was set, e.g. `s3` or `s3.bucket`.
+    origins: BTreeMap<String, Origin>,
+    excludes: Vec<(String, Origin)>,
+    files: Vec<Origin>,
+}
+
+impl Layers {
+    fn new() -> anyhow::Result<Self> {
+        let defaults = Config::default();
+        let mut value = to_map(&defaults)?;
+        value.remove("exclude_patterns");
+        let origins = value
+            .keys()
+            .map(|key| (key.clone(), Origin::Default))
+            .collect();
+        Ok(Self {
+            value,
+            origins,
+            excludes: defaults
+                .exclude_patterns
+                .into_iter()
+                .map(|pattern| (pattern, Origin::Default))
+                .collect(),
+            files: Vec::new(),
+        })
+    }
+
+    /// Merges a whole file. `null` values count as unset.
+    fn merge(&mut self, map: Map<String, Value>, origin: Origin) -> anyhow::Result<()> {
+        for (key, value) in map {
+            if !value.is_null() {
+                self.set(&key, value, origin.clone())?;
+            }
+        }
+        self.files.push(origin);
+        Ok(())
+    }
+
+    fn set(&mut self, key: &str, value: Value, origin: Origin) -> anyhow::Result<()> {
+        if key == "exclude_patterns" {
+            return self.merge_excludes(value, origin);
+        }
+
+        let mut parts = key.split('.').peekable();
+        let mut map = &mut self.value;
+        while let Some(part) = parts.next() {
+            if parts.peek().is_none() {
+                map.insert(part.to_string(), value);
+                break;
+            }
+            let entry = map
+                .entry(part.to_string())
+                .or_insert_with(|| Value::Object(Map::new()));
+            if !entry.is_object() {
+                *entry = Value::Object(Map::new());
+            }
+            map = entry.as_object_mut().expect("just made an object");
+        }
+
+        // A value set here hides whatever was recorded beneath it.
+        let nested = format!("{}.", key);
+        self.origins.retain(|path, _| !path.starts_with(&nested));
+        self.origins.insert(key.to_string(), origin);
+        Ok(())
+    }
+
+    fn merge_excludes(&mut self, value: Value, origin: Origin) -> anyhow::Result<()> {
+        let patterns: Vec<String> = match value {
+            Value::String(pattern) => vec![pattern],
+            value => serde_json::from_value(value).map_err(|_| {
+                anyhow::anyhow!("exclude_patterns from {} must be a list of strings", origin)
+            })?,
+        };
+        merge_patterns(&mut self.excludes, patterns, || origin.clone());
+        Ok(())
+    }
+
+    /// Finds the key that makes the merged configuration invalid, by trying
+    /// each one on top of the defaults, and names the layer it came from.
+    fn explain(&self, value: &Map<String, Value>, error: serde_json::Error) -> anyhow::Error {
+        let Ok(defaults) = to_map(&Config::default()) else {
+            return anyhow::anyhow!("Invalid configuration: {}", error);
+        };
+        for (key, field) in value {
+            let mut candidate = defaults.clone();
+            candidate.insert(key.clone(), field.clone());
+            if let Err(e) = serde_json::from_value::<Config>(Value::Object(candidate)) {
+                return anyhow::anyhow!(
+                    "Invalid configuration: {} from {}: {}",
+                    key,
+                    origin_of(&self.origins, key),
+                    e
+                );
+            }
+        }
+        anyhow::anyhow!("Invalid configuration: {}", error)
+    }
+
+    fn finish(self) -> anyhow::Result<LayeredConfig> {
+        let mut value = self.value.clone();
+        value.insert(
+            "exclude_patterns".to_string(),
+            self.excludes
+                .iter()
+                .map(|(pattern, _)| Value::from(pattern.as_str()))
+                .collect(),
+        );
+        let config: Config = match serde_json::from_value(Value::Object(value.clone())) {
+            Ok(config) => config,
+            Err(e) => return Err(self.explain(&value, e)),
+        };
+
+        value.remove("exclude_patterns");
+        let mut leaves = BTreeMap::new();
+        flatten(&value, "", &mut leaves);
+        let values = leaves
+            .into_iter()
+            .map(|(key, value)| {
+                let origin = origin_of(&self.origins, &key);
+                (key, (value, origin))
+            })
+            .collect();
+
+        Ok(LayeredConfig {
+            config,
+            values,
+            excludes: self.excludes,
+            files: self.files,
+        })
+    }
+}
+
+/// Adds `patterns` to `list`, tagging new entries with `tag()`. [`RESET`]
+/// clears the list and `!<pattern>` removes one entry.
+pub(super) fn merge_patterns<T>(
+    list: &mut Vec<(String, T)>,
+    patterns: impl IntoIterator<Item = String>,
+    tag: impl Fn() -> T,
+) {
+    for pattern in patterns {
+        if pattern == RESET {
+            list.clear();
+        } else if let Some(removed) = pattern.strip_prefix('!') {
+            list.retain(|(existing, _)| existing != removed);
+        } else if !list.iter().any(|(existing, _)| *existing == pattern) {
+            list.push((pattern, tag()));
+        }
+    }
+}
+
+/// The origin recorded for `key` or the closest object containing it.
+fn origin_of(origins: &BTreeMap<String, Origin>, key: &str) -> Origin {
+    let mut path = key;
+    loop {
+        if let Some(origin) = origins.get(path) {
+            return origin.clone();
+        }
+        match path.rsplit_once('.') {
+            Some((parent, _)) => path = parent,
+            None => return Origin::Default,
+        }
+    }
+}
+
+/// Collects the scalar and list values of `map` under dotted keys. Empty
+/// objects are kept as values of their own.
+fn flatten(map: &Map<String, Value>, prefix: &str, out: &mut BTreeMap<String, Value>) {
+    for (key, value) in map {
+        if key == "exclude_patterns" && prefix.is_empty() {
+            continue;
+        }
+        let path = if prefix.is_empty() {
+            key.clone()
+        } else {
+            format!("{}.{}", prefix, key)
+        };
+        match value {
+            Value::Object(inner) if !inner.is_empty() => flatten(inner, &path, out),
+            value => {
+                out.insert(path, value.clone());
+            }
+        }
+    }
+}
+
+/// One layer on its own: [`Config`] with the same keys and types, except
+/// that the required keys of `s3` may be left to another layer. The merged
+/// configuration is checked for them.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+#[allow(dead_code)]
+pub(super) struct Layer {
+    #[serde(rename = "$schema")]
+    schema: Option<String>,
+    backup_default_path: Option<String>,
+    backup_info_default_path: Option<String>,
+    max_backup_count: Option<u32>,
+    exclude_patterns: Option<Vec<String>>,
+    ssh_key_file: Option<String>,
+    s3: Option<S3Layer>,
+    hooks: Option<HooksConfig>,
+    profiles: Option<BTreeMap<String, ProfileConfig>>,
+    schedules: Option<Vec<ScheduleConfig>>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+#[allow(dead_code)]
+struct S3Layer {
+    endpoint: Option<String>,
+    bucket: Option<String>,
+    prefix: Option<String>,
+    region: Option<String>,
+}
+
+pub(super) fn to_map(config: &Config) -> anyhow::Result<Map<String, Value>> {
+    match serde_json::to_value(config)? {
+        Value::Object(map) => Ok(map),
+        _ => Err(anyhow::anyhow!("Configuration is not an object")),
+    }
+}
+
+/// Reads one config file, which must be valid on its own: unknown keys and
+/// values of the wrong type are errors.
+pub(super) fn read_file(path: &Path) -> anyhow::Result<Map<String, Value>> {
+    let format = Format::from_path(path)?;
+    let content = std::fs::read_to_string(path)
+        .map_err(|e| anyhow::anyhow!("Cannot read config {}: {}", path.display(), e))?;
+    format
+        .parse::<Layer>(&content)
+        .and_then(|_| format.parse(&content))
+        .map_err(|e| anyhow::anyhow!("Invalid config {}: {:#}", path.display(), e))
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn map(value: Value) -> Map<String, Value> {
+        match value {
+            Value::Object(map) => map,
+            _ => panic!("not an object"),
+        }
+    }
+
+    fn user() -> Origin {
+        Origin::User(PathBuf::from("/home/me/.config/snapback/config.json"))
+    }
+
+    fn origin<'a>(layered: &'a LayeredConfig, key: &str) -> &'a Origin {
+        let entries = layered.entries();
+        let (_, _, origin) = entries
+            .into_iter()
+            .find(|(name, _, _)| name == key)
+            .unwrap_or_else(|| panic!("no entry for {}", key));
+        origin
+    }
+
+    #[test]
+    fn higher_layers_replace_lower_values_and_record_their_origin() {
+        let mut layers = Layers::new().unwrap();
+        layers
+            .merge(
+                map(json!({
+                    "backup_default_path": "/backups",
+                    "max_backup_count": 10,
+                    "ssh_key_file": null,
+                    "s3": { "endpoint": "http://localhost:9000", "bucket": "team" },
+                })),
+                user(),
+            )
+            .unwrap();
+        let env = Origin::Environment("SNAPBACK_MAX_BACKUPS".into());
+        layers
+            .set("max_backup_count", json!(20), env.clone())
+            .unwrap();
+        let flag = Origin::CommandLine("--set s3.bucket=mine".into());
+        layers
+            .set("s3.bucket", json!("mine"), flag.clone())
+            .unwrap();
+
+        let layered = layers.finish().unwrap();
+
+        let config = &layered.config;
+        assert_eq!(config.backup_default_path.as_deref(), Some("/backups"));
+        assert_eq!(config.max_backup_count, Some(20));
+        assert_eq!(config.ssh_key_file, None);
+        let s3 = config.s3.as_ref().unwrap();
+        assert_eq!(s3.endpoint, "http://localhost:9000");
+        assert_eq!(s3.bucket, "mine");
+        assert_eq!(origin(&layered, "backup_default_path"), &user());
+        assert_eq!(origin(&layered, "max_backup_count"), &env);
+        assert_eq!(origin(&layered, "s3.endpoint"), &user());
+        assert_eq!(origin(&layered, "s3.bucket"), &flag);
+        assert_eq!(
+            origin(&layered, "backup_info_default_path"),
+            &Origin::Default
+        );
+        assert_eq!(layered.files(), [user()]);
+    }
+
+    #[test]
+    fn exclude_patterns_are_added_removed_and_reset() {
+        let system = Origin::System(PathBuf::from("/etc/snapback/config.json"));
+        let mut layers = Layers::new().unwrap();
+        layers
+            .merge(
+                map(json!({ "exclude_patterns": ["*.bak", "*.tmp"] })),
+                system.clone(),
+            )
+            .unwrap();
+        layers
+            .merge(
+                map(json!({ "exclude_patterns": ["!*.log", "*.swp"] })),
+                user(),
+            )
+            .unwrap();
+
+        let layered = layers.finish().unwrap();
+        assert_eq!(
+            layered.config.exclude_patterns,
+            [
+                "target/",
+                "node_modules/",
+                ".git/",
+                "*.tmp",
+                "*.bak",
+                "*.swp"
+            ]
+        );
+        let origins: Vec<_> = layered
+            .entries()
+            .into_iter()
+            .filter(|(key, _, _)| key == "exclude_patterns[]")
+            .map(|(_, pattern, origin)| (pattern, origin.clone()))
+            .collect();
+        assert_eq!(origins[3], ("\"*.tmp\"".to_string(), Origin::Default));
+        assert_eq!(origins[4], ("\"*.bak\"".to_string(), system));
+        assert_eq!(origins[5], ("\"*.swp\"".to_string(), user()));
+
+        let mut layers = Layers::new().unwrap();
+        let flag = Origin::CommandLine("--set exclude_patterns=...".into());
+        layers
+            .set("exclude_patterns", json!(["!reset", "dist/"]), flag.clone())
+            .unwrap();
+        layers.set("exclude_patterns", json!("*.o"), flag).unwrap();
+        assert_eq!(
+            layers.finish().unwrap().config.exclude_patterns,
+            ["dist/", "*.o"]
+        );
+    }
+
+    #[test]
+    fn invalid_values_name_the_layer_they_came_from() {
+        let mut layers = Layers::new().unwrap();
+        layers
+            .merge(map(json!({ "max_backup_count": "many" })), user())
+            .unwrap();
+
+        let e = layers.finish().unwrap_err().to_string();
+        assert!(
+            e.starts_with(&format!(
+                "Invalid configuration: max_backup_count from {}",
+                user()
+            )),
+            "{}",
+            e
+        );
+
+        let mut layers = Layers::new().unwrap();
+        let e = layers
+            .set("exclude_patterns", json!(3), user())
+            .unwrap_err()
+            .to_string();
+        assert!(e.contains("must be a list of strings"), "{}", e);
+    }
+
+    #[test]
+    fn overrides_apply_on_top_of_the_config_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("config.json");
+        std::fs::write(
+            &path,
+            r#"{ "max_backup_count": 3, "backup_default_path": "/backups" }"#,
+        )
+        .unwrap();
+        let options = LoadOptions {
+            config_file: Some(path.clone()),
+            overrides: vec![
+                "max_backup_count=7".into(),
+                "hooks.on_failure=notify-send failed".into(),
+            ],
+        };
+
+        let layered = load(&options).unwrap();
+        assert_eq!(layered.config.max_backup_count, Some(7));
+        assert_eq!(origin(&layered, "backup_default_path"), &Origin::File(path));
+        // Values that are not JSON are taken as strings
+        assert_eq!(
+            layered.config.hooks.on_failure.as_deref(),
+            Some("notify-send failed")
+        );
+        assert_eq!(
+            origin(&layered, "hooks.on_failure"),
+            &Origin::CommandLine("--set hooks.on_failure=notify-send failed".into())
+        );
+    }
+
+    #[test]
+    fn overrides_must_name_a_known_key() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("config.json");
+        std::fs::write(&path, "{}").unwrap();
+        for (assignment, message) in [
+            ("max_backups=3", "Unknown configuration key max_backups"),
+            (
+                "max_backup_count",
+                "Expected key=value, got max_backup_count",
+            ),
+        ] {
+            let options = LoadOptions {
+                config_file: Some(path.clone()),
+                overrides: vec![assignment.into()],
+            };
+            let e = load(&options).unwrap_err().to_string();
+            assert!(e.starts_with(message), "{}", e);
+        }
+    }
+
+    #[test]
+    fn files_are_checked_on_their_own() {
+        let dir = tempfile::tempdir().unwrap();
+        let write = |name: &str, text: &str| {
+            let path = dir.path().join(name);
+            std::fs::write(&path, text).unwrap();
+            path
+        };
+
+        let path = write("unknown.toml", "[hooks]\npre_creat = \"sync\"\n");
+        let e = format!("{:#}", read_file(&path).unwrap_err());
+        assert!(
+            e.starts_with(&format!("Invalid config {}", path.display())),
+            "{}",
+            e
+        );
+        assert!(e.contains("unknown field `pre_creat`"), "{}", e);
+
+        let path = write("type.yaml", "max_backup_count: lots\n");
+        let e = format!("{:#}", read_file(&path).unwrap_err());
+        assert!(e.contains("line 1 column"), "{}", e);
+
+        // Another layer may supply the bucket
+        let path = write(
+            "partial.json",
+            r#"{ "s3": { "endpoint": "http://minio" } }"#,
+        );
+        read_file(&path).unwrap();
+        let e = load(&LoadOptions {
+            config_file: Some(path.clone()),
+            overrides: Vec::new(),
+        })
+        .unwrap_err()
+        .to_string();
+        assert!(e.contains("missing field `bucket`"), "{}", e);
+        let layered = load(&LoadOptions {
+            config_file: Some(path),
+            overrides: vec!["s3.bucket=team".into()],
+        })
+        .unwrap();
+        assert_eq!(layered.config.s3.unwrap().bucket, "team");
+    }
+
+    #[test]
+    fn one_file_per_layer() {
+        let dir = tempfile::tempdir().unwrap();
+        let base = dir.path().join("snapback.json");
+        assert_eq!(single_file(&base).unwrap(), None);
+
+        std::fs::write(dir.path().join("snapback.yml"), "").unwrap();
+        assert_eq!(
+            single_file(&base).unwrap(),
+            Some(dir.path().join("snapback.yml"))
+        );
+
+        std::fs::write(dir.path().join("snapback.toml"), "").unwrap();
+        let e = single_file(&base).unwrap_err().to_string();
+        assert!(e.starts_with("Found several config files"), "{}", e);
+    }
+}
@@ -1,28 +1,155 @@
 use std::{
-    env,
-    fs::{create_dir_all, File},
-    io::Write,
+    collections::BTreeMap,
     path::{Path, PathBuf},
 };
 
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
+use crate::util::atomic;
+
+pub mod edit;
+pub mod format;
+pub mod layers;
+
+pub use edit::ConfigFile;
+pub use format::Format;
+pub use layers::{LayeredConfig, LoadOptions, Origin};
+
+/// Settings that are not given fall back to their defaults; unknown keys
+/// are rejected.
+#[derive(Debug, Serialize, Deserialize, Clone, JsonSchema)]
+#[serde(default, deny_unknown_fields)]
 pub struct Config {
+    /// Where editors find the schema printed by `snapback config schema`.
+    /// Ignored by snapback.
+    #[serde(rename = "$schema", skip_serializing_if = "Option::is_none")]
+    pub schema: Option<String>,
+    /// Directory for manifests and content.
     pub backup_default_path: Option<String>,
+    /// Directory for the backup info records.
     pub backup_info_default_path: Option<String>,
     pub max_backup_count: Option<u32>,
-    pub compress_backups: Option<bool>,
+    /// Glob patterns of paths left out of backups.
     pub exclude_patterns: Vec<String>,
+    /// Private key used for `sftp://` repositories instead of the SSH agent
+    /// and the default keys in `~/.ssh`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ssh_key_file: Option<String>,
+    /// Keep the repository in S3-compatible object storage instead of the
+    /// local backup and info paths.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub s3: Option<S3Config>,
+    /// Commands run around `create` and `restore`.
+    #[serde(default, skip_serializing_if = "HooksConfig::is_empty")]
+    pub hooks: HooksConfig,
+    /// Named sets of sources with their own settings, selected with
+    /// `--profile`.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub profiles: BTreeMap<String, ProfileConfig>,
+    /// Backups `snapback daemon` takes on its own.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub schedules: Vec<ScheduleConfig>,
+}
+
+/// Location of a repository in an S3-compatible bucket. Credentials are not
+/// stored here; they come from `AWS_ACCESS_KEY_ID`, `AWS_SECRET_ACCESS_KEY`
+/// and, optionally, `AWS_SESSION_TOKEN`.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct S3Config {
+    /// Base URL of the service, e.g. `https://s3.eu-central-1.amazonaws.com`
+    /// or `http://localhost:9000` for MinIO.
+    pub endpoint: String,
+    pub bucket: String,
+    /// Key prefix inside the bucket, so several repositories can share one.
+    #[serde(default)]
+    pub prefix: Option<String>,
+    /// Signing region. MinIO accepts the default, `us-east-1`.
+    #[serde(default)]
+    pub region: Option<String>,
+}
+
+/// A named group of source roots. Settings that are set here replace the
+/// global ones while the profile is in use; exclude patterns are added to
+/// the global list, with the same `!reset` and `!<pattern>` entries as in
+/// config files.
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq, Eq, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct ProfileConfig {
+    /// Roots backed up by `snapback create --profile <name>`.
+    #[serde(default)]
+    pub sources: Vec<PathBuf>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub exclude_patterns: Option<Vec<String>>,
+    /// Prune every source to this many snapshots after it is backed up.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub keep_last: Option<u32>,
+    /// Repository directory or URL, as for `--repo`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub repository: Option<String>,
+}
+
+/// Shell commands run before and after `create` and `restore`. A pre hook
+/// that fails aborts the operation; `on_failure` runs whenever a hook or
+/// the operation itself failed. Hooks see `SNAPBACK_*` environment
+/// variables describing the operation and the snapshot.
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq, Eq, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct HooksConfig {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pre_create: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub post_create: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pre_restore: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub post_restore: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub on_failure: Option<String>,
+    /// Seconds a hook may run before it is killed. Defaults to 300.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timeout_secs: Option<u64>,
+}
+
+impl HooksConfig {
+    pub fn is_empty(&self) -> bool {
+        self == &Self::default()
+    }
+
+    pub fn get_timeout_secs(&self) -> u64 {
+        self.timeout_secs.unwrap_or(300)
+    }
+}
+
+/// When `snapback daemon` backs up one path, and what it does afterwards.
+/// Exactly one of `every` and `cron` must be set.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct ScheduleConfig {
+    pub path: PathBuf,
+    /// Fixed interval such as `30m`, `6h` or `1d12h`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub every: Option<String>,
+    /// Five-field cron expression in local time, e.g. `0 3 * * *`, or one
+    /// of `@hourly`, `@daily`, `@weekly`, `@monthly`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cron: Option<String>,
+    /// Prune to this many snapshots after every backup.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub keep_last: Option<u32>,
+    /// Read back and check the path's content after every backup.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub verify: bool,
 }
 
 impl Default for Config {
     fn default() -> Self {
         Self {
+            schema: None,
             backup_default_path: None,
             backup_info_default_path: None,
             max_backup_count: Some(100),
-            compress_backups: Some(false),
             exclude_patterns: vec![
                 "target/".to_string(),
                 "node_modules/".to_string(),
@@ -30,133 +157,122 @@ impl Default for Config {
                 "*.tmp".to_string(),
                 "*.log".to_string(),
             ],
+            ssh_key_file: None,
+            s3: None,
+            hooks: HooksConfig::default(),
+            profiles: BTreeMap::new(),
+            schedules: Vec::new(),
         }
     }
 }
 
 impl Config {
-    /// Load configuration from multiple sources with priority:
-    /// 1. Environment variables
-    /// 2. Config file in user's config directory
-    /// 3. Config file in current directory  
-    /// 4. Default values
+    /// Load configuration from every layer: defaults, the system, user and
+    /// project files, then environment variables. See [`layers`].
     pub fn load() -> anyhow::Result<Self> {
-        let mut config = Self::default();
-
-        // Try to load from config file
-        if let Ok(file_config) = Self::load_from_file() {
-            config = Self::merge_configs(config, file_config);
-        }
-
-        // Override with environment variables
-        config = Self::load_from_env(config);
-
-        Ok(config)
+        Ok(Self::load_layered(&LoadOptions::default())?.config)
     }
 
-    /// Legacy method for backwards compatibility
-    pub fn read_config() -> Self {
-        Self::load().unwrap_or_default()
+    /// Load configuration from an explicit file instead of the usual
+    /// locations. Unlike `load`, a missing or malformed file is an error.
+    /// Environment variables still take precedence.
+    pub fn load_from_path(path: &Path) -> anyhow::Result<Self> {
+        let options = LoadOptions {
+            config_file: Some(path.to_path_buf()),
+            ..LoadOptions::default()
+        };
+        Ok(Self::load_layered(&options)?.config)
     }
 
-    fn load_from_file() -> anyhow::Result<Self> {
-        let config_paths = [
-            Self::get_user_config_path(),
-            Self::get_local_config_path(),
-        ];
-
-        for config_path in &config_paths {
-            if config_path.exists() {
-                let content = std::fs::read_to_string(config_path)?;
-                let config: Config = serde_json::from_str(&content)?;
-                return Ok(config);
-            }
-        }
-
-        Self::create_default_config()?;
-        Ok(Self::default())
+    /// Loads the configuration and remembers which layer every value came
+    /// from.
+    pub fn load_layered(options: &LoadOptions) -> anyhow::Result<LayeredConfig> {
+        layers::load(options)
     }
 
-    fn load_from_env(mut config: Config) -> Self {
-        // Override with environment variables if present
-        if let Ok(backup_path) = env::var("SNAPBACK_BACKUP_PATH") {
-            config.backup_default_path = Some(backup_path);
-        }
-
-        if let Ok(info_path) = env::var("SNAPBACK_INFO_PATH") {
-            config.backup_info_default_path = Some(info_path);
-        }
+    /// Checks a single config file on its own, without the other layers.
+    pub fn validate_file(path: &Path) -> anyhow::Result<()> {
+        layers::read_file(path).map(|_| ())
+    }
 
-        if let Ok(max_count) = env::var("SNAPBACK_MAX_BACKUPS") {
-            if let Ok(count) = max_count.parse::<u32>() {
-                config.max_backup_count = Some(count);
+    /// The value of a key such as `max_backup_count` or `s3.bucket`, or
+    /// `None` if it is not set.
+    pub fn get(&self, key: &str) -> anyhow::Result<Option<serde_json::Value>> {
+        layers::check_key(key)?;
+        let mut value = serde_json::Value::Object(layers::to_map(self)?);
+        for part in key.split('.') {
+            match value.get_mut(part) {
+                Some(inner) => value = inner.take(),
+                None => return Ok(None),
             }
         }
+        Ok(Some(value).filter(|value| !value.is_null()))
+    }
 
-        if let Ok(compress) = env::var("SNAPBACK_COMPRESS") {
-            config.compress_backups = Some(compress.to_lowercase() == "true");
-        }
-
-        config
+    /// JSON Schema of the config file, for editor completion and checks.
+    pub fn json_schema() -> serde_json::Value {
+        serde_json::to_value(schemars::schema_for!(Config)).expect("schemas serialize to JSON")
     }
 
-    fn merge_configs(mut base: Config, override_config: Config) -> Config {
-        if override_config.backup_default_path.is_some() {
-            base.backup_default_path = override_config.backup_default_path;
-        }
-        if override_config.backup_info_default_path.is_some() {
-            base.backup_info_default_path = override_config.backup_info_default_path;
-        }
-        if override_config.max_backup_count.is_some() {
-            base.max_backup_count = override_config.max_backup_count;
-        }
-        if override_config.compress_backups.is_some() {
-            base.compress_backups = override_config.compress_backups;
-        }
-        if !override_config.exclude_patterns.is_empty() {
-            base.exclude_patterns = override_config.exclude_patterns;
-        }
-        base
+    /// Points both repository directories at `dir`: manifests and content
+    /// go directly into it, backup info records into `dir/backup_info`. Any
+    /// configured object storage is ignored.
+    pub fn with_repository(mut self, dir: &Path) -> Self {
+        self.s3 = None;
+        self.backup_default_path = Some(dir.to_string_lossy().to_string());
+        self.backup_info_default_path = Some(dir.join("backup_info").to_string_lossy().to_string());
+        self
     }
 
-    fn create_default_config() -> anyhow::Result<()> {
-        let config_path = Self::get_user_config_path();
-        
-        if let Some(parent) = config_path.parent() {
-            create_dir_all(parent)?;
+    /// The configuration to use for profile `name`: the profile's settings
+    /// replace the global ones, and its repository replaces the configured
+    /// paths.
+    pub fn with_profile(&self, name: &str) -> anyhow::Result<Self> {
+        let profile = self.profiles.get(name).ok_or_else(|| {
+            let known: Vec<_> = self.profiles.keys().map(String::as_str).collect();
+            if known.is_empty() {
+                anyhow::anyhow!("No profile named {}; no profiles are configured", name)
+            } else {
+                anyhow::anyhow!(
+                    "No profile named {}; configured: {}",
+                    name,
+                    known.join(", ")
+                )
+            }
+        })?;
+
+        let mut config = self.clone();
+        if let Some(patterns) = &profile.exclude_patterns {
+            let mut merged = config
+                .exclude_patterns
+                .into_iter()
+                .map(|pattern| (pattern, ()))
+                .collect();
+            layers::merge_patterns(&mut merged, patterns.iter().cloned(), || ());
+            config.exclude_patterns = merged.into_iter().map(|(pattern, _)| pattern).collect();
         }
-
-        let default_config = Self::default();
-        let json = serde_json::to_string_pretty(&default_config)?;
-        
-        let mut file = File::create(&config_path)?;
-        file.write_all(json.as_bytes())?;
-        
-        println!("Created default config at: {}", config_path.display());
-        Ok(())
+        Ok(match &profile.repository {
+            Some(repository) => config.with_repository(Path::new(repository)),
+            None => config,
+        })
     }
 
     pub fn save(&self) -> anyhow::Result<()> {
         let config_path = Self::get_user_config_path();
-        
-        if let Some(parent) = config_path.parent() {
-            create_dir_all(parent)?;
-        }
-
-        let json = serde_json::to_string_pretty(self)?;
-        std::fs::write(&config_path, json)?;
-        
-        println!("Config saved to: {}", config_path.display());
-        Ok(())
+        let text = Format::from_path(&config_path)?.to_string(self)?;
+        atomic::write(&config_path, text)
     }
 
-    fn get_user_config_path() -> PathBuf {
-        if let Some(config_dir) = dirs::config_dir() {
+    /// `config.json` in the user config directory, or the `.toml`, `.yaml`
+    /// or `.yml` file next to it if that is the one that exists.
+    pub fn get_user_config_path() -> PathBuf {
+        let path = if let Some(config_dir) = dirs::config_dir() {
             config_dir.join("snapback").join("config.json")
         } else {
             // Fallback for systems without standard config dir
             Self::get_home_config_path()
-        }
+        };
+        format::existing(&path).into_iter().next().unwrap_or(path)
     }
 
     fn get_home_config_path() -> PathBuf {
@@ -166,10 +282,13 @@ impl Config {
             .join("config.json")
     }
 
-    fn get_local_config_path() -> PathBuf {
-        std::env::current_dir()
+    /// `snapback.json` in the current directory, or the `.toml`, `.yaml` or
+    /// `.yml` file next to it if that is the one that exists.
+    pub fn get_local_config_path() -> PathBuf {
+        let path = std::env::current_dir()
             .unwrap_or_else(|_| PathBuf::from("."))
-            .join("snapback.json")
+            .join("snapback.json");
+        format::existing(&path).into_iter().next().unwrap_or(path)
     }
 
     // Getters with smart defaults
@@ -195,7 +314,7 @@ impl Config {
             }
         }
 
-        // Smart default based on OS  
+        // Smart default based on OS
         self.get_default_data_dir()
             .join("snapback")
             .join("backup_info")
@@ -219,48 +338,128 @@ impl Config {
         self.max_backup_count.unwrap_or(100)
     }
 
-    pub fn is_compress_enabled(&self) -> bool {
-        self.compress_backups.unwrap_or(false)
-    }
-
     pub fn get_exclude_patterns(&self) -> &[String] {
         &self.exclude_patterns
     }
 
+    /// Whether the file at `path`, relative to the backed-up root, matches
+    /// an exclude pattern. Patterns are globs matched against whole path
+    /// components, so `.git/` does not catch `.github`:
+    ///
+    /// - `name/` matches a directory at any depth, and everything in it
+    /// - a pattern with a `/` elsewhere, such as `docs/*.pdf`, matches the
+    ///   relative path of the file or of a directory above it; `*` does not
+    ///   cross a `/`
+    /// - any other pattern, such as `*.log`, matches a file or directory
+    ///   name at any depth
     pub fn should_exclude(&self, path: &Path) -> bool {
-        let path_str = path.to_string_lossy();
-        
-        for pattern in &self.exclude_patterns {
-            if pattern.ends_with('/') {
-                // Directory pattern
-                let dir_pattern = pattern.trim_end_matches('/');
-                if path_str.contains(dir_pattern) {
-                    return true;
-                }
-            } else if pattern.starts_with("*.") {
-                // Extension pattern
-                let ext = pattern.trim_start_matches("*.");
-                if let Some(file_ext) = path.extension() {
-                    if file_ext == ext {
-                        return true;
-                    }
-                }
-            } else if path_str.contains(pattern) {
-                // General pattern
-                return true;
+        self.excludes(path, false)
+    }
+
+    /// Like [`should_exclude`](Self::should_exclude), for the directory at
+    /// `path`. Nothing inside an excluded directory needs to be looked at.
+    pub fn should_exclude_dir(&self, path: &Path) -> bool {
+        self.excludes(path, true)
+    }
+
+    fn excludes(&self, path: &Path, is_dir: bool) -> bool {
+        let names: Vec<_> = path
+            .components()
+            .map(|component| component.as_os_str().to_string_lossy())
+            .collect();
+        // Every component of a file's path but the last is a directory
+        let dirs = if is_dir {
+            &names[..]
+        } else {
+            &names[..names.len().saturating_sub(1)]
+        };
+        let options = glob::MatchOptions {
+            require_literal_separator: true,
+            ..Default::default()
+        };
+
+        self.exclude_patterns.iter().any(|pattern| {
+            let (pattern, dir_only) = match pattern.strip_suffix('/') {
+                Some(pattern) => (pattern, true),
+                None => (pattern.as_str(), false),
+            };
+            let pattern = pattern.trim_start_matches('/');
+            let glob = glob::Pattern::new(pattern)
+                .unwrap_or_else(|_| glob::Pattern::new(&glob::Pattern::escape(pattern)).unwrap());
+            if pattern.contains('/') {
+                let candidates = if dir_only { dirs } else { &names[..] };
+                (1..=candidates.len())
+                    .any(|end| glob.matches_with(&candidates[..end].join("/"), options))
+            } else if dir_only {
+                dirs.iter().any(|name| glob.matches_with(name, options))
+            } else {
+                names.iter().any(|name| glob.matches_with(name, options))
             }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn excluding(patterns: &[&str]) -> Config {
+        Config {
+            exclude_patterns: patterns.iter().map(|pattern| pattern.to_string()).collect(),
+            ..Config::default()
         }
-        
-        false
     }
 
-    pub fn print_config(&self) {
-        println!("SnapBack Configuration:");
-        println!("  Backup Path: {}", self.get_default_backup_path());
-        println!("  Info Path: {}", self.get_default_backup_info_path());
-        println!("  Max Backups: {}", self.get_max_backup_count());
-        println!("  Compression: {}", self.is_compress_enabled());
-        println!("  Exclude Patterns: {:?}", self.exclude_patterns);
-        println!("  Config File: {}", Self::get_user_config_path().display());
+    #[test]
+    fn directory_patterns_match_whole_components() {
+        let config = Config::default();
+        assert!(config.should_exclude(Path::new(".git/config")));
+        assert!(config.should_exclude(Path::new("crates/app/target/debug/app")));
+        assert!(config.should_exclude_dir(Path::new("web/node_modules")));
+        assert!(!config.should_exclude(Path::new(".github/workflows/ci.yml")));
+        assert!(!config.should_exclude(Path::new(".gitignore")));
+        assert!(!config.should_exclude(Path::new("src/target.rs")));
+        // A file that happens to share a directory pattern's name is kept
+        assert!(!config.should_exclude(Path::new("docs/target")));
+    }
+
+    #[test]
+    fn name_patterns_match_any_component() {
+        let config = excluding(&["*.log", ".DS_Store"]);
+        assert!(config.should_exclude(Path::new("logs/today.log")));
+        assert!(config.should_exclude(Path::new("a/b/.DS_Store")));
+        assert!(!config.should_exclude(Path::new("catalog.md")));
+        assert!(!config.should_exclude(Path::new("today.log.gz")));
+    }
+
+    #[test]
+    fn patterns_with_a_slash_match_the_relative_path() {
+        let config = excluding(&["docs/*.pdf", "/build/out/"]);
+        assert!(config.should_exclude(Path::new("docs/manual.pdf")));
+        assert!(!config.should_exclude(Path::new("docs/old/manual.pdf")));
+        assert!(!config.should_exclude(Path::new("src/docs/manual.pdf")));
+        assert!(config.should_exclude(Path::new("build/out/app")));
+        assert!(config.should_exclude_dir(Path::new("build/out")));
+        assert!(!config.should_exclude(Path::new("build/output/app")));
+    }
+
+    #[test]
+    fn schema_covers_every_key_and_nothing_else() {
+        let schema = Config::json_schema();
+        assert_eq!(schema["additionalProperties"], false);
+        let properties = schema["properties"].as_object().unwrap();
+        for key in [
+            "$schema",
+            "max_backup_count",
+            "exclude_patterns",
+            "s3",
+            "hooks",
+        ] {
+            assert!(properties.contains_key(key), "{}", key);
+        }
+        assert_eq!(
+            schema["definitions"]["S3Config"]["required"],
+            serde_json::json!(["bucket", "endpoint"])
+        );
     }
 }
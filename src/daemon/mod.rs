@@ -0,0 +1,442 @@
+//! `snapback daemon`: scheduled backups without cron.
+//!
+//! Every configured schedule has a next due time, and the daemon wakes at
+//! least every [`MAX_SLEEP`] to compare it with the wall clock. Runs missed
+//! while the machine was suspended or the daemon was stopped are therefore
+//! caught up once, not once per missed slot. Runs happen one at a time,
+//! since each needs the repository lock, so a path is never backed up twice
+//! at once. When each path last ran is kept in a state file, and the
+//! current status is answered as JSON on a Unix socket for
+//! `snapback status`.
+
+use std::{
+    collections::{BTreeMap, HashSet},
+    fs,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    thread,
+    time::Duration,
+};
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    config::ScheduleConfig,
+    repository::{CreateOptions, Repository, VerifyOptions},
+    util,
+};
+
+pub mod schedule;
+
+pub use schedule::Trigger;
+
+/// Longest the daemon sleeps before looking at the clock again.
+const MAX_SLEEP: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Clone)]
+pub struct DaemonOptions {
+    /// Unix socket `snapback status` connects to.
+    pub socket: PathBuf,
+    /// Where the last run of every path is remembered across restarts.
+    pub state_file: PathBuf,
+    /// Used for every scheduled backup.
+    pub create: CreateOptions,
+}
+
+impl Default for DaemonOptions {
+    fn default() -> Self {
+        Self {
+            socket: default_socket_path(),
+            state_file: data_dir().join("daemon.json"),
+            create: CreateOptions::default(),
+        }
+    }
+}
+
+/// `$XDG_RUNTIME_DIR/snapback.sock` where there is a runtime directory,
+/// otherwise a socket in snapback's data directory.
+pub fn default_socket_path() -> PathBuf {
+    dirs::runtime_dir()
+        .map(|dir| dir.join("snapback.sock"))
+        .unwrap_or_else(|| data_dir().join("daemon.sock"))
+}
+
+fn data_dir() -> PathBuf {
+    dirs::data_dir()
+        .or_else(|| dirs::home_dir().map(|home| home.join(".local").join("share")))
+        .unwrap_or_else(|| PathBuf::from("."))
+        .join("snapback")
+}
+
+/// What `snapback status` shows.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DaemonStatus {
+    pub pid: u32,
+    pub started: DateTime<Utc>,
+    pub schedules: Vec<ScheduleStatus>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ScheduleStatus {
+    pub path: PathBuf,
+    /// The schedule as configured, e.g. `every 6h` or `cron 0 3 * * *`.
+    pub schedule: String,
+    pub running: bool,
+    /// `None` for cron expressions that never fire again.
+    pub next_run: Option<DateTime<Utc>>,
+    pub last_run: Option<RunRecord>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RunRecord {
+    pub started: DateTime<Utc>,
+    pub finished: DateTime<Utc>,
+    pub outcome: RunOutcome,
+    /// Snapshot written by the run; `None` when nothing had changed.
+    pub snapshot: Option<u32>,
+    /// Why the run failed, or what else it did.
+    pub message: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum RunOutcome {
+    Success,
+    /// The backup was written, but some files could not be read.
+    Partial,
+    Failed,
+}
+
+struct Job {
+    schedule: ScheduleConfig,
+    trigger: Trigger,
+}
+
+/// Runs the schedules of the repository's config until the process is
+/// stopped, handing every finished run to `on_run`, together with the error
+/// if the run could not be saved to the state file. Only returns when the
+/// schedules are invalid or the socket or state file cannot be used.
+pub fn run(
+    repo: &Repository,
+    options: &DaemonOptions,
+    mut on_run: impl FnMut(&Path, &RunRecord, Option<&anyhow::Error>),
+) -> anyhow::Result<()> {
+    let jobs = jobs(&repo.config().schedules)?;
+    let mut state = load_state(&options.state_file)?;
+
+    let now = Utc::now();
+    let status = Arc::new(Mutex::new(DaemonStatus {
+        pid: std::process::id(),
+        started: now,
+        schedules: jobs
+            .iter()
+            .map(|job| {
+                let last_run = state.get(&job.schedule.path).cloned();
+                ScheduleStatus {
+                    path: job.schedule.path.clone(),
+                    schedule: describe(&job.schedule),
+                    running: false,
+                    next_run: first_run(&job.trigger, last_run.as_ref(), now),
+                    last_run,
+                }
+            })
+            .collect(),
+    }));
+
+    let _socket = socket::listen(&options.socket, Arc::clone(&status))?;
+
+    loop {
+        let now = Utc::now();
+        let next_runs: Vec<_> = lock(&status)
+            .schedules
+            .iter()
+            .map(|schedule| schedule.next_run)
+            .collect();
+        let due = next_runs
+            .iter()
+            .enumerate()
+            .filter_map(|(index, next)| next.filter(|next| *next <= now).map(|next| (next, index)))
+            .min();
+
+        let Some((_, index)) = due else {
+            let sleep = next_runs
+                .iter()
+                .flatten()
+                .min()
+                .and_then(|next| (*next - now).to_std().ok())
+                .map_or(MAX_SLEEP, |until| until.min(MAX_SLEEP));
+            thread::sleep(sleep);
+            continue;
+        };
+
+        let job = &jobs[index];
+        lock(&status).schedules[index].running = true;
+        let record = run_job(repo, job, &options.create);
+        {
+            let mut status = lock(&status);
+            let schedule = &mut status.schedules[index];
+            schedule.running = false;
+            schedule.next_run = job.trigger.next_after(record.finished);
+            schedule.last_run = Some(record.clone());
+        }
+
+        state.insert(job.schedule.path.clone(), record.clone());
+        let saved = save_state(&options.state_file, &state).map_err(|e| {
+            e.context(format!(
+                "Cannot save daemon state to {}",
+                options.state_file.display()
+            ))
+        });
+        on_run(&job.schedule.path, &record, saved.as_ref().err());
+    }
+}
+
+/// Asks the daemon listening on `socket` for its status.
+pub fn status(socket: &Path) -> anyhow::Result<DaemonStatus> {
+    socket::query(socket)
+}
+
+fn jobs(schedules: &[ScheduleConfig]) -> anyhow::Result<Vec<Job>> {
+    if schedules.is_empty() {
+        return Err(anyhow::anyhow!(
+            "No schedules are configured; add a `schedules` section to the config"
+        ));
+    }
+    let mut paths = HashSet::new();
+    schedules
+        .iter()
+        .map(|schedule| {
+            if !paths.insert(&schedule.path) {
+                return Err(anyhow::anyhow!(
+                    "{} is scheduled more than once",
+                    schedule.path.display()
+                ));
+            }
+            Ok(Job {
+                schedule: schedule.clone(),
+                trigger: Trigger::from_config(schedule)?,
+            })
+        })
+        .collect()
+}
+
+fn describe(schedule: &ScheduleConfig) -> String {
+    match (&schedule.every, &schedule.cron) {
+        (Some(every), _) => format!("every {}", every),
+        (_, Some(cron)) => format!("cron {}", cron),
+        _ => String::new(),
+    }
+}
+
+/// A path that ran before is due one interval after that run, which may
+/// already have passed. Without a previous run, intervals start right away
+/// and cron expressions wait for their next match.
+fn first_run(
+    trigger: &Trigger,
+    last_run: Option<&RunRecord>,
+    now: DateTime<Utc>,
+) -> Option<DateTime<Utc>> {
+    match (last_run, trigger) {
+        (Some(last_run), _) => trigger.next_after(last_run.started),
+        (None, Trigger::Every(_)) => Some(now),
+        (None, Trigger::Cron(cron)) => cron.next_after(now),
+    }
+}
+
+fn run_job(repo: &Repository, job: &Job, create: &CreateOptions) -> RunRecord {
+    let started = Utc::now();
+    let path = &job.schedule.path;
+    let mut record = RunRecord {
+        started,
+        finished: started,
+        outcome: RunOutcome::Success,
+        snapshot: None,
+        message: None,
+    };
+
+    let result = (|| {
+        let mut notes = Vec::new();
+        let report = repo.create_snapshot(path, create)?;
+        record.snapshot = report.snapshot.as_ref().map(|snapshot| snapshot.number);
+        if let Some(snapshot) = report.snapshot.as_ref().filter(|s| s.is_partial()) {
+            record.outcome = RunOutcome::Partial;
+            notes.push(format!(
+                "{} files could not be backed up",
+                snapshot.failures.len()
+            ));
+        }
+
+        if let Some(keep) = job.schedule.keep_last {
+            let pruned = repo.prune(path, keep)?;
+            if !pruned.removed.is_empty() {
+                notes.push(format!(
+                    "pruned {} backups and {} objects",
+                    pruned.removed.len(),
+                    pruned.objects_removed
+                ));
+            }
+        }
+
+        if job.schedule.verify {
+            let verified = repo.verify(Some(path), &VerifyOptions { read_data: true })?;
+            if let Some(problem) = verified.problems.first() {
+                return Err(anyhow::anyhow!(
+                    "verify found {} problems, first in {}: {}",
+                    verified.problems.len(),
+                    problem.key,
+                    problem.error
+                ));
+            }
+        }
+        anyhow::Ok(notes)
+    })();
+
+    match result {
+        Ok(notes) => record.message = (!notes.is_empty()).then(|| notes.join("; ")),
+        Err(e) => {
+            record.outcome = RunOutcome::Failed;
+            record.message = Some(format!("{:#}", e));
+        }
+    }
+    record.finished = Utc::now();
+    record
+}
+
+fn load_state(path: &Path) -> anyhow::Result<BTreeMap<PathBuf, RunRecord>> {
+    match fs::read(path) {
+        Ok(content) => serde_json::from_slice(&content)
+            .map_err(|e| anyhow::anyhow!("Invalid daemon state {}: {}", path.display(), e)),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(BTreeMap::new()),
+        Err(e) => Err(anyhow::anyhow!(
+            "Cannot read daemon state {}: {}",
+            path.display(),
+            e
+        )),
+    }
+}
+
+fn save_state(path: &Path, state: &BTreeMap<PathBuf, RunRecord>) -> anyhow::Result<()> {
+    util::atomic::write(path, serde_json::to_string_pretty(state)?)
+}
+
+fn lock(status: &Mutex<DaemonStatus>) -> std::sync::MutexGuard<'_, DaemonStatus> {
+    status
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+#[cfg(unix)]
+mod socket {
+    use std::{
+        fs,
+        io::Read,
+        os::unix::net::{UnixListener, UnixStream},
+        path::{Path, PathBuf},
+        sync::{Arc, Mutex},
+        thread,
+    };
+
+    use super::{lock, DaemonStatus};
+
+    /// Removes the socket file when the daemon stops.
+    pub(super) struct Socket(PathBuf);
+
+    impl Drop for Socket {
+        fn drop(&mut self) {
+            let _ = fs::remove_file(&self.0);
+        }
+    }
+
+    /// Answers every connection on `path` with the current status. A socket
+    /// file left by a daemon that is gone is replaced.
+    pub(super) fn listen(path: &Path, status: Arc<Mutex<DaemonStatus>>) -> anyhow::Result<Socket> {
+        if path.exists() {
+            if UnixStream::connect(path).is_ok() {
+                return Err(anyhow::anyhow!(
+                    "Another snapback daemon is listening on {}",
+                    path.display()
+                ));
+            }
+            fs::remove_file(path)?;
+        }
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let listener = UnixListener::bind(path)
+            .map_err(|e| anyhow::anyhow!("Cannot listen on {}: {}", path.display(), e))?;
+
+        thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                let status = lock(&status).clone();
+                let _ = serde_json::to_writer(stream, &status);
+            }
+        });
+        Ok(Socket(path.to_path_buf()))
+    }
+
+    pub(super) fn query(path: &Path) -> anyhow::Result<DaemonStatus> {
+        let mut stream = UnixStream::connect(path).map_err(|e| {
+            anyhow::anyhow!(
+                "No snapback daemon is running (cannot connect to {}: {})",
+                path.display(),
+                e
+            )
+        })?;
+        let mut content = Vec::new();
+        stream.read_to_end(&mut content)?;
+        Ok(serde_json::from_slice(&content)?)
+    }
+}
+
+#[cfg(not(unix))]
+mod socket {
+    use std::{
+        path::Path,
+        sync::{Arc, Mutex},
+    };
+
+    use super::DaemonStatus;
+
+    pub(super) struct Socket;
+
+    pub(super) fn listen(
+        _path: &Path,
+        _status: Arc<Mutex<DaemonStatus>>,
+    ) -> anyhow::Result<Socket> {
+        Err(anyhow::anyhow!("snapback daemon needs Unix domain sockets"))
+    }
+
+    pub(super) fn query(_path: &Path) -> anyhow::Result<DaemonStatus> {
+        Err(anyhow::anyhow!("snapback status needs Unix domain sockets"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    #[test]
+    fn missed_runs_are_caught_up_once() {
+        let now = Utc.with_ymd_and_hms(2025, 1, 15, 12, 0, 0).unwrap();
+        let hourly = Trigger::Every(chrono::Duration::hours(1));
+        let ran_at = |started| RunRecord {
+            started,
+            finished: started,
+            outcome: RunOutcome::Success,
+            snapshot: None,
+            message: None,
+        };
+
+        assert_eq!(first_run(&hourly, None, now), Some(now));
+        let recent = ran_at(now - chrono::Duration::minutes(20));
+        assert_eq!(
+            first_run(&hourly, Some(&recent), now),
+            Some(now + chrono::Duration::minutes(40))
+        );
+        // Days of missed runs make one run that is due right away
+        let old = ran_at(now - chrono::Duration::days(3));
+        assert!(first_run(&hourly, Some(&old), now).is_some_and(|next| next <= now));
+    }
+}
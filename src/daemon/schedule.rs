@@ -0,0 +1,324 @@
+//! When scheduled backups are due: fixed intervals and cron expressions.
+
+use chrono::{
+    DateTime, Datelike, Duration, Local, NaiveDate, NaiveDateTime, TimeZone, Timelike, Utc,
+};
+
+use crate::config::ScheduleConfig;
+
+/// How far ahead a cron expression is searched before it is taken to never
+/// fire, as `0 0 30 2 *` does.
+const CRON_HORIZON_DAYS: i64 = 5 * 366;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Trigger {
+    Every(Duration),
+    Cron(Cron),
+}
+
+impl Trigger {
+    pub fn from_config(schedule: &ScheduleConfig) -> anyhow::Result<Self> {
+        let trigger = match (&schedule.every, &schedule.cron) {
+            (Some(every), None) => parse_interval(every).map(Self::Every),
+            (None, Some(cron)) => Cron::parse(cron).map(Self::Cron),
+            _ => Err(anyhow::anyhow!("set exactly one of `every` and `cron`")),
+        };
+        trigger
+            .map_err(|e| anyhow::anyhow!("Invalid schedule for {}: {}", schedule.path.display(), e))
+    }
+
+    /// The first time after `time` at which the trigger fires, if any.
+    pub fn next_after(&self, time: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        match self {
+            Self::Every(interval) => time.checked_add_signed(*interval),
+            Self::Cron(cron) => cron.next_after(time),
+        }
+    }
+}
+
+/// Parses intervals such as `90s`, `30m`, `6h`, `1d12h` or `2w`.
+pub fn parse_interval(text: &str) -> anyhow::Result<Duration> {
+    let mut seconds = 0i64;
+    let mut digits = String::new();
+    for c in text.trim().chars() {
+        if c.is_ascii_digit() {
+            digits.push(c);
+            continue;
+        }
+        let unit = match c {
+            's' => 1,
+            'm' => 60,
+            'h' => 60 * 60,
+            'd' => 24 * 60 * 60,
+            'w' => 7 * 24 * 60 * 60,
+            _ => return Err(anyhow::anyhow!("unknown unit {:?} in interval {}", c, text)),
+        };
+        let count: i64 = digits
+            .parse()
+            .map_err(|_| anyhow::anyhow!("invalid interval {}", text))?;
+        seconds = count
+            .checked_mul(unit)
+            .and_then(|part| seconds.checked_add(part))
+            .ok_or_else(|| anyhow::anyhow!("interval {} is too long", text))?;
+        digits.clear();
+    }
+    if !digits.is_empty() {
+        return Err(anyhow::anyhow!(
+            "interval {} needs a unit: s, m, h, d or w",
+            text
+        ));
+    }
+    if seconds == 0 {
+        return Err(anyhow::anyhow!(
+            "interval {} must be longer than zero",
+            text
+        ));
+    }
+    Duration::try_seconds(seconds).ok_or_else(|| anyhow::anyhow!("interval {} is too long", text))
+}
+
+/// A five-field cron expression (minute, hour, day of month, month, day of
+/// week), evaluated in local time. Fields take numbers, `*`, ranges, steps
+/// and lists; Sunday is 0 or 7. As in cron, when both day fields are
+/// restricted a day matching either of them fires.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Cron {
+    minutes: u64,
+    hours: u64,
+    days: u64,
+    months: u64,
+    weekdays: u64,
+    any_day: bool,
+    any_weekday: bool,
+}
+
+impl Cron {
+    pub fn parse(text: &str) -> anyhow::Result<Self> {
+        let expanded = match text.trim() {
+            "@hourly" => "0 * * * *",
+            "@daily" | "@midnight" => "0 0 * * *",
+            "@weekly" => "0 0 * * 0",
+            "@monthly" => "0 0 1 * *",
+            "@yearly" | "@annually" => "0 0 1 1 *",
+            other => other,
+        };
+        let fields: Vec<_> = expanded.split_whitespace().collect();
+        let [minute, hour, day, month, weekday] = fields[..] else {
+            return Err(anyhow::anyhow!(
+                "cron expression {} needs five fields: minute hour day month weekday",
+                text
+            ));
+        };
+
+        let mut weekdays = parse_field(weekday, 0, 7)?;
+        if weekdays & (1 << 7) != 0 {
+            weekdays = (weekdays | 1) & !(1 << 7);
+        }
+        Ok(Self {
+            minutes: parse_field(minute, 0, 59)?,
+            hours: parse_field(hour, 0, 23)?,
+            days: parse_field(day, 1, 31)?,
+            months: parse_field(month, 1, 12)?,
+            weekdays,
+            any_day: day.starts_with('*'),
+            any_weekday: weekday.starts_with('*'),
+        })
+    }
+
+    /// The first matching minute after `time`.
+    pub fn next_after(&self, time: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        let local = time.with_timezone(&Local).naive_local();
+        let mut candidate = local.with_second(0)?.with_nanosecond(0)? + Duration::minutes(1);
+        let horizon = candidate + Duration::days(CRON_HORIZON_DAYS);
+
+        while candidate < horizon {
+            let date = candidate.date();
+            if !has(self.months, date.month()) {
+                candidate = first_of_next_month(date)?;
+            } else if !self.matches_day(date) {
+                candidate = date.succ_opt()?.and_hms_opt(0, 0, 0)?;
+            } else if !has(self.hours, candidate.hour()) {
+                candidate = date.and_hms_opt(candidate.hour(), 0, 0)? + Duration::hours(1);
+            } else if !has(self.minutes, candidate.minute()) {
+                candidate += Duration::minutes(1);
+            } else {
+                // Local times skipped by a clock change never happen.
+                if let Some(found) = Local.from_local_datetime(&candidate).earliest() {
+                    let found = found.with_timezone(&Utc);
+                    if found > time {
+                        return Some(found);
+                    }
+                }
+                candidate += Duration::minutes(1);
+            }
+        }
+        None
+    }
+
+    fn matches_day(&self, date: NaiveDate) -> bool {
+        let day = has(self.days, date.day());
+        let weekday = has(self.weekdays, date.weekday().num_days_from_sunday());
+        match (self.any_day, self.any_weekday) {
+            (true, true) => true,
+            (true, false) => weekday,
+            (false, true) => day,
+            (false, false) => day || weekday,
+        }
+    }
+}
+
+fn has(set: u64, value: u32) -> bool {
+    set & (1 << value) != 0
+}
+
+fn first_of_next_month(date: NaiveDate) -> Option<NaiveDateTime> {
+    let (year, month) = match date.month() {
+        12 => (date.year() + 1, 1),
+        month => (date.year(), month + 1),
+    };
+    NaiveDate::from_ymd_opt(year, month, 1)?.and_hms_opt(0, 0, 0)
+}
+
+/// Parses one cron field into a bit set of the values it allows.
+fn parse_field(field: &str, min: u32, max: u32) -> anyhow::Result<u64> {
+    let invalid = || anyhow::anyhow!("invalid cron field {} (allowed {}-{})", field, min, max);
+    let number = |text: &str| {
+        text.parse::<u32>()
+            .ok()
+            .filter(|n| (min..=max).contains(n))
+            .ok_or_else(invalid)
+    };
+
+    let mut set = 0u64;
+    for part in field.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => (
+                range,
+                step.parse::<u32>()
+                    .ok()
+                    .filter(|s| *s > 0)
+                    .ok_or_else(invalid)?,
+            ),
+            None => (part, 1),
+        };
+        let (start, end) = match range {
+            "*" => (min, max),
+            _ => match range.split_once('-') {
+                Some((start, end)) => (number(start)?, number(end)?),
+                // `5/15` means every 15 starting at 5
+                None if part.contains('/') => (number(range)?, max),
+                None => (number(range)?, number(range)?),
+            },
+        };
+        if start > end {
+            return Err(invalid());
+        }
+        for value in (start..=end).step_by(step as usize) {
+            set |= 1 << value;
+        }
+    }
+    Ok(set)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A local wall-clock time, as cron expressions see it.
+    fn local(year: i32, month: u32, day: u32, hour: u32, minute: u32) -> DateTime<Utc> {
+        Local
+            .with_ymd_and_hms(year, month, day, hour, minute, 0)
+            .unwrap()
+            .with_timezone(&Utc)
+    }
+
+    fn next(cron: &str, after: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        Cron::parse(cron).unwrap().next_after(after)
+    }
+
+    #[test]
+    fn intervals_add_up_their_parts() {
+        assert_eq!(parse_interval("90s").unwrap(), Duration::seconds(90));
+        assert_eq!(parse_interval("30m").unwrap(), Duration::minutes(30));
+        assert_eq!(parse_interval(" 1d12h ").unwrap(), Duration::hours(36));
+        assert_eq!(parse_interval("2w").unwrap(), Duration::weeks(2));
+        for bad in ["", "10", "0m", "5x", "h", "99999999999999999w"] {
+            assert!(parse_interval(bad).is_err(), "{:?}", bad);
+        }
+    }
+
+    #[test]
+    fn cron_fields_take_lists_ranges_and_steps() {
+        let cron = Cron::parse("*/15 9-17 * * 1-5").unwrap();
+        assert_eq!(cron.minutes, 1 | 1 << 15 | 1 << 30 | 1 << 45);
+        assert_eq!(cron.hours, (9..=17).map(|hour| 1 << hour).sum::<u64>());
+        assert_eq!(
+            Cron::parse("5/20 * * * *").unwrap().minutes,
+            1 << 5 | 1 << 25 | 1 << 45
+        );
+        assert_eq!(
+            Cron::parse("0 0 * * 7").unwrap(),
+            Cron::parse("0 0 * * 0").unwrap()
+        );
+        assert_eq!(
+            Cron::parse("@daily").unwrap(),
+            Cron::parse("0 0 * * *").unwrap()
+        );
+        for bad in [
+            "* * * *",
+            "60 * * * *",
+            "* * 0 * *",
+            "5-1 * * * *",
+            "*/0 * * * *",
+        ] {
+            assert!(Cron::parse(bad).is_err(), "{:?}", bad);
+        }
+    }
+
+    #[test]
+    fn cron_finds_the_next_matching_minute() {
+        // Wednesday 2025-01-15, 10:07
+        let now = local(2025, 1, 15, 10, 7);
+        assert_eq!(next("*/15 * * * *", now), Some(local(2025, 1, 15, 10, 15)));
+        assert_eq!(next("0 3 * * *", now), Some(local(2025, 1, 16, 3, 0)));
+        assert_eq!(next("30 9 * * 1", now), Some(local(2025, 1, 20, 9, 30)));
+        assert_eq!(next("@monthly", now), Some(local(2025, 2, 1, 0, 0)));
+        // A matching minute is never returned for itself
+        assert_eq!(next("7 10 * * *", now), Some(local(2025, 1, 16, 10, 7)));
+    }
+
+    #[test]
+    fn restricted_day_fields_match_either_day() {
+        // The 20th, or any Friday: Friday the 17th comes first
+        let now = local(2025, 1, 15, 12, 0);
+        assert_eq!(next("0 0 20 * 5", now), Some(local(2025, 1, 17, 0, 0)));
+        assert_eq!(next("0 0 20 * *", now), Some(local(2025, 1, 20, 0, 0)));
+    }
+
+    #[test]
+    fn impossible_dates_never_fire() {
+        assert_eq!(next("0 0 30 2 *", local(2025, 1, 15, 12, 0)), None);
+    }
+
+    #[test]
+    fn schedules_need_exactly_one_trigger() {
+        let schedule = |every: Option<&str>, cron: Option<&str>| ScheduleConfig {
+            path: "/data".into(),
+            every: every.map(String::from),
+            cron: cron.map(String::from),
+            keep_last: None,
+            verify: false,
+        };
+        assert_eq!(
+            Trigger::from_config(&schedule(Some("1h"), None)).unwrap(),
+            Trigger::Every(Duration::hours(1))
+        );
+        assert!(Trigger::from_config(&schedule(None, Some("@hourly"))).is_ok());
+        let error = Trigger::from_config(&schedule(Some("1h"), Some("@hourly"))).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Invalid schedule for /data: set exactly one of `every` and `cron`"
+        );
+        assert!(Trigger::from_config(&schedule(None, None)).is_err());
+    }
+}
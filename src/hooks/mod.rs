@@ -0,0 +1,346 @@
+//! User commands run around `create` and `restore`.
+//!
+//! Hooks are run through the shell with the `SNAPBACK_*` variables of
+//! [`HookEnv`] set. Their output goes to snapback's stderr, so it never mixes
+//! with anything written to stdout. A hook that runs longer than its timeout
+//! is killed together with everything it started, and counts as failed.
+
+use std::{
+    path::Path,
+    process::{Child, Command, Stdio},
+    thread,
+    time::{Duration, Instant},
+};
+
+use crate::config::HooksConfig;
+
+/// How often a running hook is checked for completion.
+const POLL: Duration = Duration::from_millis(50);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Operation {
+    Create,
+    Restore,
+}
+
+impl Operation {
+    fn name(self) -> &'static str {
+        match self {
+            Self::Create => "create",
+            Self::Restore => "restore",
+        }
+    }
+
+    fn pre(self, hooks: &HooksConfig) -> Option<&str> {
+        match self {
+            Self::Create => hooks.pre_create.as_deref(),
+            Self::Restore => hooks.pre_restore.as_deref(),
+        }
+    }
+
+    fn post(self, hooks: &HooksConfig) -> Option<&str> {
+        match self {
+            Self::Create => hooks.post_create.as_deref(),
+            Self::Restore => hooks.post_restore.as_deref(),
+        }
+    }
+}
+
+/// Environment handed to hooks. Every name gets the `SNAPBACK_` prefix.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct HookEnv(Vec<(String, String)>);
+
+impl HookEnv {
+    pub fn new(operation: Operation, root: &Path) -> Self {
+        let mut env = Self::default();
+        env.set("OPERATION", operation.name());
+        env.set("PATH", root.display());
+        env
+    }
+
+    pub fn set(&mut self, name: &str, value: impl ToString) {
+        let name = format!("SNAPBACK_{}", name);
+        self.0.retain(|(existing, _)| *existing != name);
+        self.0.push((name, value.to_string()));
+    }
+}
+
+/// Runs `operation` between its pre and post hooks. `describe` adds what the
+/// operation produced to the environment of the post hook. If the pre hook,
+/// the operation or the post hook fails, the on-failure hook runs with
+/// `SNAPBACK_STATUS` set to `failed` and the message in `SNAPBACK_ERROR`.
+pub(crate) fn around<T>(
+    hooks: &HooksConfig,
+    operation: Operation,
+    mut env: HookEnv,
+    run: impl FnOnce() -> anyhow::Result<T>,
+    describe: impl FnOnce(&T, &mut HookEnv),
+) -> anyhow::Result<T> {
+    let result = match operation.pre(hooks) {
+        Some(command) => {
+            env.set("HOOK", format!("pre-{}", operation.name()));
+            run_hook(command, &env, hooks.get_timeout_secs())
+                .map_err(|e| anyhow::anyhow!("pre-{} hook failed: {:#}", operation.name(), e))
+        }
+        None => Ok(()),
+    }
+    .and_then(|_| run())
+    .and_then(|value| {
+        let Some(command) = operation.post(hooks) else {
+            return Ok(value);
+        };
+        describe(&value, &mut env);
+        env.set("HOOK", format!("post-{}", operation.name()));
+        run_hook(command, &env, hooks.get_timeout_secs()).map_err(|e| {
+            anyhow::anyhow!(
+                "{} finished, but the post-{} hook failed: {:#}",
+                operation.name(),
+                operation.name(),
+                e
+            )
+        })?;
+        Ok(value)
+    });
+
+    let e = match result {
+        Ok(value) => return Ok(value),
+        Err(e) => e,
+    };
+    let Some(command) = hooks.on_failure.as_deref() else {
+        return Err(e);
+    };
+    env.set("HOOK", "on-failure");
+    env.set("STATUS", "failed");
+    env.set("ERROR", format!("{:#}", e));
+    match run_hook(command, &env, hooks.get_timeout_secs()) {
+        Ok(()) => Err(e),
+        Err(hook_error) => Err(anyhow::anyhow!(
+            "{:#}; the on-failure hook failed too: {:#}",
+            e,
+            hook_error
+        )),
+    }
+}
+
+fn run_hook(command: &str, env: &HookEnv, timeout_secs: u64) -> anyhow::Result<()> {
+    let mut child = shell(command)
+        .envs(env.0.iter().map(|(name, value)| (name, value)))
+        .stdin(Stdio::null())
+        .stdout(std::io::stderr())
+        .spawn()
+        .map_err(|e| anyhow::anyhow!("cannot run {:?}: {}", command, e))?;
+
+    let deadline = Instant::now() + Duration::from_secs(timeout_secs);
+    loop {
+        if let Some(status) = child.try_wait()? {
+            return if status.success() {
+                Ok(())
+            } else {
+                Err(anyhow::anyhow!("{}", status))
+            };
+        }
+        if Instant::now() >= deadline {
+            kill(&mut child);
+            let _ = child.wait();
+            return Err(anyhow::anyhow!(
+                "did not finish within {} seconds and was killed",
+                timeout_secs
+            ));
+        }
+        thread::sleep(POLL);
+    }
+}
+
+/// Kills the hook's whole process group, so commands the shell started
+/// do not outlive it.
+#[cfg(unix)]
+fn kill(child: &mut Child) {
+    // A negative pid signals the group the hook leads.
+    unsafe { libc::kill(-(child.id() as libc::pid_t), libc::SIGKILL) };
+}
+
+#[cfg(not(unix))]
+fn kill(child: &mut Child) {
+    let _ = child.kill();
+}
+
+#[cfg(unix)]
+fn shell(command: &str) -> Command {
+    use std::os::unix::process::CommandExt;
+
+    let mut shell = Command::new("sh");
+    // Its own process group, which a timeout kills as a whole.
+    shell.arg("-c").arg(command).process_group(0);
+    shell
+}
+
+#[cfg(not(unix))]
+fn shell(command: &str) -> Command {
+    let mut shell = Command::new("cmd");
+    shell.arg("/C").arg(command);
+    shell
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use std::{cell::Cell, fs};
+
+    use super::*;
+
+    /// A hook that appends its name, status and error to `log`.
+    fn record(log: &Path) -> String {
+        format!(
+            "echo \"$SNAPBACK_HOOK $SNAPBACK_OPERATION ${{SNAPBACK_STATUS:-}} ${{SNAPBACK_ERROR:-}}\" >> '{}'",
+            log.display()
+        )
+    }
+
+    fn lines(log: &Path) -> Vec<String> {
+        fs::read_to_string(log)
+            .unwrap_or_default()
+            .lines()
+            .map(str::to_owned)
+            .collect()
+    }
+
+    #[test]
+    fn post_hook_sees_what_the_operation_produced() {
+        let dir = tempfile::tempdir().unwrap();
+        let log = dir.path().join("log");
+        let hooks = HooksConfig {
+            pre_create: Some(record(&log)),
+            post_create: Some(format!(
+                "echo \"$SNAPBACK_HOOK $SNAPBACK_PATH $SNAPBACK_SNAPSHOT\" >> '{}'",
+                log.display()
+            )),
+            ..Default::default()
+        };
+        let env = HookEnv::new(Operation::Create, Path::new("/data"));
+
+        let value = around(
+            &hooks,
+            Operation::Create,
+            env,
+            || Ok(7),
+            |number, env| env.set("SNAPSHOT", number),
+        )
+        .unwrap();
+
+        assert_eq!(value, 7);
+        assert_eq!(lines(&log), ["pre-create create  ", "post-create /data 7"]);
+    }
+
+    #[test]
+    fn failing_pre_hook_skips_the_operation_and_runs_on_failure() {
+        let dir = tempfile::tempdir().unwrap();
+        let log = dir.path().join("log");
+        let hooks = HooksConfig {
+            pre_restore: Some("exit 3".into()),
+            post_restore: Some(record(&log)),
+            on_failure: Some(record(&log)),
+            ..Default::default()
+        };
+        let ran = Cell::new(false);
+        let env = HookEnv::new(Operation::Restore, Path::new("/data"));
+
+        let e = around(
+            &hooks,
+            Operation::Restore,
+            env,
+            || {
+                ran.set(true);
+                Ok(())
+            },
+            |_, _| {},
+        )
+        .unwrap_err();
+
+        assert!(!ran.get());
+        assert_eq!(e.to_string(), "pre-restore hook failed: exit status: 3");
+        assert_eq!(
+            lines(&log),
+            ["on-failure restore failed pre-restore hook failed: exit status: 3"]
+        );
+    }
+
+    #[test]
+    fn failing_operation_reaches_on_failure_but_not_the_post_hook() {
+        let dir = tempfile::tempdir().unwrap();
+        let log = dir.path().join("log");
+        let hooks = HooksConfig {
+            post_create: Some(record(&log)),
+            on_failure: Some(record(&log)),
+            ..Default::default()
+        };
+        let env = HookEnv::new(Operation::Create, Path::new("/data"));
+
+        let e = around::<()>(
+            &hooks,
+            Operation::Create,
+            env,
+            || Err(anyhow::anyhow!("disk full")),
+            |_, _| {},
+        )
+        .unwrap_err();
+
+        assert_eq!(e.to_string(), "disk full");
+        assert_eq!(lines(&log), ["on-failure create failed disk full"]);
+    }
+
+    #[test]
+    fn failing_post_hook_and_on_failure_hook_are_both_reported() {
+        let hooks = HooksConfig {
+            post_create: Some("exit 1".into()),
+            on_failure: Some("exit 2".into()),
+            ..Default::default()
+        };
+        let env = HookEnv::new(Operation::Create, Path::new("/data"));
+
+        let e = around(&hooks, Operation::Create, env, || Ok(()), |_, _| {}).unwrap_err();
+
+        assert_eq!(
+            e.to_string(),
+            "create finished, but the post-create hook failed: exit status: 1; \
+             the on-failure hook failed too: exit status: 2"
+        );
+    }
+
+    #[test]
+    fn hooks_that_run_too_long_are_killed_with_their_children() {
+        let dir = tempfile::tempdir().unwrap();
+        let marker = dir.path().join("marker");
+        let hooks = HooksConfig {
+            pre_create: Some(format!("(sleep 3; touch '{}') & wait", marker.display())),
+            timeout_secs: Some(1),
+            ..Default::default()
+        };
+        let env = HookEnv::new(Operation::Create, Path::new("/data"));
+        let started = Instant::now();
+
+        let e = around(&hooks, Operation::Create, env, || Ok(()), |_, _| {}).unwrap_err();
+
+        assert!(started.elapsed() < Duration::from_secs(3));
+        assert_eq!(
+            e.to_string(),
+            "pre-create hook failed: did not finish within 1 seconds and was killed"
+        );
+        thread::sleep(Duration::from_secs(3));
+        assert!(!marker.exists());
+    }
+
+    #[test]
+    fn setting_a_name_again_replaces_its_value() {
+        let mut env = HookEnv::new(Operation::Create, Path::new("/data"));
+        env.set("STATUS", "ok");
+        env.set("STATUS", "failed");
+
+        assert_eq!(
+            env.0,
+            [
+                ("SNAPBACK_OPERATION".to_owned(), "create".to_owned()),
+                ("SNAPBACK_PATH".to_owned(), "/data".to_owned()),
+                ("SNAPBACK_STATUS".to_owned(), "failed".to_owned()),
+            ]
+        );
+    }
+}
@@ -0,0 +1,28 @@
+//! SnapBack: incremental, content-addressed backups.
+//!
+//! [`Repository`] is the entry point for embedding snapback. It creates,
+//! lists and restores [`Snapshot`]s and returns structured reports instead of
+//! printing; the `snapback` binary is a thin CLI on top of it.
+
+mod backup;
+pub mod config;
+pub mod daemon;
+mod hooks;
+pub mod lock;
+pub mod output;
+pub mod repository;
+pub mod serve;
+pub mod snapshot;
+pub mod storage;
+mod util;
+pub mod watch;
+
+pub use config::Config;
+pub use repository::{
+    ArchiveFormat, Change, ChangeKind, CopiedSource, CopyOptions, CopyReport, CreateOptions,
+    CreateReport, DeltaReport, ExportReport, GitExportOptions, GitExportReport, ImportOptions,
+    PruneReport, Repository, RestoreOptions, RestoreReport, VerifyOptions, VerifyProblem,
+    VerifyReport,
+};
+pub use snapshot::{BackupInfo, FailedFile, Snapshot, SnapshotEntry, SnapshotStatus};
+pub use storage::{LocalStorage, MemoryStorage, Storage};
@@ -0,0 +1,197 @@
+use std::sync::Arc;
+
+use chrono::DateTime;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::storage::{self, Storage};
+
+/// A lock object under `locks/`. Readers (list, restore) take shared
+/// locks and may run side by side; writers (create) need the repository to
+/// themselves.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LockInfo {
+    pub exclusive: bool,
+    pub pid: u32,
+    pub hostname: String,
+    pub time: DateTime<chrono::Utc>,
+}
+
+impl LockInfo {
+    fn conflicts_with(&self, other: &LockInfo) -> bool {
+        self.exclusive || other.exclusive
+    }
+
+    /// A lock is stale when it was taken on this host by a process that no
+    /// longer exists. Locks from other hosts are never considered stale,
+    /// because we cannot tell whether their owner is still running.
+    pub fn is_stale(&self) -> bool {
+        self.hostname == current_hostname() && !process_alive(self.pid)
+    }
+
+    fn describe(&self) -> String {
+        format!(
+            "{} lock held by PID {} on {} since {}",
+            if self.exclusive {
+                "exclusive"
+            } else {
+                "shared"
+            },
+            self.pid,
+            self.hostname,
+            self.time.format("%Y-%m-%d %H:%M:%S UTC")
+        )
+    }
+}
+
+/// Held for the duration of a repository operation; the lock object is
+/// removed when it is dropped.
+#[derive(Debug)]
+pub struct RepositoryLock {
+    storage: Arc<dyn Storage>,
+    key: String,
+}
+
+impl RepositoryLock {
+    pub fn shared(storage: &Arc<dyn Storage>) -> anyhow::Result<Self> {
+        Self::acquire(storage, false)
+    }
+
+    pub fn exclusive(storage: &Arc<dyn Storage>) -> anyhow::Result<Self> {
+        Self::acquire(storage, true)
+    }
+
+    fn acquire(storage: &Arc<dyn Storage>, exclusive: bool) -> anyhow::Result<Self> {
+        let info = LockInfo {
+            exclusive,
+            pid: std::process::id(),
+            hostname: current_hostname(),
+            time: chrono::Utc::now(),
+        };
+
+        // Publish our lock first and look for conflicts afterwards, so two
+        // processes racing for the repository both see each other.
+        let key = storage::lock_key(&Uuid::new_v4().to_string());
+        storage.put(&key, serde_json::to_string_pretty(&info)?.as_bytes())?;
+        let lock = Self {
+            storage: Arc::clone(storage),
+            key,
+        };
+
+        let locks = read_locks(storage.as_ref())
+            .map_err(|e| anyhow::anyhow!("Cannot check the repository locks: {:#}", e))?;
+        for (other, other_key) in locks {
+            if other_key == lock.key {
+                continue;
+            }
+            // Nobody can tell what an unreadable lock protects, so it is
+            // taken to be held
+            let other = match other {
+                Lock::Held(other) => other,
+                Lock::Unreadable(error) => {
+                    return Err(anyhow::anyhow!(
+                        "Repository is locked by {}, which cannot be read: {}. If no other snapback is running, run `snapback unlock --all`",
+                        other_key,
+                        error
+                    ))
+                }
+            };
+            if !info.conflicts_with(&other) {
+                continue;
+            }
+            if other.is_stale() {
+                let _ = storage.delete(&other_key);
+                continue;
+            }
+            return Err(anyhow::anyhow!(
+                "Repository is locked: {}. If that process is gone, run `snapback unlock`",
+                other.describe()
+            ));
+        }
+
+        Ok(lock)
+    }
+}
+
+impl Drop for RepositoryLock {
+    fn drop(&mut self) {
+        let _ = self.storage.delete(&self.key);
+    }
+}
+
+/// What `unlock` removed.
+#[derive(Debug, Clone, Default)]
+pub struct UnlockReport {
+    pub removed: Vec<LockInfo>,
+    /// Keys of lock objects that could not be read, removed with `all`.
+    pub unreadable: Vec<String>,
+}
+
+/// Removes stale locks, or every lock when `all` is set, including ones
+/// that cannot be read.
+pub fn unlock(storage: &dyn Storage, all: bool) -> anyhow::Result<UnlockReport> {
+    let mut report = UnlockReport::default();
+    for (lock, key) in read_locks(storage)? {
+        match lock {
+            Lock::Held(info) if all || info.is_stale() => {
+                storage.delete(&key)?;
+                report.removed.push(info);
+            }
+            Lock::Unreadable(_) if all => {
+                storage.delete(&key)?;
+                report.unreadable.push(key);
+            }
+            _ => {}
+        }
+    }
+    Ok(report)
+}
+
+enum Lock {
+    Held(LockInfo),
+    /// The lock object exists but cannot be read or parsed.
+    Unreadable(String),
+}
+
+/// Reads every lock object. Locks released while they are read are left
+/// out.
+fn read_locks(storage: &dyn Storage) -> anyhow::Result<Vec<(Lock, String)>> {
+    let mut locks = Vec::new();
+    for key in storage.list(storage::LOCKS_DIR)? {
+        if !key.ends_with(".json") {
+            continue;
+        }
+        let lock = match storage.get(&key) {
+            Ok(content) => match serde_json::from_slice(&content) {
+                Ok(info) => Lock::Held(info),
+                Err(e) => Lock::Unreadable(e.to_string()),
+            },
+            Err(_) if !storage.exists(&key)? => continue,
+            Err(e) => Lock::Unreadable(format!("{:#}", e)),
+        };
+        locks.push((lock, key));
+    }
+    Ok(locks)
+}
+
+fn current_hostname() -> String {
+    hostname::get()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_else(|_| "unknown".to_string())
+}
+
+#[cfg(unix)]
+fn process_alive(pid: u32) -> bool {
+    let Ok(pid) = libc::pid_t::try_from(pid) else {
+        return false;
+    };
+    // Signal 0 only checks whether the process exists. EPERM means it does
+    // but belongs to another user.
+    let result = unsafe { libc::kill(pid, 0) };
+    result == 0 || std::io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+}
+
+#[cfg(not(unix))]
+fn process_alive(_pid: u32) -> bool {
+    true
+}
@@ -1,96 +1,1366 @@
-mod config;
-mod backup;
-mod util;
-
-use std::path::PathBuf;
 use clap::{Parser, Subcommand};
+use std::{
+    fs::File,
+    io::{self, BufWriter},
+    path::{Path, PathBuf},
+    time::Duration,
+};
 
-use crate::{backup::Backup, config::Config};
+use chrono::{DateTime, NaiveDate, NaiveDateTime, Utc};
+use snapback::{
+    config::{ConfigFile, LayeredConfig, LoadOptions, Origin},
+    daemon::{self, DaemonOptions, DaemonStatus, RunOutcome, RunRecord},
+    output::{
+        Backup, BackupError, ChangeEvent, ConfigEntry, CopyResult, CreateResult, DaemonStarted,
+        DeltaResult, ExportResult, GitExportResult, ListResult, Output, OutputFormat, PruneResult,
+        RestoreResult, RunEvent, SnapshotSummary, Source, UnlockResult, VerifyResult,
+    },
+    serve,
+    watch::{self, WatchOptions},
+    ArchiveFormat, Change, ChangeKind, Config, CopyOptions, CopyReport, CreateOptions,
+    CreateReport, DeltaReport, ExportReport, GitExportOptions, ImportOptions, Repository,
+    RestoreOptions, VerifyOptions,
+};
 
 fn main() {
     let args = Args::parse();
-    
+    let out = Output::new(args.output_format(), args.command.name());
+    // These work on the config files themselves, so a broken configuration
+    // is reported or can be repaired rather than stopping them.
+    if let Command::Config { action } = &args.command {
+        if let Some(result) = config_file_action(action, &args, &out) {
+            if let Err(e) = result {
+                fail(&out, e);
+            }
+            return;
+        }
+    }
+    let mut layered =
+        load_config(args.config.as_deref(), &args.set).unwrap_or_else(|e| fail(&out, e));
+    let base = layered.config.clone();
+    let config = select_config(&base, args.profile.as_deref(), args.repo.as_deref())
+        .unwrap_or_else(|e| fail(&out, e));
+
     match args.command {
-        Command::Create { path } => {
-            println!("Creating backup for path: {:?}", path);
-            //need to handle and get error messaage informative
-            let backup = Backup::new(path);
-            match backup {
-                Ok(mut backup) => {
-                    match backup.write_backup() {
-                        Ok(_) => {
-                            println!("backup written");
-                        },
+        Command::Create {
+            targets,
+            all,
+            jobs,
+            allow_partial,
+        } => {
+            let mut options = CreateOptions::default();
+            if let Some(jobs) = jobs {
+                options.jobs = jobs;
+            }
+            let targets = create_targets(
+                &base,
+                &config,
+                args.profile.as_deref(),
+                args.repo.as_deref(),
+                &targets,
+                all,
+            )
+            .unwrap_or_else(|e| fail(&out, e));
+
+            let mut result = CreateResult::default();
+            let mut partial = false;
+            for target in targets {
+                let profile = target.profile.as_deref();
+                if let (true, Some(profile)) = (out.is_text(), profile) {
+                    println!("Profile {}:", profile);
+                }
+                let repo = match Repository::open(target.config) {
+                    Ok(repo) => repo,
+                    Err(e) => {
+                        let message = format!("Cannot open the repository: {:#}", e);
+                        create_error(&out, &mut result, profile, None, message);
+                        continue;
+                    }
+                };
+                for path in &target.paths {
+                    if out.is_text() {
+                        println!("Creating backup for path: {:?}", path);
+                    }
+                    let report = match repo.create_snapshot(path, &options) {
+                        Ok(report) => report,
                         Err(e) => {
-                            eprintln!("error -> {:#?}", e);
+                            let message = format!("Backup of {} failed: {:#}", path.display(), e);
+                            create_error(&out, &mut result, profile, Some(path), message);
+                            continue;
+                        }
+                    };
+                    if out.is_text() {
+                        print_create_report(&report);
+                        report_partial(&report, allow_partial);
+                    }
+                    partial |= report.is_partial();
+                    let mut backup = Backup::new(&report, profile);
+                    if let Some(keep) = target.keep_last {
+                        match repo.prune(path, keep) {
+                            Ok(report) => {
+                                if out.is_text() && !report.removed.is_empty() {
+                                    println!(
+                                        "Pruned {} old backups; {} kept",
+                                        report.removed.len(),
+                                        report.kept
+                                    );
+                                }
+                                backup.pruned = report.removed;
+                            }
+                            Err(e) => {
+                                let message = format!("Prune failed: {:#}", e);
+                                create_error(&out, &mut result, profile, Some(path), message);
+                            }
+                        }
+                    }
+                    backup_events(&out, &backup);
+                    result.backups.push(backup);
+                }
+            }
+
+            let message = match result.errors.len() {
+                0 if partial && !allow_partial => {
+                    "Some backups are partial; pass --allow-partial to accept them".to_string()
+                }
+                0 => {
+                    out.result(&result);
+                    return;
+                }
+                1 => result.errors[0].message.clone(),
+                count => format!("{} backups failed", count),
+            };
+            // Text output has already reported each failure as it happened
+            out.failed_result(&result, &message);
+            std::process::exit(1);
+        }
+        Command::Watch {
+            path,
+            debounce,
+            min_interval,
+            jobs,
+        } => {
+            let out = out.streaming();
+            let mut options = WatchOptions {
+                debounce: Duration::from_secs(debounce),
+                min_interval: Duration::from_secs(min_interval),
+                ..WatchOptions::default()
+            };
+            if let Some(jobs) = jobs {
+                options.create.jobs = jobs;
+            }
+            if out.is_text() {
+                println!("Watching {} (Ctrl-C to stop)", path.display());
+            }
+            let watched = Repository::open(config).and_then(|repo| {
+                watch::watch(&repo, &path, &options, |report| match report {
+                    Ok(report) if out.is_text() => {
+                        print_create_report(&report);
+                        report_partial(&report, true);
+                    }
+                    Ok(report) => backup_events(&out, &Backup::new(&report, None)),
+                    Err(e) if out.is_text() => eprintln!("error -> {:#}", e),
+                    Err(e) => out.event(
+                        "error",
+                        &BackupError {
+                            profile: None,
+                            path: Some(path.clone()),
+                            message: format!("Backup of {} failed: {:#}", path.display(), e),
                         },
+                    ),
+                })
+            });
+            if let Err(e) = watched {
+                fail(&out, format!("watch failed: {:#}", e));
+            }
+        }
+        Command::Restore {
+            backup_number,
+            path,
+            target,
+            dry_run,
+        } => {
+            if out.is_text() {
+                println!("Restoring backup #{} to path: {:?}", backup_number, path);
+            }
+            let options = RestoreOptions {
+                target: target.clone(),
+                dry_run,
+            };
+            let report = Repository::open(config)
+                .and_then(|repo| repo.restore(&path, backup_number, &options))
+                .unwrap_or_else(|e| fail(&out, format!("Restore failed: {:#}", e)));
+
+            if out.is_text() {
+                if !report.not_backed_up.is_empty() {
+                    println!(
+                        "Warning: as of backup #{} these files were not backed up and keep their previous version:",
+                        report.snapshot
+                    );
+                    for failure in &report.not_backed_up {
+                        println!("  {}: {}", failure.path, failure.error);
                     }
+                }
+                for path in &report.restored {
+                    println!("✓ Restored: {}", path.display());
+                }
+                for failure in &report.failed {
+                    println!("✗ Failed to restore {}: {}", failure.path, failure.error);
+                }
+            }
+            for path in &report.restored {
+                out.event("restored", &serde_json::json!({ "path": path }));
+            }
+            for failure in &report.failed {
+                out.event("restore_failed", failure);
+            }
+
+            let result = RestoreResult::new(&report, path, target, dry_run);
+            if !report.failed.is_empty() {
+                fail_with(
+                    &out,
+                    &result,
+                    format!(
+                        "Restore failed: {} files could not be recovered from backup #{}",
+                        report.failed.len(),
+                        report.snapshot
+                    ),
+                );
+            }
+            if out.is_text() {
+                println!("Restore completed successfully");
+            }
+            out.result(&result);
+        }
+        Command::List { path } => {
+            if out.is_text() {
+                println!("Listing backups for: {:?}", path);
+            }
+            let (source, snapshots) = Repository::open(config)
+                .and_then(|repo| {
+                    let source = repo.source(&path)?;
+                    Ok((source, repo.list_snapshots(&path)?))
+                })
+                .unwrap_or_else(|e| fail(&out, format!("Failed to list backups: {:#}", e)));
+
+            let result = ListResult {
+                source: Source::from(&source),
+                snapshots: snapshots.iter().map(SnapshotSummary::from).collect(),
+            };
+            for snapshot in &result.snapshots {
+                out.event("snapshot", snapshot);
+            }
+            out.result(&result);
+            if out.is_text() {
+                print_snapshots(&path, &result);
+            }
+        }
+        Command::Unlock { all } => {
+            let report = Repository::open(config)
+                .and_then(|repo| repo.unlock(all))
+                .unwrap_or_else(|e| fail(&out, format!("Failed to remove locks: {:#}", e)));
+            if out.is_text() && report.removed.is_empty() && report.unreadable.is_empty() {
+                println!("No locks removed");
+            }
+            for info in &report.removed {
+                if out.is_text() {
+                    println!(
+                        "Removed lock held by PID {} on {} since {}",
+                        info.pid,
+                        info.hostname,
+                        info.time.format("%Y-%m-%d %H:%M:%S UTC")
+                    );
+                }
+                out.event("lock", info);
+            }
+            if out.is_text() {
+                for key in &report.unreadable {
+                    println!("Removed unreadable lock {}", key);
+                }
+            }
+            out.result(&UnlockResult::from(report));
+        }
+        Command::Prune { path, keep } => {
+            let keep = keep.unwrap_or_else(|| config.get_max_backup_count());
+            let report = Repository::open(config)
+                .and_then(|repo| repo.prune(&path, keep))
+                .unwrap_or_else(|e| fail(&out, format!("Prune failed: {:#}", e)));
+            if out.is_text() {
+                if report.removed.is_empty() {
+                    println!("Nothing to prune; {} backups kept", report.kept);
+                } else {
+                    let numbers: Vec<_> = report.removed.iter().map(u32::to_string).collect();
+                    println!(
+                        "Removed backups {}; {} backups kept",
+                        numbers.join(", "),
+                        report.kept
+                    );
+                }
+                println!(
+                    "Removed {} unreferenced content objects",
+                    report.objects_removed
+                );
+            }
+            out.result(&PruneResult::from(&report));
+        }
+        Command::Verify { path, read_data } => {
+            let options = VerifyOptions { read_data };
+            let report = Repository::open(config)
+                .and_then(|repo| repo.verify(path.as_deref(), &options))
+                .unwrap_or_else(|e| fail(&out, format!("Verify failed: {:#}", e)));
+            for problem in &report.problems {
+                if out.is_text() {
+                    println!("✗ {}: {}", problem.key, problem.error);
+                }
+                out.event("problem", problem);
+            }
+            if out.is_text() {
+                println!(
+                    "Checked {} backups of {} paths and {} content objects",
+                    report.snapshots,
+                    report.sources.len(),
+                    report.objects
+                );
+                if read_data {
+                    println!("Read {} bytes", report.bytes);
+                }
+            }
+            let result = VerifyResult::from(&report);
+            if !report.is_ok() {
+                let message = format!("Verify failed: {} problems found", report.problems.len());
+                fail_with(&out, &result, message);
+            }
+            if out.is_text() {
+                println!("No problems found");
+            }
+            out.result(&result);
+        }
+        Command::Daemon { socket, jobs } => {
+            let out = out.streaming();
+            let mut options = DaemonOptions::default();
+            if let Some(socket) = socket {
+                options.socket = socket;
+            }
+            if let Some(jobs) = jobs {
+                options.create.jobs = jobs;
+            }
+            if out.is_text() {
+                println!(
+                    "Running {} schedules, status on {}",
+                    config.schedules.len(),
+                    options.socket.display()
+                );
+            }
+            out.event(
+                "started",
+                &DaemonStarted {
+                    socket: options.socket.clone(),
+                    schedules: config.schedules.len(),
                 },
-                Err(e) => {
-                    eprintln!("error -> {:#?}", e);
-                },
+            );
+            let ran = Repository::open(config).and_then(|repo| {
+                daemon::run(&repo, &options, |path, record, state_error| {
+                    let state_error = state_error.map(|e| format!("{:#}", e));
+                    if out.is_text() {
+                        println!(
+                            "[{}] {}: {}",
+                            record.finished.format("%Y-%m-%d %H:%M:%S UTC"),
+                            path.display(),
+                            describe_run(record)
+                        );
+                        if let Some(e) = &state_error {
+                            eprintln!("{}", e);
+                        }
+                    }
+                    out.event(
+                        "run",
+                        &RunEvent {
+                            path,
+                            record,
+                            state_error,
+                        },
+                    );
+                })
+            });
+            if let Err(e) = ran {
+                fail(&out, format!("daemon failed: {:#}", e));
             }
         }
-        Command::Restore { backup_number , path} => {
-            println!("Restoring backup #{} to path: {:?}", backup_number, path);
-            match Backup::restore(backup_number, &path) {
-                Ok(_) => println!("Restore completed successfully"),
-                Err(e) => eprintln!("Restore failed: {}", e),
+        Command::Status { socket } => {
+            let socket = socket.unwrap_or_else(daemon::default_socket_path);
+            let status = daemon::status(&socket).unwrap_or_else(|e| fail(&out, format!("{:#}", e)));
+            if out.is_text() {
+                print_daemon_status(&status);
             }
+            out.result(&status);
         }
-        Command::List { path } => {
-            println!("Listing backups for: {:?}", path);
-            match Backup::list_backups(&path) {
-                Ok(_) => {},
-                Err(e) => eprintln!("Failed to list backups: {}", e),
-            }
-        }
-        Command::Config { action } => {
-            match action {
-                ConfigAction::Show => {
-                    let config = Config::load().unwrap_or_default();
-                    config.print_config();
-                }
-                ConfigAction::Init => {
-                    match Config::default().save() {
-                        Ok(_) => println!("Configuration initialized successfully"),
-                        Err(e) => eprintln!("Failed to initialize config: {}", e),
+        Command::Copy {
+            from,
+            to,
+            prefix,
+            snapshots,
+        } => {
+            let options = CopyOptions {
+                prefixes: prefix,
+                snapshots: snapshots.map(|list| list.0),
+            };
+            let source = match from {
+                Some(from) => config.clone().with_repository(Path::new(&from)),
+                None => config.clone(),
+            };
+            let destination = config.with_repository(Path::new(&to));
+            let report = Repository::open(source)
+                .and_then(|source| {
+                    let destination = Repository::open(destination)?;
+                    source.copy_to(&destination, &options)
+                })
+                .unwrap_or_else(|e| fail(&out, format!("Copy failed: {:#}", e)));
+            if out.is_text() {
+                print_copy_report(&report);
+            }
+            out.result(&CopyResult::new(&report, None));
+        }
+        Command::Export {
+            path,
+            number,
+            output,
+            format,
+        } => {
+            let to_stdout = output == "-";
+            // The archive may be on stdout, so the summary goes to stderr
+            let out = if to_stdout { out.to_stderr() } else { out };
+            let format = match format.or_else(|| ArchiveFormat::from_path(Path::new(&output))) {
+                Some(format) => format,
+                None if to_stdout => ArchiveFormat::Tar,
+                None => fail(
+                    &out,
+                    format!(
+                        "Cannot tell the archive format of {}; use --format tar, tar.gz or zip",
+                        output
+                    ),
+                ),
+            };
+            if to_stdout && format == ArchiveFormat::Zip {
+                fail(
+                    &out,
+                    "zip archives cannot be written to stdout; use -o <file>.zip or --format tar",
+                );
+            }
+
+            let report = Repository::open(config)
+                .and_then(|repo| {
+                    if to_stdout {
+                        let stdout = BufWriter::new(io::stdout().lock());
+                        return repo.export_tar(
+                            &path,
+                            number,
+                            format == ArchiveFormat::TarGz,
+                            stdout,
+                        );
+                    }
+                    let file = BufWriter::new(File::create(&output)?);
+                    let report = match format {
+                        ArchiveFormat::Zip => repo.export_zip(&path, number, file),
+                        _ => repo.export_tar(&path, number, format == ArchiveFormat::TarGz, file),
+                    };
+                    if report.is_err() {
+                        let _ = std::fs::remove_file(&output);
                     }
+                    report
+                })
+                .unwrap_or_else(|e| fail(&out, format!("Export failed: {:#}", e)));
+            if out.is_text() {
+                print_export_report(&report, &output);
+            }
+            let destination = (!to_stdout).then(|| PathBuf::from(&output));
+            out.result(&ExportResult::new(&report, destination));
+        }
+        Command::Import {
+            archive,
+            root,
+            time,
+            format,
+            strip_components,
+        } => {
+            if out.is_text() {
+                println!("Importing {} as {}", archive.display(), root.display());
+            }
+            let options = ImportOptions {
+                time,
+                format,
+                strip_components,
+            };
+            let report = Repository::open(config)
+                .and_then(|repo| repo.import_archive(&archive, &root, &options))
+                .unwrap_or_else(|e| fail(&out, format!("Import failed: {:#}", e)));
+            if out.is_text() {
+                print_create_report(&report);
+            }
+            let backup = Backup::new(&report, None);
+            for change in &backup.changes {
+                out.event(
+                    "change",
+                    &ChangeEvent {
+                        root: &backup.source.path,
+                        change,
+                    },
+                );
+            }
+            out.result(&backup);
+        }
+        Command::Serve { http } => {
+            // stdout may carry the protocol, so errors never go there
+            let out = out.to_stderr();
+            let served = Repository::open(config).and_then(|repo| match http {
+                Some(addr) => {
+                    let token = std::env::var("SNAPBACK_SERVE_TOKEN").ok();
+                    serve::serve_http(repo.storage(), &addr, token.as_deref(), || {
+                        if out.is_text() {
+                            eprintln!("Serving repository on http://{}", addr);
+                        }
+                    })
+                }
+                // stdout carries the protocol, so nothing else may be printed
+                None => serve::serve_stdio(repo.storage()),
+            });
+            if let Err(e) = served {
+                fail(&out, format!("serve failed: {:#}", e));
+            }
+        }
+        Command::Bundle { action } => {
+            let report = Repository::open(config)
+                .and_then(|repo| match &action {
+                    BundleAction::Create { path, output } => repo.create_bundle(path, output),
+                    BundleAction::Import { bundle } => repo.import_bundle(bundle),
+                })
+                .unwrap_or_else(|e| fail(&out, format!("Bundle failed: {:#}", e)));
+            let output = match action {
+                BundleAction::Create { output, .. } => Some(output),
+                BundleAction::Import { .. } => None,
+            };
+            if out.is_text() {
+                print_copy_report(&report);
+                if let Some(output) = &output {
+                    println!("Bundle written to {}", output.display());
                 }
-                ConfigAction::Path { backup_path, info_path } => {
-                    let mut config = Config::load().unwrap_or_default();
-                    
-                    if let Some(backup_path) = backup_path {
-                        config.backup_default_path = Some(backup_path);
+            }
+            out.result(&CopyResult::new(&report, output));
+        }
+        Command::ExportGit { path, dir, branch } => {
+            let options = GitExportOptions { branch };
+            let report = Repository::open(config)
+                .and_then(|repo| repo.export_git(&path, &dir, &options))
+                .unwrap_or_else(|e| fail(&out, format!("Git export failed: {:#}", e)));
+            if out.is_text() {
+                if report.exported.is_empty() {
+                    println!("{} is up to date", report.branch);
+                } else {
+                    let numbers: Vec<_> = report.exported.iter().map(u32::to_string).collect();
+                    println!(
+                        "Committed backups {} to {} in {}",
+                        numbers.join(", "),
+                        report.branch,
+                        dir.display()
+                    );
+                }
+                if let Some(head) = &report.head {
+                    println!("{} is at {}", report.branch, head);
+                }
+            }
+            out.result(&GitExportResult::new(&report, dir));
+        }
+        Command::DeltaBundle {
+            path,
+            from,
+            to,
+            output,
+        } => {
+            let report = Repository::open(config)
+                .and_then(|repo| repo.create_delta_bundle(&path, from, to, &output))
+                .unwrap_or_else(|e| fail(&out, format!("Delta bundle failed: {:#}", e)));
+            if out.is_text() {
+                print_delta_report(&report);
+                println!("Bundle written to {}", output.display());
+            }
+            delta_result(&out, &report, Some(output));
+        }
+        Command::ApplyBundle { bundle, tree } => {
+            let report = match tree {
+                Some(dir) => snapback::repository::apply_delta_to_tree(&bundle, &dir, &config),
+                None => Repository::open(config).and_then(|repo| repo.apply_delta_bundle(&bundle)),
+            }
+            .unwrap_or_else(|e| fail(&out, format!("Apply failed: {:#}", e)));
+            if out.is_text() {
+                print_delta_report(&report);
+            }
+            delta_result(&out, &report, None);
+        }
+        Command::Config { action } => match action {
+            ConfigAction::Show { origin: false } => {
+                if out.is_text() {
+                    print_config(&config, args.config.as_deref());
+                }
+                out.result(&config);
+            }
+            ConfigAction::Show { origin: true } => {
+                let mut flags = Vec::new();
+                if let Some(profile) = &args.profile {
+                    flags.push(format!("--profile {}", profile));
+                }
+                if let Some(repo) = &args.repo {
+                    flags.push(format!("--repo {}", repo.display()));
+                }
+                if !flags.is_empty() {
+                    if let Err(e) = layered.apply(config, Origin::CommandLine(flags.join(" "))) {
+                        fail(&out, e);
                     }
-                    if let Some(info_path) = info_path {
-                        config.backup_info_default_path = Some(info_path);
+                }
+                let mut entries = Vec::new();
+                for (key, value, origin) in layered.entries() {
+                    if out.is_text() {
+                        println!("{} = {}  ({})", key, value, origin);
                     }
-                    
-                    match config.save() {
-                        Ok(_) => println!("Configuration updated successfully"),
-                        Err(e) => eprintln!("Failed to update config: {}", e),
+                    entries.push(ConfigEntry {
+                        key,
+                        value: serde_json::from_str(&value).unwrap_or_default(),
+                        origin: origin.to_string(),
+                    });
+                }
+                out.result(&serde_json::json!({ "entries": entries }));
+            }
+            ConfigAction::Get { key } => match config.get(&key) {
+                Ok(Some(value)) => {
+                    if out.is_text() {
+                        print_value(&value);
                     }
+                    out.result(&serde_json::json!({ "key": key, "value": value }));
+                }
+                Ok(None) => fail(&out, format!("{} is not set", key)),
+                Err(e) => fail(&out, e),
+            },
+            ConfigAction::Init => {
+                let path = Config::get_user_config_path();
+                if let Err(e) = Config::default().save() {
+                    fail(&out, format!("Failed to initialize config: {:#}", e));
+                }
+                if out.is_text() {
+                    println!("Config saved to: {}", path.display());
+                    println!("Configuration initialized successfully");
+                }
+                out.result(&serde_json::json!({ "file": path }));
+            }
+            action => unreachable!("{:?} is handled before the configuration is loaded", action),
+        },
+    }
+}
+
+/// Reports an error in the chosen output format and exits with status 1.
+fn fail(out: &Output, message: impl std::fmt::Display) -> ! {
+    let message = message.to_string();
+    if out.is_text() {
+        eprintln!("{}", message);
+    }
+    out.error(&message);
+    std::process::exit(1)
+}
+
+/// Like [`fail`], for a command that still has a result to show.
+fn fail_with(out: &Output, result: &impl serde::Serialize, message: impl std::fmt::Display) -> ! {
+    let message = message.to_string();
+    if out.is_text() {
+        eprintln!("{}", message);
+    }
+    out.failed_result(result, &message);
+    std::process::exit(1)
+}
+
+/// Reads the configuration once for the whole run: an explicit `--config`
+/// file replaces the usual files, and `--set` overrides are applied last.
+fn load_config(config_file: Option<&Path>, overrides: &[String]) -> anyhow::Result<LayeredConfig> {
+    Config::load_layered(&LoadOptions {
+        config_file: config_file.map(Path::to_path_buf),
+        overrides: overrides.to_vec(),
+    })
+}
+
+/// Runs the config subcommands that do not need a valid configuration.
+fn config_file_action(
+    action: &ConfigAction,
+    args: &Args,
+    out: &Output,
+) -> Option<anyhow::Result<()>> {
+    Some(match action {
+        ConfigAction::Validate { file } => validate_config(file.as_deref(), args, out),
+        // The schema is JSON already, so text output prints it as it is
+        ConfigAction::Schema => {
+            let schema = Config::json_schema();
+            if out.is_text() {
+                println!(
+                    "{}",
+                    serde_json::to_string_pretty(&schema).expect("schemas serialize to JSON")
+                );
+            }
+            out.result(&schema);
+            Ok(())
+        }
+        ConfigAction::Set {
+            key,
+            values,
+            add,
+            project,
+        } => edit_config(args, *project, |file| {
+            let values: Vec<_> = values.iter().map(String::as_str).collect();
+            match (*add, &values[..]) {
+                (true, values) => file.add(key, values)?,
+                (false, [value]) => file.set(key, value)?,
+                (false, _) => {
+                    return Err(anyhow::anyhow!(
+                        "Give one value, or use --add to append several to a list"
+                    ))
                 }
             }
+            if out.is_text() {
+                println!("Set {} in {}", key, file.path().display());
+            }
+            Ok(())
+        })
+        .map(|file| out.result(&serde_json::json!({ "file": file, "key": key }))),
+        ConfigAction::Unset {
+            key,
+            values,
+            project,
+        } => {
+            let mut missing = Vec::new();
+            edit_config(args, *project, |file| {
+                missing = unset_config(args, out, file, key, values)?;
+                Ok(())
+            })
+            .map(|file| {
+                out.result(&serde_json::json!({ "file": file, "key": key, "missing": missing }))
+            })
         }
+        ConfigAction::Path {
+            backup_path,
+            info_path,
+        } => edit_config(args, false, |file| {
+            if let Some(backup_path) = backup_path {
+                file.set("backup_default_path", backup_path)?;
+            }
+            if let Some(info_path) = info_path {
+                file.set("backup_info_default_path", info_path)?;
+            }
+            if out.is_text() {
+                println!("Config saved to: {}", file.path().display());
+                println!("Configuration updated successfully");
+            }
+            Ok(())
+        })
+        .map(|file| out.result(&serde_json::json!({ "file": file }))),
+        ConfigAction::Show { .. } | ConfigAction::Get { .. } | ConfigAction::Init => return None,
+    })
+}
+
+/// Opens the file `config set` and `unset` change: the `--config` file, the
+/// project file with `--project`, or else the user file. It is written only
+/// if `edit` succeeds. Returns the path of the file.
+fn edit_config(
+    args: &Args,
+    project: bool,
+    edit: impl FnOnce(&mut ConfigFile) -> anyhow::Result<()>,
+) -> anyhow::Result<PathBuf> {
+    let path = match (&args.config, project) {
+        (Some(_), true) => {
+            return Err(anyhow::anyhow!(
+                "--project cannot be used together with --config"
+            ))
+        }
+        (Some(path), false) => path.clone(),
+        (None, true) => Config::get_local_config_path(),
+        (None, false) => Config::get_user_config_path(),
+    };
+    let mut file = ConfigFile::open(&path)?;
+    edit(&mut file)?;
+    file.save()?;
+    if file.drops_comments() {
+        eprintln!(
+            "Warning: {} was rewritten without its comments",
+            path.display()
+        );
     }
+    Ok(path)
+}
+
+/// Removes `key`, or only `values` from the list at `key`. An exclude
+/// pattern that comes from another layer cannot be removed from this file,
+/// so a `!pattern` entry is added to drop it instead. Returns the values
+/// that were in neither.
+fn unset_config(
+    args: &Args,
+    out: &Output,
+    file: &mut ConfigFile,
+    key: &str,
+    values: &[String],
+) -> anyhow::Result<Vec<String>> {
+    if values.is_empty() {
+        if !file.unset(key)? {
+            return Err(anyhow::anyhow!(
+                "{} is not set in {}",
+                key,
+                file.path().display()
+            ));
+        }
+        if out.is_text() {
+            println!("Removed {} from {}", key, file.path().display());
+        }
+        return Ok(Vec::new());
+    }
+
+    let values: Vec<_> = values.iter().map(String::as_str).collect();
+    let mut missing = file.remove(key, &values)?;
+    if key == "exclude_patterns" && !missing.is_empty() {
+        // The rest of the configuration may be what is broken
+        let inherited = load_config(args.config.as_deref(), &[])
+            .map(|layered| layered.config.exclude_patterns)
+            .unwrap_or_default();
+        let negated: Vec<_> = missing
+            .iter()
+            .filter(|pattern| inherited.iter().any(|existing| existing == *pattern))
+            .map(|pattern| format!("!{}", pattern))
+            .collect();
+        if !negated.is_empty() {
+            let negated: Vec<_> = negated.iter().map(String::as_str).collect();
+            file.add(key, &negated)?;
+            missing.retain(|pattern| !inherited.iter().any(|existing| existing == pattern));
+        }
+    }
+    if missing.len() == values.len() {
+        return Err(anyhow::anyhow!(
+            "{} is not in {} in {}",
+            missing.join(", "),
+            key,
+            file.path().display()
+        ));
+    }
+    if out.is_text() {
+        for value in &missing {
+            eprintln!("{} is not in {}", value, key);
+        }
+        println!("Updated {} in {}", key, file.path().display());
+    }
+    Ok(missing.into_iter().map(String::from).collect())
+}
+
+/// Prints a value for `config get`: strings as they are, list items one
+/// per line, anything else as JSON.
+fn print_value(value: &serde_json::Value) {
+    match value {
+        serde_json::Value::String(text) => println!("{}", text),
+        serde_json::Value::Array(items) => items.iter().for_each(print_value),
+        value => println!(
+            "{}",
+            serde_json::to_string_pretty(value).expect("values serialize to JSON")
+        ),
+    }
+}
+
+/// Checks `file` alone, or every layer and the merged result, including the
+/// schedule expressions.
+fn validate_config(file: Option<&Path>, args: &Args, out: &Output) -> anyhow::Result<()> {
+    if let Some(file) = file {
+        Config::validate_file(file)?;
+        if out.is_text() {
+            println!("✓ {} is valid", file.display());
+        }
+        out.result(&serde_json::json!({ "files": [file.display().to_string()] }));
+        return Ok(());
+    }
+
+    let layered = load_config(args.config.as_deref(), &args.set)?;
+    let files: Vec<_> = layered.files().iter().map(ToString::to_string).collect();
+    if out.is_text() {
+        for origin in &files {
+            println!("✓ {}", origin);
+        }
+    }
+    let config = &layered.config;
+    for schedule in &config.schedules {
+        daemon::Trigger::from_config(schedule)?;
+    }
+    select_config(config, args.profile.as_deref(), args.repo.as_deref())?;
+    if out.is_text() {
+        println!("Configuration is valid");
+    }
+    out.result(&serde_json::json!({ "files": files }));
+    Ok(())
+}
+
+/// Applies `--profile` and then `--repo`, which overrides the repository of
+/// the profile too.
+fn select_config(
+    base: &Config,
+    profile: Option<&str>,
+    repo: Option<&Path>,
+) -> anyhow::Result<Config> {
+    let config = match profile {
+        Some(name) => base.with_profile(name)?,
+        None => base.clone(),
+    };
+    Ok(match repo {
+        Some(dir) => config.with_repository(dir),
+        None => config,
+    })
+}
+
+/// Paths backed up by one `create` run, all with the same configuration.
+struct CreateTarget {
+    profile: Option<String>,
+    config: Config,
+    paths: Vec<PathBuf>,
+    keep_last: Option<u32>,
+}
+
+/// Works out what `create` backs up. Arguments naming a profile stand for
+/// its sources; anything else, including `./name`, is a path backed up with
+/// `config`. Without arguments the `--profile` is backed up.
+fn create_targets(
+    base: &Config,
+    config: &Config,
+    profile: Option<&str>,
+    repo: Option<&Path>,
+    targets: &[String],
+    all: bool,
+) -> anyhow::Result<Vec<CreateTarget>> {
+    let profile_target = |name: &str| -> anyhow::Result<CreateTarget> {
+        let config = select_config(base, Some(name), repo)?;
+        let profile = &base.profiles[name];
+        if profile.sources.is_empty() {
+            return Err(anyhow::anyhow!("Profile {} has no sources", name));
+        }
+        Ok(CreateTarget {
+            profile: Some(name.to_string()),
+            config,
+            paths: profile.sources.clone(),
+            keep_last: profile.keep_last,
+        })
+    };
+
+    if all {
+        if base.profiles.is_empty() {
+            return Err(anyhow::anyhow!("No profiles are configured"));
+        }
+        return base
+            .profiles
+            .keys()
+            .map(|name| profile_target(name))
+            .collect();
+    }
+    if targets.is_empty() {
+        return match profile {
+            Some(name) => Ok(vec![profile_target(name)?]),
+            None => Err(anyhow::anyhow!(
+                "Nothing to back up: give a path or profile name, --profile or --all"
+            )),
+        };
+    }
+
+    targets
+        .iter()
+        .map(|target| {
+            if base.profiles.contains_key(target) && !target.contains(['/', '\\']) {
+                if Path::new(target).exists() {
+                    return Err(anyhow::anyhow!(
+                        "{} is both a profile and a path; use --profile {} or ./{}",
+                        target,
+                        target,
+                        target
+                    ));
+                }
+                return profile_target(target);
+            }
+            if !Path::new(target).exists() {
+                return Err(anyhow::anyhow!("No such path or profile: {}", target));
+            }
+            Ok(CreateTarget {
+                profile: None,
+                config: config.clone(),
+                paths: vec![PathBuf::from(target)],
+                keep_last: profile.and_then(|name| base.profiles[name].keep_last),
+            })
+        })
+        .collect()
+}
+
+fn print_export_report(report: &ExportReport, output: &str) {
+    for failure in &report.not_backed_up {
+        eprintln!(
+            "Warning: {} was not backed up in backup #{}; its previous version was exported: {}",
+            failure.path, report.snapshot, failure.error
+        );
+    }
+    let destination = if output == "-" { "stdout" } else { output };
+    eprintln!(
+        "Exported backup #{} of {}: {} files ({} bytes) to {}",
+        report.snapshot,
+        report.source.path_to_root.display(),
+        report.files,
+        report.bytes,
+        destination
+    );
+}
+
+fn print_copy_report(report: &CopyReport) {
+    for copied in &report.sources {
+        let root = copied.source.path_to_root.display();
+        if copied.snapshots.is_empty() {
+            println!("{}: up to date", root);
+        } else {
+            let numbers: Vec<_> = copied.snapshots.iter().map(u32::to_string).collect();
+            println!("{}: copied snapshots {}", root, numbers.join(", "));
+        }
+    }
+    println!(
+        "Copied {} objects ({} bytes), {} already present",
+        report.objects_copied, report.bytes_copied, report.objects_present
+    );
+}
+
+#[derive(Debug, Clone)]
+struct SnapshotList(Vec<u32>);
+
+/// Parses `0,2-4` into `[0, 2, 3, 4]`.
+fn parse_snapshot_list(value: &str) -> Result<SnapshotList, String> {
+    let mut numbers = Vec::new();
+    for part in value.split(',').map(str::trim) {
+        let parse = |n: &str| {
+            n.trim()
+                .parse::<u32>()
+                .map_err(|_| format!("invalid snapshot number: {}", n))
+        };
+        match part.split_once('-') {
+            Some((start, end)) => numbers.extend(parse(start)?..=parse(end)?),
+            None => numbers.push(parse(part)?),
+        }
+    }
+    Ok(SnapshotList(numbers))
+}
+
+fn parse_archive_format(value: &str) -> Result<ArchiveFormat, String> {
+    match value.to_lowercase().as_str() {
+        "tar" => Ok(ArchiveFormat::Tar),
+        "tar.gz" | "tgz" => Ok(ArchiveFormat::TarGz),
+        "zip" => Ok(ArchiveFormat::Zip),
+        other => Err(format!(
+            "unknown archive format: {} (expected tar, tar.gz or zip)",
+            other
+        )),
+    }
+}
+
+/// Accepts RFC 3339 times, `YYYY-MM-DD HH:MM:SS` and `YYYY-MM-DD`; times
+/// without an offset are UTC.
+fn parse_time(value: &str) -> Result<DateTime<Utc>, String> {
+    if let Ok(time) = DateTime::parse_from_rfc3339(value) {
+        return Ok(time.with_timezone(&Utc));
+    }
+    for format in ["%Y-%m-%d %H:%M:%S", "%Y-%m-%dT%H:%M:%S"] {
+        if let Ok(time) = NaiveDateTime::parse_from_str(value, format) {
+            return Ok(time.and_utc());
+        }
+    }
+    NaiveDate::parse_from_str(value, "%Y-%m-%d")
+        .map(|date| date.and_time(Default::default()).and_utc())
+        .map_err(|_| {
+            format!(
+                "invalid time: {} (expected e.g. 2021-03-14 or 2021-03-14T09:30:00Z)",
+                value
+            )
+        })
+}
+
+fn print_create_report(report: &CreateReport) {
+    if report.removed_temp_files > 0 {
+        println!(
+            "Removed {} leftover temp files from an interrupted run",
+            report.removed_temp_files
+        );
+    }
+    print_changes(&report.changes);
+
+    let Some(snapshot) = &report.snapshot else {
+        println!("No changes detected. Skipping backup creation.");
+        return;
+    };
+    for entry in snapshot.entries.iter().filter(|e| e.changed_during_backup) {
+        println!("File changed during backup: {}", entry.path);
+    }
+    println!(
+        "Backup #{} created with {} changes",
+        snapshot.number,
+        snapshot.entries.len()
+    );
+    println!("backup written");
+}
+
+fn print_changes(changes: &[Change]) {
+    for change in changes {
+        let label = match change.kind {
+            ChangeKind::Added => "New file",
+            ChangeKind::Modified => "File changed",
+            ChangeKind::Reappeared => "File restored",
+            ChangeKind::Deleted => "File deleted",
+        };
+        println!("{}: {}", label, change.path);
+    }
+}
+
+fn print_delta_report(report: &DeltaReport) {
+    print_changes(&report.changes);
+    println!(
+        "{}: backup #{} to #{}, {} changes, {} objects ({} bytes)",
+        report.source.path_to_root.display(),
+        report.from,
+        report.to,
+        report.changes.len(),
+        report.objects,
+        report.bytes
+    );
+}
+
+fn print_snapshots(path: &Path, list: &ListResult) {
+    if list.snapshots.is_empty() {
+        println!("No backup files found for this project");
+        return;
+    }
+
+    println!("Available backups for: {}", path.display());
+    println!("Backup prefix: {}", list.source.prefix);
+    println!("─────────────────────────────────────────────");
+    for snapshot in &list.snapshots {
+        println!(
+            "Backup #{}: {} changes, {} deletions ({})",
+            snapshot.number,
+            snapshot.changes,
+            snapshot.deletions,
+            snapshot.timestamp.format("%Y-%m-%d %H:%M:%S UTC")
+        );
+        if !snapshot.changed_during_backup.is_empty() {
+            println!(
+                "  {} files changed during backup",
+                snapshot.changed_during_backup.len()
+            );
+        }
+        if !snapshot.failures.is_empty() {
+            println!(
+                "  partial: {} files could not be backed up",
+                snapshot.failures.len()
+            );
+        }
+    }
+    println!("\nUse: snapback restore <backup_number> <path>");
+}
+
+/// Emits the `change` events of one backup, then the `backup` event.
+fn backup_events(out: &Output, backup: &Backup) {
+    for change in &backup.changes {
+        let root = &backup.source.path;
+        out.event("change", &ChangeEvent { root, change });
+    }
+    out.event("backup", backup);
+}
+
+/// Records a failed path of `create`, reporting it right away.
+fn create_error(
+    out: &Output,
+    result: &mut CreateResult,
+    profile: Option<&str>,
+    path: Option<&Path>,
+    message: String,
+) {
+    if out.is_text() {
+        eprintln!("{}", message);
+    }
+    let error = BackupError {
+        profile: profile.map(String::from),
+        path: path.map(Path::to_path_buf),
+        message,
+    };
+    out.event("error", &error);
+    result.errors.push(error);
+}
+
+fn delta_result(out: &Output, report: &DeltaReport, output: Option<PathBuf>) {
+    let result = DeltaResult::new(report, output);
+    for change in &result.changes {
+        let root = &result.source.path;
+        out.event("change", &ChangeEvent { root, change });
+    }
+    out.result(&result);
+}
+
+/// Reports files a backup could not store, and how to accept them if the
+/// user has not opted in to partial backups.
+fn report_partial(report: &CreateReport, allow_partial: bool) {
+    let Some(snapshot) = report.snapshot.as_ref().filter(|s| s.is_partial()) else {
+        return;
+    };
+
+    eprintln!(
+        "warning: backup is partial, {} files could not be backed up:",
+        snapshot.failures.len()
+    );
+    for failure in &snapshot.failures {
+        eprintln!("  {}: {}", failure.path, failure.error);
+    }
+    if !allow_partial {
+        eprintln!("Pass --allow-partial to accept partial backups");
+    }
+}
+
+fn describe_run(record: &RunRecord) -> String {
+    let outcome = match (record.outcome, record.snapshot) {
+        (RunOutcome::Failed, _) => "failed".to_string(),
+        (RunOutcome::Partial, Some(number)) => format!("backup #{} (partial)", number),
+        (_, Some(number)) => format!("backup #{}", number),
+        (_, None) => "no changes".to_string(),
+    };
+    match &record.message {
+        Some(message) => format!("{}: {}", outcome, message),
+        None => outcome,
+    }
+}
+
+fn print_daemon_status(status: &DaemonStatus) {
+    println!(
+        "Daemon PID {} running since {}",
+        status.pid,
+        status.started.format("%Y-%m-%d %H:%M:%S UTC")
+    );
+    for schedule in &status.schedules {
+        println!("{} ({})", schedule.path.display(), schedule.schedule);
+        match &schedule.last_run {
+            Some(record) => println!(
+                "  last run: {}, {}",
+                record.started.format("%Y-%m-%d %H:%M:%S UTC"),
+                describe_run(record)
+            ),
+            None => println!("  last run: never"),
+        }
+        if schedule.running {
+            println!("  running now");
+        } else {
+            match schedule.next_run {
+                Some(next) => println!("  next run: {}", next.format("%Y-%m-%d %H:%M:%S UTC")),
+                None => println!("  next run: never"),
+            }
+        }
+    }
+}
+
+fn print_config(config: &Config, config_file: Option<&Path>) {
+    println!("SnapBack Configuration:");
+    println!("  Backup Path: {}", config.get_default_backup_path());
+    println!("  Info Path: {}", config.get_default_backup_info_path());
+    if let Some(s3) = &config.s3 {
+        println!(
+            "  S3 Storage: {}/{}/{}",
+            s3.endpoint.trim_end_matches('/'),
+            s3.bucket,
+            s3.prefix.as_deref().unwrap_or("")
+        );
+    }
+    println!("  Max Backups: {}", config.get_max_backup_count());
+    println!("  Exclude Patterns: {:?}", config.get_exclude_patterns());
+    for (name, profile) in &config.profiles {
+        let sources: Vec<_> = profile
+            .sources
+            .iter()
+            .map(|source| source.display().to_string())
+            .collect();
+        println!("  Profile {}: {}", name, sources.join(", "));
+    }
+    for schedule in &config.schedules {
+        let when = schedule
+            .every
+            .as_ref()
+            .map(|every| format!("every {}", every))
+            .or_else(|| schedule.cron.as_ref().map(|cron| format!("cron {}", cron)))
+            .unwrap_or_default();
+        println!("  Schedule: {} ({})", schedule.path.display(), when);
+    }
+    println!(
+        "  Config File: {}",
+        config_file
+            .map(Path::to_path_buf)
+            .unwrap_or_else(Config::get_user_config_path)
+            .display()
+    );
 }
 
 #[derive(Parser, Debug)]
 #[command(name = "backup")]
 #[command(version, about = "A backup tool", long_about = None)]
 struct Args {
+    /// Read configuration from this file instead of the default locations
+    #[arg(long, global = true)]
+    config: Option<PathBuf>,
+    /// Use this directory as the repository, overriding the configured paths
+    #[arg(long, global = true)]
+    repo: Option<PathBuf>,
+    /// Use the settings and repository of this profile
+    #[arg(long, global = true)]
+    profile: Option<String>,
+    /// Override a configuration value, e.g. --set max_backup_count=20 or
+    /// --set s3.bucket=backups (repeatable)
+    #[arg(long = "set", global = true, value_name = "KEY=VALUE")]
+    set: Vec<String>,
+    /// Print one JSON document with the result instead of text
+    #[arg(long, global = true, conflicts_with = "ndjson")]
+    json: bool,
+    /// Print a JSON line per event, such as each changed file, then the result
+    #[arg(long, global = true)]
+    ndjson: bool,
     #[command(subcommand)]
     command: Command,
 }
 
+impl Args {
+    fn output_format(&self) -> OutputFormat {
+        match (self.json, self.ndjson) {
+            (true, _) => OutputFormat::Json,
+            (_, true) => OutputFormat::Ndjson,
+            _ => OutputFormat::Text,
+        }
+    }
+}
+
 #[derive(Subcommand, Debug)]
 enum Command {
     /// Create a backup of the specified path
     Create {
-        /// Path to directory or file to backup
+        /// Paths to back up, or names of profiles whose sources to back up
+        targets: Vec<String>,
+        /// Back up the sources of every profile
+        #[arg(long, conflicts_with = "targets")]
+        all: bool,
+        /// Number of files to hash and store in parallel (defaults to CPU count)
+        #[arg(short, long)]
+        jobs: Option<usize>,
+        /// Exit successfully even if some files could not be backed up
+        #[arg(long)]
+        allow_partial: bool,
+    },
+    /// Watch a path and create a backup whenever changes settle
+    Watch {
+        /// Path to directory to watch
         path: PathBuf,
+        /// Seconds without changes before a backup is taken
+        #[arg(long, default_value_t = 2)]
+        debounce: u64,
+        /// Minimum seconds between two backups
+        #[arg(long, default_value_t = 60)]
+        min_interval: u64,
+        /// Number of files to hash and store in parallel (defaults to CPU count)
+        #[arg(short, long)]
+        jobs: Option<usize>,
     },
     /// Restore a backup by number
     Restore {
@@ -98,12 +1368,141 @@ enum Command {
         backup_number: u32,
         /// Path to directory or file to restore to
         path: PathBuf,
+        /// Restore into this directory instead of the original location
+        #[arg(long)]
+        target: Option<PathBuf>,
+        /// Show what would be restored without writing anything
+        #[arg(long)]
+        dry_run: bool,
     },
     /// List all available backups for a path
     List {
         /// Path to directory or file to list backups for
         path: PathBuf,
     },
+    /// Remove stale repository locks left by crashed processes
+    Unlock {
+        /// Remove every lock, including ones that may still be in use
+        #[arg(long)]
+        all: bool,
+    },
+    /// Keep only the latest backups of a path and free unreferenced content
+    Prune {
+        /// Path whose backups to prune
+        path: PathBuf,
+        /// Number of backups to keep (defaults to max_backup_count)
+        #[arg(long)]
+        keep: Option<u32>,
+    },
+    /// Check that backups are readable and their content is intact
+    Verify {
+        /// Only check the backups of this path
+        path: Option<PathBuf>,
+        /// Read every content object back and check its hash
+        #[arg(long)]
+        read_data: bool,
+    },
+    /// Run the configured schedules in the foreground
+    Daemon {
+        /// Socket to answer `snapback status` on
+        #[arg(long)]
+        socket: Option<PathBuf>,
+        /// Number of files to hash and store in parallel (defaults to CPU count)
+        #[arg(short, long)]
+        jobs: Option<usize>,
+    },
+    /// Show the schedules of a running daemon
+    Status {
+        /// Socket the daemon listens on
+        #[arg(long)]
+        socket: Option<PathBuf>,
+    },
+    /// Copy snapshots to another repository, transferring only missing content
+    Copy {
+        /// Repository to copy from (defaults to the configured one)
+        #[arg(long)]
+        from: Option<String>,
+        /// Repository to copy to: a directory, or an sftp://, ssh:// or http:// location
+        #[arg(long)]
+        to: String,
+        /// Only copy the backups with this prefix (repeatable)
+        #[arg(long)]
+        prefix: Vec<String>,
+        /// Copy snapshots up to the highest listed, e.g. `0-3,5`; earlier ones are always included
+        #[arg(long, value_parser = parse_snapshot_list)]
+        snapshots: Option<SnapshotList>,
+    },
+    /// Write a backup of a path to a tar, tar.gz or zip archive
+    Export {
+        /// Path to the backed-up directory or file
+        path: PathBuf,
+        /// Backup number to export
+        number: u32,
+        /// Archive to write, or `-` for stdout
+        #[arg(short, long)]
+        output: String,
+        /// Archive format: tar, tar.gz or zip (defaults to the output's extension, or tar on stdout)
+        #[arg(long, value_parser = parse_archive_format)]
+        format: Option<ArchiveFormat>,
+    },
+    /// Turn the backups of a path into a git repository, one commit per backup
+    ExportGit {
+        /// Path to the backed-up directory or file
+        path: PathBuf,
+        /// Git repository to commit to; created if it does not exist
+        dir: PathBuf,
+        /// Branch to commit the backups to
+        #[arg(long, default_value = "main")]
+        branch: String,
+    },
+    /// Record the files of a tar, tar.gz or zip archive as a new backup of a path
+    Import {
+        /// Archive to import
+        archive: PathBuf,
+        /// Path the archive's files are recorded under, as if it had been backed up
+        #[arg(long = "as", value_name = "PATH")]
+        root: PathBuf,
+        /// When the archive was taken, e.g. 2021-03-14 or 2021-03-14T09:30:00Z
+        #[arg(long, value_parser = parse_time)]
+        time: DateTime<Utc>,
+        /// Archive format: tar, tar.gz or zip (defaults to the archive's extension)
+        #[arg(long, value_parser = parse_archive_format)]
+        format: Option<ArchiveFormat>,
+        /// Drop this many leading directories from every archive path
+        #[arg(long, default_value_t = 0)]
+        strip_components: usize,
+    },
+    /// Serve the repository to remote clients over stdin/stdout, or HTTP
+    Serve {
+        /// Listen for HTTP requests on this address instead, e.g. 127.0.0.1:7878
+        #[arg(long)]
+        http: Option<String>,
+    },
+    /// Pack a path's full backup history into one file, or load such a file
+    Bundle {
+        #[command(subcommand)]
+        action: BundleAction,
+    },
+    /// Write the changes of a path between two backups to a portable file
+    DeltaBundle {
+        /// Path to the backed-up directory or file
+        path: PathBuf,
+        /// Backup the receiving side already has
+        from: u32,
+        /// Backup to bring it to
+        to: u32,
+        /// Bundle file to write
+        #[arg(short, long)]
+        output: PathBuf,
+    },
+    /// Apply a delta bundle to the repository, or to a working tree with --tree
+    ApplyBundle {
+        /// Delta bundle to apply
+        bundle: PathBuf,
+        /// Update this directory, which must be at the bundle's starting backup, instead
+        #[arg(long)]
+        tree: Option<PathBuf>,
+    },
     /// Configuration management
     Config {
         #[command(subcommand)]
@@ -111,10 +1510,107 @@ enum Command {
     },
 }
 
+impl Command {
+    /// The name reported as `command` in JSON output.
+    fn name(&self) -> &'static str {
+        match self {
+            Command::Create { .. } => "create",
+            Command::Watch { .. } => "watch",
+            Command::Restore { .. } => "restore",
+            Command::List { .. } => "list",
+            Command::Unlock { .. } => "unlock",
+            Command::Prune { .. } => "prune",
+            Command::Verify { .. } => "verify",
+            Command::Daemon { .. } => "daemon",
+            Command::Status { .. } => "status",
+            Command::Copy { .. } => "copy",
+            Command::Export { .. } => "export",
+            Command::ExportGit { .. } => "export-git",
+            Command::Import { .. } => "import",
+            Command::Serve { .. } => "serve",
+            Command::Bundle {
+                action: BundleAction::Create { .. },
+            } => "bundle create",
+            Command::Bundle {
+                action: BundleAction::Import { .. },
+            } => "bundle import",
+            Command::DeltaBundle { .. } => "delta-bundle",
+            Command::ApplyBundle { .. } => "apply-bundle",
+            Command::Config { action } => match action {
+                ConfigAction::Show { .. } => "config show",
+                ConfigAction::Validate { .. } => "config validate",
+                ConfigAction::Schema => "config schema",
+                ConfigAction::Get { .. } => "config get",
+                ConfigAction::Set { .. } => "config set",
+                ConfigAction::Unset { .. } => "config unset",
+                ConfigAction::Init => "config init",
+                ConfigAction::Path { .. } => "config path",
+            },
+        }
+    }
+}
+
+#[derive(Subcommand, Debug)]
+enum BundleAction {
+    /// Write every backup of a path, with the content it needs, to a bundle
+    Create {
+        /// Path to the backed-up directory or file
+        path: PathBuf,
+        /// Bundle file to write, e.g. project.snapbundle
+        #[arg(short, long)]
+        output: PathBuf,
+    },
+    /// Load a bundle into the repository, skipping content it already has
+    Import {
+        /// Bundle file to load
+        bundle: PathBuf,
+    },
+}
+
 #[derive(Subcommand, Debug)]
 enum ConfigAction {
     /// Show current configuration
-    Show,
+    Show {
+        /// List every value with the layer it came from
+        #[arg(long)]
+        origin: bool,
+    },
+    /// Check the configuration files for unknown keys and invalid values
+    Validate {
+        /// Check only this file, without the other layers
+        file: Option<PathBuf>,
+    },
+    /// Print the JSON Schema of the config file
+    Schema,
+    /// Print the value of a setting, such as max_backup_count or s3.bucket
+    Get {
+        /// Setting to print; nested settings are joined with dots
+        key: String,
+    },
+    /// Change a setting in the user config file
+    Set {
+        /// Setting to change; nested settings are joined with dots
+        key: String,
+        /// New value: JSON such as 20, true or ["a", "b"], or plain text
+        #[arg(required = true)]
+        values: Vec<String>,
+        /// Append the values to a list, such as exclude_patterns
+        #[arg(long)]
+        add: bool,
+        /// Change ./snapback.json instead of the user config file
+        #[arg(long)]
+        project: bool,
+    },
+    /// Remove a setting, or some entries of a list, from the user config file
+    Unset {
+        /// Setting to remove
+        key: String,
+        /// Remove only these entries of the list
+        values: Vec<String>,
+        /// Change ./snapback.json instead of the user config file
+        #[arg(long)]
+        project: bool,
+    },
     /// Initialize default configuration file
     Init,
     /// Set backup and info paths
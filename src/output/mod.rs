@@ -0,0 +1,528 @@
+//! Machine-readable output of the `snapback` binary (`--json` and
+//! `--ndjson`).
+//!
+//! With `--json` a command prints a single document when it is done:
+//!
+//! ```json
+//! {"schema_version": 1, "command": "list", "ok": true, "result": {...}}
+//! ```
+//!
+//! A failed command has `"ok": false` and `"error": {"message": "..."}`,
+//! and still carries its `result` if it got that far, e.g. a restore where
+//! some files failed. The exit status is the same as without `--json`.
+//!
+//! With `--ndjson` a command first prints one line per event, such as every
+//! changed or restored file, each with `schema_version` and a `type`, and
+//! ends with the document above on one line, with `"type": "result"`.
+//! Events are printed once the work they describe is done, e.g. a path's
+//! `change` events after its snapshot is written, not while it runs.
+//! `watch` and `daemon` never finish, so they only print events, in both
+//! modes.
+//!
+//! The types in this module are the `result` and event objects.
+//! [`SCHEMA_VERSION`] is raised whenever a field is removed or changes
+//! meaning; fields may be added without raising it.
+
+use std::{
+    io::{self, Write},
+    path::{Path, PathBuf},
+};
+
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use serde_json::{Map, Value};
+
+use crate::{
+    daemon::RunRecord,
+    lock::{LockInfo, UnlockReport},
+    BackupInfo, Change, CopyReport, CreateReport, DeltaReport, ExportReport, FailedFile,
+    GitExportReport, PruneReport, RestoreReport, Snapshot, SnapshotStatus, VerifyProblem,
+    VerifyReport,
+};
+
+pub const SCHEMA_VERSION: u32 = 1;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum OutputFormat {
+    /// Human-readable text.
+    #[default]
+    Text,
+    Json,
+    Ndjson,
+}
+
+/// Prints the events and the result of one command in the chosen format.
+/// In [`OutputFormat::Text`] nothing is printed; the caller writes text.
+#[derive(Debug, Clone)]
+pub struct Output {
+    format: OutputFormat,
+    command: &'static str,
+    stderr: bool,
+}
+
+impl Output {
+    pub fn new(format: OutputFormat, command: &'static str) -> Self {
+        Self {
+            format,
+            command,
+            stderr: false,
+        }
+    }
+
+    /// Prints events even with `--json`, for commands that never finish.
+    pub fn streaming(mut self) -> Self {
+        if self.format == OutputFormat::Json {
+            self.format = OutputFormat::Ndjson;
+        }
+        self
+    }
+
+    /// Writes to stderr, for commands whose stdout carries data.
+    pub fn to_stderr(mut self) -> Self {
+        self.stderr = true;
+        self
+    }
+
+    pub fn is_text(&self) -> bool {
+        self.format == OutputFormat::Text
+    }
+
+    /// Prints an event of type `kind`, whose fields are those of `event`,
+    /// with `--ndjson`.
+    pub fn event(&self, kind: &str, event: &impl Serialize) {
+        if self.format != OutputFormat::Ndjson {
+            return;
+        }
+        let mut line = Map::new();
+        line.insert("schema_version".to_string(), SCHEMA_VERSION.into());
+        line.insert("type".to_string(), kind.into());
+        match serde_json::to_value(event) {
+            Ok(Value::Object(fields)) => line.extend(fields),
+            Ok(value) => {
+                line.insert("value".to_string(), value);
+            }
+            Err(e) => {
+                line.insert("error".to_string(), e.to_string().into());
+            }
+        }
+        self.print(&Value::Object(line));
+    }
+
+    /// Prints the result of a successful command.
+    pub fn result(&self, result: &impl Serialize) {
+        self.document(true, Some(result), None);
+    }
+
+    /// Prints the result of a command that did its work but still failed,
+    /// such as a partial backup.
+    pub fn failed_result(&self, result: &impl Serialize, message: &str) {
+        self.document(false, Some(result), Some(message));
+    }
+
+    /// Prints a command that failed without a result.
+    pub fn error(&self, message: &str) {
+        self.document(false, None::<&()>, Some(message));
+    }
+
+    fn document(&self, ok: bool, result: Option<&impl Serialize>, message: Option<&str>) {
+        if self.format == OutputFormat::Text {
+            return;
+        }
+        let mut document = Map::new();
+        document.insert("schema_version".to_string(), SCHEMA_VERSION.into());
+        if self.format == OutputFormat::Ndjson {
+            document.insert("type".to_string(), "result".into());
+        }
+        document.insert("command".to_string(), self.command.into());
+        document.insert("ok".to_string(), ok.into());
+        if let Some(result) = result {
+            let result = serde_json::to_value(result).unwrap_or_else(|e| e.to_string().into());
+            document.insert("result".to_string(), result);
+        }
+        if let Some(message) = message {
+            document.insert(
+                "error".to_string(),
+                serde_json::json!({ "message": message }),
+            );
+        }
+        self.print(&Value::Object(document));
+    }
+
+    fn print(&self, value: &Value) {
+        let text = match self.format {
+            OutputFormat::Json => serde_json::to_string_pretty(value),
+            _ => serde_json::to_string(value),
+        }
+        .expect("JSON values serialize");
+        // A closed pipe is not worth a panic; the reader is gone
+        let _ = if self.stderr {
+            writeln!(io::stderr(), "{}", text)
+        } else {
+            writeln!(io::stdout(), "{}", text)
+        };
+    }
+}
+
+/// A backed-up root.
+#[derive(Debug, Clone, Serialize)]
+pub struct Source {
+    pub path: PathBuf,
+    pub prefix: String,
+}
+
+impl From<&BackupInfo> for Source {
+    fn from(info: &BackupInfo) -> Self {
+        Self {
+            path: info.path_to_root.clone(),
+            prefix: info.backup_prefix.clone(),
+        }
+    }
+}
+
+/// One snapshot as `list` shows it.
+#[derive(Debug, Clone, Serialize)]
+pub struct SnapshotSummary {
+    pub number: u32,
+    pub timestamp: DateTime<Utc>,
+    pub status: SnapshotStatus,
+    /// Files added or changed.
+    pub changes: usize,
+    pub deletions: usize,
+    /// Files that kept changing while they were read.
+    pub changed_during_backup: Vec<String>,
+    /// Files that could not be backed up.
+    pub failures: Vec<FailedFile>,
+}
+
+impl From<&Snapshot> for SnapshotSummary {
+    fn from(snapshot: &Snapshot) -> Self {
+        Self {
+            number: snapshot.number,
+            timestamp: snapshot.timestamp,
+            status: snapshot.status,
+            changes: snapshot.changes(),
+            deletions: snapshot.deletions(),
+            changed_during_backup: snapshot
+                .entries
+                .iter()
+                .filter(|entry| entry.changed_during_backup)
+                .map(|entry| entry.path.clone())
+                .collect(),
+            failures: snapshot.failures.clone(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum BackupStatus {
+    Complete,
+    Partial,
+    /// Nothing had changed, so no snapshot was written.
+    Unchanged,
+}
+
+/// One backed-up path of `create` or `watch`, or an `import`. Also the
+/// `backup` event.
+#[derive(Debug, Clone, Serialize)]
+pub struct Backup {
+    /// Profile the path was backed up for, if any.
+    pub profile: Option<String>,
+    #[serde(flatten)]
+    pub source: Source,
+    pub status: BackupStatus,
+    pub snapshot: Option<SnapshotSummary>,
+    pub changes: Vec<Change>,
+    /// Temp files of an interrupted run that were cleaned up first.
+    pub removed_temp_files: usize,
+    /// Snapshots deleted afterwards because of the profile's `keep_last`.
+    pub pruned: Vec<u32>,
+}
+
+impl Backup {
+    pub fn new(report: &CreateReport, profile: Option<&str>) -> Self {
+        let status = match &report.snapshot {
+            None => BackupStatus::Unchanged,
+            Some(snapshot) if snapshot.is_partial() => BackupStatus::Partial,
+            Some(_) => BackupStatus::Complete,
+        };
+        Self {
+            profile: profile.map(str::to_string),
+            source: Source::from(&report.source),
+            status,
+            snapshot: report.snapshot.as_ref().map(SnapshotSummary::from),
+            changes: report.changes.clone(),
+            removed_temp_files: report.removed_temp_files,
+            pruned: Vec::new(),
+        }
+    }
+}
+
+/// A path `create` could not back up. Also the `error` event.
+#[derive(Debug, Clone, Serialize)]
+pub struct BackupError {
+    pub profile: Option<String>,
+    pub path: Option<PathBuf>,
+    pub message: String,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct CreateResult {
+    pub backups: Vec<Backup>,
+    pub errors: Vec<BackupError>,
+}
+
+/// A `change` event: one file of a backup or delta bundle.
+#[derive(Debug, Clone, Serialize)]
+pub struct ChangeEvent<'a> {
+    /// The backed-up root the file belongs to.
+    pub root: &'a Path,
+    #[serde(flatten)]
+    pub change: &'a Change,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct RestoreResult {
+    pub path: PathBuf,
+    pub snapshot: u32,
+    /// Directory restored into instead of the original location.
+    pub target: Option<PathBuf>,
+    pub dry_run: bool,
+    /// Files written, or that would be written by a dry run. Also the
+    /// `restored` event, with a `path` field.
+    pub restored: Vec<PathBuf>,
+    /// Files that could not be restored. Also the `restore_failed` event.
+    pub failed: Vec<FailedFile>,
+    /// Files not backed up as of the snapshot; their previous version was
+    /// restored.
+    pub not_backed_up: Vec<FailedFile>,
+}
+
+impl RestoreResult {
+    pub fn new(
+        report: &RestoreReport,
+        path: PathBuf,
+        target: Option<PathBuf>,
+        dry_run: bool,
+    ) -> Self {
+        Self {
+            path,
+            snapshot: report.snapshot,
+            target,
+            dry_run,
+            restored: report.restored.clone(),
+            failed: report.failed.clone(),
+            not_backed_up: report.not_backed_up.clone(),
+        }
+    }
+}
+
+/// The snapshots of one root. Every one is also a `snapshot` event.
+#[derive(Debug, Clone, Serialize)]
+pub struct ListResult {
+    #[serde(flatten)]
+    pub source: Source,
+    pub snapshots: Vec<SnapshotSummary>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct PruneResult {
+    #[serde(flatten)]
+    pub source: Source,
+    pub removed: Vec<u32>,
+    pub kept: usize,
+    pub objects_removed: usize,
+}
+
+impl From<&PruneReport> for PruneResult {
+    fn from(report: &PruneReport) -> Self {
+        Self {
+            source: Source::from(&report.source),
+            removed: report.removed.clone(),
+            kept: report.kept,
+            objects_removed: report.objects_removed,
+        }
+    }
+}
+
+/// Every problem is also a `problem` event.
+#[derive(Debug, Clone, Serialize)]
+pub struct VerifyResult {
+    pub sources: Vec<Source>,
+    pub snapshots: usize,
+    pub objects: usize,
+    /// Bytes read back with `--read-data`.
+    pub bytes: u64,
+    pub problems: Vec<VerifyProblem>,
+}
+
+impl From<&VerifyReport> for VerifyResult {
+    fn from(report: &VerifyReport) -> Self {
+        Self {
+            sources: report.sources.iter().map(Source::from).collect(),
+            snapshots: report.snapshots,
+            objects: report.objects,
+            bytes: report.bytes,
+            problems: report.problems.clone(),
+        }
+    }
+}
+
+/// Result of `copy`, `bundle create` and `bundle import`.
+#[derive(Debug, Clone, Serialize)]
+pub struct CopyResult {
+    pub sources: Vec<CopiedSource>,
+    pub objects_copied: usize,
+    pub bytes_copied: u64,
+    pub objects_present: usize,
+    /// Bundle written by `bundle create`.
+    pub output: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct CopiedSource {
+    #[serde(flatten)]
+    pub source: Source,
+    /// Snapshots written by this run.
+    pub snapshots: Vec<u32>,
+}
+
+impl CopyResult {
+    pub fn new(report: &CopyReport, output: Option<PathBuf>) -> Self {
+        Self {
+            sources: report
+                .sources
+                .iter()
+                .map(|copied| CopiedSource {
+                    source: Source::from(&copied.source),
+                    snapshots: copied.snapshots.clone(),
+                })
+                .collect(),
+            objects_copied: report.objects_copied,
+            bytes_copied: report.bytes_copied,
+            objects_present: report.objects_present,
+            output,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ExportResult {
+    #[serde(flatten)]
+    pub source: Source,
+    pub snapshot: u32,
+    pub files: usize,
+    pub bytes: u64,
+    pub not_backed_up: Vec<FailedFile>,
+    /// Archive written, or `None` for stdout.
+    pub output: Option<PathBuf>,
+}
+
+impl ExportResult {
+    pub fn new(report: &ExportReport, output: Option<PathBuf>) -> Self {
+        Self {
+            source: Source::from(&report.source),
+            snapshot: report.snapshot,
+            files: report.files,
+            bytes: report.bytes,
+            not_backed_up: report.not_backed_up.clone(),
+            output,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct GitExportResult {
+    #[serde(flatten)]
+    pub source: Source,
+    /// The git repository committed to.
+    pub repository: PathBuf,
+    pub branch: String,
+    pub exported: Vec<u32>,
+    pub head: Option<String>,
+}
+
+impl GitExportResult {
+    pub fn new(report: &GitExportReport, repository: PathBuf) -> Self {
+        Self {
+            source: Source::from(&report.source),
+            repository,
+            branch: report.branch.clone(),
+            exported: report.exported.clone(),
+            head: report.head.clone(),
+        }
+    }
+}
+
+/// Result of `delta-bundle` and `apply-bundle`. Every change is also a
+/// `change` event.
+#[derive(Debug, Clone, Serialize)]
+pub struct DeltaResult {
+    #[serde(flatten)]
+    pub source: Source,
+    pub from: u32,
+    pub to: u32,
+    pub objects: usize,
+    pub bytes: u64,
+    pub changes: Vec<Change>,
+    /// Bundle written by `delta-bundle`.
+    pub output: Option<PathBuf>,
+}
+
+impl DeltaResult {
+    pub fn new(report: &DeltaReport, output: Option<PathBuf>) -> Self {
+        Self {
+            source: Source::from(&report.source),
+            from: report.from,
+            to: report.to,
+            objects: report.objects,
+            bytes: report.bytes,
+            changes: report.changes.clone(),
+            output,
+        }
+    }
+}
+
+/// A `run` event of `daemon`.
+#[derive(Debug, Clone, Serialize)]
+pub struct RunEvent<'a> {
+    pub path: &'a Path,
+    #[serde(flatten)]
+    pub record: &'a RunRecord,
+    /// Why the run could not be saved to the daemon's state file, so it
+    /// will not count as the path's last run after a restart.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub state_error: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct UnlockResult {
+    /// Locks removed. Every one is also a `lock` event.
+    pub removed: Vec<LockInfo>,
+    /// Lock objects that could not be read, removed with `--all`.
+    pub unreadable: Vec<String>,
+}
+
+impl From<UnlockReport> for UnlockResult {
+    fn from(report: UnlockReport) -> Self {
+        Self {
+            removed: report.removed,
+            unreadable: report.unreadable,
+        }
+    }
+}
+
+/// The `started` event of `daemon`.
+#[derive(Debug, Clone, Serialize)]
+pub struct DaemonStarted {
+    pub socket: PathBuf,
+    pub schedules: usize,
+}
+
+/// A setting as `config show --origin` lists it.
+#[derive(Debug, Clone, Serialize)]
+pub struct ConfigEntry {
+    pub key: String,
+    pub value: Value,
+    pub origin: String,
+}
@@ -0,0 +1,552 @@
+use std::{
+    io::{Seek, Write},
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+
+use crate::{
+    backup::{self, bundle, copy, delta, export, git, import, pipeline, prune, verify, Backup},
+    config::Config,
+    hooks::{self, HookEnv, Operation},
+    lock::{self, RepositoryLock, UnlockReport},
+    snapshot::{BackupInfo, FailedFile, Snapshot},
+    storage::{
+        self, LocalStorage, RemoteStorage, S3Credentials, S3Storage, SftpStorage, Storage,
+        SFTP_SCHEME,
+    },
+};
+
+/// Handle to a snapback repository: snapshot manifests, content objects and
+/// one record per backed-up root, kept in a [`Storage`] backend.
+///
+/// Every operation takes the repository lock it needs for its duration and
+/// returns structured results; nothing is printed.
+#[derive(Debug, Clone)]
+pub struct Repository {
+    config: Config,
+    storage: Arc<dyn Storage>,
+}
+
+#[derive(Debug, Clone)]
+pub struct CreateOptions {
+    /// Number of files hashed and stored in parallel.
+    pub jobs: usize,
+    /// Only re-examine these files and directories, e.g. the ones a file
+    /// watcher reported; everything else is taken to be unchanged. `None`
+    /// scans the whole root. Ignored for the first snapshot of a root.
+    pub changed_paths: Option<Vec<PathBuf>>,
+}
+
+impl Default for CreateOptions {
+    fn default() -> Self {
+        Self {
+            jobs: pipeline::default_jobs(),
+            changed_paths: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct RestoreOptions {
+    /// Restore into this directory instead of the original location. Paths
+    /// are taken relative to the backed-up root.
+    pub target: Option<PathBuf>,
+    /// Work out what would be restored without writing anything.
+    pub dry_run: bool,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct CreateReport {
+    pub source: BackupInfo,
+    /// The written snapshot, or `None` when nothing changed.
+    pub snapshot: Option<Snapshot>,
+    pub changes: Vec<Change>,
+    /// Temp files from an interrupted run that were cleaned up first.
+    pub removed_temp_files: usize,
+}
+
+impl CreateReport {
+    pub fn is_partial(&self) -> bool {
+        self.snapshot.as_ref().is_some_and(Snapshot::is_partial)
+    }
+}
+
+#[derive(Debug, Clone, Serialize, PartialEq, Eq)]
+pub struct Change {
+    pub path: String,
+    pub kind: ChangeKind,
+}
+
+impl Change {
+    pub(crate) fn new(path: &str, kind: ChangeKind) -> Self {
+        Self {
+            path: path.to_string(),
+            kind,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ChangeKind {
+    Added,
+    Modified,
+    /// The file was recorded as deleted earlier and is back.
+    Reappeared,
+    Deleted,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct CopyOptions {
+    /// Only copy the roots with these backup prefixes. Empty copies all.
+    pub prefixes: Vec<String>,
+    /// Only copy up to the highest of these snapshot numbers. Earlier
+    /// snapshots are always included, since later ones build on them.
+    pub snapshots: Option<Vec<u32>>,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct CopyReport {
+    pub sources: Vec<CopiedSource>,
+    pub objects_copied: usize,
+    pub bytes_copied: u64,
+    /// Content objects the destination already had.
+    pub objects_present: usize,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct CopiedSource {
+    pub source: BackupInfo,
+    /// Snapshots written to the destination by this run.
+    pub snapshots: Vec<u32>,
+}
+
+/// Archive formats `export` writes and `import` reads.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ArchiveFormat {
+    Tar,
+    TarGz,
+    Zip,
+}
+
+impl ArchiveFormat {
+    /// Guesses the format from a file name: `.tar`, `.tar.gz` or `.tgz`,
+    /// and `.zip`.
+    pub fn from_path(path: &Path) -> Option<Self> {
+        let name = path.file_name()?.to_string_lossy().to_lowercase();
+        if name.ends_with(".tar.gz") || name.ends_with(".tgz") {
+            Some(Self::TarGz)
+        } else if name.ends_with(".tar") {
+            Some(Self::Tar)
+        } else if name.ends_with(".zip") {
+            Some(Self::Zip)
+        } else {
+            None
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ImportOptions {
+    /// When the archive was taken. Becomes the time of the new snapshot and
+    /// must not be earlier than the root's latest snapshot.
+    pub time: DateTime<Utc>,
+    /// Archive format; guessed from the archive's file name when `None`.
+    pub format: Option<ArchiveFormat>,
+    /// Leading path components to drop from every archive entry, like
+    /// `tar --strip-components`.
+    pub strip_components: usize,
+}
+
+impl ImportOptions {
+    pub fn new(time: DateTime<Utc>) -> Self {
+        Self {
+            time,
+            format: None,
+            strip_components: 0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ExportReport {
+    pub source: BackupInfo,
+    pub snapshot: u32,
+    /// Number of files written to the archive.
+    pub files: usize,
+    /// Their total size before compression.
+    pub bytes: u64,
+    /// Files that failed to back up at or before the snapshot and were not
+    /// backed up since. Their previous version, if any, was exported instead.
+    pub not_backed_up: Vec<FailedFile>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct DeltaReport {
+    pub source: BackupInfo,
+    pub from: u32,
+    pub to: u32,
+    /// Content objects written to the bundle, the repository or the tree.
+    pub objects: usize,
+    pub bytes: u64,
+    /// How the files of the root differ between `from` and `to`.
+    pub changes: Vec<Change>,
+}
+
+#[derive(Debug, Clone)]
+pub struct GitExportOptions {
+    /// Branch the snapshots are committed to. A new repository checks it
+    /// out.
+    pub branch: String,
+}
+
+impl Default for GitExportOptions {
+    fn default() -> Self {
+        Self {
+            branch: "main".to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct GitExportReport {
+    pub source: BackupInfo,
+    pub branch: String,
+    /// Snapshots committed by this run; earlier ones were already there.
+    pub exported: Vec<u32>,
+    /// Commit at the tip of the branch, if it has any.
+    pub head: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct PruneReport {
+    pub source: BackupInfo,
+    /// Snapshots deleted by this run.
+    pub removed: Vec<u32>,
+    /// Snapshots left. The oldest of them now holds the complete state.
+    pub kept: usize,
+    /// Content objects deleted because no snapshot referred to them.
+    pub objects_removed: usize,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct VerifyOptions {
+    /// Read every content object back and check its hash, not only that
+    /// it exists.
+    pub read_data: bool,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct VerifyReport {
+    pub sources: Vec<BackupInfo>,
+    /// Manifests that were read successfully.
+    pub snapshots: usize,
+    /// Distinct content objects found in the repository.
+    pub objects: usize,
+    /// Bytes read back, when data was read.
+    pub bytes: u64,
+    pub problems: Vec<VerifyProblem>,
+}
+
+impl VerifyReport {
+    pub fn is_ok(&self) -> bool {
+        self.problems.is_empty()
+    }
+}
+
+#[derive(Debug, Clone, Serialize, PartialEq, Eq)]
+pub struct VerifyProblem {
+    /// Manifest or content object the problem was found in.
+    pub key: String,
+    pub error: String,
+}
+
+impl VerifyProblem {
+    pub(crate) fn new(key: &str, error: impl std::fmt::Display) -> Self {
+        Self {
+            key: key.to_string(),
+            error: format!("{:#}", error),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct RestoreReport {
+    pub snapshot: u32,
+    pub restored: Vec<PathBuf>,
+    /// Files that are part of the snapshot but could not be recovered.
+    pub failed: Vec<FailedFile>,
+    /// Files that failed to back up at or before the snapshot and were not
+    /// backed up since. Their previous version, if any, was restored instead.
+    pub not_backed_up: Vec<FailedFile>,
+}
+
+impl Repository {
+    /// Opens the repository described by `config`. Its paths are resolved
+    /// once here; operations never read configuration on their own.
+    pub fn open(config: Config) -> anyhow::Result<Self> {
+        let backup_path = config.get_default_backup_path();
+        let storage: Arc<dyn Storage> = match &config.s3 {
+            Some(s3) => Arc::new(S3Storage::new(s3, S3Credentials::from_env()?)?),
+            None if storage::is_remote(&backup_path) => {
+                Arc::new(RemoteStorage::connect(&backup_path)?)
+            }
+            None if backup_path.starts_with(SFTP_SCHEME) => Arc::new(SftpStorage::connect(
+                &backup_path,
+                config.ssh_key_file.as_deref().map(Path::new),
+            )?),
+            None => Arc::new(LocalStorage::new(
+                backup_path,
+                config.get_default_backup_info_path(),
+            )),
+        };
+        Ok(Self::with_storage(config, storage))
+    }
+
+    /// Uses `storage` instead of the directories named in `config`. The
+    /// rest of `config`, such as exclude patterns, still applies.
+    pub fn with_storage(config: Config, storage: Arc<dyn Storage>) -> Self {
+        Self { config, storage }
+    }
+
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    pub fn storage(&self) -> &dyn Storage {
+        self.storage.as_ref()
+    }
+
+    /// Scans `root` and records a new snapshot of everything that changed
+    /// since the previous one. The configured create hooks run around it.
+    pub fn create_snapshot(
+        &self,
+        root: &Path,
+        options: &CreateOptions,
+    ) -> anyhow::Result<CreateReport> {
+        let mut env = HookEnv::new(Operation::Create, root);
+        if let Ok(source) = self.source(root) {
+            env.set("PREFIX", source.backup_prefix);
+        }
+        hooks::around(
+            &self.config.hooks,
+            Operation::Create,
+            env,
+            || {
+                let _lock = RepositoryLock::exclusive(&self.storage)?;
+                Backup::new(self, root.to_path_buf(), options)?.write_backup()
+            },
+            |report, env| {
+                env.set("PREFIX", &report.source.backup_prefix);
+                let status = match &report.snapshot {
+                    Some(snapshot) => {
+                        env.set("SNAPSHOT", snapshot.number);
+                        env.set("CHANGES", snapshot.changes());
+                        env.set("DELETIONS", snapshot.deletions());
+                        env.set("FAILURES", snapshot.failures.len());
+                        if snapshot.is_partial() {
+                            "partial"
+                        } else {
+                            "complete"
+                        }
+                    }
+                    None => "unchanged",
+                };
+                env.set("STATUS", status);
+            },
+        )
+    }
+
+    /// Lists the snapshots of `root`, oldest first.
+    pub fn list_snapshots(&self, root: &Path) -> anyhow::Result<Vec<Snapshot>> {
+        let _lock = RepositoryLock::shared(&self.storage)?;
+        backup::list_backups(self, root)
+    }
+
+    /// Returns the record of the backed-up root `root`.
+    pub fn source(&self, root: &Path) -> anyhow::Result<BackupInfo> {
+        backup::find_backup_info(self, root)
+    }
+
+    /// Restores `root` to its state at snapshot `number`. The configured
+    /// restore hooks run around it, except for dry runs.
+    pub fn restore(
+        &self,
+        root: &Path,
+        number: u32,
+        options: &RestoreOptions,
+    ) -> anyhow::Result<RestoreReport> {
+        let run = || {
+            let _lock = RepositoryLock::shared(&self.storage)?;
+            backup::restore(self, number, root, options)
+        };
+        if options.dry_run {
+            return run();
+        }
+
+        let mut env = HookEnv::new(Operation::Restore, root);
+        env.set("SNAPSHOT", number);
+        if let Ok(source) = self.source(root) {
+            env.set("PREFIX", source.backup_prefix);
+        }
+        if let Some(target) = &options.target {
+            env.set("TARGET", target.display());
+        }
+        hooks::around(
+            &self.config.hooks,
+            Operation::Restore,
+            env,
+            run,
+            |report, env| {
+                env.set("RESTORED", report.restored.len());
+                env.set("FAILURES", report.failed.len());
+                let status = if report.failed.is_empty() {
+                    "complete"
+                } else {
+                    "partial"
+                };
+                env.set("STATUS", status);
+            },
+        )
+    }
+
+    /// Records the files of `archive` as a new snapshot of `root`, taken at
+    /// `options.time`. Files missing from the archive are recorded as
+    /// deleted, just as if `root` had been backed up in that state.
+    pub fn import_archive(
+        &self,
+        archive: &Path,
+        root: &Path,
+        options: &ImportOptions,
+    ) -> anyhow::Result<CreateReport> {
+        let _lock = RepositoryLock::exclusive(&self.storage)?;
+        import::import(self, archive, root, options)
+    }
+
+    /// Writes `root` as of snapshot `number` to `out` as a tar archive,
+    /// gzip-compressed if `gzip` is set. Files are streamed from the
+    /// repository; nothing is restored to disk.
+    pub fn export_tar(
+        &self,
+        root: &Path,
+        number: u32,
+        gzip: bool,
+        out: impl Write,
+    ) -> anyhow::Result<ExportReport> {
+        let _lock = RepositoryLock::shared(&self.storage)?;
+        export::export_tar(self, root, number, gzip, out)
+    }
+
+    /// Like [`Repository::export_tar`], but writes a zip archive, which
+    /// needs a seekable output.
+    pub fn export_zip(
+        &self,
+        root: &Path,
+        number: u32,
+        out: impl Write + Seek,
+    ) -> anyhow::Result<ExportReport> {
+        let _lock = RepositoryLock::shared(&self.storage)?;
+        export::export_zip(self, root, number, out)
+    }
+
+    /// Copies snapshots, info records and missing content objects into
+    /// `destination`. Snapshots the destination already has are skipped.
+    pub fn copy_to(
+        &self,
+        destination: &Repository,
+        options: &CopyOptions,
+    ) -> anyhow::Result<CopyReport> {
+        let _source_lock = RepositoryLock::shared(&self.storage)?;
+        let _destination_lock = RepositoryLock::exclusive(&destination.storage)?;
+        copy::copy(self, destination, options)
+    }
+
+    /// Commits every snapshot of `root` to a git repository in `dir`,
+    /// creating it if needed. Snapshots already exported to the branch are
+    /// skipped.
+    pub fn export_git(
+        &self,
+        root: &Path,
+        dir: &Path,
+        options: &GitExportOptions,
+    ) -> anyhow::Result<GitExportReport> {
+        let _lock = RepositoryLock::shared(&self.storage)?;
+        git::export(self, root, dir, options)
+    }
+
+    /// Writes the full history of `root` to a single bundle file at
+    /// `output`: its record, every snapshot and all content they reference.
+    pub fn create_bundle(&self, root: &Path, output: &Path) -> anyhow::Result<CopyReport> {
+        let _lock = RepositoryLock::shared(&self.storage)?;
+        bundle::create(self, root, output)
+    }
+
+    /// Loads a bundle written by [`Repository::create_bundle`]. Content the
+    /// repository already has is not stored again, and snapshots it already
+    /// has are skipped.
+    pub fn import_bundle(&self, bundle: &Path) -> anyhow::Result<CopyReport> {
+        let _lock = RepositoryLock::exclusive(&self.storage)?;
+        bundle::import(self, bundle)
+    }
+
+    /// Writes the changes of `root` between snapshots `from` and `to` to a
+    /// delta bundle at `output`, leaving out content the `from` state has.
+    pub fn create_delta_bundle(
+        &self,
+        root: &Path,
+        from: u32,
+        to: u32,
+        output: &Path,
+    ) -> anyhow::Result<DeltaReport> {
+        let _lock = RepositoryLock::shared(&self.storage)?;
+        delta::create(self, root, from, to, output)
+    }
+
+    /// Adds the snapshots of a delta bundle. The repository must already
+    /// hold the bundle's root at its `from` state; this is checked file by
+    /// file before anything is written.
+    pub fn apply_delta_bundle(&self, bundle: &Path) -> anyhow::Result<DeltaReport> {
+        let _lock = RepositoryLock::exclusive(&self.storage)?;
+        delta::apply_to_repository(self, bundle)
+    }
+
+    /// Keeps only the latest `keep` snapshots of `root` and deletes content
+    /// that no snapshot of any root refers to any more. Earlier snapshot
+    /// numbers can no longer be restored afterwards.
+    pub fn prune(&self, root: &Path, keep: u32) -> anyhow::Result<PruneReport> {
+        let _lock = RepositoryLock::exclusive(&self.storage)?;
+        prune::prune(self, root, keep)
+    }
+
+    /// Checks that the snapshots of `root`, or of every root when `None`,
+    /// can be read and that the content they refer to is intact. Problems
+    /// are collected in the report rather than returned as errors.
+    pub fn verify(
+        &self,
+        root: Option<&Path>,
+        options: &VerifyOptions,
+    ) -> anyhow::Result<VerifyReport> {
+        let _lock = RepositoryLock::shared(&self.storage)?;
+        verify::verify(self, root, options)
+    }
+
+    /// Removes stale locks, or every lock when `all` is set.
+    pub fn unlock(&self, all: bool) -> anyhow::Result<UnlockReport> {
+        lock::unlock(self.storage(), all)
+    }
+}
+
+/// Brings the working tree `dir` from a delta bundle's `from` state to its
+/// `to` state without going through a repository. The tree must match the
+/// `from` state exactly, ignoring files excluded by `config`, or nothing is
+/// changed.
+pub fn apply_delta_to_tree(
+    bundle: &Path,
+    dir: &Path,
+    config: &Config,
+) -> anyhow::Result<DeltaReport> {
+    delta::apply_to_tree(bundle, dir, config)
+}
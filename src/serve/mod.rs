@@ -0,0 +1,380 @@
+//! `snapback serve`: exposes a repository's storage to remote clients.
+//!
+//! The server knows nothing about snapshots. It answers the storage
+//! operations described in [`protocol`], so locking, manifests and content
+//! deduplication all work the same as against local storage.
+
+use std::{
+    fs::{self, File},
+    io::{self, BufReader, BufWriter, Cursor, Read, Write},
+    net::ToSocketAddrs,
+    thread,
+};
+
+use crate::{
+    storage::{Storage, CONTENT_DIR},
+    util::{self, spool::Spooled},
+};
+
+pub mod protocol;
+
+use protocol::{Request, Response, PROTOCOL_VERSION};
+
+/// Requests handled at the same time by the HTTP server.
+const HTTP_WORKERS: usize = 4;
+
+/// Serves requests from stdin and answers on stdout until the client hangs
+/// up. This is what `ssh host snapback serve` runs.
+pub fn serve_stdio(storage: &dyn Storage) -> anyhow::Result<()> {
+    let mut input = BufReader::new(io::stdin().lock());
+    let mut output = BufWriter::new(io::stdout().lock());
+
+    while let Some(request) = protocol::read_message::<Request>(&mut input)? {
+        let (response, payload) = handle(storage, request, &mut input)?;
+        protocol::write_message(&mut output, &response)?;
+        if let Some(mut payload) = payload {
+            io::copy(&mut payload, &mut output)?;
+        }
+        output.flush()?;
+    }
+    Ok(())
+}
+
+/// Serves the protocol over HTTP on `addr`. When `token` is set, requests
+/// must carry it as `Authorization: Bearer <token>`. Without a token anyone
+/// who can connect owns the repository, so only loopback addresses are
+/// served then. `on_listen` is called once the server is listening.
+pub fn serve_http(
+    storage: &dyn Storage,
+    addr: &str,
+    token: Option<&str>,
+    on_listen: impl FnOnce(),
+) -> anyhow::Result<()> {
+    let token = token.filter(|token| !token.is_empty());
+    if token.is_none() && !is_loopback(addr)? {
+        return Err(anyhow::anyhow!(
+            "Refusing to serve {} without a token; set SNAPBACK_SERVE_TOKEN or listen on 127.0.0.1",
+            addr
+        ));
+    }
+    let server = tiny_http::Server::http(addr)
+        .map_err(|e| anyhow::anyhow!("Cannot listen on {}: {}", addr, e))?;
+    on_listen();
+
+    thread::scope(|scope| {
+        for _ in 0..HTTP_WORKERS {
+            scope.spawn(|| {
+                while let Ok(mut request) = server.recv() {
+                    let response = respond_http(storage, &mut request, token);
+                    let _ = request.respond(response);
+                }
+            });
+        }
+    });
+    Ok(())
+}
+
+/// Whether every address `addr` resolves to is a loopback address.
+fn is_loopback(addr: &str) -> anyhow::Result<bool> {
+    let addrs: Vec<_> = addr
+        .to_socket_addrs()
+        .map_err(|e| anyhow::anyhow!("Cannot listen on {}: {}", addr, e))?
+        .collect();
+    Ok(!addrs.is_empty() && addrs.iter().all(|addr| addr.ip().is_loopback()))
+}
+
+type HttpResponse = tiny_http::Response<Box<dyn Read + Send>>;
+
+fn respond_http(
+    storage: &dyn Storage,
+    request: &mut tiny_http::Request,
+    token: Option<&str>,
+) -> HttpResponse {
+    if *request.method() != tiny_http::Method::Post || request.url() != "/" {
+        return http_status(404, "Not found");
+    }
+    if let Some(token) = token {
+        let expected = format!("Bearer {}", token);
+        let authorized = request.headers().iter().any(|h| {
+            h.field.equiv("Authorization")
+                && constant_time_eq(h.value.as_str().as_bytes(), expected.as_bytes())
+        });
+        if !authorized {
+            return http_status(401, "Unauthorized");
+        }
+    }
+
+    let mut body = BufReader::new(request.as_reader());
+    let result = protocol::read_message::<Request>(&mut body).and_then(|request| {
+        let request = request.ok_or_else(|| anyhow::anyhow!("Empty request"))?;
+        handle(storage, request, &mut body)
+    });
+    let (response, payload) = match result {
+        Ok(answer) => answer,
+        Err(e) => return http_status(400, &format!("{:#}", e)),
+    };
+
+    let mut line = match serde_json::to_vec(&response) {
+        Ok(line) => line,
+        Err(e) => return http_status(500, &e.to_string()),
+    };
+    line.push(b'\n');
+    let length = line.len() as u64 + payload.as_ref().map_or(0, Spooled::len);
+    let reader: Box<dyn Read + Send> = match payload {
+        Some(payload) => Box::new(Cursor::new(line).chain(payload)),
+        None => Box::new(Cursor::new(line)),
+    };
+    tiny_http::Response::new(
+        tiny_http::StatusCode(200),
+        Vec::new(),
+        reader,
+        usize::try_from(length).ok(),
+        None,
+    )
+}
+
+/// Compares without stopping at the first difference, so the time taken
+/// does not tell how much of a guessed token was right.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+fn http_status(code: u16, message: &str) -> HttpResponse {
+    let body = message.as_bytes().to_vec();
+    let length = body.len();
+    tiny_http::Response::new(
+        tiny_http::StatusCode(code),
+        Vec::new(),
+        Box::new(Cursor::new(body)),
+        Some(length),
+        None,
+    )
+}
+
+/// Runs one request. A storage failure becomes an error response; only a
+/// broken connection is returned as `Err`.
+fn handle(
+    storage: &dyn Storage,
+    request: Request,
+    input: &mut dyn Read,
+) -> anyhow::Result<(Response, Option<Spooled>)> {
+    if let Request::Put { key, size } = &request {
+        return receive(storage, key, *size, input).map(|response| (response, None));
+    }
+
+    let result = match request {
+        Request::Hello { version } if version == PROTOCOL_VERSION => Ok((
+            Response {
+                version: Some(PROTOCOL_VERSION),
+                ..Response::ok()
+            },
+            None,
+        )),
+        Request::Hello { version } => Ok((
+            Response {
+                version: Some(PROTOCOL_VERSION),
+                ..Response::error(format!(
+                    "Unsupported protocol version {}; this server speaks {}",
+                    version, PROTOCOL_VERSION
+                ))
+            },
+            None,
+        )),
+        Request::Put { .. } => unreachable!("handled above"),
+        Request::Get { key } => storage
+            .reader(&key)
+            .and_then(|mut reader| Spooled::from_reader(&mut reader))
+            .map(|payload| {
+                let response = Response {
+                    size: Some(payload.len()),
+                    ..Response::ok()
+                };
+                (response, Some(payload))
+            }),
+        Request::List { dir } => storage.list(&dir).map(|keys| {
+            let response = Response {
+                keys: Some(keys),
+                ..Response::ok()
+            };
+            (response, None)
+        }),
+        Request::Delete { key } => storage.delete(&key).map(|_| (Response::ok(), None)),
+        Request::Want { keys } => storage.missing(&keys).map(|missing| {
+            let response = Response {
+                keys: Some(missing),
+                ..Response::ok()
+            };
+            (response, None)
+        }),
+        Request::Modified { key } => {
+            let response = Response {
+                modified: storage.modified(&key),
+                ..Response::ok()
+            };
+            Ok((response, None))
+        }
+        Request::RemoveLeftovers => {
+            let response = Response {
+                removed: Some(storage.remove_leftovers()),
+                ..Response::ok()
+            };
+            Ok((response, None))
+        }
+    };
+
+    Ok(result.unwrap_or_else(|e| (Response::error(format!("{:#}", e)), None)))
+}
+
+/// Receives a `put` payload into the staging directory and stores it. A
+/// content object is only stored if its bytes hash to its key.
+fn receive(
+    storage: &dyn Storage,
+    key: &str,
+    size: u64,
+    input: &mut dyn Read,
+) -> anyhow::Result<Response> {
+    let staging_dir = storage.staging_dir();
+    fs::create_dir_all(&staging_dir)?;
+    let temp_path = util::atomic::temp_path_in(&staging_dir);
+
+    let mut file = match File::create(&temp_path) {
+        Ok(file) => file,
+        Err(e) => {
+            // Skip the payload so the next request starts in the right place.
+            protocol::copy_payload(input, size, &mut io::sink())?;
+            return Ok(Response::error(format!("Cannot stage {}: {}", key, e)));
+        }
+    };
+    let copied = util::hash::hash_reader_with(input.take(size), |chunk| file.write_all(chunk));
+    let hash = match copied {
+        Ok((hash, copied)) if copied == size => hash,
+        Ok((_, copied)) => {
+            let _ = fs::remove_file(&temp_path);
+            return Err(anyhow::anyhow!(
+                "Connection closed after {} of {} payload bytes",
+                copied,
+                size
+            ));
+        }
+        Err(e) => {
+            let _ = fs::remove_file(&temp_path);
+            return Err(e);
+        }
+    };
+    drop(file);
+
+    let expected = key
+        .strip_prefix(CONTENT_DIR)
+        .and_then(|name| name.strip_prefix('/'))
+        .and_then(|name| name.strip_suffix(".dat"));
+    if expected.is_some_and(|expected| expected != hash) {
+        let _ = fs::remove_file(&temp_path);
+        return Ok(Response::error(format!(
+            "Refusing {}: its content hashes to {}",
+            key, hash
+        )));
+    }
+
+    match storage.put_file(key, &temp_path) {
+        Ok(()) => Ok(Response::ok()),
+        Err(e) => {
+            let _ = fs::remove_file(&temp_path);
+            Ok(Response::error(format!("{:#}", e)))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::{self, MemoryStorage};
+
+    /// Runs `request` with `payload` following it and returns the response
+    /// and the payload that came back.
+    fn call(storage: &dyn Storage, request: Request, payload: &[u8]) -> (Response, Vec<u8>) {
+        let mut input = payload;
+        let (response, answer) = handle(storage, request, &mut input).unwrap();
+        let mut bytes = Vec::new();
+        if let Some(mut answer) = answer {
+            answer.read_to_end(&mut bytes).unwrap();
+        }
+        (response, bytes)
+    }
+
+    fn put(storage: &dyn Storage, key: &str, data: &[u8]) -> Response {
+        let request = Request::Put {
+            key: key.to_string(),
+            size: data.len() as u64,
+        };
+        call(storage, request, data).0
+    }
+
+    fn content_key(data: &[u8]) -> String {
+        storage::content_key(&util::hash::hash_reader_with(data, |_| Ok(())).unwrap().0)
+    }
+
+    #[test]
+    fn hello_checks_the_protocol_version() {
+        let storage = MemoryStorage::new();
+        let (response, _) = call(&storage, Request::Hello { version: 1 }, b"");
+        assert!(response.ok);
+        assert_eq!(response.version, Some(PROTOCOL_VERSION));
+        let (response, _) = call(&storage, Request::Hello { version: 99 }, b"");
+        assert!(!response.ok);
+        assert!(response
+            .error
+            .unwrap()
+            .contains("Unsupported protocol version 99"));
+    }
+
+    #[test]
+    fn want_answers_with_the_objects_the_server_lacks() {
+        let storage = MemoryStorage::new();
+        let stored = content_key(b"stored");
+        assert!(put(&storage, &stored, b"stored").ok);
+
+        let keys = vec![stored.clone(), content_key(b"new")];
+        let (response, _) = call(&storage, Request::Want { keys }, b"");
+        assert_eq!(response.keys, Some(vec![content_key(b"new")]));
+
+        let (response, payload) = call(&storage, Request::Get { key: stored }, b"");
+        assert_eq!(response.size, Some(6));
+        assert_eq!(payload, b"stored");
+    }
+
+    #[test]
+    fn content_that_does_not_match_its_key_is_refused() {
+        let storage = MemoryStorage::new();
+        let key = content_key(b"expected");
+        let response = put(&storage, &key, b"tampered");
+        assert!(!response.ok);
+        assert!(response.error.unwrap().starts_with("Refusing"));
+        assert!(!storage.exists(&key).unwrap());
+        // Other objects are stored as they come
+        assert!(put(&storage, "info/root.json", b"{}").ok);
+    }
+
+    #[test]
+    fn a_truncated_payload_ends_the_session() {
+        let storage = MemoryStorage::new();
+        let request = Request::Put {
+            key: "info/root.json".to_string(),
+            size: 10,
+        };
+        let error = handle(&storage, request, &mut &b"{}"[..]).err().unwrap();
+        assert!(error.to_string().contains("after 2 of 10 payload bytes"));
+        assert!(!storage.exists("info/root.json").unwrap());
+    }
+
+    #[test]
+    fn storage_errors_become_error_responses() {
+        let storage = MemoryStorage::new();
+        let request = Request::Get {
+            key: "info/missing.json".to_string(),
+        };
+        let (response, payload) = call(&storage, request, b"");
+        assert!(!response.ok);
+        assert!(payload.is_empty());
+        assert!(response.into_result().is_err());
+    }
+}
@@ -0,0 +1,185 @@
+//! Wire protocol between `snapback serve` and a remote repository client.
+//!
+//! Every message is one line of JSON. When the line carries a `size` field,
+//! exactly that many raw bytes follow it before the next message. Over stdio
+//! the client sends a request and waits for its response, one at a time.
+//! Over HTTP every request is a `POST /` whose body is one request message
+//! and whose response body is one response message.
+//!
+//! A session starts with `hello`, and the server answers with its protocol
+//! version. The operations mirror the [`Storage`](crate::storage::Storage)
+//! trait:
+//!
+//! | request                          | response                             |
+//! |----------------------------------|--------------------------------------|
+//! | `{"op":"hello","version":1}`     | `{"ok":true,"version":1}`            |
+//! | `{"op":"put","key":K,"size":N}` + N bytes | `{"ok":true}`               |
+//! | `{"op":"get","key":K}`           | `{"ok":true,"size":N}` + N bytes     |
+//! | `{"op":"list","dir":D}`          | `{"ok":true,"keys":[...]}`           |
+//! | `{"op":"delete","key":K}`        | `{"ok":true}`                        |
+//! | `{"op":"want","keys":[...]}`     | `{"ok":true,"keys":[missing...]}`    |
+//! | `{"op":"modified","key":K}`      | `{"ok":true,"modified":T}`           |
+//! | `{"op":"remove_leftovers"}`      | `{"ok":true,"removed":N}`            |
+//!
+//! `want` is the have/want negotiation: the client names the objects it is
+//! about to send and the server answers with the ones it does not have yet,
+//! so content already on the server never crosses the wire. A failed
+//! operation is answered with `{"ok":false,"error":"..."}`.
+
+use std::io::{BufRead, Read, Write};
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+pub const PROTOCOL_VERSION: u32 = 1;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum Request {
+    Hello { version: u32 },
+    Put { key: String, size: u64 },
+    Get { key: String },
+    List { dir: String },
+    Delete { key: String },
+    Want { keys: Vec<String> },
+    Modified { key: String },
+    RemoveLeftovers,
+}
+
+impl Request {
+    /// Size of the payload that follows the request line.
+    pub fn payload_size(&self) -> u64 {
+        match self {
+            Request::Put { size, .. } => *size,
+            _ => 0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Response {
+    pub ok: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub version: Option<u32>,
+    /// Size of the payload that follows the response line.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub size: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub keys: Option<Vec<String>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub modified: Option<DateTime<Utc>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub removed: Option<usize>,
+}
+
+impl Response {
+    pub fn ok() -> Self {
+        Self {
+            ok: true,
+            ..Self::default()
+        }
+    }
+
+    pub fn error(error: impl Into<String>) -> Self {
+        Self {
+            error: Some(error.into()),
+            ..Self::default()
+        }
+    }
+
+    /// Turns an error response into an `Err`.
+    pub fn into_result(self) -> anyhow::Result<Self> {
+        if self.ok {
+            Ok(self)
+        } else {
+            Err(anyhow::anyhow!(
+                "{}",
+                self.error.as_deref().unwrap_or("remote repository error")
+            ))
+        }
+    }
+}
+
+/// Writes one message line. The caller sends the payload, if any, right after.
+pub fn write_message<T: Serialize>(writer: &mut dyn Write, message: &T) -> anyhow::Result<()> {
+    let mut line = serde_json::to_vec(message)?;
+    line.push(b'\n');
+    writer.write_all(&line)?;
+    Ok(())
+}
+
+/// Reads one message line, or `None` at a clean end of stream.
+pub fn read_message<T: for<'de> Deserialize<'de>>(
+    reader: &mut dyn BufRead,
+) -> anyhow::Result<Option<T>> {
+    let mut line = String::new();
+    if reader.read_line(&mut line)? == 0 {
+        return Ok(None);
+    }
+    serde_json::from_str(line.trim_end())
+        .map(Some)
+        .map_err(|e| anyhow::anyhow!("Malformed protocol message: {}", e))
+}
+
+/// Reads exactly `size` payload bytes into `sink`.
+pub fn copy_payload(reader: &mut dyn Read, size: u64, sink: &mut dyn Write) -> anyhow::Result<()> {
+    let copied = std::io::copy(&mut reader.take(size), sink)?;
+    if copied != size {
+        return Err(anyhow::anyhow!(
+            "Connection closed after {} of {} payload bytes",
+            copied,
+            size
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn messages_are_json_lines_followed_by_their_payload() {
+        let mut wire = Vec::new();
+        let put = Request::Put {
+            key: "info/root.json".to_string(),
+            size: 2,
+        };
+        write_message(&mut wire, &put).unwrap();
+        wire.extend_from_slice(b"{}");
+        write_message(&mut wire, &Request::RemoveLeftovers).unwrap();
+        assert_eq!(
+            String::from_utf8_lossy(&wire),
+            "{\"op\":\"put\",\"key\":\"info/root.json\",\"size\":2}\n{}{\"op\":\"remove_leftovers\"}\n"
+        );
+
+        let mut reader = &wire[..];
+        let request: Request = read_message(&mut reader).unwrap().unwrap();
+        let mut payload = Vec::new();
+        copy_payload(&mut reader, request.payload_size(), &mut payload).unwrap();
+        assert_eq!(payload, b"{}");
+        let request: Request = read_message(&mut reader).unwrap().unwrap();
+        assert!(matches!(request, Request::RemoveLeftovers));
+        assert!(read_message::<Request>(&mut reader).unwrap().is_none());
+    }
+
+    #[test]
+    fn responses_leave_out_unset_fields() {
+        let response = Response {
+            keys: Some(vec!["content/a.dat".to_string()]),
+            ..Response::ok()
+        };
+        assert_eq!(
+            serde_json::to_string(&response).unwrap(),
+            r#"{"ok":true,"keys":["content/a.dat"]}"#
+        );
+        assert_eq!(
+            serde_json::to_string(&Response::error("gone")).unwrap(),
+            r#"{"ok":false,"error":"gone"}"#
+        );
+        let error = read_message::<Request>(&mut &b"{\"op\":\"fly\"}\n"[..]).unwrap_err();
+        assert!(error.to_string().starts_with("Malformed protocol message"));
+    }
+}
@@ -0,0 +1,168 @@
+use std::path::{Path, PathBuf};
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::storage::{self, Storage};
+
+/// Record of a backed-up root directory. Every root gets its own prefix, and
+/// its snapshots are stored under that prefix.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BackupInfo {
+    pub timestamp: DateTime<Utc>,
+    pub path_to_root: PathBuf,
+    pub backup_prefix: String,
+}
+
+impl BackupInfo {
+    /// Reads every readable info record together with its key.
+    pub(crate) fn read_all(storage: &dyn Storage) -> anyhow::Result<Vec<(BackupInfo, String)>> {
+        storage
+            .list(storage::INFO_DIR)?
+            .into_iter()
+            .map(|key| {
+                let content = storage.get(&key)?;
+                let info = serde_json::from_slice(&content)
+                    .map_err(|e| anyhow::anyhow!("Unreadable backup info {}: {}", key, e))?;
+                Ok((info, key))
+            })
+            .collect()
+    }
+}
+
+/// One `backup_N.json`: the entries that changed since the previous snapshot
+/// of the same root. Replaying snapshots `0..=N` gives the state at `N`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Snapshot {
+    /// Taken from the file name, not stored in it.
+    #[serde(skip)]
+    pub number: u32,
+    #[serde(default = "unrecorded_timestamp")]
+    pub timestamp: DateTime<Utc>,
+    #[serde(default)]
+    pub status: SnapshotStatus,
+    /// Files that existed but could not be read or stored. They are not in
+    /// `entries`, so their previous version stays the latest one.
+    #[serde(default)]
+    pub failures: Vec<FailedFile>,
+    #[serde(rename = "files")]
+    pub entries: Vec<SnapshotEntry>,
+}
+
+#[derive(Debug, Default, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum SnapshotStatus {
+    #[default]
+    Complete,
+    Partial,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct FailedFile {
+    pub path: String,
+    pub error: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct SnapshotEntry {
+    pub path: String,
+    pub size: u64,
+    pub hash: String,
+    pub modify_time: DateTime<Utc>,
+    pub deleted: bool,
+    pub content_type: ContentType,
+    /// Path of the stored content, relative to the backup directory.
+    pub content_path: Option<String>,
+    /// The file kept changing while it was read; only one of its states was
+    /// stored.
+    #[serde(default)]
+    pub changed_during_backup: bool,
+    /// Unix permission bits, if they were recorded.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mode: Option<u32>,
+    /// The file's own modification time, if it was recorded. `modify_time`
+    /// is when this entry was made.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mtime: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub enum ContentType {
+    FullCopy,
+    Delta { base_hash: String },
+    Unchanged,
+}
+
+/// Manifests written before snapshot status existed are a bare entry list.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum StoredSnapshot {
+    Current(Snapshot),
+    Legacy(Vec<SnapshotEntry>),
+}
+
+fn unrecorded_timestamp() -> DateTime<Utc> {
+    DateTime::<Utc>::MIN_UTC
+}
+
+impl Snapshot {
+    pub(crate) fn new(number: u32) -> Self {
+        Self {
+            number,
+            timestamp: Utc::now(),
+            status: SnapshotStatus::Complete,
+            failures: Vec::new(),
+            entries: Vec::new(),
+        }
+    }
+
+    pub fn is_partial(&self) -> bool {
+        self.status == SnapshotStatus::Partial
+    }
+
+    pub fn changes(&self) -> usize {
+        self.entries.iter().filter(|e| !e.deleted).count()
+    }
+
+    pub fn deletions(&self) -> usize {
+        self.entries.iter().filter(|e| e.deleted).count()
+    }
+
+    pub(crate) fn record_failure(&mut self, failure: FailedFile) {
+        self.status = SnapshotStatus::Partial;
+        self.failures.push(failure);
+    }
+
+    /// Reads `backup_N.json`. Snapshots that predate recorded timestamps take
+    /// the manifest's modification time instead.
+    pub(crate) fn read(storage: &dyn Storage, key: &str, number: u32) -> anyhow::Result<Snapshot> {
+        let content = storage.get(key)?;
+        let mut snapshot = match serde_json::from_slice::<StoredSnapshot>(&content) {
+            Ok(StoredSnapshot::Current(snapshot)) => snapshot,
+            Ok(StoredSnapshot::Legacy(entries)) => Snapshot {
+                timestamp: unrecorded_timestamp(),
+                entries,
+                ..Snapshot::new(number)
+            },
+            Err(e) => return Err(anyhow::anyhow!("Unreadable manifest {}: {}", key, e)),
+        };
+
+        snapshot.number = number;
+        if snapshot.timestamp == unrecorded_timestamp() {
+            if let Some(modified) = storage.modified(key) {
+                snapshot.timestamp = modified;
+            }
+        }
+        Ok(snapshot)
+    }
+}
+
+/// Parses the snapshot number out of a `<prefix>/backup_N.json` key.
+pub(crate) fn snapshot_number(key: &str) -> Option<u32> {
+    let file_name = Path::new(key).file_name()?.to_string_lossy();
+    file_name
+        .strip_prefix("backup_")?
+        .strip_suffix(".json")?
+        .parse()
+        .ok()
+}
@@ -0,0 +1,393 @@
+//! `.snapbundle` files: a repository subset packed into one file.
+//!
+//! A bundle is an uncompressed tar archive whose entries are named by their
+//! storage keys. It starts with a `SNAPBUNDLE` header and ends with
+//! `checksums.json`, which maps every other entry to its SHA-256, so a
+//! truncated or damaged bundle is caught before anything is imported.
+//! Staying uncompressed keeps the entries seekable: [`BundleReader`] serves
+//! any object without unpacking the rest.
+//!
+//! Bundles are written and read through the [`Storage`] trait, which lets
+//! `snapback copy`'s replication logic fill and empty them.
+
+use std::{
+    collections::BTreeMap,
+    fs::{self, File},
+    io::{self, BufReader, BufWriter, Read, Seek, SeekFrom},
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use super::{check_key, Storage, CONTENT_DIR};
+use crate::util;
+
+const HEADER_ENTRY: &str = "SNAPBUNDLE";
+const CHECKSUMS_ENTRY: &str = "checksums.json";
+const BUNDLE_VERSION: u32 = 1;
+
+#[derive(Serialize, Deserialize)]
+struct BundleHeader {
+    format: String,
+    version: u32,
+}
+
+/// Entry name to hex SHA-256 of its data.
+type Checksums = BTreeMap<String, String>;
+
+/// Writes a new bundle. Objects are appended in the order they are put; the
+/// file only appears at its final path once [`BundleWriter::finish`] has
+/// written the checksums.
+#[derive(Debug)]
+pub(crate) struct BundleWriter {
+    path: PathBuf,
+    temp_path: PathBuf,
+    state: Mutex<Option<WriterState>>,
+}
+
+struct WriterState {
+    builder: tar::Builder<BufWriter<File>>,
+    checksums: Checksums,
+}
+
+impl std::fmt::Debug for WriterState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("WriterState")
+            .field("entries", &self.checksums.len())
+            .finish_non_exhaustive()
+    }
+}
+
+impl BundleWriter {
+    pub(crate) fn create(path: &Path) -> anyhow::Result<Self> {
+        let dir = match path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        let temp_path = util::atomic::temp_path_in(dir);
+        let file = File::create(&temp_path)
+            .map_err(|e| anyhow::anyhow!("Cannot create {}: {}", path.display(), e))?;
+
+        let mut builder = tar::Builder::new(BufWriter::new(file));
+        let header = serde_json::to_vec_pretty(&BundleHeader {
+            format: "snapbundle".to_string(),
+            version: BUNDLE_VERSION,
+        })?;
+        append(&mut builder, HEADER_ENTRY, header.len() as u64, &header[..])?;
+
+        Ok(Self {
+            path: path.to_path_buf(),
+            temp_path,
+            state: Mutex::new(Some(WriterState {
+                builder,
+                checksums: Checksums::new(),
+            })),
+        })
+    }
+
+    /// Writes the checksums and moves the bundle into place.
+    pub(crate) fn finish(&self) -> anyhow::Result<()> {
+        let mut state = self
+            .lock()
+            .take()
+            .ok_or_else(|| anyhow::anyhow!("Bundle is already finished"))?;
+        let checksums = serde_json::to_vec_pretty(&state.checksums)?;
+        append(
+            &mut state.builder,
+            CHECKSUMS_ENTRY,
+            checksums.len() as u64,
+            &checksums[..],
+        )?;
+
+        let file = state.builder.into_inner()?.into_inner()?;
+        file.sync_all()?;
+        fs::rename(&self.temp_path, &self.path)?;
+        Ok(())
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Option<WriterState>> {
+        self.state
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// Appends `key` unless it is already in the bundle.
+    fn append_object(&self, key: &str, size: u64, data: impl Read) -> anyhow::Result<()> {
+        check_key(key)?;
+        let mut guard = self.lock();
+        let state = guard
+            .as_mut()
+            .ok_or_else(|| anyhow::anyhow!("Bundle is already finished"))?;
+        if state.checksums.contains_key(key) {
+            return Ok(());
+        }
+        let hash = append(&mut state.builder, key, size, data)?;
+        state.checksums.insert(key.to_string(), hash);
+        Ok(())
+    }
+}
+
+impl Drop for BundleWriter {
+    fn drop(&mut self) {
+        if self.lock().is_some() {
+            let _ = fs::remove_file(&self.temp_path);
+        }
+    }
+}
+
+impl Storage for BundleWriter {
+    fn put(&self, key: &str, data: &[u8]) -> anyhow::Result<()> {
+        self.append_object(key, data.len() as u64, data)
+    }
+
+    fn put_file(&self, key: &str, file: &Path) -> anyhow::Result<()> {
+        let size = fs::metadata(file)?.len();
+        self.append_object(key, size, BufReader::new(File::open(file)?))?;
+        fs::remove_file(file)?;
+        Ok(())
+    }
+
+    fn get(&self, key: &str) -> anyhow::Result<Vec<u8>> {
+        Err(anyhow::anyhow!(
+            "Cannot read {} from a bundle that is being written",
+            key
+        ))
+    }
+
+    fn list(&self, dir: &str) -> anyhow::Result<Vec<String>> {
+        let guard = self.lock();
+        Ok(guard
+            .as_ref()
+            .map(|state| children(state.checksums.keys(), dir))
+            .unwrap_or_default())
+    }
+
+    fn delete(&self, key: &str) -> anyhow::Result<()> {
+        Err(anyhow::anyhow!("Cannot delete {} from a bundle", key))
+    }
+
+    fn exists(&self, key: &str) -> anyhow::Result<bool> {
+        Ok(self
+            .lock()
+            .as_ref()
+            .is_some_and(|state| state.checksums.contains_key(key)))
+    }
+}
+
+/// Read-only access to an existing bundle. Opening it reads the index of
+/// entries and checks every checksum except those of content objects, whose
+/// names already carry their hash and are verified as they are copied out.
+#[derive(Debug)]
+pub(crate) struct BundleReader {
+    path: PathBuf,
+    /// Data offset and size of every object.
+    entries: BTreeMap<String, (u64, u64)>,
+}
+
+impl BundleReader {
+    pub(crate) fn open(path: &Path) -> anyhow::Result<Self> {
+        let damaged = |reason: String| anyhow::anyhow!("{} is damaged: {}", path.display(), reason);
+        let file = File::open(path)
+            .map_err(|e| anyhow::anyhow!("Cannot open {}: {}", path.display(), e))?;
+
+        let mut archive = tar::Archive::new(BufReader::new(file));
+        let mut entries = BTreeMap::new();
+        let mut computed = Checksums::new();
+        let mut header = None;
+        let mut checksums = None;
+        let entries_iter = archive.entries().map_err(|e| read_error(path, false, e))?;
+        for entry in entries_iter {
+            let mut entry = entry.map_err(|e| read_error(path, header.is_some(), e))?;
+            let name = entry
+                .path()
+                .map_err(|e| read_error(path, header.is_some(), e))?
+                .to_string_lossy()
+                .to_string();
+            if header.is_none() {
+                if name != HEADER_ENTRY {
+                    return Err(anyhow::anyhow!(
+                        "{} is not a snapback bundle",
+                        path.display()
+                    ));
+                }
+                let mut data = Vec::new();
+                entry
+                    .read_to_end(&mut data)
+                    .map_err(|e| read_error(path, false, e))?;
+                let parsed: BundleHeader = serde_json::from_slice(&data)
+                    .map_err(|_| anyhow::anyhow!("{} is not a snapback bundle", path.display()))?;
+                if parsed.version > BUNDLE_VERSION {
+                    return Err(anyhow::anyhow!(
+                        "{} is a version {} bundle; this snapback reads up to version {}",
+                        path.display(),
+                        parsed.version,
+                        BUNDLE_VERSION
+                    ));
+                }
+                header = Some(parsed);
+                continue;
+            }
+            if name == CHECKSUMS_ENTRY {
+                let mut data = Vec::new();
+                entry
+                    .read_to_end(&mut data)
+                    .map_err(|e| read_error(path, true, e))?;
+                let parsed: Checksums = serde_json::from_slice(&data)
+                    .map_err(|e| damaged(format!("unreadable checksums: {}", e)))?;
+                checksums = Some(parsed);
+                continue;
+            }
+
+            check_key(&name)?;
+            let position = (entry.raw_file_position(), entry.size());
+            let hash = if name.starts_with(&format!("{}/", CONTENT_DIR)) {
+                None
+            } else {
+                let (hash, _) = util::hash::hash_reader_with(&mut entry, |_| Ok(()))
+                    .map_err(|e| read_error(path, true, e))?;
+                Some(hash)
+            };
+            if let Some(hash) = hash {
+                computed.insert(name.clone(), hash);
+            }
+            entries.insert(name, position);
+        }
+
+        let checksums =
+            checksums.ok_or_else(|| damaged("it has no checksums; it may be truncated".into()))?;
+        for name in entries.keys() {
+            let Some(expected) = checksums.get(name) else {
+                return Err(damaged(format!("{} has no checksum", name)));
+            };
+            let matches = match computed.get(name) {
+                Some(actual) => actual == expected,
+                None => super::content_key(expected) == *name,
+            };
+            if !matches {
+                return Err(damaged(format!("checksum mismatch for {}", name)));
+            }
+        }
+        if let Some(missing) = checksums.keys().find(|name| !entries.contains_key(*name)) {
+            return Err(damaged(format!("{} is missing", missing)));
+        }
+
+        Ok(Self {
+            path: path.to_path_buf(),
+            entries,
+        })
+    }
+
+    fn entry(&self, key: &str) -> anyhow::Result<(u64, u64)> {
+        self.entries
+            .get(key)
+            .copied()
+            .ok_or_else(|| anyhow::anyhow!("No object stored under {}", key))
+    }
+}
+
+impl Storage for BundleReader {
+    fn put(&self, key: &str, _data: &[u8]) -> anyhow::Result<()> {
+        Err(anyhow::anyhow!("Cannot write {} into a bundle", key))
+    }
+
+    fn get(&self, key: &str) -> anyhow::Result<Vec<u8>> {
+        let mut data = Vec::new();
+        self.reader(key)?.read_to_end(&mut data)?;
+        Ok(data)
+    }
+
+    fn reader(&self, key: &str) -> anyhow::Result<Box<dyn Read + Send + '_>> {
+        let (offset, size) = self.entry(key)?;
+        let mut file = File::open(&self.path)?;
+        file.seek(SeekFrom::Start(offset))?;
+        Ok(Box::new(BufReader::new(file).take(size)))
+    }
+
+    fn list(&self, dir: &str) -> anyhow::Result<Vec<String>> {
+        Ok(children(self.entries.keys(), dir))
+    }
+
+    fn delete(&self, key: &str) -> anyhow::Result<()> {
+        Err(anyhow::anyhow!("Cannot delete {} from a bundle", key))
+    }
+
+    fn exists(&self, key: &str) -> anyhow::Result<bool> {
+        Ok(self.entries.contains_key(key))
+    }
+}
+
+/// Describes a failure to read `path` as a tar archive: before the bundle
+/// header it is some other file, after it the bundle is damaged.
+fn read_error(path: &Path, in_bundle: bool, error: impl std::fmt::Display) -> anyhow::Error {
+    if in_bundle {
+        anyhow::anyhow!("{} is damaged: {}", path.display(), error)
+    } else {
+        anyhow::anyhow!("{} is not a snapback bundle", path.display())
+    }
+}
+
+/// Appends one regular-file entry and returns the SHA-256 of its data.
+fn append(
+    builder: &mut tar::Builder<BufWriter<File>>,
+    name: &str,
+    size: u64,
+    data: impl Read,
+) -> anyhow::Result<String> {
+    let mut header = tar::Header::new_gnu();
+    header.set_entry_type(tar::EntryType::Regular);
+    header.set_size(size);
+    header.set_mode(0o644);
+    header.set_mtime(chrono::Utc::now().timestamp().max(0) as u64);
+
+    let mut hasher = HashingReader::new(data);
+    builder.append_data(&mut header, name, &mut hasher)?;
+    if hasher.read != size {
+        return Err(anyhow::anyhow!(
+            "{} changed size while it was added to the bundle",
+            name
+        ));
+    }
+    Ok(hasher.finish())
+}
+
+fn children<'a>(keys: impl Iterator<Item = &'a String>, dir: &str) -> Vec<String> {
+    let prefix = format!("{}/", dir);
+    keys.filter(|key| {
+        key.strip_prefix(&prefix)
+            .is_some_and(|name| !name.contains('/'))
+    })
+    .cloned()
+    .collect()
+}
+
+/// Hashes everything read through it.
+struct HashingReader<R> {
+    inner: R,
+    hasher: Sha256,
+    read: u64,
+}
+
+impl<R: Read> HashingReader<R> {
+    fn new(inner: R) -> Self {
+        Self {
+            inner,
+            hasher: Sha256::new(),
+            read: 0,
+        }
+    }
+
+    fn finish(self) -> String {
+        format!("{:x}", self.hasher.finalize())
+    }
+}
+
+impl<R: Read> Read for HashingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.hasher.update(&buf[..read]);
+        self.read += read as u64;
+        Ok(read)
+    }
+}
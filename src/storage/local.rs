@@ -0,0 +1,180 @@
+use std::{
+    fs::{self, File},
+    io::{ErrorKind, Read},
+    path::{Path, PathBuf},
+};
+
+use chrono::{DateTime, Utc};
+
+use super::{check_key, Storage, CONTENT_DIR, INFO_DIR, LOCKS_DIR};
+use crate::util;
+
+/// The on-disk layout: everything lives under the backup directory, except
+/// `info/` records, which go to the separately configured info directory.
+#[derive(Debug, Clone)]
+pub struct LocalStorage {
+    backup_dir: PathBuf,
+    info_dir: PathBuf,
+}
+
+impl LocalStorage {
+    pub fn new(backup_dir: impl Into<PathBuf>, info_dir: impl Into<PathBuf>) -> Self {
+        Self {
+            backup_dir: backup_dir.into(),
+            info_dir: info_dir.into(),
+        }
+    }
+
+    pub fn backup_dir(&self) -> &Path {
+        &self.backup_dir
+    }
+
+    pub fn info_dir(&self) -> &Path {
+        &self.info_dir
+    }
+
+    fn path(&self, key: &str) -> anyhow::Result<PathBuf> {
+        check_key(key)?;
+        Ok(match key.split_once('/') {
+            Some((INFO_DIR, rest)) => self.info_dir.join(rest),
+            _ if key == INFO_DIR => self.info_dir.clone(),
+            _ => self.backup_dir.join(key),
+        })
+    }
+}
+
+impl Storage for LocalStorage {
+    fn put(&self, key: &str, data: &[u8]) -> anyhow::Result<()> {
+        util::atomic::write(&self.path(key)?, data)
+    }
+
+    fn put_file(&self, key: &str, file: &Path) -> anyhow::Result<()> {
+        let path = self.path(key)?;
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        File::open(file)?.sync_all()?;
+        util::atomic::publish(file, &path)
+    }
+
+    fn get(&self, key: &str) -> anyhow::Result<Vec<u8>> {
+        let path = self.path(key)?;
+        fs::read(&path).map_err(|e| anyhow::anyhow!("Cannot read {}: {}", path.display(), e))
+    }
+
+    fn reader(&self, key: &str) -> anyhow::Result<Box<dyn Read + Send + '_>> {
+        let path = self.path(key)?;
+        let file = File::open(&path)
+            .map_err(|e| anyhow::anyhow!("Cannot read {}: {}", path.display(), e))?;
+        Ok(Box::new(file))
+    }
+
+    fn list(&self, dir: &str) -> anyhow::Result<Vec<String>> {
+        let entries = match fs::read_dir(self.path(dir)?) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e.into()),
+        };
+
+        let mut keys = Vec::new();
+        for entry in entries {
+            let entry = entry?;
+            let name = entry.file_name().to_string_lossy().to_string();
+            if entry.file_type()?.is_file() && !name.starts_with(util::atomic::TEMP_PREFIX) {
+                keys.push(format!("{}/{}", dir, name));
+            }
+        }
+        keys.sort();
+        Ok(keys)
+    }
+
+    fn delete(&self, key: &str) -> anyhow::Result<()> {
+        match fs::remove_file(self.path(key)?) {
+            Err(e) if e.kind() != ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+
+    fn exists(&self, key: &str) -> anyhow::Result<bool> {
+        Ok(self.path(key)?.is_file())
+    }
+
+    fn modified(&self, key: &str) -> Option<DateTime<Utc>> {
+        let modified = fs::metadata(self.path(key).ok()?).ok()?.modified().ok()?;
+        Some(modified.into())
+    }
+
+    /// Content is staged inside `content/` so publishing it is a rename.
+    fn staging_dir(&self) -> PathBuf {
+        self.backup_dir.join(CONTENT_DIR)
+    }
+
+    /// Removes temp files from the info directory and from every directory
+    /// directly under the backup directory. `locks/` is left alone: holders
+    /// of shared locks may be writing theirs while a writer cleans up.
+    fn remove_leftovers(&self) -> usize {
+        let locks_dir = self.backup_dir.join(LOCKS_DIR);
+        let mut dirs = vec![self.info_dir.clone()];
+        if let Ok(entries) = fs::read_dir(&self.backup_dir) {
+            dirs.extend(
+                entries
+                    .filter_map(|entry| entry.ok())
+                    .filter(|entry| entry.file_type().is_ok_and(|t| t.is_dir()))
+                    .map(|entry| entry.path())
+                    .filter(|dir| *dir != locks_dir),
+            );
+        }
+
+        dirs.iter()
+            .map(|dir| util::atomic::remove_leftovers(dir))
+            .sum()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn leftovers_are_hidden_and_removed_except_in_locks() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = LocalStorage::new(dir.path().join("backups"), dir.path().join("info"));
+        storage.put("info/root.json", b"{}").unwrap();
+        storage.put("content/abc.dat", b"abc").unwrap();
+        storage.put("root/backup_0.json", b"{}").unwrap();
+        storage.put("locks/held.json", b"{}").unwrap();
+        let leftovers = [
+            util::atomic::temp_path_in(&dir.path().join("info")),
+            util::atomic::temp_path_in(&dir.path().join("backups/content")),
+            util::atomic::temp_path_in(&dir.path().join("backups/root")),
+        ];
+        for path in &leftovers {
+            fs::write(path, "half written").unwrap();
+        }
+        let lock_temp = util::atomic::temp_path_in(&dir.path().join("backups/locks"));
+        fs::write(&lock_temp, "being written").unwrap();
+
+        assert_eq!(storage.list("content").unwrap(), vec!["content/abc.dat"]);
+        assert_eq!(storage.list("info").unwrap(), vec!["info/root.json"]);
+
+        assert_eq!(storage.remove_leftovers(), 3);
+        assert!(leftovers.iter().all(|path| !path.exists()));
+        assert!(lock_temp.exists());
+        assert_eq!(storage.get("content/abc.dat").unwrap(), b"abc");
+        assert_eq!(storage.remove_leftovers(), 0);
+    }
+
+    #[test]
+    fn put_replaces_objects_without_leaving_temp_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = LocalStorage::new(dir.path(), dir.path().join("info"));
+        storage.put("root/backup_0.json", b"old").unwrap();
+        storage.put("root/backup_0.json", b"new").unwrap();
+        assert_eq!(storage.get("root/backup_0.json").unwrap(), b"new");
+        let names: Vec<_> = fs::read_dir(dir.path().join("root"))
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect();
+        assert_eq!(names, vec!["backup_0.json"]);
+    }
+}
@@ -0,0 +1,61 @@
+use std::{collections::BTreeMap, sync::Mutex};
+
+use super::{check_key, Storage};
+
+/// Keeps every object in memory. Nothing survives the process, which makes it
+/// useful for tests and for trying things out without touching the disk.
+#[derive(Debug, Default)]
+pub struct MemoryStorage {
+    objects: Mutex<BTreeMap<String, Vec<u8>>>,
+}
+
+impl MemoryStorage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn objects(&self) -> std::sync::MutexGuard<'_, BTreeMap<String, Vec<u8>>> {
+        // A panic while holding the lock cannot leave a half-written object,
+        // so a poisoned map is still consistent.
+        self.objects
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+impl Storage for MemoryStorage {
+    fn put(&self, key: &str, data: &[u8]) -> anyhow::Result<()> {
+        check_key(key)?;
+        self.objects().insert(key.to_string(), data.to_vec());
+        Ok(())
+    }
+
+    fn get(&self, key: &str) -> anyhow::Result<Vec<u8>> {
+        self.objects()
+            .get(key)
+            .cloned()
+            .ok_or_else(|| anyhow::anyhow!("No object stored under {}", key))
+    }
+
+    fn list(&self, dir: &str) -> anyhow::Result<Vec<String>> {
+        let prefix = format!("{}/", dir);
+        Ok(self
+            .objects()
+            .keys()
+            .filter(|key| {
+                key.strip_prefix(&prefix)
+                    .is_some_and(|name| !name.contains('/'))
+            })
+            .cloned()
+            .collect())
+    }
+
+    fn delete(&self, key: &str) -> anyhow::Result<()> {
+        self.objects().remove(key);
+        Ok(())
+    }
+
+    fn exists(&self, key: &str) -> anyhow::Result<bool> {
+        Ok(self.objects().contains_key(key))
+    }
+}
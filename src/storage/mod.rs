@@ -0,0 +1,128 @@
+//! Where a repository keeps its data.
+//!
+//! Everything snapback stores is an object addressed by a `/`-separated key:
+//!
+//! - `content/<sha256>.dat`: file contents, named by their hash
+//! - `<prefix>/backup_N.json`: snapshot manifests of one backed-up root
+//! - `info/<prefix>.json`: the record of a backed-up root
+//! - `locks/<id>.json`: repository locks
+//!
+//! A [`Storage`] backend only has to put, get, list and delete such objects;
+//! the backup logic never touches paths itself.
+
+use std::{
+    io::{Cursor, Read},
+    path::{Path, PathBuf},
+};
+
+use chrono::{DateTime, Utc};
+
+mod bundle;
+mod local;
+mod memory;
+mod remote;
+mod s3;
+mod sftp;
+
+pub(crate) use bundle::{BundleReader, BundleWriter};
+pub use local::LocalStorage;
+pub use memory::MemoryStorage;
+pub use remote::{is_remote, RemoteStorage, SSH_SCHEME};
+pub use s3::{S3Credentials, S3Storage};
+pub use sftp::{SftpStorage, SFTP_SCHEME};
+
+pub const CONTENT_DIR: &str = "content";
+pub const INFO_DIR: &str = "info";
+pub const LOCKS_DIR: &str = "locks";
+
+pub trait Storage: std::fmt::Debug + Send + Sync {
+    /// Stores `data` under `key`, replacing any previous object. Readers see
+    /// either the old object or the complete new one.
+    fn put(&self, key: &str, data: &[u8]) -> anyhow::Result<()>;
+
+    /// Stores the contents of a local file under `key` and consumes the file.
+    /// Files created in [`Storage::staging_dir`] can be moved into place
+    /// without being copied.
+    fn put_file(&self, key: &str, file: &Path) -> anyhow::Result<()> {
+        let data = std::fs::read(file)?;
+        self.put(key, &data)?;
+        std::fs::remove_file(file)?;
+        Ok(())
+    }
+
+    fn get(&self, key: &str) -> anyhow::Result<Vec<u8>>;
+
+    /// Streams the object under `key`.
+    fn reader(&self, key: &str) -> anyhow::Result<Box<dyn Read + Send + '_>> {
+        Ok(Box::new(Cursor::new(self.get(key)?)))
+    }
+
+    /// Returns the keys of the objects directly inside `dir`, e.g. `content`
+    /// or a snapshot prefix. A directory that does not exist is empty.
+    fn list(&self, dir: &str) -> anyhow::Result<Vec<String>>;
+
+    /// Removes the object under `key`. Removing a missing object succeeds.
+    fn delete(&self, key: &str) -> anyhow::Result<()>;
+
+    fn exists(&self, key: &str) -> anyhow::Result<bool>;
+
+    /// Returns the keys in `keys` that are not stored. Remote backends answer
+    /// this in a single round trip, so callers with many candidate objects
+    /// should prefer it over repeated `exists` calls.
+    fn missing(&self, keys: &[String]) -> anyhow::Result<Vec<String>> {
+        let mut missing = Vec::new();
+        for key in keys {
+            if !self.exists(key)? {
+                missing.push(key.clone());
+            }
+        }
+        Ok(missing)
+    }
+
+    /// When the object under `key` was last written, if the backend knows.
+    fn modified(&self, _key: &str) -> Option<DateTime<Utc>> {
+        None
+    }
+
+    /// Local directory where content is assembled before [`Storage::put_file`].
+    fn staging_dir(&self) -> PathBuf {
+        std::env::temp_dir()
+    }
+
+    /// Cleans up whatever an interrupted run left behind and returns how many
+    /// objects were removed. Callers hold the exclusive lock, so no other
+    /// writer can be mid-write; lock objects themselves must be left alone.
+    fn remove_leftovers(&self) -> usize {
+        0
+    }
+}
+
+pub fn content_key(hash: &str) -> String {
+    format!("{}/{}.dat", CONTENT_DIR, hash)
+}
+
+pub fn manifest_key(prefix: &str, number: u32) -> String {
+    format!("{}/backup_{}.json", prefix, number)
+}
+
+pub fn info_key(prefix: &str) -> String {
+    format!("{}/{}.json", INFO_DIR, prefix)
+}
+
+pub fn lock_key(id: &str) -> String {
+    format!("{}/{}.json", LOCKS_DIR, id)
+}
+
+/// Rejects keys that could escape the repository.
+fn check_key(key: &str) -> anyhow::Result<()> {
+    let valid = !key.is_empty()
+        && !key.starts_with('/')
+        && key
+            .split('/')
+            .all(|part| !part.is_empty() && part != "." && part != "..");
+    if valid {
+        Ok(())
+    } else {
+        Err(anyhow::anyhow!("Invalid storage key: {:?}", key))
+    }
+}
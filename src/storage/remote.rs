@@ -0,0 +1,316 @@
+//! Client for repositories served by `snapback serve`.
+//!
+//! `ssh://[user@]host[:port][/path]` runs `snapback serve` on the host over
+//! `ssh` and speaks the protocol on its stdin and stdout; the path, when
+//! given, is passed as `--repo`. `http://` and `https://` locations talk to
+//! `snapback serve --http`.
+
+use std::{
+    fs::{self, File},
+    io::{self, BufRead, BufReader, BufWriter, Cursor, Read, Write},
+    path::Path,
+    process::{Child, ChildStdin, ChildStdout, Command, Stdio},
+    sync::Mutex,
+};
+
+use chrono::{DateTime, Utc};
+
+use super::{check_key, Storage};
+use crate::{
+    serve::protocol::{self, Request, Response, PROTOCOL_VERSION},
+    util::spool::Spooled,
+};
+
+pub const SSH_SCHEME: &str = "ssh://";
+
+/// Whether `location` names a repository behind `snapback serve`.
+pub fn is_remote(location: &str) -> bool {
+    [SSH_SCHEME, "http://", "https://"]
+        .iter()
+        .any(|scheme| location.starts_with(scheme))
+}
+
+pub struct RemoteStorage {
+    location: String,
+    transport: Transport,
+}
+
+enum Transport {
+    Stdio(Mutex<Connection>),
+    Http {
+        agent: ureq::Agent,
+        url: String,
+        token: Option<String>,
+    },
+}
+
+struct Connection {
+    child: Child,
+    input: Option<BufWriter<ChildStdin>>,
+    output: BufReader<ChildStdout>,
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        // Closing stdin tells the server to exit.
+        drop(self.input.take());
+        let _ = self.child.wait();
+    }
+}
+
+impl std::fmt::Debug for RemoteStorage {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("RemoteStorage")
+            .field("location", &self.location)
+            .finish_non_exhaustive()
+    }
+}
+
+impl RemoteStorage {
+    /// Connects to `location`. HTTP servers that require a token get the one
+    /// in `SNAPBACK_SERVE_TOKEN`. `SNAPBACK_REMOTE_COMMAND` overrides the
+    /// program run on ssh hosts, `snapback` by default.
+    pub fn connect(location: &str) -> anyhow::Result<Self> {
+        if !location.starts_with(SSH_SCHEME) {
+            let storage = Self {
+                location: location.to_string(),
+                transport: Transport::Http {
+                    agent: ureq::AgentBuilder::new().build(),
+                    url: location.trim_end_matches('/').to_string() + "/",
+                    token: std::env::var("SNAPBACK_SERVE_TOKEN").ok(),
+                },
+            };
+            storage.hello()?;
+            return Ok(storage);
+        }
+
+        let url = url::Url::parse(location)
+            .map_err(|e| anyhow::anyhow!("Invalid remote location {}: {}", location, e))?;
+        let host = url
+            .host_str()
+            .ok_or_else(|| anyhow::anyhow!("Remote location {} has no host", location))?;
+        let destination = match url.username() {
+            "" => host.to_string(),
+            user => format!("{}@{}", user, host),
+        };
+
+        let program =
+            std::env::var("SNAPBACK_REMOTE_COMMAND").unwrap_or_else(|_| "snapback".to_string());
+        let mut remote_command = format!("{} serve", program);
+        let path = percent_encoding::percent_decode_str(url.path()).decode_utf8_lossy();
+        if !path.is_empty() && path != "/" {
+            let path = path.strip_prefix("/~/").unwrap_or(&path);
+            remote_command.push_str(&format!(" --repo {}", shell_quote(path)));
+        }
+
+        let mut command = Command::new("ssh");
+        if let Some(port) = url.port() {
+            command.arg("-p").arg(port.to_string());
+        }
+        command.arg(destination).arg(remote_command);
+        Self::spawn(location, command)
+    }
+
+    /// Runs `command` and speaks the protocol on its stdin and stdout. Its
+    /// stderr is passed through.
+    pub fn spawn(location: &str, mut command: Command) -> anyhow::Result<Self> {
+        let mut child = command
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::inherit())
+            .spawn()
+            .map_err(|e| anyhow::anyhow!("Cannot start {:?}: {}", command, e))?;
+        let input = child.stdin.take().map(BufWriter::new);
+        let output = child
+            .stdout
+            .take()
+            .map(BufReader::new)
+            .ok_or_else(|| anyhow::anyhow!("{:?} has no stdout", command))?;
+
+        let storage = Self {
+            location: location.to_string(),
+            transport: Transport::Stdio(Mutex::new(Connection {
+                child,
+                input,
+                output,
+            })),
+        };
+        storage.hello()?;
+        Ok(storage)
+    }
+
+    fn hello(&self) -> anyhow::Result<()> {
+        let request = Request::Hello {
+            version: PROTOCOL_VERSION,
+        };
+        self.call(&request, None, None).map_err(|e| {
+            anyhow::anyhow!("Cannot open remote repository {}: {:#}", self.location, e)
+        })?;
+        Ok(())
+    }
+
+    /// Sends `request`, followed by `payload` when the request carries one,
+    /// and returns the response. A response payload is written to `sink`.
+    fn call(
+        &self,
+        request: &Request,
+        payload: Option<&mut dyn Read>,
+        sink: Option<&mut dyn Write>,
+    ) -> anyhow::Result<Response> {
+        let mut discard = io::sink();
+        let sink = sink.unwrap_or(&mut discard);
+        let mut empty = io::empty();
+        let payload = payload.unwrap_or(&mut empty);
+
+        let response = match &self.transport {
+            Transport::Stdio(connection) => {
+                let mut connection = connection
+                    .lock()
+                    .map_err(|_| anyhow::anyhow!("Remote connection is unusable"))?;
+                let Connection { input, output, .. } = &mut *connection;
+                let input = input
+                    .as_mut()
+                    .ok_or_else(|| anyhow::anyhow!("Remote connection is closed"))?;
+
+                protocol::write_message(input, request)?;
+                protocol::copy_payload(payload, request.payload_size(), input)?;
+                input.flush()?;
+                receive(output, sink)?
+            }
+            Transport::Http { agent, url, token } => {
+                let mut line = serde_json::to_vec(request)?;
+                line.push(b'\n');
+                let length = line.len() as u64 + request.payload_size();
+                let body = Cursor::new(line).chain(payload.take(request.payload_size()));
+
+                let mut call = agent.post(url).set("Content-Length", &length.to_string());
+                if let Some(token) = token {
+                    call = call.set("Authorization", &format!("Bearer {}", token));
+                }
+                let response = match call.send(body) {
+                    Ok(response) => response,
+                    Err(ureq::Error::Status(code, response)) => {
+                        let message = response.into_string().unwrap_or_default();
+                        return Err(anyhow::anyhow!(
+                            "{} answered {}: {}",
+                            self.location,
+                            code,
+                            message.trim()
+                        ));
+                    }
+                    Err(e) => return Err(e.into()),
+                };
+                receive(&mut BufReader::new(response.into_reader()), sink)?
+            }
+        };
+        response.into_result()
+    }
+
+    fn keys(&self, request: &Request) -> anyhow::Result<Vec<String>> {
+        Ok(self.call(request, None, None)?.keys.unwrap_or_default())
+    }
+}
+
+/// Reads a response line and its payload.
+fn receive(output: &mut dyn BufRead, sink: &mut dyn Write) -> anyhow::Result<Response> {
+    let response: Response = protocol::read_message(output)?
+        .ok_or_else(|| anyhow::anyhow!("Remote repository closed the connection"))?;
+    if let Some(size) = response.size {
+        protocol::copy_payload(output, size, sink)?;
+    }
+    Ok(response)
+}
+
+impl Storage for RemoteStorage {
+    fn put(&self, key: &str, data: &[u8]) -> anyhow::Result<()> {
+        check_key(key)?;
+        let request = Request::Put {
+            key: key.to_string(),
+            size: data.len() as u64,
+        };
+        self.call(&request, Some(&mut &data[..]), None)?;
+        Ok(())
+    }
+
+    fn put_file(&self, key: &str, file: &Path) -> anyhow::Result<()> {
+        check_key(key)?;
+        let mut source = File::open(file)?;
+        let request = Request::Put {
+            key: key.to_string(),
+            size: source.metadata()?.len(),
+        };
+        self.call(&request, Some(&mut source), None)?;
+        fs::remove_file(file)?;
+        Ok(())
+    }
+
+    fn get(&self, key: &str) -> anyhow::Result<Vec<u8>> {
+        check_key(key)?;
+        let mut data = Vec::new();
+        let request = Request::Get {
+            key: key.to_string(),
+        };
+        self.call(&request, None, Some(&mut data))?;
+        Ok(data)
+    }
+
+    /// Downloads into a temp file, so large objects do not sit in memory.
+    fn reader(&self, key: &str) -> anyhow::Result<Box<dyn Read + Send + '_>> {
+        check_key(key)?;
+        let mut spooled = Spooled::new()?;
+        let request = Request::Get {
+            key: key.to_string(),
+        };
+        self.call(&request, None, Some(&mut spooled))?;
+        spooled.rewind()?;
+        Ok(Box::new(spooled))
+    }
+
+    fn list(&self, dir: &str) -> anyhow::Result<Vec<String>> {
+        check_key(dir)?;
+        self.keys(&Request::List {
+            dir: dir.to_string(),
+        })
+    }
+
+    fn delete(&self, key: &str) -> anyhow::Result<()> {
+        check_key(key)?;
+        let request = Request::Delete {
+            key: key.to_string(),
+        };
+        self.call(&request, None, None)?;
+        Ok(())
+    }
+
+    fn exists(&self, key: &str) -> anyhow::Result<bool> {
+        Ok(self.missing(&[key.to_string()])?.is_empty())
+    }
+
+    fn missing(&self, keys: &[String]) -> anyhow::Result<Vec<String>> {
+        for key in keys {
+            check_key(key)?;
+        }
+        self.keys(&Request::Want {
+            keys: keys.to_vec(),
+        })
+    }
+
+    fn modified(&self, key: &str) -> Option<DateTime<Utc>> {
+        let request = Request::Modified {
+            key: key.to_string(),
+        };
+        self.call(&request, None, None).ok()?.modified
+    }
+
+    fn remove_leftovers(&self) -> usize {
+        self.call(&Request::RemoveLeftovers, None, None)
+            .ok()
+            .and_then(|response| response.removed)
+            .unwrap_or(0)
+    }
+}
+
+/// Quotes `value` for the remote shell that ssh hands the command to.
+fn shell_quote(value: &str) -> String {
+    format!("'{}'", value.replace('\'', r"'\''"))
+}
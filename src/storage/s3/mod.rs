@@ -0,0 +1,423 @@
+//! S3-compatible object storage: AWS S3, MinIO and anything else that speaks
+//! the S3 REST API with SigV4 signatures. Buckets are addressed path-style
+//! (`<endpoint>/<bucket>/<key>`), which every implementation supports.
+
+use std::{
+    fs::{self, File},
+    io::Read,
+    path::Path,
+    thread,
+    time::Duration,
+};
+
+use chrono::{DateTime, Utc};
+
+use super::{check_key, Storage};
+use crate::config::S3Config;
+
+mod sign;
+
+/// Objects larger than this are uploaded in parts of this size.
+const PART_SIZE: u64 = 8 * 1024 * 1024;
+
+/// Attempts per request before a transient failure is reported.
+const MAX_ATTEMPTS: u32 = 5;
+
+#[derive(Clone)]
+pub struct S3Credentials {
+    pub access_key: String,
+    pub secret_key: String,
+    pub session_token: Option<String>,
+}
+
+impl S3Credentials {
+    /// Reads the standard `AWS_ACCESS_KEY_ID`, `AWS_SECRET_ACCESS_KEY` and
+    /// `AWS_SESSION_TOKEN` variables.
+    pub fn from_env() -> anyhow::Result<Self> {
+        let var = |name: &str| std::env::var(name).ok().filter(|value| !value.is_empty());
+        match (var("AWS_ACCESS_KEY_ID"), var("AWS_SECRET_ACCESS_KEY")) {
+            (Some(access_key), Some(secret_key)) => Ok(Self {
+                access_key,
+                secret_key,
+                session_token: var("AWS_SESSION_TOKEN"),
+            }),
+            _ => Err(anyhow::anyhow!(
+                "S3 storage needs AWS_ACCESS_KEY_ID and AWS_SECRET_ACCESS_KEY to be set"
+            )),
+        }
+    }
+}
+
+impl std::fmt::Debug for S3Credentials {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("S3Credentials")
+            .field("access_key", &self.access_key)
+            .finish_non_exhaustive()
+    }
+}
+
+pub struct S3Storage {
+    agent: ureq::Agent,
+    scheme: String,
+    host: String,
+    /// Path of the endpoint plus the bucket, e.g. `/my-bucket`.
+    bucket_path: String,
+    /// Key prefix inside the bucket, empty or ending in `/`.
+    prefix: String,
+    region: String,
+    credentials: S3Credentials,
+}
+
+impl std::fmt::Debug for S3Storage {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("S3Storage")
+            .field("endpoint", &format!("{}://{}", self.scheme, self.host))
+            .field("bucket_path", &self.bucket_path)
+            .field("prefix", &self.prefix)
+            .field("region", &self.region)
+            .finish_non_exhaustive()
+    }
+}
+
+impl S3Storage {
+    pub fn new(config: &S3Config, credentials: S3Credentials) -> anyhow::Result<Self> {
+        let endpoint = url::Url::parse(&config.endpoint)
+            .map_err(|e| anyhow::anyhow!("Invalid S3 endpoint {}: {}", config.endpoint, e))?;
+        let host = match (endpoint.host_str(), endpoint.port()) {
+            (Some(host), Some(port)) => format!("{}:{}", host, port),
+            (Some(host), None) => host.to_string(),
+            (None, _) => {
+                return Err(anyhow::anyhow!(
+                    "S3 endpoint {} has no host",
+                    config.endpoint
+                ))
+            }
+        };
+        if config.bucket.is_empty() {
+            return Err(anyhow::anyhow!("S3 bucket is not configured"));
+        }
+
+        let prefix = config
+            .prefix
+            .as_deref()
+            .map(|prefix| prefix.trim_matches('/'))
+            .filter(|prefix| !prefix.is_empty())
+            .map(|prefix| format!("{}/", prefix))
+            .unwrap_or_default();
+
+        Ok(Self {
+            agent: ureq::AgentBuilder::new()
+                .timeout_connect(Duration::from_secs(30))
+                .timeout_read(Duration::from_secs(300))
+                .build(),
+            scheme: endpoint.scheme().to_string(),
+            host,
+            bucket_path: format!(
+                "{}/{}",
+                endpoint.path().trim_end_matches('/'),
+                sign::encode(&config.bucket, false)
+            ),
+            prefix,
+            region: config
+                .region
+                .clone()
+                .unwrap_or_else(|| "us-east-1".to_string()),
+            credentials,
+        })
+    }
+
+    fn object_path(&self, key: &str) -> String {
+        format!(
+            "{}/{}",
+            self.bucket_path,
+            sign::encode(&format!("{}{}", self.prefix, key), true)
+        )
+    }
+
+    /// Sends a signed request, retrying throttling, server errors and network
+    /// failures with exponential backoff. A 404 comes back as `None`.
+    fn send(
+        &self,
+        method: &str,
+        path: &str,
+        query: &[(&str, &str)],
+        body: &[u8],
+    ) -> anyhow::Result<Option<ureq::Response>> {
+        let query: Vec<(String, String)> = query
+            .iter()
+            .map(|(key, value)| (sign::encode(key, false), sign::encode(value, false)))
+            .collect();
+        let mut url = format!("{}://{}{}", self.scheme, self.host, path);
+        if !query.is_empty() {
+            let pairs: Vec<_> = query.iter().map(|(k, v)| format!("{}={}", k, v)).collect();
+            url.push('?');
+            url.push_str(&pairs.join("&"));
+        }
+        let payload_hash = sign::hex_sha256(body);
+
+        let mut attempt = 1;
+        loop {
+            let request = sign::Request {
+                method,
+                host: &self.host,
+                path,
+                query: &query,
+                payload_hash: &payload_hash,
+            };
+            let credentials = sign::Credentials {
+                access_key: &self.credentials.access_key,
+                secret_key: &self.credentials.secret_key,
+                session_token: self.credentials.session_token.as_deref(),
+            };
+
+            let mut call = self.agent.request(method, &url);
+            for (name, value) in sign::sign(&request, &credentials, &self.region, Utc::now()) {
+                call = call.set(name, &value);
+            }
+            let result = if matches!(method, "PUT" | "POST") {
+                call.send_bytes(body)
+            } else {
+                call.call()
+            };
+
+            let retryable = match result {
+                Ok(response) => return Ok(Some(response)),
+                Err(ureq::Error::Status(404, _)) => return Ok(None),
+                Err(ureq::Error::Status(code, response)) => {
+                    if attempt >= MAX_ATTEMPTS || !(code == 429 || code >= 500) {
+                        return Err(anyhow::anyhow!(
+                            "S3 {} {} failed with status {}{}",
+                            method,
+                            path,
+                            code,
+                            error_code(response)
+                                .map(|code| format!(" ({})", code))
+                                .unwrap_or_default()
+                        ));
+                    }
+                    true
+                }
+                Err(ureq::Error::Transport(e)) => {
+                    if attempt >= MAX_ATTEMPTS {
+                        return Err(anyhow::anyhow!("S3 {} {} failed: {}", method, path, e));
+                    }
+                    true
+                }
+            };
+
+            if retryable {
+                thread::sleep(Duration::from_millis(100 << attempt));
+                attempt += 1;
+            }
+        }
+    }
+
+    fn head(&self, key: &str) -> anyhow::Result<Option<ureq::Response>> {
+        check_key(key)?;
+        self.send("HEAD", &self.object_path(key), &[], &[])
+    }
+
+    /// Uploads `file` in `PART_SIZE` parts. An upload that fails midway is
+    /// aborted so the bucket does not keep the parts around.
+    fn put_multipart(&self, key: &str, file: &Path) -> anyhow::Result<()> {
+        let path = self.object_path(key);
+        let response = self
+            .send("POST", &path, &[("uploads", "")], &[])?
+            .ok_or_else(|| anyhow::anyhow!("S3 bucket not found"))?;
+        let upload_id = xml_values(&read_body(response)?, "UploadId")
+            .into_iter()
+            .next()
+            .ok_or_else(|| anyhow::anyhow!("S3 did not return an upload id for {}", key))?;
+
+        let result = (|| {
+            let mut source = File::open(file)?;
+            let mut parts = Vec::new();
+            loop {
+                let mut part = Vec::with_capacity(PART_SIZE as usize);
+                (&mut source).take(PART_SIZE).read_to_end(&mut part)?;
+                if part.is_empty() && !parts.is_empty() {
+                    break;
+                }
+
+                let number = (parts.len() + 1).to_string();
+                let response = self
+                    .send(
+                        "PUT",
+                        &path,
+                        &[("partNumber", &number), ("uploadId", &upload_id)],
+                        &part,
+                    )?
+                    .ok_or_else(|| anyhow::anyhow!("S3 upload {} disappeared", upload_id))?;
+                let etag = response
+                    .header("ETag")
+                    .ok_or_else(|| {
+                        anyhow::anyhow!("S3 did not return an ETag for part {}", number)
+                    })?
+                    .to_string();
+                parts.push(format!(
+                    "<Part><PartNumber>{}</PartNumber><ETag>{}</ETag></Part>",
+                    number,
+                    xml_escape(&etag)
+                ));
+                if (part.len() as u64) < PART_SIZE {
+                    break;
+                }
+            }
+
+            let body = format!(
+                "<CompleteMultipartUpload>{}</CompleteMultipartUpload>",
+                parts.concat()
+            );
+            let response = self
+                .send("POST", &path, &[("uploadId", &upload_id)], body.as_bytes())?
+                .ok_or_else(|| anyhow::anyhow!("S3 upload {} disappeared", upload_id))?;
+            // Completion can fail after the 200 status has been sent.
+            let body = read_body(response)?;
+            if let Some(code) = xml_values(&body, "Code").into_iter().next() {
+                return Err(anyhow::anyhow!(
+                    "S3 could not complete the upload of {}: {}",
+                    key,
+                    code
+                ));
+            }
+            Ok(())
+        })();
+
+        if result.is_err() {
+            let _ = self.send("DELETE", &path, &[("uploadId", &upload_id)], &[]);
+        }
+        result
+    }
+}
+
+impl Storage for S3Storage {
+    fn put(&self, key: &str, data: &[u8]) -> anyhow::Result<()> {
+        check_key(key)?;
+        self.send("PUT", &self.object_path(key), &[], data)?
+            .ok_or_else(|| anyhow::anyhow!("S3 bucket not found"))?;
+        Ok(())
+    }
+
+    fn put_file(&self, key: &str, file: &Path) -> anyhow::Result<()> {
+        check_key(key)?;
+        if fs::metadata(file)?.len() > PART_SIZE {
+            self.put_multipart(key, file)?;
+        } else {
+            self.put(key, &fs::read(file)?)?;
+        }
+        fs::remove_file(file)?;
+        Ok(())
+    }
+
+    fn get(&self, key: &str) -> anyhow::Result<Vec<u8>> {
+        let mut data = Vec::new();
+        self.reader(key)?.read_to_end(&mut data)?;
+        Ok(data)
+    }
+
+    fn reader(&self, key: &str) -> anyhow::Result<Box<dyn Read + Send + '_>> {
+        check_key(key)?;
+        match self.send("GET", &self.object_path(key), &[], &[])? {
+            Some(response) => Ok(Box::new(response.into_reader())),
+            None => Err(anyhow::anyhow!("No object stored under {}", key)),
+        }
+    }
+
+    fn list(&self, dir: &str) -> anyhow::Result<Vec<String>> {
+        check_key(dir)?;
+        let list_prefix = format!("{}{}/", self.prefix, dir);
+        let mut keys = Vec::new();
+        let mut token: Option<String> = None;
+        loop {
+            let mut query = vec![
+                ("list-type", "2"),
+                ("prefix", list_prefix.as_str()),
+                ("delimiter", "/"),
+            ];
+            if let Some(token) = &token {
+                query.push(("continuation-token", token.as_str()));
+            }
+            let response = self
+                .send("GET", &self.bucket_path, &query, &[])?
+                .ok_or_else(|| anyhow::anyhow!("S3 bucket not found"))?;
+            let body = read_body(response)?;
+
+            keys.extend(
+                xml_values(&body, "Key")
+                    .into_iter()
+                    .filter_map(|key| key.strip_prefix(&self.prefix).map(str::to_string)),
+            );
+            let truncated =
+                xml_values(&body, "IsTruncated").first().map(String::as_str) == Some("true");
+            token = xml_values(&body, "NextContinuationToken")
+                .into_iter()
+                .next();
+            if !truncated || token.is_none() {
+                break;
+            }
+        }
+        keys.sort();
+        Ok(keys)
+    }
+
+    fn delete(&self, key: &str) -> anyhow::Result<()> {
+        check_key(key)?;
+        self.send("DELETE", &self.object_path(key), &[], &[])?;
+        Ok(())
+    }
+
+    fn exists(&self, key: &str) -> anyhow::Result<bool> {
+        Ok(self.head(key)?.is_some())
+    }
+
+    fn modified(&self, key: &str) -> Option<DateTime<Utc>> {
+        let response = self.head(key).ok()??;
+        let modified = DateTime::parse_from_rfc2822(response.header("Last-Modified")?).ok()?;
+        Some(modified.with_timezone(&Utc))
+    }
+}
+
+fn read_body(response: ureq::Response) -> anyhow::Result<String> {
+    let mut body = String::new();
+    response.into_reader().read_to_string(&mut body)?;
+    Ok(body)
+}
+
+fn error_code(response: ureq::Response) -> Option<String> {
+    xml_values(&read_body(response).ok()?, "Code")
+        .into_iter()
+        .next()
+}
+
+/// Returns the text of every `<tag>` element. S3 responses are flat enough
+/// that this is all the XML handling snapback needs.
+fn xml_values(xml: &str, tag: &str) -> Vec<String> {
+    let open = format!("<{}>", tag);
+    let close = format!("</{}>", tag);
+    let mut values = Vec::new();
+    let mut rest = xml;
+    while let Some(start) = rest.find(&open) {
+        rest = &rest[start + open.len()..];
+        let Some(end) = rest.find(&close) else {
+            break;
+        };
+        values.push(xml_unescape(&rest[..end]));
+        rest = &rest[end + close.len()..];
+    }
+    values
+}
+
+fn xml_unescape(text: &str) -> String {
+    text.replace("&quot;", "\"")
+        .replace("&apos;", "'")
+        .replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&amp;", "&")
+}
+
+fn xml_escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
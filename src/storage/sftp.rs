@@ -0,0 +1,355 @@
+//! Repositories on a remote machine, reached over SFTP.
+//!
+//! The location is a URL such as `sftp://backup@vault.internal/srv/snapback`.
+//! A path starting with `/~/` is taken relative to the remote home directory.
+//! The repository uses the same layout as a `--repo` directory: manifests and
+//! content under the path, info records under `<path>/backup_info`.
+
+use std::{
+    fs::File,
+    io::{self, Read},
+    net::TcpStream,
+    path::{Path, PathBuf},
+};
+
+use chrono::{DateTime, Utc};
+use ssh2::{CheckResult, ErrorCode, KnownHostFileKind, RenameFlags, Session, Sftp};
+use uuid::Uuid;
+
+use super::{check_key, Storage, INFO_DIR, LOCKS_DIR};
+use crate::util::atomic::TEMP_PREFIX;
+
+pub const SFTP_SCHEME: &str = "sftp://";
+
+/// Prefix of an existing file that `rename_over` moved out of the way of its
+/// replacement.
+const ASIDE_PREFIX: &str = ".old-";
+
+/// Key files tried, in order, when no key file is configured and the SSH agent
+/// cannot authenticate.
+const DEFAULT_KEYS: [&str; 3] = ["id_ed25519", "id_ecdsa", "id_rsa"];
+
+pub struct SftpStorage {
+    // The session must outlive the SFTP channel opened on it.
+    sftp: Sftp,
+    _session: Session,
+    location: String,
+    root: PathBuf,
+}
+
+impl std::fmt::Debug for SftpStorage {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SftpStorage")
+            .field("location", &self.location)
+            .finish_non_exhaustive()
+    }
+}
+
+impl SftpStorage {
+    /// Connects to `location`, verifying the host against
+    /// `~/.ssh/known_hosts`. Authentication uses `key_file` when given,
+    /// otherwise the SSH agent and then the usual key files in `~/.ssh`.
+    pub fn connect(location: &str, key_file: Option<&Path>) -> anyhow::Result<Self> {
+        let url = url::Url::parse(location)
+            .map_err(|e| anyhow::anyhow!("Invalid SFTP location {}: {}", location, e))?;
+        let host = url
+            .host_str()
+            .ok_or_else(|| anyhow::anyhow!("SFTP location {} has no host", location))?
+            .to_string();
+        let port = url.port().unwrap_or(22);
+        let user = match url.username() {
+            "" => std::env::var("USER")
+                .map_err(|_| anyhow::anyhow!("SFTP location {} has no user", location))?,
+            user => user.to_string(),
+        };
+        let path = percent_encoding::percent_decode_str(url.path())
+            .decode_utf8_lossy()
+            .to_string();
+        let root = match path.strip_prefix("/~/") {
+            Some(relative) => PathBuf::from(relative),
+            None => PathBuf::from(&path),
+        };
+
+        let tcp = TcpStream::connect((host.as_str(), port))
+            .map_err(|e| anyhow::anyhow!("Cannot connect to {}:{}: {}", host, port, e))?;
+        let mut session = Session::new()?;
+        session.set_tcp_stream(tcp);
+        session
+            .handshake()
+            .map_err(|e| anyhow::anyhow!("SSH handshake with {} failed: {}", host, e))?;
+        verify_host(&session, &host, port)?;
+        authenticate(&session, &user, key_file)?;
+
+        let sftp = session.sftp()?;
+        Ok(Self {
+            sftp,
+            _session: session,
+            location: location.to_string(),
+            root,
+        })
+    }
+
+    fn path(&self, key: &str) -> anyhow::Result<PathBuf> {
+        check_key(key)?;
+        Ok(match key.split_once('/') {
+            Some((INFO_DIR, rest)) => self.root.join("backup_info").join(rest),
+            _ if key == INFO_DIR => self.root.join("backup_info"),
+            _ => self.root.join(key),
+        })
+    }
+
+    fn create_dir_all(&self, dir: &Path) -> anyhow::Result<()> {
+        if dir.as_os_str().is_empty() || self.sftp.stat(dir).is_ok() {
+            return Ok(());
+        }
+        if let Some(parent) = dir.parent() {
+            self.create_dir_all(parent)?;
+        }
+        if let Err(e) = self.sftp.mkdir(dir, 0o755) {
+            // Another process may have created it in the meantime.
+            if self.sftp.stat(dir).is_err() {
+                return Err(anyhow::anyhow!("Cannot create {}: {}", dir.display(), e));
+            }
+        }
+        Ok(())
+    }
+
+    /// Streams `source` into a temp file next to `key`'s path and renames it
+    /// into place, so readers never see a partly written object.
+    fn write(&self, key: &str, source: &mut dyn Read) -> anyhow::Result<()> {
+        let path = self.path(key)?;
+        let dir = path
+            .parent()
+            .ok_or_else(|| anyhow::anyhow!("{} has no parent directory", path.display()))?;
+        self.create_dir_all(dir)?;
+
+        let temp_path = dir.join(format!("{}{}", TEMP_PREFIX, Uuid::new_v4()));
+        let result = (|| {
+            let mut temp = self.sftp.create(&temp_path)?;
+            io::copy(source, &mut temp)?;
+            temp.fsync().ok();
+            drop(temp);
+            self.rename_over(&temp_path, &path)
+        })();
+        if result.is_err() {
+            let _ = self.sftp.unlink(&temp_path);
+        }
+        result
+    }
+
+    /// SFTP version 3, which OpenSSH speaks, refuses to rename over an
+    /// existing file. In that case the old file is renamed aside first and
+    /// removed once the new one is in place, so the old version is never
+    /// lost: if the second rename fails it is moved back, and if the process
+    /// dies in between, [`Storage::remove_leftovers`] moves it back. Only info
+    /// records are ever replaced, under the exclusive lock.
+    fn rename_over(&self, from: &Path, to: &Path) -> anyhow::Result<()> {
+        let flags = Some(RenameFlags::OVERWRITE | RenameFlags::ATOMIC | RenameFlags::NATIVE);
+        if self.sftp.rename(from, to, flags).is_ok() {
+            return Ok(());
+        }
+        let Some(name) = to.file_name().filter(|_| self.sftp.stat(to).is_ok()) else {
+            self.sftp.rename(from, to, flags)?;
+            return Ok(());
+        };
+
+        let aside = to.with_file_name(format!(
+            "{}{}-{}",
+            ASIDE_PREFIX,
+            Uuid::new_v4().simple(),
+            name.to_string_lossy()
+        ));
+        self.sftp.rename(to, &aside, flags)?;
+        if let Err(e) = self.sftp.rename(from, to, flags) {
+            let _ = self.sftp.rename(&aside, to, flags);
+            return Err(e.into());
+        }
+        let _ = self.sftp.unlink(&aside);
+        Ok(())
+    }
+
+    /// Removes the temp files in `dir`. A file left aside by an interrupted
+    /// [`rename_over`](Self::rename_over) is removed if its replacement made
+    /// it into place, and moved back otherwise.
+    fn remove_temp_files(&self, dir: &Path) -> usize {
+        let Ok(entries) = self.sftp.readdir(dir) else {
+            return 0;
+        };
+        entries
+            .iter()
+            .filter(|(path, stat)| stat.is_file() && is_temp(path))
+            .filter(|(path, _)| match set_aside_from(path) {
+                Some(original) if self.sftp.stat(&original).is_err() => {
+                    let _ = self.sftp.rename(path, &original, None);
+                    false
+                }
+                _ => true,
+            })
+            .filter(|(path, _)| self.sftp.unlink(path).is_ok())
+            .count()
+    }
+}
+
+impl Storage for SftpStorage {
+    fn put(&self, key: &str, data: &[u8]) -> anyhow::Result<()> {
+        self.write(key, &mut &data[..])
+    }
+
+    fn put_file(&self, key: &str, file: &Path) -> anyhow::Result<()> {
+        self.write(key, &mut File::open(file)?)?;
+        std::fs::remove_file(file)?;
+        Ok(())
+    }
+
+    fn get(&self, key: &str) -> anyhow::Result<Vec<u8>> {
+        let mut data = Vec::new();
+        self.reader(key)?.read_to_end(&mut data)?;
+        Ok(data)
+    }
+
+    fn reader(&self, key: &str) -> anyhow::Result<Box<dyn Read + Send + '_>> {
+        let path = self.path(key)?;
+        let file = self
+            .sftp
+            .open(&path)
+            .map_err(|e| anyhow::anyhow!("Cannot read {}: {}", path.display(), e))?;
+        Ok(Box::new(file))
+    }
+
+    fn list(&self, dir: &str) -> anyhow::Result<Vec<String>> {
+        let entries = match self.sftp.readdir(&self.path(dir)?) {
+            Ok(entries) => entries,
+            Err(e) if not_found(&e) => return Ok(Vec::new()),
+            Err(e) => return Err(e.into()),
+        };
+
+        let mut keys: Vec<_> = entries
+            .iter()
+            .filter(|(path, stat)| stat.is_file() && !is_temp(path))
+            .filter_map(|(path, _)| path.file_name())
+            .map(|name| format!("{}/{}", dir, name.to_string_lossy()))
+            .collect();
+        keys.sort();
+        Ok(keys)
+    }
+
+    fn delete(&self, key: &str) -> anyhow::Result<()> {
+        match self.sftp.unlink(&self.path(key)?) {
+            Err(e) if !not_found(&e) => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+
+    fn exists(&self, key: &str) -> anyhow::Result<bool> {
+        match self.sftp.stat(&self.path(key)?) {
+            Ok(stat) => Ok(stat.is_file()),
+            Err(e) if not_found(&e) => Ok(false),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn modified(&self, key: &str) -> Option<DateTime<Utc>> {
+        let mtime = self.sftp.stat(&self.path(key).ok()?).ok()?.mtime?;
+        DateTime::from_timestamp(i64::try_from(mtime).ok()?, 0)
+    }
+
+    /// Like local storage, skips `locks/`, where shared lock holders may be
+    /// writing.
+    fn remove_leftovers(&self) -> usize {
+        let locks_dir = self.root.join(LOCKS_DIR);
+        let mut dirs = vec![self.root.clone()];
+        if let Ok(entries) = self.sftp.readdir(&self.root) {
+            dirs.extend(
+                entries
+                    .into_iter()
+                    .filter(|(path, stat)| stat.is_dir() && *path != locks_dir)
+                    .map(|(path, _)| path),
+            );
+        }
+        dirs.iter().map(|dir| self.remove_temp_files(dir)).sum()
+    }
+}
+
+/// `SSH_FX_NO_SUCH_FILE` and `SSH_FX_NO_SUCH_PATH`.
+fn not_found(e: &ssh2::Error) -> bool {
+    matches!(e.code(), ErrorCode::SFTP(2) | ErrorCode::SFTP(10))
+}
+
+/// In-flight files and files set aside while being replaced; neither is
+/// ever listed.
+fn is_temp(path: &Path) -> bool {
+    path.file_name().is_some_and(|name| {
+        let name = name.to_string_lossy();
+        name.starts_with(TEMP_PREFIX) || name.starts_with(ASIDE_PREFIX)
+    })
+}
+
+/// The path a file set aside by `rename_over` was moved from.
+fn set_aside_from(path: &Path) -> Option<PathBuf> {
+    let name = path.file_name()?.to_string_lossy();
+    let (_, original) = name.strip_prefix(ASIDE_PREFIX)?.split_once('-')?;
+    Some(path.with_file_name(original))
+}
+
+/// Refuses hosts whose key is missing from, or does not match,
+/// `~/.ssh/known_hosts`, like `ssh` with `StrictHostKeyChecking=yes`.
+fn verify_host(session: &Session, host: &str, port: u16) -> anyhow::Result<()> {
+    let (key, _) = session
+        .host_key()
+        .ok_or_else(|| anyhow::anyhow!("{} did not send a host key", host))?;
+    let mut known_hosts = session.known_hosts()?;
+    if let Some(file) = dirs::home_dir().map(|home| home.join(".ssh").join("known_hosts")) {
+        if file.exists() {
+            known_hosts.read_file(&file, KnownHostFileKind::OpenSSH)?;
+        }
+    }
+
+    match known_hosts.check_port(host, port, key) {
+        CheckResult::Match => Ok(()),
+        CheckResult::Mismatch => Err(anyhow::anyhow!(
+            "Host key for {} does not match ~/.ssh/known_hosts; refusing to connect",
+            host
+        )),
+        CheckResult::NotFound => Err(anyhow::anyhow!(
+            "{} is not in ~/.ssh/known_hosts; connect once with ssh to add it",
+            host
+        )),
+        CheckResult::Failure => Err(anyhow::anyhow!("Could not verify the host key of {}", host)),
+    }
+}
+
+fn authenticate(session: &Session, user: &str, key_file: Option<&Path>) -> anyhow::Result<()> {
+    if let Some(key_file) = key_file {
+        session
+            .userauth_pubkey_file(user, None, key_file, None)
+            .map_err(|e| {
+                anyhow::anyhow!(
+                    "SSH key {} was not accepted for {}: {}",
+                    key_file.display(),
+                    user,
+                    e
+                )
+            })?;
+        return Ok(());
+    }
+
+    if session.userauth_agent(user).is_ok() {
+        return Ok(());
+    }
+    let ssh_dir = dirs::home_dir().unwrap_or_default().join(".ssh");
+    for name in DEFAULT_KEYS {
+        let key_file = ssh_dir.join(name);
+        if key_file.exists()
+            && session
+                .userauth_pubkey_file(user, None, &key_file, None)
+                .is_ok()
+        {
+            return Ok(());
+        }
+    }
+    Err(anyhow::anyhow!(
+        "SSH authentication failed for {}: no agent identity or key in {} was accepted",
+        user,
+        ssh_dir.display()
+    ))
+}
@@ -0,0 +1,72 @@
+use std::{
+    fs::{self, File},
+    io::Write,
+    path::Path,
+};
+
+use uuid::Uuid;
+
+/// Prefix of in-flight files inside the repository. Anything carrying it was
+/// left behind by an interrupted run.
+pub(crate) const TEMP_PREFIX: &str = ".tmp-";
+
+/// Returns a fresh temp file path next to `target`'s final location.
+pub(crate) fn temp_path_in(dir: &Path) -> std::path::PathBuf {
+    dir.join(format!("{}{}", TEMP_PREFIX, Uuid::new_v4()))
+}
+
+/// Writes `contents` to `path` so readers see either the old file or the
+/// complete new one: data goes to a temp file, is fsynced, then renamed over
+/// `path`, and the directory entry is synced too.
+pub(crate) fn write(path: &Path, contents: impl AsRef<[u8]>) -> anyhow::Result<()> {
+    let dir = path
+        .parent()
+        .ok_or_else(|| anyhow::anyhow!("{} has no parent directory", path.display()))?;
+    fs::create_dir_all(dir)?;
+
+    let temp_path = temp_path_in(dir);
+    let result = File::create(&temp_path)
+        .and_then(|mut file| {
+            file.write_all(contents.as_ref())?;
+            file.sync_all()
+        })
+        .and_then(|_| fs::rename(&temp_path, path));
+    if let Err(e) = result {
+        let _ = fs::remove_file(&temp_path);
+        return Err(e.into());
+    }
+
+    sync_dir(dir);
+    Ok(())
+}
+
+/// Moves a fully written and synced temp file to its final name.
+pub(crate) fn publish(temp_path: &Path, path: &Path) -> anyhow::Result<()> {
+    fs::rename(temp_path, path)?;
+    if let Some(dir) = path.parent() {
+        sync_dir(dir);
+    }
+    Ok(())
+}
+
+/// Best effort: makes a rename in `dir` durable. Not every platform lets a
+/// directory be opened and synced, so failures are ignored.
+fn sync_dir(dir: &Path) {
+    if let Ok(dir) = File::open(dir) {
+        let _ = dir.sync_all();
+    }
+}
+
+/// Deletes temp files left in `dir` by an interrupted run and returns how many
+/// were removed. Missing directories are treated as clean.
+pub(crate) fn remove_leftovers(dir: &Path) -> usize {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return 0;
+    };
+
+    entries
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.file_name().to_string_lossy().starts_with(TEMP_PREFIX))
+        .filter(|entry| fs::remove_file(entry.path()).is_ok())
+        .count()
+}
@@ -1,19 +1,28 @@
-use std::{fs::File, io::Read};
+use std::io::Read;
 
 use sha2::{Digest, Sha256};
 
-pub(crate) fn calculate_file_hash(path: &std::path::Path) -> anyhow::Result<String> {
-      let mut file = File::open(path)?;
-      let mut hasher = Sha256::new();
-      let mut buffer = [0; 8192];
+/// Streams `reader` through SHA-256 and hands every chunk to `sink` as it is
+/// read, so callers can copy the content in the same pass. An error from
+/// `sink` stops the read.
+/// Returns the hex digest and the number of bytes read.
+pub(crate) fn hash_reader_with<R: Read, F: FnMut(&[u8]) -> std::io::Result<()>>(
+    mut reader: R,
+    mut sink: F,
+) -> anyhow::Result<(String, u64)> {
+    let mut hasher = Sha256::new();
+    let mut buffer = [0; 64 * 1024];
+    let mut size = 0u64;
 
-      loop {
-          let bytes_read = file.read(&mut buffer)?;
-          if bytes_read == 0 {
-              break;
-          }
-          hasher.update(&buffer[..bytes_read]);
-      }
+    loop {
+        let bytes_read = reader.read(&mut buffer)?;
+        if bytes_read == 0 {
+            break;
+        }
+        hasher.update(&buffer[..bytes_read]);
+        sink(&buffer[..bytes_read])?;
+        size += bytes_read as u64;
+    }
 
-      Ok(format!("{:x}", hasher.finalize()))
-  }
\ No newline at end of file
+    Ok((format!("{:x}", hasher.finalize()), size))
+}
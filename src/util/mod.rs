@@ -1 +1,3 @@
+pub mod atomic;
 pub mod hash;
+pub mod spool;
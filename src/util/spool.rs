@@ -0,0 +1,70 @@
+use std::{
+    fs::{self, File},
+    io::{self, Read, Seek, SeekFrom, Write},
+    path::PathBuf,
+};
+
+use super::atomic;
+
+/// A temporary file holding a copy of a stream, so it can be sent with a
+/// known length or read after the source is gone. Removed when dropped.
+pub(crate) struct Spooled {
+    file: File,
+    path: PathBuf,
+    len: u64,
+}
+
+impl Spooled {
+    /// Creates an empty spool file in the system temp directory.
+    pub(crate) fn new() -> anyhow::Result<Self> {
+        let path = atomic::temp_path_in(&std::env::temp_dir());
+        let file = File::options()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .open(&path)?;
+        Ok(Self { file, path, len: 0 })
+    }
+
+    /// Copies `reader` into a new spool file, ready to be read back.
+    pub(crate) fn from_reader(reader: &mut dyn Read) -> anyhow::Result<Self> {
+        let mut spooled = Self::new()?;
+        io::copy(reader, &mut spooled)?;
+        spooled.rewind()?;
+        Ok(spooled)
+    }
+
+    /// Moves back to the start so the written data can be read.
+    pub(crate) fn rewind(&mut self) -> io::Result<()> {
+        self.file.flush()?;
+        self.file.seek(SeekFrom::Start(0)).map(|_| ())
+    }
+
+    pub(crate) fn len(&self) -> u64 {
+        self.len
+    }
+}
+
+impl Read for Spooled {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.file.read(buf)
+    }
+}
+
+impl Write for Spooled {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.file.write(buf)?;
+        self.len += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+impl Drop for Spooled {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
@@ -0,0 +1,153 @@
+//! `snapback watch`: continuous backups driven by file system events.
+//!
+//! The watcher collects the paths that changed, waits until they have been
+//! quiet for the debounce window, and then creates a snapshot that only
+//! re-examines those paths (see [`CreateOptions::changed_paths`]). Events
+//! under excluded paths, or inside a local repository kept within the
+//! watched tree, are ignored. When the kernel drops events, the next
+//! snapshot scans the whole tree instead.
+
+use std::{
+    collections::BTreeSet,
+    path::{Path, PathBuf},
+    sync::mpsc::{self, RecvTimeoutError},
+    time::{Duration, Instant},
+};
+
+use notify::{EventKind, RecursiveMode, Watcher};
+
+use crate::{
+    config::Config,
+    repository::{CreateOptions, CreateReport, Repository},
+    storage,
+};
+
+/// How often pending changes are checked against the debounce window.
+const TICK: Duration = Duration::from_millis(250);
+
+#[derive(Debug, Clone)]
+pub struct WatchOptions {
+    /// How long the tree must be quiet before a snapshot is taken.
+    pub debounce: Duration,
+    /// Minimum time between two snapshots, however busy the tree is.
+    pub min_interval: Duration,
+    /// Used for every snapshot; `changed_paths` is filled in by the watcher.
+    pub create: CreateOptions,
+}
+
+impl Default for WatchOptions {
+    fn default() -> Self {
+        Self {
+            debounce: Duration::from_secs(2),
+            min_interval: Duration::from_secs(60),
+            create: CreateOptions::default(),
+        }
+    }
+}
+
+/// Watches `root` and snapshots it after changes settle, handing every
+/// result to `on_snapshot`. A full snapshot is taken first, to pick up
+/// anything that changed while nobody was watching. Only returns if the
+/// watcher itself fails.
+pub fn watch(
+    repo: &Repository,
+    root: &Path,
+    options: &WatchOptions,
+    mut on_snapshot: impl FnMut(anyhow::Result<CreateReport>),
+) -> anyhow::Result<()> {
+    let absolute_root = root
+        .canonicalize()
+        .map_err(|e| anyhow::anyhow!("Cannot watch {}: {}", root.display(), e))?;
+
+    let (tx, rx) = mpsc::channel();
+    let mut watcher = notify::recommended_watcher(tx)?;
+    watcher.watch(&absolute_root, RecursiveMode::Recursive)?;
+
+    let full = CreateOptions {
+        changed_paths: None,
+        ..options.create.clone()
+    };
+    on_snapshot(repo.create_snapshot(root, &full));
+    let mut last_snapshot = Instant::now();
+    // Looked up after the first snapshot, which creates the directories.
+    let ignored = repository_dirs(repo.config(), &absolute_root);
+
+    let mut pending = BTreeSet::new();
+    let mut rescan = false;
+    let mut last_change: Option<Instant> = None;
+    loop {
+        match rx.recv_timeout(TICK) {
+            Ok(Ok(event)) => {
+                if event.need_rescan() {
+                    rescan = true;
+                    last_change = Some(Instant::now());
+                } else if !matches!(event.kind, EventKind::Access(_)) {
+                    for path in &event.paths {
+                        let Ok(relative) = path.strip_prefix(&absolute_root) else {
+                            continue;
+                        };
+                        let excluded = if path.is_dir() {
+                            repo.config().should_exclude_dir(relative)
+                        } else {
+                            repo.config().should_exclude(relative)
+                        };
+                        if excluded || ignored.iter().any(|dir| path.starts_with(dir)) {
+                            continue;
+                        }
+                        if relative.as_os_str().is_empty() {
+                            rescan = true;
+                        } else {
+                            pending.insert(relative.to_path_buf());
+                        }
+                        last_change = Some(Instant::now());
+                    }
+                }
+            }
+            // The watcher lost track of something; only a full scan is safe.
+            Ok(Err(_)) => {
+                rescan = true;
+                last_change = Some(Instant::now());
+            }
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => {
+                return Err(anyhow::anyhow!("The file watcher stopped"));
+            }
+        }
+
+        let settled = last_change.is_some_and(|at| at.elapsed() >= options.debounce);
+        if !settled || last_snapshot.elapsed() < options.min_interval {
+            continue;
+        }
+        let create = CreateOptions {
+            changed_paths: (!rescan).then(|| pending.iter().cloned().collect()),
+            ..options.create.clone()
+        };
+        let result = repo.create_snapshot(root, &create);
+        // Keep the changes for the next attempt if this one failed, e.g.
+        // because another process held the repository lock.
+        if result.is_ok() {
+            pending.clear();
+            rescan = false;
+            last_change = None;
+        }
+        last_snapshot = Instant::now();
+        on_snapshot(result);
+    }
+}
+
+/// Directories of a local repository that lie inside the watched tree.
+/// Writing a snapshot changes them, which must not trigger another one.
+fn repository_dirs(config: &Config, root: &Path) -> Vec<PathBuf> {
+    if config.s3.is_some() {
+        return Vec::new();
+    }
+    let backup_path = config.get_default_backup_path();
+    if storage::is_remote(&backup_path) || backup_path.starts_with(storage::SFTP_SCHEME) {
+        return Vec::new();
+    }
+    [backup_path, config.get_default_backup_info_path()]
+        .iter()
+        .filter_map(|dir| Path::new(dir).canonicalize().ok())
+        .filter(|dir| dir.starts_with(root))
+        .collect()
+}
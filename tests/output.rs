@@ -0,0 +1,129 @@
+//! Checks of the `--json` and `--ndjson` output of the built binary.
+
+use std::{
+    fs,
+    path::Path,
+    process::{Command, Output},
+};
+
+use serde_json::{json, Value};
+
+/// Runs snapback in `dir` on the repository `dir/repo`, away from the
+/// config files of whoever runs the tests.
+fn snapback(dir: &Path, args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_snapback"))
+        .current_dir(dir)
+        .env("HOME", dir)
+        .env("XDG_CONFIG_HOME", dir.join("config"))
+        .env("SNAPBACK_SYSTEM_CONFIG", dir.join("none"))
+        .arg("--repo")
+        .arg(dir.join("repo"))
+        .args(args)
+        .output()
+        .unwrap()
+}
+
+fn parse_lines(output: &Output) -> Vec<Value> {
+    String::from_utf8(output.stdout.clone())
+        .unwrap()
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect()
+}
+
+#[test]
+fn json_prints_one_document() {
+    let dir = tempfile::tempdir().unwrap();
+    fs::create_dir(dir.path().join("data")).unwrap();
+    fs::write(dir.path().join("data/a.txt"), "a").unwrap();
+
+    let output = snapback(dir.path(), &["--json", "create", "data"]);
+    assert!(output.status.success());
+    let document: Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(document["schema_version"], 1);
+    assert_eq!(document["command"], "create");
+    assert_eq!(document["ok"], true);
+    assert!(document.get("type").is_none());
+    assert!(document.get("error").is_none());
+    let backup = &document["result"]["backups"][0];
+    assert_eq!(backup["status"], "complete");
+    assert_eq!(backup["snapshot"]["number"], 0);
+    assert_eq!(
+        backup["changes"],
+        json!([{ "path": "data/a.txt", "kind": "added" }])
+    );
+
+    let output = snapback(dir.path(), &["--json", "list", "data"]);
+    let document: Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(document["command"], "list");
+    assert_eq!(document["result"]["path"], "data");
+    assert_eq!(document["result"]["snapshots"][0]["changes"], 1);
+}
+
+#[test]
+fn ndjson_prints_events_then_the_result() {
+    let dir = tempfile::tempdir().unwrap();
+    fs::create_dir(dir.path().join("data")).unwrap();
+    fs::write(dir.path().join("data/a.txt"), "a").unwrap();
+    assert!(snapback(dir.path(), &["create", "data"]).status.success());
+    fs::write(dir.path().join("data/b.txt"), "b").unwrap();
+    fs::remove_file(dir.path().join("data/a.txt")).unwrap();
+
+    let output = snapback(dir.path(), &["--ndjson", "create", "data"]);
+    assert!(output.status.success());
+    let lines = parse_lines(&output);
+    assert!(lines.iter().all(|line| line["schema_version"] == 1));
+    let types: Vec<_> = lines.iter().map(|line| line["type"].clone()).collect();
+    assert_eq!(types, ["change", "change", "backup", "result"]);
+    assert_eq!(
+        lines[0],
+        json!({
+            "schema_version": 1,
+            "type": "change",
+            "root": "data",
+            "path": "data/b.txt",
+            "kind": "added",
+        })
+    );
+    assert_eq!(lines[1]["kind"], "deleted");
+    assert_eq!(lines[2]["snapshot"]["number"], 1);
+    assert_eq!(lines[3]["command"], "create");
+    assert_eq!(lines[3]["ok"], true);
+    assert_eq!(lines[3]["result"]["backups"][0], {
+        let mut backup = lines[2].clone();
+        let fields = backup.as_object_mut().unwrap();
+        fields.remove("schema_version");
+        fields.remove("type");
+        backup
+    });
+
+    let output = snapback(dir.path(), &["--ndjson", "list", "data"]);
+    let types: Vec<_> = parse_lines(&output)
+        .iter()
+        .map(|line| line["type"].clone())
+        .collect();
+    assert_eq!(types, ["snapshot", "snapshot", "result"]);
+}
+
+#[test]
+fn failures_keep_the_envelope_and_the_exit_status() {
+    let dir = tempfile::tempdir().unwrap();
+    fs::create_dir(dir.path().join("data")).unwrap();
+
+    for (format, document) in [("--json", None), ("--ndjson", Some(json!("result")))] {
+        let output = snapback(dir.path(), &[format, "restore", "0", "data"]);
+        assert!(!output.status.success());
+        let last = match format {
+            "--json" => serde_json::from_slice(&output.stdout).unwrap(),
+            _ => parse_lines(&output).pop().unwrap(),
+        };
+        assert_eq!(last["command"], "restore");
+        assert_eq!(last["ok"], false);
+        assert!(last.get("result").is_none());
+        assert_eq!(
+            last["error"]["message"],
+            "Restore failed: No backup found for path: data"
+        );
+        assert_eq!(last.get("type").cloned(), document);
+    }
+}
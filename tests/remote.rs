@@ -0,0 +1,109 @@
+//! Checks of the remote backends. `snapback serve` is started from the
+//! built binary. The S3 and SFTP checks need real servers, so they are
+//! ignored by default; run them with `cargo test --test remote -- --ignored`
+//! after setting:
+//!
+//! - S3: `SNAPBACK_TEST_S3_ENDPOINT` (e.g. `http://localhost:9000` for
+//!   MinIO), `SNAPBACK_TEST_S3_BUCKET`, `AWS_ACCESS_KEY_ID` and
+//!   `AWS_SECRET_ACCESS_KEY`. Every run uses a fresh key prefix.
+//! - SFTP: `SNAPBACK_TEST_SFTP_URL`, e.g. `sftp://me@localhost/tmp/snapback`,
+//!   and optionally `SNAPBACK_TEST_SSH_KEY`. Every run uses a fresh
+//!   directory below the URL's path. The host must be in `known_hosts`.
+
+use std::{fs, path::Path, process::Command, sync::Arc};
+
+use snapback::{
+    config::S3Config,
+    storage::{RemoteStorage, S3Credentials, S3Storage, SftpStorage},
+    Config, CreateOptions, Repository, RestoreOptions, Storage,
+};
+use uuid::Uuid;
+
+fn env(name: &str) -> String {
+    std::env::var(name).unwrap_or_else(|_| panic!("{} must be set for this test", name))
+}
+
+/// Exercises the [`Storage`] contract, then a backup and restore through
+/// `storage`.
+fn round_trip(storage: Arc<dyn Storage>) {
+    storage.put("info/root.json", b"old").unwrap();
+    storage.put("info/root.json", b"new").unwrap();
+    assert_eq!(storage.get("info/root.json").unwrap(), b"new");
+    assert_eq!(storage.list("info").unwrap(), vec!["info/root.json"]);
+    assert!(storage.list("nothing-here").unwrap().is_empty());
+    let keys = vec!["info/root.json".to_string(), "info/other.json".to_string()];
+    assert_eq!(storage.missing(&keys).unwrap(), vec!["info/other.json"]);
+    storage.delete("info/root.json").unwrap();
+    storage.delete("info/root.json").unwrap();
+    assert!(!storage.exists("info/root.json").unwrap());
+
+    let dir = tempfile::tempdir().unwrap();
+    let root = dir.path().join("project");
+    fs::create_dir_all(root.join("sub")).unwrap();
+    fs::write(root.join("a.txt"), "one").unwrap();
+    fs::write(root.join("sub/b.txt"), "two").unwrap();
+    let repo = Repository::with_storage(Config::default(), storage);
+    repo.create_snapshot(&root, &CreateOptions::default())
+        .unwrap();
+    fs::write(root.join("a.txt"), "changed").unwrap();
+    let second = repo
+        .create_snapshot(&root, &CreateOptions::default())
+        .unwrap();
+    assert_eq!(second.snapshot.unwrap().number, 1);
+
+    let target = dir.path().join("restored");
+    let options = RestoreOptions {
+        target: Some(target.clone()),
+        ..Default::default()
+    };
+    let report = repo.restore(&root, 0, &options).unwrap();
+    assert!(report.failed.is_empty(), "{:?}", report.failed);
+    assert_eq!(fs::read_to_string(target.join("a.txt")).unwrap(), "one");
+    assert_eq!(fs::read_to_string(target.join("sub/b.txt")).unwrap(), "two");
+    assert!(repo.verify(None, &Default::default()).unwrap().is_ok());
+}
+
+#[test]
+#[ignore = "needs an S3 server, see the module docs"]
+fn s3_round_trip() {
+    let config = S3Config {
+        endpoint: env("SNAPBACK_TEST_S3_ENDPOINT"),
+        bucket: env("SNAPBACK_TEST_S3_BUCKET"),
+        prefix: Some(format!("snapback-test-{}", Uuid::new_v4())),
+        region: std::env::var("SNAPBACK_TEST_S3_REGION").ok(),
+    };
+    let storage = S3Storage::new(&config, S3Credentials::from_env().unwrap()).unwrap();
+    round_trip(Arc::new(storage));
+}
+
+#[test]
+#[ignore = "needs an SFTP server, see the module docs"]
+fn sftp_round_trip() {
+    let location = format!(
+        "{}/snapback-test-{}",
+        env("SNAPBACK_TEST_SFTP_URL").trim_end_matches('/'),
+        Uuid::new_v4()
+    );
+    let key = std::env::var("SNAPBACK_TEST_SSH_KEY").ok();
+    let storage = SftpStorage::connect(&location, key.as_deref().map(Path::new)).unwrap();
+    round_trip(Arc::new(storage));
+}
+
+#[test]
+fn serve_round_trip() {
+    let dir = tempfile::tempdir().unwrap();
+    let repo = dir.path().join("repo");
+    // Keep the server away from the config files of whoever runs the tests
+    let mut command = Command::new(env!("CARGO_BIN_EXE_snapback"));
+    command
+        .current_dir(dir.path())
+        .env("HOME", dir.path())
+        .env("XDG_CONFIG_HOME", dir.path().join("config"))
+        .env("SNAPBACK_SYSTEM_CONFIG", dir.path().join("none"))
+        .arg("--repo")
+        .arg(&repo)
+        .arg("serve");
+    let storage = RemoteStorage::spawn("test", command).unwrap();
+    round_trip(Arc::new(storage));
+    assert!(repo.join("content").is_dir());
+}
@@ -0,0 +1,745 @@
+//! End-to-end checks of [`Repository`] on an in-memory repository.
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use chrono::TimeZone;
+use snapback::{
+    config::LoadOptions, lock::RepositoryLock, Config, CopyOptions, CreateOptions,
+    GitExportOptions, ImportOptions, MemoryStorage, Repository, RestoreOptions, SnapshotStatus,
+    Storage,
+};
+
+struct Fixture {
+    repo: Repository,
+    storage: Arc<dyn Storage>,
+    root: PathBuf,
+    dir: tempfile::TempDir,
+}
+
+impl Fixture {
+    fn new() -> Self {
+        Self::with_storage(Arc::new(MemoryStorage::new()))
+    }
+
+    fn with_storage(storage: Arc<dyn Storage>) -> Self {
+        Self::with_config(Config::default(), storage)
+    }
+
+    fn with_config(config: Config, storage: Arc<dyn Storage>) -> Self {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("project");
+        fs::create_dir(&root).unwrap();
+        Self {
+            repo: Repository::with_storage(config, Arc::clone(&storage)),
+            storage,
+            root,
+            dir,
+        }
+    }
+
+    fn write(&self, path: &str, content: &str) {
+        let path = self.root.join(path);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, content).unwrap();
+    }
+
+    /// The paths in the snapshot `create` takes now, relative to the root.
+    fn backed_up(&self) -> Vec<PathBuf> {
+        let report = self.create();
+        let mut paths: Vec<_> = report
+            .changes
+            .iter()
+            .map(|change| {
+                Path::new(&change.path)
+                    .strip_prefix(&self.root)
+                    .unwrap()
+                    .to_path_buf()
+            })
+            .collect();
+        paths.sort();
+        paths
+    }
+
+    fn create(&self) -> snapback::CreateReport {
+        self.repo
+            .create_snapshot(&self.root, &CreateOptions::default())
+            .unwrap()
+    }
+
+    /// Restores snapshot `number` into a fresh directory and returns it.
+    fn restore(&self, number: u32) -> PathBuf {
+        let target = self.dir.path().join(format!("restored-{}", number));
+        let options = RestoreOptions {
+            target: Some(target.clone()),
+            ..Default::default()
+        };
+        let report = self.repo.restore(&self.root, number, &options).unwrap();
+        assert!(report.failed.is_empty(), "{:?}", report.failed);
+        target
+    }
+}
+
+fn read(dir: &Path, path: &str) -> Option<String> {
+    fs::read_to_string(dir.join(path)).ok()
+}
+
+#[test]
+fn restores_every_snapshot() {
+    let fixture = Fixture::new();
+    fixture.write("a.txt", "one");
+    fixture.write("dir/b.txt", "kept");
+    let first = fixture.create();
+    assert_eq!(first.snapshot.unwrap().number, 0);
+    assert_eq!(first.changes.len(), 2);
+
+    fixture.write("a.txt", "two");
+    fs::remove_file(fixture.root.join("dir/b.txt")).unwrap();
+    fixture.write("c.txt", "new");
+    let second = fixture.create().snapshot.unwrap();
+    assert_eq!(
+        (second.number, second.changes(), second.deletions()),
+        (1, 2, 1)
+    );
+
+    let restored = fixture.restore(0);
+    assert_eq!(read(&restored, "a.txt").as_deref(), Some("one"));
+    assert_eq!(read(&restored, "dir/b.txt").as_deref(), Some("kept"));
+    assert_eq!(read(&restored, "c.txt"), None);
+
+    let restored = fixture.restore(1);
+    assert_eq!(read(&restored, "a.txt").as_deref(), Some("two"));
+    assert_eq!(read(&restored, "dir/b.txt"), None);
+    assert_eq!(read(&restored, "c.txt").as_deref(), Some("new"));
+}
+
+#[test]
+fn unchanged_tree_writes_no_snapshot() {
+    let fixture = Fixture::new();
+    fixture.write("a.txt", "one");
+    fixture.create();
+    assert!(fixture.create().snapshot.is_none());
+    assert_eq!(fixture.repo.list_snapshots(&fixture.root).unwrap().len(), 1);
+}
+
+#[test]
+fn excluded_paths_are_not_backed_up() {
+    let fixture = Fixture::new();
+    fixture.write("src/main.rs", "fn main() {}");
+    fixture.write("target/debug/app", "binary");
+    fixture.write("build.log", "noise");
+    fixture.write(".github/ci.yml", "on: push");
+    fixture.write("docs/target", "a file, not a build directory");
+    assert_eq!(
+        fixture.backed_up(),
+        [".github/ci.yml", "docs/target", "src/main.rs"].map(PathBuf::from)
+    );
+
+    // Nothing changes for the backup when only excluded files do
+    fixture.write("target/debug/app", "rebuilt");
+    assert!(fixture.create().snapshot.is_none());
+}
+
+#[test]
+fn profile_excludes_apply_while_it_is_used() {
+    let config: Config = serde_json::from_str(
+        r#"{"profiles": {"work": {"sources": [], "exclude_patterns": ["*.iso"]}}}"#,
+    )
+    .unwrap();
+    let fixture = Fixture::with_config(
+        config.with_profile("work").unwrap(),
+        Arc::new(MemoryStorage::new()),
+    );
+    fixture.write("a.txt", "one");
+    fixture.write("disk.iso", "image");
+    fixture.write("build.log", "noise");
+    assert_eq!(fixture.backed_up(), vec![PathBuf::from("a.txt")]);
+}
+
+#[test]
+fn negated_and_reset_excludes_change_what_is_backed_up() {
+    let load = |file: &str, overrides: &[&str]| {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("config.json");
+        fs::write(&path, file).unwrap();
+        let options = LoadOptions {
+            config_file: Some(path),
+            overrides: overrides.iter().map(|o| o.to_string()).collect(),
+        };
+        Config::load_layered(&options).unwrap().config
+    };
+    let backed_up = |config: Config| {
+        let fixture = Fixture::with_config(config, Arc::new(MemoryStorage::new()));
+        fixture.write("a.txt", "one");
+        fixture.write("build.log", "noise");
+        fixture.write("disk.iso", "image");
+        fixture.write("target/app", "binary");
+        fixture.backed_up()
+    };
+    let paths = |paths: &[&str]| paths.iter().map(PathBuf::from).collect::<Vec<_>>();
+
+    let config = load(r#"{"exclude_patterns": ["*.iso"]}"#, &[]);
+    assert_eq!(backed_up(config), paths(&["a.txt"]));
+
+    // `!*.log` drops the default pattern, so logs are backed up again
+    let config = load(
+        r#"{"exclude_patterns": ["*.iso"]}"#,
+        &[r#"exclude_patterns=["!*.log"]"#],
+    );
+    assert_eq!(backed_up(config), paths(&["a.txt", "build.log"]));
+
+    // `!reset` drops every default; only patterns after it apply
+    let config = load(r#"{"exclude_patterns": ["!reset", "*.iso"]}"#, &[]);
+    assert_eq!(
+        backed_up(config),
+        paths(&["a.txt", "build.log", "target/app"])
+    );
+}
+
+#[test]
+fn identical_files_share_one_object() {
+    let fixture = Fixture::new();
+    fixture.write("a.txt", "same");
+    fixture.write("b.txt", "same");
+    fixture.create();
+    assert_eq!(fixture.storage.list("content").unwrap().len(), 1);
+}
+
+/// Refuses to store content equal to `poison`, as a full disk or a dropped
+/// connection would.
+#[derive(Debug)]
+struct FailingStorage {
+    inner: MemoryStorage,
+    poison: &'static [u8],
+}
+
+impl Storage for FailingStorage {
+    fn put(&self, key: &str, data: &[u8]) -> anyhow::Result<()> {
+        if key.starts_with("content/") && data == self.poison {
+            return Err(anyhow::anyhow!("disk full"));
+        }
+        self.inner.put(key, data)
+    }
+
+    fn get(&self, key: &str) -> anyhow::Result<Vec<u8>> {
+        self.inner.get(key)
+    }
+
+    fn list(&self, dir: &str) -> anyhow::Result<Vec<String>> {
+        self.inner.list(dir)
+    }
+
+    fn delete(&self, key: &str) -> anyhow::Result<()> {
+        self.inner.delete(key)
+    }
+
+    fn exists(&self, key: &str) -> anyhow::Result<bool> {
+        self.inner.exists(key)
+    }
+}
+
+#[test]
+fn failed_files_make_a_partial_snapshot_and_keep_their_previous_version() {
+    let fixture = Fixture::with_storage(Arc::new(FailingStorage {
+        inner: MemoryStorage::new(),
+        poison: b"unstorable",
+    }));
+    fixture.write("a.txt", "one");
+    fixture.write("b.txt", "one");
+    fixture.create();
+
+    fixture.write("a.txt", "unstorable");
+    fixture.write("b.txt", "two");
+    let report = fixture.create();
+    assert!(report.is_partial());
+    let snapshot = report.snapshot.unwrap();
+    assert_eq!(snapshot.status, SnapshotStatus::Partial);
+    assert_eq!(snapshot.failures.len(), 1);
+    assert!(snapshot.failures[0].path.ends_with("a.txt"));
+
+    let options = RestoreOptions {
+        target: Some(fixture.dir.path().join("restored")),
+        ..Default::default()
+    };
+    let report = fixture.repo.restore(&fixture.root, 1, &options).unwrap();
+    assert_eq!(report.not_backed_up.len(), 1);
+    let restored = options.target.unwrap();
+    assert_eq!(read(&restored, "a.txt").as_deref(), Some("one"));
+    assert_eq!(read(&restored, "b.txt").as_deref(), Some("two"));
+}
+
+#[test]
+fn dry_run_restores_nothing() {
+    let fixture = Fixture::new();
+    fixture.write("a.txt", "one");
+    fixture.create();
+
+    let target = fixture.dir.path().join("restored");
+    let options = RestoreOptions {
+        target: Some(target.clone()),
+        dry_run: true,
+    };
+    let report = fixture.repo.restore(&fixture.root, 0, &options).unwrap();
+    assert_eq!(report.restored, vec![target.join("a.txt")]);
+    assert!(!target.exists());
+}
+
+#[test]
+fn held_locks_block_writers_but_not_readers() {
+    let fixture = Fixture::new();
+    fixture.write("a.txt", "one");
+    fixture.create();
+
+    let shared = RepositoryLock::shared(&fixture.storage).unwrap();
+    fixture.write("a.txt", "two");
+    let error = fixture
+        .repo
+        .create_snapshot(&fixture.root, &CreateOptions::default())
+        .unwrap_err();
+    assert!(format!("{:#}", error).contains("Repository is locked"));
+    assert_eq!(fixture.repo.list_snapshots(&fixture.root).unwrap().len(), 1);
+
+    drop(shared);
+    let exclusive = RepositoryLock::exclusive(&fixture.storage).unwrap();
+    assert!(fixture.repo.list_snapshots(&fixture.root).is_err());
+    drop(exclusive);
+    assert!(fixture.create().snapshot.is_some());
+}
+
+#[test]
+fn unreadable_locks_are_held_until_unlocked() {
+    let fixture = Fixture::new();
+    fixture.write("a.txt", "one");
+    fixture.storage.put("locks/broken.json", b"{").unwrap();
+
+    let error = fixture
+        .repo
+        .create_snapshot(&fixture.root, &CreateOptions::default())
+        .unwrap_err();
+    assert!(format!("{:#}", error).contains("snapback unlock --all"));
+
+    let report = fixture.repo.unlock(true).unwrap();
+    assert_eq!(report.unreadable, vec!["locks/broken.json".to_string()]);
+    assert!(fixture.create().snapshot.is_some());
+}
+
+#[test]
+fn prune_folds_old_snapshots_into_the_oldest_kept() {
+    let fixture = Fixture::new();
+    fixture.write("a.txt", "one");
+    fixture.write("b.txt", "kept");
+    fixture.create();
+    fixture.write("a.txt", "two");
+    fixture.create();
+    fixture.write("a.txt", "three");
+    fixture.create();
+
+    let report = fixture.repo.prune(&fixture.root, 1).unwrap();
+    assert_eq!(report.removed, vec![0, 1]);
+    assert_eq!(report.kept, 1);
+    assert_eq!(report.objects_removed, 2);
+
+    let snapshots = fixture.repo.list_snapshots(&fixture.root).unwrap();
+    assert_eq!(snapshots.len(), 1);
+    assert_eq!(snapshots[0].number, 2);
+    let restored = fixture.restore(2);
+    assert_eq!(read(&restored, "a.txt").as_deref(), Some("three"));
+    assert_eq!(read(&restored, "b.txt").as_deref(), Some("kept"));
+    assert!(fixture
+        .repo
+        .restore(&fixture.root, 0, &RestoreOptions::default())
+        .is_err());
+}
+
+/// Writes a tar archive of `files` and returns its path.
+fn tar(dir: &Path, name: &str, files: &[(&str, &str)]) -> PathBuf {
+    let path = dir.join(name);
+    let mut archive = tar::Builder::new(fs::File::create(&path).unwrap());
+    for (file, content) in files {
+        let mut header = tar::Header::new_gnu();
+        header.set_size(content.len() as u64);
+        header.set_mode(0o644);
+        header.set_cksum();
+        archive
+            .append_data(&mut header, file, content.as_bytes())
+            .unwrap();
+    }
+    archive.finish().unwrap();
+    path
+}
+
+#[test]
+fn older_archives_are_refused_and_records_keep_their_time() {
+    let fixture = Fixture::new();
+    let at = |year| chrono::Utc.with_ymd_and_hms(year, 1, 1, 0, 0, 0).unwrap();
+    let import = |name, files: &[(&str, &str)], year| {
+        let archive = tar(fixture.dir.path(), name, files);
+        fixture
+            .repo
+            .import_archive(&archive, &fixture.root, &ImportOptions::new(at(year)))
+    };
+    let first = import("2021.tar", &[("a.txt", "one")], 2021).unwrap();
+    assert_eq!(first.source.timestamp, at(2021));
+    let second = import("2023.tar", &[("a.txt", "three")], 2023).unwrap();
+    assert_eq!(second.snapshot.unwrap().number, 1);
+    assert_eq!(second.source.timestamp, at(2021));
+
+    let error = import("2022.tar", &[("a.txt", "two")], 2022).unwrap_err();
+    assert!(format!("{:#}", error).contains("import archives oldest first"));
+    let snapshots = fixture.repo.list_snapshots(&fixture.root).unwrap();
+    let times: Vec<_> = snapshots
+        .iter()
+        .map(|snapshot| snapshot.timestamp)
+        .collect();
+    assert_eq!(times, vec![at(2021), at(2023)]);
+    assert_eq!(read(&fixture.restore(1), "a.txt").as_deref(), Some("three"));
+}
+
+#[test]
+fn copy_transfers_only_what_the_destination_lacks() {
+    let fixture = Fixture::new();
+    fixture.write("a.txt", "one");
+    fixture.write("b.txt", "kept");
+    fixture.create();
+    fixture.write("a.txt", "two");
+    fixture.create();
+
+    let copy = Fixture::new();
+    let first = CopyOptions {
+        snapshots: Some(vec![0]),
+        ..Default::default()
+    };
+    let report = fixture.repo.copy_to(&copy.repo, &first).unwrap();
+    assert_eq!(report.sources[0].snapshots, vec![0]);
+    assert_eq!((report.objects_copied, report.objects_present), (2, 0));
+
+    let report = fixture
+        .repo
+        .copy_to(&copy.repo, &CopyOptions::default())
+        .unwrap();
+    assert_eq!(report.sources[0].snapshots, vec![1]);
+    assert_eq!(report.objects_copied, 1);
+
+    let report = fixture
+        .repo
+        .copy_to(&copy.repo, &CopyOptions::default())
+        .unwrap();
+    assert!(report.sources[0].snapshots.is_empty());
+    assert_eq!(report.objects_copied, 0);
+
+    let target = copy.dir.path().join("restored");
+    let options = RestoreOptions {
+        target: Some(target.clone()),
+        ..Default::default()
+    };
+    copy.repo.restore(&fixture.root, 1, &options).unwrap();
+    assert_eq!(read(&target, "a.txt").as_deref(), Some("two"));
+    assert_eq!(read(&target, "b.txt").as_deref(), Some("kept"));
+}
+
+#[test]
+fn copy_refuses_corrupt_content_and_foreign_prefixes() {
+    let fixture = Fixture::new();
+    fixture.write("a.txt", "one");
+    fixture.create();
+
+    // The destination already backs up the same directory on its own
+    let destination = Repository::with_storage(Config::default(), Arc::new(MemoryStorage::new()));
+    destination
+        .create_snapshot(&fixture.root, &CreateOptions::default())
+        .unwrap();
+    let error = fixture
+        .repo
+        .copy_to(&destination, &CopyOptions::default())
+        .unwrap_err();
+    assert!(error.to_string().contains("Destination already backs up"));
+
+    let key = fixture.storage.list("content").unwrap().remove(0);
+    fixture.storage.put(&key, b"bit rot").unwrap();
+    let fresh = Repository::with_storage(Config::default(), Arc::new(MemoryStorage::new()));
+    let error = fixture
+        .repo
+        .copy_to(&fresh, &CopyOptions::default())
+        .unwrap_err();
+    assert!(format!("{:#}", error).contains("is corrupt in the source repository"));
+    assert!(fresh.list_snapshots(&fixture.root).is_err());
+}
+
+/// The files of a tar archive, by path.
+fn untar(archive: impl std::io::Read) -> Vec<(String, String)> {
+    let mut archive = tar::Archive::new(archive);
+    let mut files: Vec<_> = archive
+        .entries()
+        .unwrap()
+        .map(|entry| {
+            let mut entry = entry.unwrap();
+            let path = entry.path().unwrap().to_string_lossy().to_string();
+            let mut content = String::new();
+            std::io::Read::read_to_string(&mut entry, &mut content).unwrap();
+            (path, content)
+        })
+        .collect();
+    files.sort();
+    files
+}
+
+#[test]
+fn exports_hold_the_snapshot_as_it_was() {
+    let fixture = Fixture::new();
+    fixture.write("a.txt", "one");
+    fixture.write("dir/b.txt", "kept");
+    fixture.create();
+    fixture.write("a.txt", "two");
+    fs::remove_file(fixture.root.join("dir/b.txt")).unwrap();
+    fixture.create();
+    let pairs = |files: &[(&str, &str)]| -> Vec<(String, String)> {
+        files
+            .iter()
+            .map(|(path, content)| (path.to_string(), content.to_string()))
+            .collect()
+    };
+
+    let mut tar = Vec::new();
+    let report = fixture
+        .repo
+        .export_tar(&fixture.root, 0, false, &mut tar)
+        .unwrap();
+    assert_eq!((report.files, report.bytes), (2, 7));
+    assert_eq!(
+        untar(&tar[..]),
+        pairs(&[("a.txt", "one"), ("dir/b.txt", "kept")])
+    );
+
+    let mut gz = Vec::new();
+    fixture
+        .repo
+        .export_tar(&fixture.root, 1, true, &mut gz)
+        .unwrap();
+    let decoded = flate2::read::GzDecoder::new(&gz[..]);
+    assert_eq!(untar(decoded), pairs(&[("a.txt", "two")]));
+
+    let mut zip = std::io::Cursor::new(Vec::new());
+    fixture.repo.export_zip(&fixture.root, 0, &mut zip).unwrap();
+    let mut archive = zip::ZipArchive::new(zip).unwrap();
+    let mut content = String::new();
+    std::io::Read::read_to_string(&mut archive.by_name("dir/b.txt").unwrap(), &mut content)
+        .unwrap();
+    assert_eq!(archive.len(), 2);
+    assert_eq!(content, "kept");
+
+    assert!(fixture
+        .repo
+        .export_tar(&fixture.root, 2, false, &mut Vec::new())
+        .is_err());
+}
+
+#[test]
+fn bundles_carry_a_root_to_another_repository() {
+    let fixture = Fixture::new();
+    fixture.write("a.txt", "bundled content");
+    fixture.create();
+    fixture.write("b.txt", "more");
+    fixture.create();
+    let bundle = fixture.dir.path().join("project.snapbundle");
+    let report = fixture.repo.create_bundle(&fixture.root, &bundle).unwrap();
+    assert_eq!(report.sources[0].snapshots, vec![0, 1]);
+
+    let other = Fixture::new();
+    let report = other.repo.import_bundle(&bundle).unwrap();
+    assert_eq!(report.objects_copied, 2);
+    assert_eq!(other.repo.list_snapshots(&fixture.root).unwrap().len(), 2);
+    let report = other.repo.import_bundle(&bundle).unwrap();
+    assert!(report.sources[0].snapshots.is_empty());
+    assert_eq!(report.objects_copied, 0);
+}
+
+#[test]
+fn damaged_bundles_are_rejected_before_anything_is_imported() {
+    let fixture = Fixture::new();
+    fixture.write("a.txt", "bundled content");
+    fixture.create();
+    let bundle = fixture.dir.path().join("project.snapbundle");
+    fixture.repo.create_bundle(&fixture.root, &bundle).unwrap();
+    let bytes = fs::read(&bundle).unwrap();
+
+    let damage = |text: &[u8]| {
+        let at = bytes
+            .windows(text.len())
+            .position(|window| window == text)
+            .unwrap();
+        let mut damaged = bytes.clone();
+        damaged[at] ^= 0x20;
+        let path = fixture.dir.path().join("damaged.snapbundle");
+        fs::write(&path, &damaged).unwrap();
+        path
+    };
+    let other = Fixture::new();
+    // Manifests and records are checked against the checksums up front
+    let error = other.repo.import_bundle(&damage(b"a.txt")).unwrap_err();
+    assert!(format!("{:#}", error).contains("checksum mismatch"));
+    // Content objects are checked against their names as they are copied
+    let error = other
+        .repo
+        .import_bundle(&damage(b"bundled content"))
+        .unwrap_err();
+    assert!(format!("{:#}", error).contains("is corrupt"));
+    assert!(other.storage.list("content").unwrap().is_empty());
+    assert!(other.repo.list_snapshots(&fixture.root).is_err());
+
+    let path = fixture.dir.path().join("truncated.snapbundle");
+    fs::write(&path, &bytes[..bytes.len() / 2]).unwrap();
+    assert!(other.repo.import_bundle(&path).is_err());
+    assert!(other.storage.list("info").unwrap().is_empty());
+}
+
+/// A fixture with three snapshots of a small tree, and a delta bundle of
+/// the changes from the first to the last.
+fn delta_fixture() -> (Fixture, PathBuf) {
+    let fixture = Fixture::new();
+    fixture.write("a.txt", "one");
+    fixture.write("b.txt", "kept");
+    fixture.write("gone.txt", "soon deleted");
+    fixture.create();
+    fixture.write("a.txt", "two");
+    fixture.create();
+    fixture.write("c.txt", "new");
+    fs::remove_file(fixture.root.join("gone.txt")).unwrap();
+    fixture.create();
+    let bundle = fixture.dir.path().join("changes.bin");
+    let report = fixture
+        .repo
+        .create_delta_bundle(&fixture.root, 0, 2, &bundle)
+        .unwrap();
+    assert_eq!(report.objects, 2);
+    (fixture, bundle)
+}
+
+#[test]
+fn delta_bundles_bring_a_repository_forward() {
+    let (fixture, bundle) = delta_fixture();
+    let mirror = Fixture::new();
+    let error = mirror.repo.apply_delta_bundle(&bundle).unwrap_err();
+    assert!(format!("{:#}", error).contains("has no backup #0"));
+
+    let first = CopyOptions {
+        snapshots: Some(vec![0]),
+        ..Default::default()
+    };
+    fixture.repo.copy_to(&mirror.repo, &first).unwrap();
+    let report = mirror.repo.apply_delta_bundle(&bundle).unwrap();
+    assert_eq!((report.from, report.to, report.objects), (0, 2, 2));
+    assert_eq!(mirror.repo.list_snapshots(&fixture.root).unwrap().len(), 3);
+    let target = mirror.dir.path().join("restored");
+    let options = RestoreOptions {
+        target: Some(target.clone()),
+        ..Default::default()
+    };
+    mirror.repo.restore(&fixture.root, 2, &options).unwrap();
+    assert_eq!(read(&target, "a.txt").as_deref(), Some("two"));
+    assert_eq!(read(&target, "gone.txt"), None);
+
+    // Applying it again finds everything in place
+    let report = mirror.repo.apply_delta_bundle(&bundle).unwrap();
+    assert_eq!(report.objects, 0);
+    assert_eq!(mirror.repo.list_snapshots(&fixture.root).unwrap().len(), 3);
+}
+
+#[test]
+fn delta_bundles_bring_a_working_tree_forward() {
+    let (fixture, bundle) = delta_fixture();
+    let tree = fixture.restore(0);
+    // Excluded files are not part of the state and stay as they are
+    fs::create_dir(tree.join("target")).unwrap();
+    fs::write(tree.join("target/app"), "binary").unwrap();
+
+    fs::write(tree.join("b.txt"), "edited").unwrap();
+    let config = Config::default();
+    let error = snapback::repository::apply_delta_to_tree(&bundle, &tree, &config).unwrap_err();
+    assert!(format!("{:#}", error).contains("b.txt differs; nothing was applied"));
+    assert_eq!(read(&tree, "a.txt").as_deref(), Some("one"));
+
+    fs::write(tree.join("b.txt"), "kept").unwrap();
+    snapback::repository::apply_delta_to_tree(&bundle, &tree, &config).unwrap();
+    assert_eq!(read(&tree, "a.txt").as_deref(), Some("two"));
+    assert_eq!(read(&tree, "c.txt").as_deref(), Some("new"));
+    assert_eq!(read(&tree, "gone.txt"), None);
+    assert_eq!(read(&tree, "target/app").as_deref(), Some("binary"));
+}
+
+#[test]
+fn git_exports_continue_after_the_last_exported_snapshot() {
+    let fixture = Fixture::new();
+    fixture.write("a.txt", "one");
+    fixture.write("gone.txt", "soon deleted");
+    fixture.create();
+    let dir = fixture.dir.path().join("history");
+    let options = GitExportOptions::default();
+    let report = fixture
+        .repo
+        .export_git(&fixture.root, &dir, &options)
+        .unwrap();
+    assert_eq!(report.exported, vec![0]);
+
+    fixture.write("a.txt", "two");
+    fs::remove_file(fixture.root.join("gone.txt")).unwrap();
+    fixture.create();
+    fixture.write("b.txt", "new");
+    fixture.create();
+    let report = fixture
+        .repo
+        .export_git(&fixture.root, &dir, &options)
+        .unwrap();
+    assert_eq!(report.exported, vec![1, 2]);
+    let report = fixture
+        .repo
+        .export_git(&fixture.root, &dir, &options)
+        .unwrap();
+    assert!(report.exported.is_empty());
+
+    // The checked-out branch follows the last snapshot
+    assert_eq!(read(&dir, "a.txt").as_deref(), Some("two"));
+    assert_eq!(read(&dir, "b.txt").as_deref(), Some("new"));
+    assert_eq!(read(&dir, "gone.txt"), None);
+    let git = git2::Repository::open(&dir).unwrap();
+    let mut walk = git.revwalk().unwrap();
+    walk.push_head().unwrap();
+    assert_eq!(walk.count(), 3);
+    let head = git.head().unwrap().peel_to_commit().unwrap();
+    assert!(head.message().unwrap().starts_with("Backup #2 of"));
+
+    // Another root cannot continue this branch
+    let other = Fixture::with_storage(Arc::clone(&fixture.storage));
+    other.write("c.txt", "other");
+    other.create();
+    let error = other
+        .repo
+        .export_git(&other.root, &dir, &options)
+        .unwrap_err();
+    assert!(error.to_string().contains("choose another with --branch"));
+}
+
+#[test]
+fn git_exports_keep_local_changes() {
+    let fixture = Fixture::new();
+    fixture.write("a.txt", "one");
+    fixture.create();
+    let dir = fixture.dir.path().join("history");
+    let options = GitExportOptions::default();
+    fixture
+        .repo
+        .export_git(&fixture.root, &dir, &options)
+        .unwrap();
+
+    fs::write(dir.join("a.txt"), "edited by hand").unwrap();
+    fixture.write("a.txt", "two");
+    fixture.create();
+    let error = fixture
+        .repo
+        .export_git(&fixture.root, &dir, &options)
+        .unwrap_err();
+    assert!(error.to_string().contains("has local changes"));
+    assert_eq!(read(&dir, "a.txt").as_deref(), Some("edited by hand"));
+}